@@ -0,0 +1,220 @@
+//! Benchmarks of the hooks on the hot paths of instrumented programs, which
+//! call them through the C ABI just as the pass does.
+//!
+//! The runtime is linked as an rlib, which needs `std`:
+//!
+//! ```text
+//! cargo bench --features std
+//! cargo bench --features std,shadow-chunk-16k
+//! ```
+//!
+//! The size of shadow chunks is fixed when the runtime is built, so each size
+//! is measured by a run of its own; benchmark IDs name the size that they ran
+//! with, so that the results of different runs can be compared.
+
+use std::ffi::c_void;
+use std::hint::black_box;
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use bsan_rt::{Provenance, SourceInfo};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+extern "C" {
+    fn bsan_malloc(ptr: *mut c_void, size: usize, prov: *mut Provenance);
+    fn bsan_free(ptr: *mut c_void, loc: *const SourceInfo);
+    fn bsan_release_alloc_metadata(lock_address: *mut c_void);
+    fn bsan_store_prov(ptr: *mut c_void, prov: *const Provenance);
+    fn bsan_load_prov(ptr: *const c_void, prov: *mut Provenance);
+    fn bsan_clear_shadow(ptr: *mut c_void, len: usize);
+    fn bsan_retag(ptr: *mut c_void, retag_kind: u8, place_kind: u8) -> u64;
+    fn bsan_read(
+        ptr: *mut c_void,
+        access_size: u64,
+        prov: *const Provenance,
+        loc: *const SourceInfo,
+    );
+    fn bsan_write(
+        ptr: *mut c_void,
+        access_size: u64,
+        prov: *const Provenance,
+        loc: *const SourceInfo,
+    );
+}
+
+// The raw values of `RetagKind::Raw`, `RetagKind::Default` and
+// `PlaceKind::Default`.
+const RETAG_RAW: u8 = 2;
+const RETAG_DEFAULT: u8 = 3;
+const PLACE_DEFAULT: u8 = 2;
+
+const CHUNK_SIZE: &str = if cfg!(feature = "shadow-chunk-16k") {
+    "16k"
+} else if cfg!(feature = "shadow-chunk-32k") {
+    "32k"
+} else if cfg!(feature = "shadow-chunk-128k") {
+    "128k"
+} else {
+    "64k"
+};
+
+const WORD: usize = size_of::<usize>();
+
+/// An allocation that the runtime knows about, freed when dropped.
+struct Alloc {
+    ptr: *mut c_void,
+    prov: Provenance,
+}
+
+impl Alloc {
+    fn new(size: usize) -> Self {
+        unsafe {
+            let ptr = libc::malloc(size);
+            assert!(!ptr.is_null());
+            let mut prov = MaybeUninit::uninit();
+            bsan_malloc(ptr, size, prov.as_mut_ptr());
+            Alloc { ptr, prov: prov.assume_init() }
+        }
+    }
+}
+
+impl Drop for Alloc {
+    fn drop(&mut self) {
+        unsafe {
+            bsan_release_alloc_metadata(self.prov.lock_address);
+            bsan_free(self.ptr, ptr::null());
+            libc::free(self.ptr);
+        }
+    }
+}
+
+// Allocations that are live while another benchmark runs, so that it looks
+// allocations up in a registry of `count` of them. Those of `malloc` are
+// mostly in address order, which the tree is balanced against as they are
+// inserted; every other one is freed to leave gaps between them.
+fn live_allocations(count: usize) -> Vec<Alloc> {
+    let mut allocs: Vec<Alloc> = (0..2 * count).map(|_| Alloc::new(32)).collect();
+    let mut index = 0;
+    allocs.retain(|_| {
+        index += 1;
+        index % 2 == 0
+    });
+    allocs
+}
+
+fn shadow(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("shadow/{CHUNK_SIZE}"));
+    let target = Alloc::new(8);
+    // Strides of a word stay within a chunk, and those of a megabyte move to
+    // another chunk on every access with any of the chunk sizes.
+    const SLOTS: usize = 256;
+    for (name, stride) in [("dense", WORD), ("sparse", 1 << 20)] {
+        let len = SLOTS * stride;
+        let buf = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(buf, libc::MAP_FAILED);
+        let slots: Vec<*mut c_void> =
+            (0..SLOTS).map(|i| unsafe { buf.byte_add(i * stride) }).collect();
+        group.throughput(Throughput::Elements(SLOTS as u64));
+        group.bench_function(BenchmarkId::new("store", name), |b| {
+            b.iter(|| {
+                for &slot in &slots {
+                    unsafe { bsan_store_prov(slot, &target.prov) };
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("load", name), |b| {
+            b.iter(|| {
+                for &slot in &slots {
+                    let mut prov = MaybeUninit::uninit();
+                    unsafe {
+                        bsan_load_prov(slot, prov.as_mut_ptr());
+                        bsan_release_alloc_metadata(black_box(prov.assume_init()).lock_address);
+                    }
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("load_empty", name), |b| {
+            unsafe { bsan_clear_shadow(buf, len) };
+            b.iter(|| {
+                for &slot in &slots {
+                    let mut prov = MaybeUninit::uninit();
+                    unsafe { bsan_load_prov(slot, prov.as_mut_ptr()) };
+                    black_box(prov);
+                }
+            })
+        });
+        unsafe {
+            bsan_clear_shadow(buf, len);
+            libc::munmap(buf, len);
+        }
+    }
+    group.finish();
+}
+
+fn allocation(c: &mut Criterion) {
+    let mut group = c.benchmark_group("allocation");
+    for live in [0, 1_000, 100_000] {
+        let _live = live_allocations(live);
+        group.bench_with_input(BenchmarkId::new("malloc_free", live), &live, |b, _| {
+            b.iter(|| drop(black_box(Alloc::new(64))))
+        });
+    }
+    group.finish();
+}
+
+fn retag(c: &mut Criterion) {
+    let mut group = c.benchmark_group("retag");
+    let target = Alloc::new(64);
+    for (name, kind) in [("raw", RETAG_RAW), ("default", RETAG_DEFAULT)] {
+        group.bench_function(name, |b| {
+            b.iter(|| unsafe { black_box(bsan_retag(target.ptr, kind, PLACE_DEFAULT)) })
+        });
+    }
+    group.finish();
+}
+
+fn access(c: &mut Criterion) {
+    let mut group = c.benchmark_group("access");
+    // Accesses through provenance find the allocation from its metadata, and
+    // hit the thread's access cache when they repeat through one pointer.
+    for pointers in [1, 16, 1024] {
+        let allocs: Vec<Alloc> = (0..pointers).map(|_| Alloc::new(64)).collect();
+        group.throughput(Throughput::Elements(pointers as u64));
+        group.bench_with_input(BenchmarkId::new("read", pointers), &pointers, |b, _| {
+            b.iter(|| {
+                for alloc in &allocs {
+                    unsafe { bsan_read(alloc.ptr.byte_add(8), 8, &alloc.prov, ptr::null()) };
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("write", pointers), &pointers, |b, _| {
+            b.iter(|| {
+                for alloc in &allocs {
+                    unsafe { bsan_write(alloc.ptr.byte_add(8), 8, &alloc.prov, ptr::null()) };
+                }
+            })
+        });
+    }
+    // Accesses without provenance look the allocation up in the registry.
+    group.throughput(Throughput::Elements(1));
+    for live in [1_000, 100_000] {
+        let allocs = live_allocations(live);
+        let target = &allocs[live / 2];
+        group.bench_with_input(BenchmarkId::new("read_by_address", live), &live, |b, _| {
+            b.iter(|| unsafe { bsan_read(black_box(target.ptr), 8, ptr::null(), ptr::null()) })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, shadow, allocation, retag, access);
+criterion_main!(benches);
@@ -0,0 +1,180 @@
+//! Validation of the arguments that instrumented code passes to the hooks.
+//!
+//! By default, the runtime trusts the instrumentation pass, and hooks sanitize
+//! arguments that they can't use: a null output pointer is skipped, an unknown
+//! retag kind is treated as a default retag, and so on. Setting
+//! `BSAN_STRICT_ABI=1` makes every hook validate its arguments instead. The
+//! first violation is reported along with the address of the instrumented code
+//! that made the call, and the process is terminated as on any other failure of
+//! the runtime, since any results after that point can't be trusted.
+
+use core::ffi::c_void;
+use core::fmt::{self, Write};
+
+use crate::backtrace;
+use crate::global::{self, GlobalContext};
+use crate::io::{self, FdWriter};
+use crate::registry::AllocMetadata;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AbiMode {
+    Permissive,
+    Strict,
+}
+
+impl AbiMode {
+    /// Reads the mode from the environment.
+    pub fn from_env() -> Self {
+        if io::env_flag(c"BSAN_STRICT_ABI") { AbiMode::Strict } else { AbiMode::Permissive }
+    }
+}
+
+/// The kind of retag requested by the pass. This mirrors `RetagKind` in
+/// `rustc_middle::mir`.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RetagKind {
+    FnEntry = 0,
+    TwoPhase = 1,
+    Raw = 2,
+    Default = 3,
+}
+
+impl RetagKind {
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        match raw {
+            0 => Some(RetagKind::FnEntry),
+            1 => Some(RetagKind::TwoPhase),
+            2 => Some(RetagKind::Raw),
+            3 => Some(RetagKind::Default),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RetagKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetagKind::FnEntry => f.write_str("fn-entry"),
+            RetagKind::TwoPhase => f.write_str("two-phase"),
+            RetagKind::Raw => f.write_str("raw"),
+            RetagKind::Default => f.write_str("default"),
+        }
+    }
+}
+
+/// The kind of place being retagged. This mirrors `PlaceKind` in
+/// `rustc_middle::mir`.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PlaceKind {
+    Freeze = 0,
+    Unpin = 1,
+    Default = 2,
+}
+
+impl PlaceKind {
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        match raw {
+            0 => Some(PlaceKind::Freeze),
+            1 => Some(PlaceKind::Unpin),
+            2 => Some(PlaceKind::Default),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AbiViolation {
+    NullArgument(&'static str),
+    InvalidRetagKind(u8),
+    InvalidPlaceKind(u8),
+    InvalidAccessKind(u8),
+    InvalidMetadata(*mut c_void),
+    InvalidSize(u64),
+    InvalidAlignment(usize),
+    UnbalancedEnable,
+}
+
+impl fmt::Display for AbiViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbiViolation::NullArgument(name) => write!(f, "`{name}` is null"),
+            AbiViolation::InvalidRetagKind(raw) => write!(f, "unknown retag kind {raw}"),
+            AbiViolation::InvalidPlaceKind(raw) => write!(f, "unknown place kind {raw}"),
+            AbiViolation::InvalidAccessKind(raw) => write!(f, "unknown access kind {raw}"),
+            AbiViolation::InvalidMetadata(addr) => {
+                write!(f, "{addr:p} is not the address of allocation metadata")
+            }
+            AbiViolation::InvalidSize(size) => write!(f, "size {size} is too large"),
+            AbiViolation::InvalidAlignment(align) => write!(f, "alignment {align} is invalid"),
+            AbiViolation::UnbalancedEnable => {
+                f.write_str("checks weren't disabled by `bsan_disable` on this thread")
+            }
+        }
+    }
+}
+
+/// Checks that `lock_address` points to allocation metadata. Null addresses
+/// are accepted, since they belong to provenance without an allocation. This
+/// is only done in strict mode.
+#[inline(always)]
+pub unsafe fn check_metadata(
+    ctx: &GlobalContext,
+    lock_address: *mut c_void,
+) -> Result<(), AbiViolation> {
+    if ctx.flags().abi_mode == AbiMode::Strict
+        && !lock_address.is_null()
+        && !AllocMetadata::is_valid(lock_address.cast())
+    {
+        return Err(AbiViolation::InvalidMetadata(lock_address));
+    }
+    Ok(())
+}
+
+/// Handles an invalid argument passed to `hook`. In permissive mode, this
+/// returns and the hook carries on with sanitized arguments. This must be
+/// called directly from the hook, so that the right frame is reported.
+#[cold]
+#[inline(never)]
+pub fn violation(ctx: &GlobalContext, hook: &str, violation: AbiViolation) {
+    if ctx.flags().abi_mode == AbiMode::Permissive {
+        return;
+    }
+    let mut out = FdWriter::log();
+    let _ = write!(out, "bsan: ABI violation in `{hook}`: {violation}");
+    match caller_pc() {
+        Some(pc) => {
+            let _ = writeln!(out, " (called from {pc:#x})");
+        }
+        None => {
+            let _ = writeln!(out);
+        }
+    }
+    out.flush();
+    global::internal_failure()
+}
+
+// The frames between the unwinder and the instrumented code: `caller_pc`,
+// `violation`, and the hook itself.
+const RUNTIME_FRAMES: usize = 3;
+
+/// The return address into the code that called the current hook.
+#[inline(never)]
+fn caller_pc() -> Option<usize> {
+    let mut pc = [0];
+    (backtrace::unwind(RUNTIME_FRAMES, &mut pc) == 1).then_some(pc[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_match_rustc() {
+        assert_eq!(RetagKind::from_raw(3), Some(RetagKind::Default));
+        assert_eq!(RetagKind::from_raw(4), None);
+        assert_eq!(PlaceKind::from_raw(0), Some(PlaceKind::Freeze));
+        assert_eq!(PlaceKind::from_raw(3), None);
+    }
+}
@@ -0,0 +1,751 @@
+use core::cell::Cell;
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::{fmt, ptr};
+
+use crate::global::GlobalContext;
+use crate::registry::{AllocMetadata, AllocState};
+use crate::report::Addr;
+use crate::tag::TagHint;
+use crate::{AllocId, BorTag, Provenance, SourceInfo, frame, thread};
+
+/// The kind of an access, with the raw values that the pass passes in an
+/// [`AccessDesc`].
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessKind {
+    Read = 0,
+    Write = 1,
+}
+
+impl AccessKind {
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        match raw {
+            0 => Some(AccessKind::Read),
+            1 => Some(AccessKind::Write),
+            _ => None,
+        }
+    }
+}
+
+/// An access in a batch passed to `bsan_check_accesses`, with the arguments
+/// that `bsan_read` or `bsan_write` would take for it. `kind` is the raw value
+/// of its [`AccessKind`].
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct AccessDesc {
+    pub ptr: *mut c_void,
+    pub size: u64,
+    pub prov: *const Provenance,
+    pub loc: *const SourceInfo,
+    pub kind: u8,
+}
+
+impl fmt::Display for AccessKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessKind::Read => f.write_str("read"),
+            AccessKind::Write => f.write_str("write"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessError {
+    NullPointer,
+    UnknownMemory,
+    /// The access extends past the address space that the host can map, as
+    /// through a non-canonical or kernel-space pointer.
+    InvalidAddress,
+    OutOfBounds {
+        alloc_id: AllocId,
+        base_addr: usize,
+        size: usize,
+    },
+    /// The access is through a pointer to an allocation that has been freed.
+    /// If its metadata has since been reused for another allocation, its
+    /// bounds are no longer known, and `base_addr` and `size` are 0.
+    UseAfterFree {
+        alloc_id: AllocId,
+        base_addr: usize,
+        size: usize,
+    },
+}
+
+impl AccessError {
+    /// The base address of the allocation that the access was checked against.
+    pub fn base_addr(&self) -> Option<usize> {
+        match *self {
+            AccessError::OutOfBounds { base_addr, .. }
+            | AccessError::UseAfterFree { base_addr, .. } => Some(base_addr).filter(|&b| b != 0),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessError::NullPointer => f.write_str("null pointer dereference"),
+            AccessError::UnknownMemory => f.write_str("access to memory outside of any allocation"),
+            AccessError::InvalidAddress => {
+                f.write_str("access to an invalid address, outside of the user address space")
+            }
+            AccessError::OutOfBounds { alloc_id, base_addr, size } => write!(
+                f,
+                "out-of-bounds access to allocation {} ({size} bytes at {})",
+                alloc_id.get(),
+                Addr(*base_addr)
+            ),
+            AccessError::UseAfterFree { alloc_id, base_addr: 0, .. } => {
+                write!(f, "access to allocation {} after it was freed", alloc_id.get())
+            }
+            AccessError::UseAfterFree { alloc_id, base_addr, size } => write!(
+                f,
+                "access to allocation {} ({size} bytes at {}) after it was freed",
+                alloc_id.get(),
+                Addr(*base_addr)
+            ),
+        }
+    }
+}
+
+// The number of times that the current thread disabled access checks without
+// enabling them again.
+#[thread_local]
+static CHECKS_DISABLED: Cell<u32> = Cell::new(0);
+
+/// Whether the current thread skips access checks, because it is in a region
+/// marked with `bsan_disable`.
+#[inline(always)]
+pub fn checks_disabled() -> bool {
+    CHECKS_DISABLED.get() != 0
+}
+
+/// Disables access checks on the current thread until [`enable_checks`] is
+/// called as often as this is.
+pub fn disable_checks() {
+    CHECKS_DISABLED.set(CHECKS_DISABLED.get().saturating_add(1));
+}
+
+/// Undoes a call to [`disable_checks`]. Returns `false` if checks weren't
+/// disabled.
+pub fn enable_checks() -> bool {
+    let depth = CHECKS_DISABLED.get();
+    CHECKS_DISABLED.set(depth.saturating_sub(1));
+    depth != 0
+}
+
+// The number of accesses of the current thread to skip before the next one
+// that is checked, when accesses are sampled.
+#[thread_local]
+static UNTIL_SAMPLE: Cell<u32> = Cell::new(0);
+
+/// Whether the current thread's next access is one of the 1 in `rate` that
+/// are checked.
+#[inline(always)]
+pub fn sampled(rate: u32) -> bool {
+    if rate <= 1 {
+        return true;
+    }
+    let until = UNTIL_SAMPLE.get();
+    UNTIL_SAMPLE.set(if until == 0 { rate - 1 } else { until - 1 });
+    until == 0
+}
+
+/// Determines the provenance of an access of `size` bytes at `addr` from the
+/// address alone. Zero-sized accesses are valid through any non-null pointer,
+/// including dangling ones like `NonNull::dangling()`, so they resolve to
+/// [`Provenance::zst`] without consulting the registry.
+pub fn resolve_access(
+    ctx: &GlobalContext,
+    addr: usize,
+    size: usize,
+) -> Result<Provenance, AccessError> {
+    if addr == 0 {
+        return Err(AccessError::NullPointer);
+    }
+    if size == 0 {
+        return Ok(Provenance::zst());
+    }
+    if !ctx.shadow().covers(addr, size) {
+        return Err(AccessError::InvalidAddress);
+    }
+    let meta = ctx.registry().find(addr).ok_or(AccessError::UnknownMemory)?;
+    let meta = unsafe { meta.as_ref() };
+    check_bounds(meta, addr, size)?;
+    Ok(meta.root_provenance())
+}
+
+/// Like [`resolve_access`], but for an access through a pointer whose
+/// provenance `prov` the instrumentation has tracked. The access is checked
+/// against the allocation that the pointer was derived from, so this also
+/// catches accesses that land in a different allocation, and accesses to
+/// freed memory, even once the metadata of the allocation has been reused for
+/// another one. Pointers without an allocation, such as those with
+/// [`Provenance::null`], are resolved from the address alone.
+///
+/// # Safety
+/// The `lock_address` of `prov` must be null or point to allocation
+/// metadata that the caller holds a reference to.
+pub unsafe fn check_access_with(
+    ctx: &GlobalContext,
+    prov: Provenance,
+    addr: usize,
+    size: usize,
+) -> Result<Provenance, AccessError> {
+    let Some(meta) = (prov.lock_address as *const AllocMetadata).as_ref() else {
+        return resolve_access(ctx, addr, size);
+    };
+    if addr == 0 {
+        return Err(AccessError::NullPointer);
+    }
+    if size == 0 {
+        return Ok(Provenance::zst());
+    }
+    if !ctx.shadow().covers(addr, size) {
+        return Err(AccessError::InvalidAddress);
+    }
+    if meta.id != prov.alloc_id {
+        return Err(AccessError::UseAfterFree { alloc_id: prov.alloc_id, base_addr: 0, size: 0 });
+    }
+    if meta.state == AllocState::Freed {
+        return Err(AccessError::UseAfterFree {
+            alloc_id: meta.id,
+            base_addr: meta.base_addr,
+            size: meta.size,
+        });
+    }
+    check_bounds(meta, addr, size)?;
+    Ok(prov)
+}
+
+/// Checks an access through a pointer whose tag is hinted to be the root tag
+/// of its allocation, which passes as long as the allocation is live and the
+/// access is within its bounds, without consulting any cache. Returns the
+/// allocation if it passes that way, and `None` if it has to be checked by
+/// [`check_access_with`] instead, as every access that fails must be.
+#[inline(always)]
+pub unsafe fn check_root_access<'a>(
+    ctx: &GlobalContext,
+    prov: Provenance,
+    addr: usize,
+    size: usize,
+) -> Option<&'a AllocMetadata> {
+    if !prov.bor_tag.hint().contains(TagHint::ROOT) || size == 0 {
+        return None;
+    }
+    let meta = (prov.lock_address as *const AllocMetadata).as_ref()?;
+    // Provenance can outlive its allocation, and the metadata block be reused
+    // for another one, whose root tag is different.
+    let passes = meta.state == AllocState::Live
+        && meta.id == prov.alloc_id
+        && meta.root_tag == prov.bor_tag
+        && addr != 0
+        && ctx.shadow().covers(addr, size)
+        && check_bounds(meta, addr, size).is_ok();
+    passes.then_some(meta)
+}
+
+/// Resolves an access that failed with `err` through a pointer with tracked
+/// provenance from its address instead, for `BSAN_LENIENT_FOREIGN`. This only
+/// succeeds if the access went out of the bounds of the pointer's allocation
+/// into a wildcard allocation, whose pointers aren't told apart, or one that a
+/// pointer to was exposed, which the pointer may have been derived from by code
+/// that the runtime can't see. Uses after free are never resolved, even if the
+/// freed memory has been reused by such an allocation.
+pub fn resolve_wildcard(
+    ctx: &GlobalContext,
+    err: AccessError,
+    addr: usize,
+    size: usize,
+) -> Option<Provenance> {
+    if !matches!(err, AccessError::OutOfBounds { .. }) {
+        return None;
+    }
+    let resolved = resolve_access(ctx, addr, size).ok()?;
+    let meta = unsafe { (resolved.lock_address as *const AllocMetadata).as_ref()? };
+    (meta.is_wildcard() || ctx.flags().lenient_foreign && meta.is_exposed()).then_some(resolved)
+}
+
+// Bumped whenever an entry of any thread's access cache may have gone stale
+// without its allocation being freed, so that every cache misses once.
+static CACHE_GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// Makes every thread's [`AccessCache`] miss on its next lookup. This is needed
+/// when the bounds of a live allocation change, or when the options that
+/// decide whether an access that passed is reported change.
+pub fn invalidate_caches() {
+    CACHE_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+// The hits on the access caches of all threads, as of their last miss.
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// The number of accesses that hit an [`AccessCache`]: those that other
+/// threads counted up to their last miss, and all of the current thread's.
+pub fn cache_hits() -> u64 {
+    CACHE_HITS.load(Ordering::Relaxed) + thread::current().access_cache.hits()
+}
+
+#[derive(Debug, Copy, Clone)]
+struct CachedCheck {
+    // The provenance that the access was made through, which is null for
+    // accesses that were resolved from their addresses.
+    lock_address: usize,
+    tag: BorTag,
+    // The allocation that the access was checked against, and its range.
+    // Every access within the range through the same provenance passes for
+    // as long as the allocation is live.
+    meta: *const AllocMetadata,
+    alloc_id: AllocId,
+    start: usize,
+    end: usize,
+    generation: u32,
+}
+
+impl CachedCheck {
+    const EMPTY: Self = Self {
+        lock_address: 0,
+        tag: BorTag::INVALID,
+        meta: ptr::null(),
+        alloc_id: AllocId::INVALID,
+        start: 0,
+        end: 0,
+        generation: 0,
+    };
+}
+
+/// The last check that a thread's access passed, so that accesses through
+/// the same pointer in a loop skip the registry and the bounds checks.
+///
+/// An entry is only trusted while its allocation is live. A hit reads the ID
+/// and state from the metadata, which remains readable even after it is
+/// freed, since metadata blocks are never unmapped; a changed ID means the
+/// block was reused for another allocation.
+#[derive(Debug)]
+pub struct AccessCache {
+    entry: Cell<CachedCheck>,
+    hits: Cell<u64>,
+}
+
+impl Default for AccessCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessCache {
+    pub const fn new() -> Self {
+        Self { entry: Cell::new(CachedCheck::EMPTY), hits: Cell::new(0) }
+    }
+
+    /// Returns the allocation that an access of `size` bytes at `addr`
+    /// through `prov` was last checked against, if the access passes the same
+    /// check.
+    #[inline(always)]
+    pub fn lookup(&self, prov: Provenance, addr: usize, size: usize) -> Option<&AllocMetadata> {
+        let entry = self.entry.get();
+        let hit = entry.lock_address == prov.lock_address.addr()
+            && entry.tag == prov.bor_tag
+            && size != 0
+            && addr >= entry.start
+            && addr < entry.end
+            && size <= entry.end - addr
+            && entry.generation == CACHE_GENERATION.load(Ordering::Relaxed);
+        if !hit {
+            return None;
+        }
+        let meta = unsafe { &*entry.meta };
+        if meta.id != entry.alloc_id || meta.state != AllocState::Live {
+            return None;
+        }
+        self.hits.set(self.hits.get() + 1);
+        Some(meta)
+    }
+
+    /// Caches that an access through `prov` passed the check against `meta`.
+    #[inline]
+    pub fn insert(&self, prov: Provenance, meta: &AllocMetadata) {
+        self.flush_hits();
+        self.entry.set(CachedCheck {
+            lock_address: prov.lock_address.addr(),
+            tag: prov.bor_tag,
+            meta,
+            alloc_id: meta.id,
+            start: meta.base_addr,
+            end: meta.base_addr + meta.size,
+            generation: CACHE_GENERATION.load(Ordering::Relaxed),
+        });
+    }
+
+    /// The number of accesses that hit the cache and aren't yet counted in
+    /// [`cache_hits`] for other threads.
+    pub fn hits(&self) -> u64 {
+        self.hits.get()
+    }
+
+    /// Adds the hits on the cache to those of [`cache_hits`], as on a miss or
+    /// when the thread exits.
+    pub fn flush_hits(&self) {
+        let hits = self.hits.replace(0);
+        if hits != 0 {
+            CACHE_HITS.fetch_add(hits, Ordering::Relaxed);
+        }
+    }
+}
+
+fn check_bounds(meta: &AllocMetadata, addr: usize, size: usize) -> Result<(), AccessError> {
+    let in_bounds =
+        addr >= meta.base_addr && size <= meta.size && addr - meta.base_addr <= meta.size - size;
+    if !in_bounds {
+        return Err(AccessError::OutOfBounds {
+            alloc_id: meta.id,
+            base_addr: meta.base_addr,
+            size: meta.size,
+        });
+    }
+    Ok(())
+}
+
+/// The history behind an invalid access of `size` bytes at `addr` through a
+/// pointer with `tag`, printed after the error itself: which bytes of the
+/// allocation `meta` were accessed, where the allocation was made and freed,
+/// its recent events, and how the tag relates to it. Each line is indented and
+/// ends in a newline.
+pub struct AccessHistory<'a> {
+    pub meta: &'a AllocMetadata,
+    pub addr: usize,
+    pub size: usize,
+    pub tag: BorTag,
+}
+
+impl fmt::Display for AccessHistory<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let meta = self.meta;
+        let id = meta.id.get();
+        let start = self.addr.wrapping_sub(meta.base_addr) as isize;
+        let end = start.wrapping_add(self.size as isize);
+        writeln!(
+            f,
+            "    the access covers offsets {}..{} of allocation {id}, which is {} bytes long",
+            Offset(start),
+            Offset(end),
+            meta.size
+        )?;
+        write!(f, "    allocation {id} ({}) was made in ", meta.kind)?;
+        write_function(f, unsafe { meta.created_in.as_ref() })?;
+        if meta.state == AllocState::Freed {
+            write!(f, "    allocation {id} was freed in ")?;
+            write_function(f, unsafe { meta.freed_in.as_ref() })?;
+        }
+        let mut events = meta.history.events().peekable();
+        if events.peek().is_some() {
+            writeln!(f, "    recent history of allocation {id}, oldest first:")?;
+            for event in events {
+                writeln!(f, "      {event}")?;
+            }
+        }
+        if !self.tag.is_valid() {
+            return Ok(());
+        }
+        let tag = self.tag.get();
+        if self.tag == meta.root_tag {
+            writeln!(f, "    the pointer has tag {tag}, the root tag of allocation {id}")?;
+        } else {
+            writeln!(
+                f,
+                "    the pointer has tag {tag}, derived from tag {} at the root of allocation {id}",
+                meta.root_tag.get()
+            )?;
+        }
+        if let Some(depth) = frame::protector_depth(self.tag) {
+            write!(f, "    tag {tag} is protected until the return of ")?;
+            write_function(f, unsafe { frame::function_at(depth) })?;
+        }
+        Ok(())
+    }
+}
+
+fn write_function(f: &mut fmt::Formatter<'_>, func: Option<&SourceInfo>) -> fmt::Result {
+    match func {
+        Some(func) => writeln!(f, "{func}"),
+        None => writeln!(f, "an unknown function"),
+    }
+}
+
+// An offset from the base of an allocation, which may be negative.
+struct Offset(isize);
+
+impl fmt::Display for Offset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 < 0 {
+            write!(f, "-{:#x}", self.0.unsigned_abs())
+        } else {
+            write!(f, "{:#x}", self.0)
+        }
+    }
+}
+
+/// The runs of consecutive lanes that are enabled in a vector access of
+/// `lanes` lanes, as `(first lane, number of lanes)`. Lane `i` is enabled if bit
+/// `i % 64` of `mask[i / 64]` is set; without a mask, every lane is. Checking
+/// each run as one access keeps wide accesses as cheap as scalar ones.
+pub struct LaneRuns<'a> {
+    mask: Option<&'a [u64]>,
+    lanes: usize,
+    next: usize,
+}
+
+impl<'a> LaneRuns<'a> {
+    pub fn new(lanes: usize, mask: Option<&'a [u64]>) -> Self {
+        debug_assert!(mask.is_none_or(|mask| mask.len() * 64 >= lanes));
+        Self { mask, lanes, next: 0 }
+    }
+
+    fn enabled(&self, lane: usize) -> bool {
+        self.mask.is_none_or(|mask| mask[lane / 64] & (1 << (lane % 64)) != 0)
+    }
+}
+
+impl Iterator for LaneRuns<'_> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < self.lanes && !self.enabled(self.next) {
+            self.next += 1;
+        }
+        let start = self.next;
+        while self.next < self.lanes && self.enabled(self.next) {
+            self.next += 1;
+        }
+        (self.next > start).then_some((start, self.next - start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ptr::NonNull;
+
+    use super::*;
+    use crate::alloc::TEST_ALLOCATOR;
+
+    #[test]
+    fn zero_sized_accesses_need_no_allocation() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        assert_eq!(resolve_access(&ctx, 0x8, 0), Ok(Provenance::zst()));
+        assert_eq!(resolve_access(&ctx, usize::MAX, 0), Ok(Provenance::zst()));
+        assert_eq!(resolve_access(&ctx, 0, 0), Err(AccessError::NullPointer));
+        assert_eq!(resolve_access(&ctx, 0x8, 1), Err(AccessError::UnknownMemory));
+    }
+
+    #[test]
+    fn accesses_are_bounds_checked() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let prov = unsafe { ctx.new_allocation(0x1000, 16).unwrap() };
+        assert_eq!(resolve_access(&ctx, 0x1000, 16), Ok(prov));
+        assert_eq!(resolve_access(&ctx, 0x1008, 8), Ok(prov));
+        assert!(matches!(resolve_access(&ctx, 0x1008, 9), Err(AccessError::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn accesses_are_checked_against_their_provenance() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let a = ctx.new_allocation(0x1000, 16).unwrap();
+            let b = ctx.new_allocation(0x1010, 16).unwrap();
+            assert_eq!(check_access_with(&ctx, a, 0x1008, 8), Ok(a));
+            // Overflowing into a neighbouring allocation is caught.
+            assert_eq!(resolve_access(&ctx, 0x1010, 8), Ok(b));
+            assert!(matches!(
+                check_access_with(&ctx, a, 0x1010, 8),
+                Err(AccessError::OutOfBounds { alloc_id, .. }) if alloc_id == a.alloc_id
+            ));
+            assert!(matches!(
+                check_access_with(&ctx, b, 0xff8, 8),
+                Err(AccessError::OutOfBounds { .. })
+            ));
+            assert!(ctx.free_allocation(0x1000));
+            assert!(matches!(
+                check_access_with(&ctx, a, 0x1000, 8),
+                Err(AccessError::UseAfterFree { base_addr: 0x1000, size: 16, .. })
+            ));
+            assert_eq!(check_access_with(&ctx, Provenance::null(), 0x1010, 8), Ok(b));
+            assert_eq!(check_access_with(&ctx, b, 0, 8), Err(AccessError::NullPointer));
+        }
+    }
+
+    #[test]
+    fn accesses_through_reused_metadata_are_uses_after_free() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let a = ctx.new_allocation(0x1000, 16).unwrap();
+            assert!(ctx.free_allocation(0x1000));
+            ctx.release_metadata(NonNull::new_unchecked(a.lock_address.cast()));
+            // Every few allocations try to reclaim retired metadata, and the pool
+            // hands the block out again, unless another test's thread that
+            // shares its shard takes it first.
+            let (base, b) = (0..1000)
+                .map(|i| (0x2000 + 0x20 * i, ctx.new_allocation(0x2000 + 0x20 * i, 16).unwrap()))
+                .find(|(_, b)| b.lock_address == a.lock_address)
+                .unwrap();
+            assert_ne!(b.alloc_id, a.alloc_id);
+            let err = check_access_with(&ctx, a, base, 8).unwrap_err();
+            assert_eq!(
+                err,
+                AccessError::UseAfterFree { alloc_id: a.alloc_id, base_addr: 0, size: 0 }
+            );
+            assert_eq!(err.base_addr(), None);
+            assert_eq!(
+                err.to_string(),
+                format!("access to allocation {} after it was freed", a.alloc_id.get())
+            );
+            assert_eq!(check_access_with(&ctx, b, base, 8), Ok(b));
+        }
+    }
+
+    #[test]
+    fn cached_checks_hold_while_the_allocation_is_live() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let cache = AccessCache::new();
+        unsafe {
+            let a = ctx.new_allocation(0x1000, 16).unwrap();
+            let meta = &*a.lock_address.cast::<AllocMetadata>();
+            assert!(cache.lookup(a, 0x1000, 8).is_none());
+            cache.insert(a, meta);
+            assert!(cache.lookup(a, 0x1008, 8).is_some());
+            assert!(cache.lookup(a, 0x1008, 9).is_none());
+            assert!(cache.lookup(a, 0x1010, 1).is_none());
+            assert!(cache.lookup(a, 0x1000, 0).is_none());
+            assert!(cache.lookup(Provenance::null(), 0x1000, 8).is_none());
+            assert!(
+                cache.lookup(Provenance { bor_tag: BorTag::new(99), ..a }, 0x1000, 8).is_none()
+            );
+            assert_eq!(cache.hits(), 1);
+            invalidate_caches();
+            assert!(cache.lookup(a, 0x1000, 8).is_none());
+            // Misses publish the hits that preceded them.
+            let hits = cache_hits();
+            cache.insert(a, meta);
+            assert!(cache.hits() == 0 && cache_hits() > hits);
+            assert!(ctx.free_allocation(0x1000));
+            assert!(cache.lookup(a, 0x1000, 8).is_none());
+        }
+    }
+
+    #[test]
+    fn root_pointers_pass_without_the_cache() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let a = ctx.new_allocation(0x1000, 16).unwrap();
+            assert!(a.bor_tag.hint().contains(TagHint::ROOT));
+            assert!(check_root_access(&ctx, a, 0x1008, 8).is_some());
+            // Anything else is left to the full check.
+            assert!(check_root_access(&ctx, a, 0x1008, 9).is_none());
+            assert!(check_root_access(&ctx, a, 0x1000, 0).is_none());
+            let derived = Provenance { bor_tag: a.bor_tag.with_hint(TagHint::NONE), ..a };
+            assert!(check_root_access(&ctx, derived, 0x1000, 8).is_none());
+            assert!(check_access_with(&ctx, derived, 0x1000, 8).is_ok());
+            assert!(ctx.free_allocation(0x1000));
+            assert!(check_root_access(&ctx, a, 0x1000, 8).is_none());
+        }
+    }
+
+    #[test]
+    fn wildcard_allocations_can_be_accessed_without_matching_provenance() {
+        let mut ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let a = ctx.new_allocation(0x1000, 16).unwrap();
+            let b = ctx.new_allocation(0x1010, 16).unwrap();
+            let c = ctx.new_allocation(0x1020, 16).unwrap();
+            let (b_meta, c_meta) = (
+                &*b.lock_address.cast::<AllocMetadata>(),
+                &*c.lock_address.cast::<AllocMetadata>(),
+            );
+            let err = check_access_with(&ctx, a, 0x1010, 8).unwrap_err();
+            assert_eq!(resolve_wildcard(&ctx, err, 0x1010, 8), None);
+            assert!(!b_meta.derive_tag(1));
+            assert!(b_meta.derive_tag(1));
+            assert!(!b_meta.derive_tag(1));
+            assert_eq!(resolve_wildcard(&ctx, err, 0x1010, 8), Some(b));
+            // The access must still be within the allocation.
+            let err = check_access_with(&ctx, a, 0x1018, 16).unwrap_err();
+            assert_eq!(resolve_wildcard(&ctx, err, 0x1018, 16), None);
+            assert_eq!(resolve_wildcard(&ctx, AccessError::NullPointer, 0x1010, 8), None);
+            // Exposed allocations are only wildcards in lenient mode.
+            c_meta.expose();
+            let err = check_access_with(&ctx, a, 0x1020, 8).unwrap_err();
+            assert_eq!(resolve_wildcard(&ctx, err, 0x1020, 8), None);
+            ctx.flags_mut().lenient_foreign = true;
+            assert_eq!(resolve_wildcard(&ctx, err, 0x1020, 8), Some(c));
+            // Not even once a freed allocation's memory is reused by one.
+            assert!(ctx.free_allocation(0x1000));
+            let d = ctx.new_allocation(0x1000, 16).unwrap();
+            (*d.lock_address.cast::<AllocMetadata>()).expose();
+            let err = check_access_with(&ctx, a, 0x1000, 8).unwrap_err();
+            assert!(matches!(err, AccessError::UseAfterFree { .. }));
+            assert_eq!(resolve_wildcard(&ctx, err, 0x1000, 8), None);
+        }
+    }
+
+    #[test]
+    fn histories_describe_the_allocation_and_tag() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let info = |function: &'static core::ffi::CStr| SourceInfo {
+            file: core::ptr::null(),
+            line: 0,
+            column: 0,
+            function: function.as_ptr(),
+        };
+        let (made, freed) = (info(c"make"), info(c"drop"));
+        unsafe {
+            frame::enter(&made);
+            let prov = ctx.new_allocation(0x1000, 16).unwrap();
+            frame::exit(&ctx);
+            frame::enter(&freed);
+            assert!(ctx.free_allocation(0x1000));
+            frame::exit(&ctx);
+            let meta = &*prov.lock_address.cast::<AllocMetadata>();
+            let history = |addr, tag| AccessHistory { meta, addr, size: 8, tag }.to_string();
+            assert_eq!(
+                history(0xffc, prov.bor_tag),
+                "    the access covers offsets -0x4..0x4 of allocation 1, which is 16 bytes long\n\
+                 \x20   allocation 1 (heap) was made in make\n\
+                 \x20   allocation 1 was freed in drop\n\
+                 \x20   the pointer has tag 1, the root tag of allocation 1\n"
+            );
+            assert!(history(0x1000, BorTag::new(7)).ends_with(
+                "the pointer has tag 7, derived from tag 1 at the root of allocation 1\n"
+            ));
+            ctx.release_metadata(NonNull::new_unchecked(prov.lock_address.cast()));
+        }
+    }
+
+    #[test]
+    fn only_enabled_lanes_are_accessed() {
+        let runs = |lanes, mask: Option<&[u64]>| LaneRuns::new(lanes, mask).collect::<Vec<_>>();
+        assert_eq!(runs(8, None), [(0, 8)]);
+        assert_eq!(runs(8, Some(&[0b1011_0110])), [(1, 2), (4, 2), (7, 1)]);
+        assert_eq!(runs(4, Some(&[0b1111_0000])), []);
+        assert_eq!(runs(0, None), []);
+        assert_eq!(runs(128, Some(&[1 << 63, 1])), [(63, 2)]);
+    }
+
+    #[test]
+    fn one_in_every_rate_accesses_is_sampled() {
+        let sampled = |rate| (0..12).filter(|_| sampled(rate)).count();
+        assert_eq!(sampled(1), 12);
+        assert_eq!(sampled(0), 12);
+        assert_eq!(sampled(4), 3);
+        assert_eq!(sampled(3), 4);
+    }
+
+    #[test]
+    fn accesses_outside_the_address_space_are_invalid() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let kernel = 0xffff_8000_0000_0000;
+        assert_eq!(resolve_access(&ctx, kernel, 8), Err(AccessError::InvalidAddress));
+        assert_eq!(resolve_access(&ctx, usize::MAX, 1), Err(AccessError::InvalidAddress));
+        assert_eq!(resolve_access(&ctx, usize::MAX - 3, 8), Err(AccessError::InvalidAddress));
+    }
+}
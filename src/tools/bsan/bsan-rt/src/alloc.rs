@@ -1,42 +1,298 @@
-use core::alloc::{AllocError, Allocator, Layout};
+//! The allocator that the runtime allocates its own memory with.
+//!
+//! The runtime intercepts the program's `malloc`, so it doesn't allocate from
+//! it: memory is mapped with the `mmap` of the [`BsanAllocator`] that
+//! `bsan_init` is given, and handed out from a free list per power-of-two size
+//! class, from 16 bytes up to [`MAX_CLASS_SIZE`]. Each class takes blocks from
+//! slabs of [`SLAB_LEN`] bytes, which are never unmapped, and allocations that
+//! are larger than the largest class are mapped on their own. The size classes
+//! are shared by every `BsanAllocator`, so memory can be freed through a copy
+//! of the allocator other than the one that allocated it.
+//!
+//! The collections of the `alloc` crate allocate from the same size classes
+//! through [`RuntimeAlloc`], the runtime's global allocator, so its data
+//! structures can be `Vec`s and `BTreeMap`s rather than fixed-size arrays. It
+//! maps memory with libc's `mmap` until `bsan_init` installs the allocator
+//! that it was given. Allocations that were mapped on their own before then
+//! are unmapped with that allocator's `munmap` as well. As
+//! with the runtime's panic handler, this is only the global allocator of the
+//! runtime itself, which is linked into programs that don't have one of their
+//! own. Code on the paths that the program's allocations take should reserve
+//! memory with `try_reserve` and the like, since a failed allocation panics.
+
+use core::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
 use core::mem::{self, zeroed};
-use core::ptr::NonNull;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use libc::{c_int, c_void, off_t};
 
+use crate::host::{self, BsanHooks};
+use crate::sync::SpinLock;
+
 pub type MMap = unsafe extern "C" fn(*mut c_void, usize, c_int, c_int, c_int, i64) -> *mut c_void;
 pub type MUnmap = unsafe extern "C" fn(*mut c_void, usize) -> c_int;
-pub type Malloc = unsafe extern "C" fn(usize) -> *mut c_void;
-pub type Free = unsafe extern "C" fn(*mut c_void);
 
+/// The functions that the runtime maps its memory with.
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct BsanAllocator {
-    malloc: Malloc,
-    free: Free,
     mmap: MMap,
     munmap: MUnmap,
 }
 
+impl BsanAllocator {
+    pub const fn new(mmap: MMap, munmap: MUnmap) -> Self {
+        Self { mmap, munmap }
+    }
+
+    /// Replaces the functions that memory is mapped with by those in `hooks`
+    /// that aren't null.
+    pub(crate) fn with_hooks(self, hooks: &BsanHooks) -> Self {
+        Self { mmap: hooks.mmap.unwrap_or(self.mmap), munmap: hooks.munmap.unwrap_or(self.munmap) }
+    }
+
+    /// Maps `len` bytes of anonymous memory, preferably at `hint`. Returns
+    /// null if the mapping failed.
+    pub(crate) unsafe fn map_anonymous(
+        &self,
+        hint: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+    ) -> *mut c_void {
+        let mapping =
+            (self.mmap)(hint, len, prot, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags, -1, 0);
+        if mapping == libc::MAP_FAILED { core::ptr::null_mut() } else { mapping }
+    }
+
+    /// Unmaps a mapping of `len` bytes made by [`BsanAllocator::map_anonymous`].
+    pub(crate) unsafe fn unmap(&self, ptr: *mut c_void, len: usize) {
+        (self.munmap)(ptr, len);
+    }
+}
+
 unsafe impl Send for BsanAllocator {}
 unsafe impl Sync for BsanAllocator {}
 
+const MIN_CLASS_SIZE: usize = 16;
+
+/// The size of the largest size class.
+pub const MAX_CLASS_SIZE: usize = 2048;
+
+const NUM_CLASSES: usize =
+    (MAX_CLASS_SIZE.trailing_zeros() - MIN_CLASS_SIZE.trailing_zeros()) as usize + 1;
+
+/// The number of bytes that each size class maps at once.
+pub const SLAB_LEN: usize = 64 * 1024;
+
+// The blocks of a size class: a list of the freed ones, linked through their
+// first word, and the rest of the slab that was mapped last, which are kept as
+// addresses so that the lock can be shared.
+#[derive(Debug)]
+struct SizeClass {
+    free: usize,
+    next: usize,
+    end: usize,
+}
+
+static CLASSES: [SpinLock<SizeClass>; NUM_CLASSES] =
+    [const { SpinLock::new(SizeClass { free: 0, next: 0, end: 0 }) }; NUM_CLASSES];
+
+// The index of the size class that blocks for `layout` are taken from, if
+// they aren't mapped on their own. Blocks are aligned to their size.
+fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_CLASS_SIZE).checked_next_power_of_two()?;
+    (size <= MAX_CLASS_SIZE)
+        .then(|| (size.trailing_zeros() - MIN_CLASS_SIZE.trailing_zeros()) as usize)
+}
+
+const fn class_size(class: usize) -> usize {
+    MIN_CLASS_SIZE << class
+}
+
+// The length of the mapping for an allocation of `size` bytes that is larger
+// than every size class.
+fn mapping_len(size: usize) -> Option<usize> {
+    size.checked_next_multiple_of(host::page_size())
+}
+
+impl BsanAllocator {
+    unsafe fn allocate_block(&self, class: usize) -> Option<NonNull<u8>> {
+        let size = class_size(class);
+        let mut blocks = CLASSES[class].lock();
+        if blocks.free != 0 {
+            let block = blocks.free;
+            blocks.free = *(block as *const usize);
+            return NonNull::new(ptr::with_exposed_provenance_mut(block));
+        }
+        if blocks.next == blocks.end {
+            let slab = self.map_anonymous(
+                ptr::null_mut(),
+                SLAB_LEN,
+                libc::PROT_READ | libc::PROT_WRITE,
+                0,
+            );
+            if slab.is_null() {
+                return None;
+            }
+            blocks.next = slab.expose_provenance();
+            blocks.end = blocks.next + SLAB_LEN;
+        }
+        let block = blocks.next;
+        blocks.next += size;
+        NonNull::new(ptr::with_exposed_provenance_mut(block))
+    }
+
+    unsafe fn free_block(&self, class: usize, block: NonNull<u8>) {
+        let mut blocks = CLASSES[class].lock();
+        *block.as_ptr().cast::<usize>() = blocks.free;
+        blocks.free = block.as_ptr().expose_provenance();
+    }
+
+    // Maps an allocation of its own, which is aligned to the page size, or to
+    // its alignment if that is larger, by trimming a larger mapping.
+    unsafe fn map_large(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let len = mapping_len(layout.size())?;
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        if layout.align() <= host::page_size() {
+            return NonNull::new(self.map_anonymous(ptr::null_mut(), len, prot, 0).cast());
+        }
+        let padded = len.checked_add(layout.align())?;
+        let mapping = self.map_anonymous(ptr::null_mut(), padded, prot, 0).cast::<u8>();
+        if mapping.is_null() {
+            return None;
+        }
+        let head = mapping.align_offset(layout.align());
+        let start = mapping.add(head);
+        if head != 0 {
+            self.unmap(mapping.cast(), head);
+        }
+        let tail = padded - head - len;
+        if tail != 0 {
+            self.unmap(start.add(len).cast(), tail);
+        }
+        NonNull::new(start)
+    }
+}
+
 unsafe impl Allocator for BsanAllocator {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        unsafe {
-            match layout.size() {
-                0 => Ok(NonNull::slice_from_raw_parts(layout.dangling(), 0)),
-                // SAFETY: `layout` is non-zero in size,
-                size => unsafe {
-                    let raw_ptr: *mut u8 = mem::transmute((self.malloc)(layout.size()));
-                    let ptr = NonNull::new(raw_ptr).ok_or(AllocError)?;
-                    Ok(NonNull::slice_from_raw_parts(ptr, size))
-                },
+        if layout.size() == 0 {
+            return Ok(NonNull::slice_from_raw_parts(layout.dangling(), 0));
+        }
+        let ptr = unsafe {
+            match class_of(layout) {
+                Some(class) => self.allocate_block(class),
+                None => self.map_large(layout),
             }
+        };
+        Ok(NonNull::slice_from_raw_parts(ptr.ok_or(AllocError)?, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+        match class_of(layout) {
+            Some(class) => self.free_block(class, ptr),
+            None => self.unmap(ptr.as_ptr().cast(), mapping_len(layout.size()).unwrap()),
+        }
+    }
+}
+
+/// An allocator backed by the host's libc. This is used until `bsan_init`
+/// provides one.
+pub const LIBC_ALLOCATOR: BsanAllocator = BsanAllocator { mmap: libc::mmap, munmap: libc::munmap };
+
+#[cfg(test)]
+pub const TEST_ALLOCATOR: BsanAllocator = LIBC_ALLOCATOR;
+
+// The functions of the allocator that `bsan_init` installed, where 0 stands
+// for libc's, as for the hooks of `host`.
+static RUNTIME_MMAP: AtomicUsize = AtomicUsize::new(0);
+static RUNTIME_MUNMAP: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes [`runtime_allocator`] return `alloc` from now on.
+pub fn install(alloc: BsanAllocator) {
+    RUNTIME_MMAP.store(alloc.mmap as usize, Ordering::Release);
+    RUNTIME_MUNMAP.store(alloc.munmap as usize, Ordering::Release);
+}
+
+/// The allocator of memory that isn't owned by a context, which is the one
+/// that `bsan_init` was given, or [`LIBC_ALLOCATOR`] until it is called.
+pub fn runtime_allocator() -> BsanAllocator {
+    unsafe {
+        match (RUNTIME_MMAP.load(Ordering::Acquire), RUNTIME_MUNMAP.load(Ordering::Acquire)) {
+            (0, _) | (_, 0) => LIBC_ALLOCATOR,
+            (mmap, munmap) => BsanAllocator {
+                mmap: mem::transmute::<usize, MMap>(mmap),
+                munmap: mem::transmute::<usize, MUnmap>(munmap),
+            },
         }
     }
+}
+
+/// The global allocator of the runtime, which allocates from the
+/// [`runtime_allocator`], since collections may be used before `bsan_init`
+/// provides one.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RuntimeAlloc;
+
+unsafe impl GlobalAlloc for RuntimeAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let block = runtime_allocator().allocate(layout);
+        block.map_or(ptr::null_mut(), |block| block.cast().as_ptr())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        runtime_allocator().deallocate(NonNull::new_unchecked(ptr), layout);
+    }
+}
+
+// Tests, and builds with the `std` feature, use the allocator of `std`.
+#[cfg(not(any(test, feature = "std")))]
+#[global_allocator]
+static GLOBAL: RuntimeAlloc = RuntimeAlloc;
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
-        (self.free)(mem::transmute(ptr.as_ptr()))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_global_allocator_reuses_blocks() {
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        unsafe {
+            let block = RuntimeAlloc.alloc(layout);
+            assert!(!block.is_null() && block.addr() % 8 == 0);
+            RuntimeAlloc.dealloc(block, layout);
+            assert_eq!(RuntimeAlloc.alloc(layout), block);
+            RuntimeAlloc.dealloc(block, layout);
+        }
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_their_size_class() {
+        let layouts = [(1, 1), (16, 8), (17, 1), (24, 64), (2048, 8), (2049, 8), (3, 8192)];
+        let allocations: Vec<_> = layouts
+            .iter()
+            .map(|&(size, align)| {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                (TEST_ALLOCATOR.allocate(layout).unwrap().cast::<u8>(), layout)
+            })
+            .collect();
+        for &(ptr, layout) in &allocations {
+            assert_eq!(ptr.addr().get() % layout.align(), 0);
+            if let Some(class) = class_of(layout) {
+                assert_eq!(ptr.addr().get() % class_size(class), 0);
+            }
+            unsafe { ptr.as_ptr().write_bytes(0xa5, layout.size()) };
+        }
+        for (ptr, layout) in allocations {
+            unsafe { TEST_ALLOCATOR.deallocate(ptr, layout) };
+        }
+        assert_eq!(class_of(Layout::new::<u8>()), Some(0));
+        assert_eq!(class_of(Layout::from_size_align(2048, 1).unwrap()), Some(NUM_CLASSES - 1));
+        assert_eq!(class_of(Layout::from_size_align(8, 4096).unwrap()), None);
     }
 }
@@ -0,0 +1,144 @@
+//! A bump arena for the data that an allocation's metadata only needs some of
+//! the time, such as its history.
+//!
+//! Each [`AllocMetadata`] has an arena of its own, which takes no memory
+//! until something is allocated from it. Values are never freed one by one:
+//! the arena's chunks are deallocated together once the metadata is, so
+//! nothing that is allocated from it needs bookkeeping to be freed, and
+//! freeing an allocation's metadata takes one deallocation per chunk, however
+//! many values it held. Chunks double in size as the arena grows, from
+//! [`MIN_CHUNK_LEN`] bytes. The runtime keeps no tree of tags or map of ranges
+//! per allocation, so its history is the only thing allocated from it so far.
+//!
+//! [`AllocMetadata`]: crate::registry::AllocMetadata
+
+use core::alloc::{Allocator, Layout};
+use core::mem;
+use core::ptr::{self, NonNull};
+
+use crate::BsanAllocator;
+use crate::sync::SpinLock;
+
+/// The size of the first chunk of an arena, including its header.
+pub const MIN_CHUNK_LEN: usize = 256;
+
+// The header at the start of each chunk, which links it to the chunk that was
+// allocated before it.
+#[repr(C)]
+struct Chunk {
+    prev: *mut Chunk,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct State {
+    // The chunk that was allocated last, and the free part of it.
+    chunk: usize,
+    next: usize,
+    end: usize,
+}
+
+#[derive(Debug)]
+pub struct Arena {
+    state: SpinLock<State>,
+}
+
+impl Default for Arena {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Arena {
+    pub const fn new() -> Self {
+        Self { state: SpinLock::new(State { chunk: 0, next: 0, end: 0 }) }
+    }
+
+    /// Moves `value` into the arena, allocating a new chunk with `allocator`
+    /// if the last one is full. Returns `None` if the chunk couldn't be
+    /// allocated. `value` is never dropped, so `T` must not need to be.
+    pub fn alloc<T>(&self, allocator: &BsanAllocator, value: T) -> Option<NonNull<T>> {
+        const { assert!(!mem::needs_drop::<T>()) };
+        let layout = Layout::new::<T>();
+        let mut state = self.state.lock();
+        let mut start = state.next.next_multiple_of(layout.align());
+        if state.chunk == 0 || start + layout.size() > state.end {
+            let last_len = match state.chunk {
+                0 => MIN_CHUNK_LEN / 2,
+                chunk => unsafe { (*ptr::with_exposed_provenance::<Chunk>(chunk)).len },
+            };
+            let header = Layout::new::<Chunk>().extend(layout).ok()?.0;
+            let len = (last_len * 2).max(header.size().next_power_of_two());
+            let chunk = allocator.allocate(chunk_layout(len)).ok()?.cast::<Chunk>().as_ptr();
+            unsafe {
+                chunk.write(Chunk { prev: ptr::with_exposed_provenance_mut(state.chunk), len })
+            };
+            state.chunk = chunk.expose_provenance();
+            state.next = state.chunk + mem::size_of::<Chunk>();
+            state.end = state.chunk + len;
+            start = state.next.next_multiple_of(layout.align());
+        }
+        state.next = start + layout.size();
+        let ptr = ptr::with_exposed_provenance_mut::<T>(start);
+        unsafe { ptr.write(value) };
+        NonNull::new(ptr)
+    }
+
+    /// Deallocates every chunk of the arena with `allocator`, emptying it.
+    ///
+    /// # Safety
+    /// Nothing that was allocated from the arena may be used afterwards, and
+    /// its chunks must have been allocated with the same kind of allocator.
+    pub unsafe fn release(&self, allocator: &BsanAllocator) {
+        let mut state = self.state.lock();
+        let mut chunk = ptr::with_exposed_provenance_mut::<Chunk>(state.chunk);
+        while let Some(current) = NonNull::new(chunk) {
+            chunk = (*current.as_ptr()).prev;
+            allocator.deallocate(current.cast(), chunk_layout((*current.as_ptr()).len));
+        }
+        *state = State { chunk: 0, next: 0, end: 0 };
+    }
+
+    /// The number of bytes of the chunks that the arena has allocated.
+    pub fn allocated_bytes(&self) -> usize {
+        let state = self.state.lock();
+        let mut chunk = ptr::with_exposed_provenance::<Chunk>(state.chunk);
+        let mut bytes = 0;
+        while !chunk.is_null() {
+            unsafe {
+                bytes += (*chunk).len;
+                chunk = (*chunk).prev;
+            }
+        }
+        bytes
+    }
+}
+
+fn chunk_layout(len: usize) -> Layout {
+    Layout::from_size_align(len, 16).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::TEST_ALLOCATOR;
+
+    #[test]
+    fn arenas_grow_and_are_released_at_once() {
+        let arena = Arena::new();
+        assert_eq!(arena.allocated_bytes(), 0);
+        let byte = arena.alloc(&TEST_ALLOCATOR, 1u8).unwrap();
+        let word = arena.alloc(&TEST_ALLOCATOR, 2u64).unwrap();
+        assert_eq!(word.addr().get() % 8, 0);
+        assert_eq!(arena.allocated_bytes(), MIN_CHUNK_LEN);
+        let big = arena.alloc(&TEST_ALLOCATOR, [3u8; 1000]).unwrap();
+        assert_eq!(arena.allocated_bytes(), MIN_CHUNK_LEN + 1024);
+        unsafe {
+            assert_eq!((byte.read(), word.read(), big.read()), (1, 2, [3; 1000]));
+            arena.release(&TEST_ALLOCATOR);
+        }
+        assert_eq!(arena.allocated_bytes(), 0);
+        assert!(arena.alloc(&TEST_ALLOCATOR, 4u32).is_some());
+        unsafe { arena.release(&TEST_ALLOCATOR) };
+    }
+}
@@ -0,0 +1,187 @@
+//! Call stacks of the instrumented program, captured when an error is reported.
+//!
+//! By default, the stack is walked with the system unwinder, which works from
+//! the unwind tables that Rust and C compilers emit even without frame
+//! pointers. Programs that can't rely on the unwinder, such as those built
+//! with `-C panic=abort` and without unwind tables, can supply a collector of
+//! their own with `bsan_set_backtrace_hook`.
+
+use core::ffi::{c_int, c_void};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The number of frames kept of each call stack.
+pub const MAX_FRAMES: usize = 64;
+
+/// Writes the return addresses of at most `max` frames of the calling thread's
+/// stack to `pcs`, innermost first, and returns how many were written. `None`
+/// stands for the unwinder.
+pub type BacktraceHook = Option<unsafe extern "C" fn(pcs: *mut usize, max: usize) -> usize>;
+
+// The collector supplied by the program, or 0 to use the unwinder.
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Uses `hook` to collect call stacks from now on.
+pub fn set_hook(hook: BacktraceHook) {
+    HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::Release);
+}
+
+fn hook() -> BacktraceHook {
+    // A null function pointer is `None`.
+    unsafe { core::mem::transmute::<usize, BacktraceHook>(HOOK.load(Ordering::Acquire)) }
+}
+
+/// The return addresses of the frames of a call stack, innermost first.
+#[derive(Debug, Copy, Clone)]
+pub struct Backtrace {
+    pcs: [usize; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Captures the call stack of the current thread, leaving out the `skip`
+    /// innermost frames below the caller of this function. A collector
+    /// supplied by the program decides for itself which frames to leave out.
+    #[inline(never)]
+    pub fn capture(skip: usize) -> Self {
+        // This frame is the first that the unwinder reports.
+        Self::collect(hook(), skip + 1, MAX_FRAMES)
+    }
+
+    /// Like [`Backtrace::capture`], keeping at most `depth` frames, which
+    /// saves unwinding the rest.
+    #[inline(never)]
+    pub fn capture_with_depth(skip: usize, depth: usize) -> Self {
+        Self::collect(hook(), skip + 1, depth.min(MAX_FRAMES))
+    }
+
+    #[inline(always)]
+    fn collect(hook: BacktraceHook, skip: usize, depth: usize) -> Self {
+        let mut trace = Self { pcs: [0; MAX_FRAMES], len: 0 };
+        trace.len = match hook {
+            Some(hook) => unsafe { hook(trace.pcs.as_mut_ptr(), depth).min(depth) },
+            None => unwind(skip, &mut trace.pcs[..depth]),
+        };
+        trace
+    }
+
+    /// A call stack of the frames at the return addresses `pcs`, innermost
+    /// first, of which the first [`MAX_FRAMES`] are kept.
+    pub fn from_frames(pcs: &[usize]) -> Self {
+        let len = pcs.len().min(MAX_FRAMES);
+        let mut trace = Self { pcs: [0; MAX_FRAMES], len };
+        trace.pcs[..len].copy_from_slice(&pcs[..len]);
+        trace
+    }
+
+    /// Drops the frames inside of the first one at `pc`, such as those of a
+    /// signal handler that interrupted code at `pc`, if there is such a frame.
+    pub fn starting_at(mut self, pc: usize) -> Self {
+        if let Some(start) = self.frames().iter().position(|&frame| frame == pc) {
+            self.pcs.copy_within(start..self.len, 0);
+            self.len -= start;
+        }
+        self
+    }
+
+    pub fn frames(&self) -> &[usize] {
+        &self.pcs[..self.len]
+    }
+}
+
+/// Displays each frame on a line of its own, as `    #N 0xADDR`.
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, pc) in self.frames().iter().enumerate() {
+            writeln!(f, "    #{i} {pc:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+type UnwindTraceFn = extern "C" fn(ctx: *mut c_void, arg: *mut c_void) -> c_int;
+
+extern "C" {
+    fn _Unwind_Backtrace(trace: UnwindTraceFn, arg: *mut c_void) -> c_int;
+    fn _Unwind_GetIP(ctx: *mut c_void) -> usize;
+}
+
+/// Writes the return addresses of the frames of the current thread's stack to
+/// `pcs` with the unwinder, starting at the caller of this function and
+/// leaving out the `skip` innermost frames. Returns how many were written.
+#[inline(never)]
+pub fn unwind(skip: usize, pcs: &mut [usize]) -> usize {
+    struct Walk<'a> {
+        skip: usize,
+        pcs: &'a mut [usize],
+        len: usize,
+    }
+
+    extern "C" fn trace(ctx: *mut c_void, arg: *mut c_void) -> c_int {
+        let walk = unsafe { &mut *arg.cast::<Walk<'_>>() };
+        if walk.skip > 0 {
+            walk.skip -= 1;
+            return 0;
+        }
+        let pc = unsafe { _Unwind_GetIP(ctx) };
+        // The outermost frame may be reported with a null return address.
+        if walk.len == walk.pcs.len() || pc == 0 {
+            // Any nonzero reason code stops the walk.
+            return 1;
+        }
+        walk.pcs[walk.len] = pc;
+        walk.len += 1;
+        0
+    }
+
+    // The unwinder reports this frame first.
+    let mut walk = Walk { skip: skip + 1, pcs, len: 0 };
+    unsafe { _Unwind_Backtrace(trace, (&raw mut walk).cast()) };
+    walk.len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn outer() -> Backtrace {
+        let trace = inner();
+        core::hint::black_box(trace)
+    }
+
+    #[inline(never)]
+    fn inner() -> Backtrace {
+        let trace = Backtrace::capture(0);
+        core::hint::black_box(trace)
+    }
+
+    #[test]
+    fn frames_start_at_the_caller() {
+        let trace = outer();
+        let frames = trace.frames();
+        assert!(frames.len() >= 2);
+        // The return address into `outer` is the second frame.
+        let start = outer as fn() -> Backtrace as usize;
+        assert!(frames[1] > start && frames[1] - start < 0x1000);
+        assert_eq!(Backtrace::capture(frames.len() + MAX_FRAMES).frames(), []);
+        let shallow = Backtrace::capture_with_depth(0, 2);
+        assert_eq!(shallow.frames().len(), 2);
+        assert_eq!(shallow.frames()[1], Backtrace::capture(0).frames()[1]);
+        assert_eq!(Backtrace::from_frames(&frames[1..]).frames(), &frames[1..]);
+    }
+
+    #[test]
+    fn supplied_collectors_are_used() {
+        unsafe extern "C" fn collect(pcs: *mut usize, max: usize) -> usize {
+            *pcs = 0x1234;
+            // Collectors that overstate the frames they wrote are clamped.
+            max + 1
+        }
+        let trace = Backtrace::collect(Some(collect), 0, MAX_FRAMES);
+        assert_eq!(trace.frames().len(), MAX_FRAMES);
+        assert_eq!(trace.frames()[0], 0x1234);
+        let trace = Backtrace { pcs: [0x1234; MAX_FRAMES], len: 1 };
+        assert_eq!(trace.to_string(), "    #0 0x1234\n");
+    }
+}
@@ -0,0 +1,160 @@
+//! Logical clocks for ordering runtime events.
+//!
+//! Every recorded event is stamped with the thread that produced it, a
+//! per-thread sequence number, and the global epoch at the time it happened.
+//! Events from the same thread are totally ordered by their sequence numbers.
+//! The epoch is advanced by events that synchronize through the runtime, such
+//! as allocations and frees, which take the registry lock. An event in an
+//! earlier epoch happened before every event in a later one, while events
+//! from different threads within the same epoch are unordered.
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Identifies a thread for the lifetime of the process. IDs are assigned in
+/// the order in which threads first record an event, starting from 1.
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u32);
+
+impl ThreadId {
+    pub const UNKNOWN: ThreadId = ThreadId(0);
+
+    /// The ID of the current thread.
+    pub fn current() -> ThreadId {
+        let id = THREAD_ID.get();
+        if id != ThreadId::UNKNOWN {
+            return id;
+        }
+        let id = ThreadId(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed));
+        THREAD_ID.set(id);
+        id
+    }
+
+    #[inline]
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+// The low bits of a thread-scoped ID, which count within its thread.
+const THREAD_SCOPED_BITS: u32 = 40;
+
+/// Turns `n`, the `n`th ID counting from 1 that the current thread took from
+/// a counter of its own, into one that is unique across threads, by putting
+/// the thread in its upper bits. Unlike IDs taken from a shared counter, these
+/// only depend on the order in which threads start, not on how they
+/// interleave, and those of the first thread are `n` itself. Returns `None`
+/// once the thread, or the process, runs out of them.
+pub fn thread_scoped_id(n: u64) -> Option<u64> {
+    let thread = u64::from(ThreadId::current().get().saturating_sub(1));
+    if n >> THREAD_SCOPED_BITS != 0 || thread >> (u64::BITS - THREAD_SCOPED_BITS) != 0 {
+        return None;
+    }
+    Some(thread << THREAD_SCOPED_BITS | n)
+}
+
+#[thread_local]
+static THREAD_ID: Cell<ThreadId> = Cell::new(ThreadId::UNKNOWN);
+
+#[thread_local]
+static THREAD_SEQ: Cell<u64> = Cell::new(0);
+
+static NEXT_THREAD_ID: AtomicU32 = AtomicU32::new(1);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EventStamp {
+    pub thread: ThreadId,
+    pub seq: u64,
+    pub epoch: u64,
+}
+
+impl EventStamp {
+    /// Whether the event stamped with `self` is known to have happened
+    /// before the one stamped with `other`.
+    pub fn happens_before(&self, other: &EventStamp) -> bool {
+        if self.thread == other.thread { self.seq < other.seq } else { self.epoch < other.epoch }
+    }
+}
+
+#[derive(Debug)]
+pub struct LogicalClock {
+    epoch: AtomicU64,
+}
+
+impl LogicalClock {
+    pub const fn new() -> Self {
+        Self { epoch: AtomicU64::new(0) }
+    }
+
+    /// Stamps an event on the current thread.
+    pub fn stamp(&self) -> EventStamp {
+        let seq = THREAD_SEQ.get() + 1;
+        THREAD_SEQ.set(seq);
+        EventStamp { thread: ThreadId::current(), seq, epoch: self.epoch.load(Ordering::Acquire) }
+    }
+
+    /// Stamps a synchronizing event on the current thread, which ends the
+    /// current epoch. The event belongs to the epoch that it ends, so that it
+    /// happens before every event of the epochs after it, like a release, but
+    /// not after the events that other threads stamped in its own epoch.
+    pub fn stamp_sync(&self) -> EventStamp {
+        let stamp = self.stamp();
+        let epoch = self.epoch.fetch_add(1, Ordering::AcqRel);
+        EventStamp { epoch, ..stamp }
+    }
+
+    /// The current epoch.
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+}
+
+impl Default for LogicalClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stamps_order_events() {
+        let clock = LogicalClock::new();
+        let a = clock.stamp();
+        let b = clock.stamp_sync();
+        let c = clock.stamp();
+        assert!(a.happens_before(&b) && b.happens_before(&c));
+        assert_eq!(b.epoch, a.epoch);
+        assert_eq!(c.epoch, b.epoch + 1);
+        let other = std::thread::scope(|scope| scope.spawn(|| clock.stamp()).join().unwrap());
+        assert_ne!(other.thread, c.thread);
+        // Events from different threads in the same epoch are unordered.
+        assert!(!c.happens_before(&other) && !other.happens_before(&c));
+        assert!(a.happens_before(&other));
+    }
+
+    #[test]
+    fn sync_events_belong_to_the_epoch_they_end() {
+        let clock = LogicalClock::new();
+        let before = std::thread::scope(|scope| scope.spawn(|| clock.stamp()).join().unwrap());
+        let sync = clock.stamp_sync();
+        assert_eq!(sync.epoch, before.epoch);
+        assert_eq!(clock.epoch(), sync.epoch + 1);
+        let after = std::thread::scope(|scope| scope.spawn(|| clock.stamp()).join().unwrap());
+        assert!(!before.happens_before(&sync) && !sync.happens_before(&before));
+        assert!(sync.happens_before(&after));
+    }
+
+    #[test]
+    fn thread_scoped_ids_are_unique_across_threads() {
+        let thread = u64::from(ThreadId::current().get() - 1);
+        assert_eq!(thread_scoped_id(5), Some(thread << THREAD_SCOPED_BITS | 5));
+        assert_eq!(thread_scoped_id(1 << THREAD_SCOPED_BITS), None);
+        let other = std::thread::spawn(|| thread_scoped_id(5)).join().unwrap();
+        assert_ne!(other, thread_scoped_id(5));
+        assert_eq!(other.unwrap() & ((1 << THREAD_SCOPED_BITS) - 1), 5);
+    }
+}
@@ -0,0 +1,194 @@
+//! Replays the traces in `tests/corpus` against the runtime.
+//!
+//! Each trace is a reduced sequence of the events that an instrumented program
+//! reports to the runtime, along with the verdict that the runtime is expected
+//! to reach for each access. They cover the FFI bugs that the runtime is meant
+//! to catch, so that changes to how accesses are checked are tested against
+//! realistic sequences of events, and not only against the unit tests of each
+//! module. A trace has one event per line, and `#` starts a comment:
+//!
+//! ```text
+//! malloc <name> <addr> <size>      a heap allocation, whose root provenance is bound to <name>
+//! global <name> <addr> <size>      a registered global, likewise
+//! free <addr> <ok|invalid>         a free, and whether it was of a live heap allocation
+//! realloc <name> <old> <new> <size>
+//!                                  a `realloc` of the heap allocation at <old> to <new>
+//! store <addr> <name|null>         a pointer with the given provenance stored at <addr>
+//! load <addr> <name|null>          a pointer loaded from <addr>, and its expected provenance
+//! copy <dst> <src> <len>           a `memcpy` or `memmove`
+//! clear <addr> <len>               memory released without a free, such as a popped frame
+//! read <addr> <size> <verdict>     an access, and its expected verdict: `ok`, `null`,
+//! write <addr> <size> <verdict>    `unknown` (outside any allocation), `invalid` (outside
+//!                                  the address space), `out-of-bounds` or `use-after-free`
+//! ```
+//!
+//! An access may name the provenance of the pointer that it is made through as
+//! a fifth word, as in `read 0x1000 8 ok buf`. Accesses without one are checked
+//! against the allocation found from the address, like those of hooks that
+//! are passed null provenance.
+//!
+//! Addresses and sizes are decimal, or hexadecimal with a `0x` prefix. Every
+//! trace is replayed with a fresh context, and all mismatches are reported
+//! together.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::mem;
+use std::path::Path;
+
+use crate::Provenance;
+use crate::access::{self, AccessError};
+use crate::alloc::TEST_ALLOCATOR;
+use crate::global::GlobalContext;
+
+const CORPUS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/corpus");
+
+fn verdict(res: Result<Provenance, AccessError>) -> &'static str {
+    match res {
+        Ok(_) => "ok",
+        Err(AccessError::NullPointer) => "null",
+        Err(AccessError::UnknownMemory) => "unknown",
+        Err(AccessError::InvalidAddress) => "invalid",
+        Err(AccessError::OutOfBounds { .. }) => "out-of-bounds",
+        Err(AccessError::UseAfterFree { .. }) => "use-after-free",
+    }
+}
+
+fn parse_num(s: &str) -> Result<usize, String> {
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    res.map_err(|_| format!("invalid number `{s}`"))
+}
+
+struct Replay {
+    ctx: GlobalContext,
+    names: HashMap<String, Provenance>,
+}
+
+impl Replay {
+    fn new() -> Self {
+        Self { ctx: GlobalContext::new(TEST_ALLOCATOR).unwrap(), names: HashMap::new() }
+    }
+
+    fn provenance(&self, name: &str) -> Result<Provenance, String> {
+        match name {
+            "null" => Ok(Provenance::null()),
+            _ => self.names.get(name).copied().ok_or_else(|| format!("unbound name `{name}`")),
+        }
+    }
+
+    fn name_of(&self, prov: Provenance) -> String {
+        if prov == Provenance::null() {
+            return "null".into();
+        }
+        match self.names.iter().find(|(_, &p)| p == prov) {
+            Some((name, _)) => name.clone(),
+            None => format!("{prov:?}"),
+        }
+    }
+
+    /// Replays a single event. Returns `Ok(Some(..))` with a description of the
+    /// mismatch if the runtime disagreed with the trace.
+    unsafe fn event(&mut self, words: &[&str]) -> Result<Option<String>, String> {
+        let ctx = &self.ctx;
+        let mismatch = |expected: &str, actual: &str| {
+            (expected != actual).then(|| format!("expected `{expected}`, got `{actual}`"))
+        };
+        Ok(match *words {
+            ["malloc" | "global", name, addr, size] => {
+                let (addr, size) = (parse_num(addr)?, parse_num(size)?);
+                let prov = match words[0] {
+                    "malloc" => ctx.new_allocation(addr, size),
+                    _ => ctx.register_global(addr, size),
+                };
+                self.names.insert(name.into(), prov.ok_or("failed to register allocation")?);
+                None
+            }
+            ["realloc", name, old, new, size] => {
+                let (old, new, size) = (parse_num(old)?, parse_num(new)?, parse_num(size)?);
+                let prov =
+                    ctx.reallocate(old, new, size, 1).ok_or("realloc of unknown allocation")?;
+                self.names.insert(name.into(), prov);
+                None
+            }
+            ["free", addr, expected] => {
+                let freed = ctx.free_allocation(parse_num(addr)?);
+                mismatch(expected, if freed { "ok" } else { "invalid" })
+            }
+            ["store", addr, name] => {
+                // Like `bsan_store_prov`, this drops stores to invalid addresses.
+                let (addr, prov) = (parse_num(addr)?, self.provenance(name)?);
+                if ctx.shadow().covers(addr, mem::size_of::<usize>())
+                    && !ctx.shadow().store(addr, prov)
+                {
+                    return Err("failed to allocate shadow memory".into());
+                }
+                None
+            }
+            ["load", addr, expected] => {
+                self.provenance(expected)?;
+                let prov = ctx.shadow().load(parse_num(addr)?);
+                mismatch(expected, &self.name_of(prov))
+            }
+            ["copy", dst, src, len] => {
+                ctx.shadow().copy_range(parse_num(dst)?, parse_num(src)?, parse_num(len)?);
+                None
+            }
+            ["clear", addr, len] => {
+                ctx.shadow().clear_range(parse_num(addr)?, parse_num(len)?);
+                None
+            }
+            ["read" | "write", addr, size, expected] => {
+                let res = access::resolve_access(ctx, parse_num(addr)?, parse_num(size)?);
+                mismatch(expected, verdict(res))
+            }
+            ["read" | "write", addr, size, expected, name] => {
+                let (addr, size, prov) =
+                    (parse_num(addr)?, parse_num(size)?, self.provenance(name)?);
+                mismatch(expected, verdict(access::check_access_with(ctx, prov, addr, size)))
+            }
+            _ => return Err("malformed event".into()),
+        })
+    }
+}
+
+fn replay(path: &Path) -> Vec<String> {
+    let contents = std::fs::read_to_string(path).unwrap();
+    let mut replay = Replay::new();
+    let mut failures = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        match unsafe { replay.event(&words) } {
+            Ok(None) => {}
+            Ok(Some(mismatch)) => {
+                failures.push(format!("{}:{}: {mismatch}", path.display(), i + 1))
+            }
+            Err(err) => failures.push(format!("{}:{}: {err}", path.display(), i + 1)),
+        }
+    }
+    failures
+}
+
+#[test]
+fn corpus_verdicts_are_unchanged() {
+    let mut traces: Vec<_> = std::fs::read_dir(CORPUS_DIR)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "trace"))
+        .collect();
+    traces.sort();
+    assert!(!traces.is_empty(), "no traces in {CORPUS_DIR}");
+    let mut report = String::new();
+    for trace in &traces {
+        for failure in replay(trace) {
+            writeln!(report, "{failure}").unwrap();
+        }
+    }
+    assert!(report.is_empty(), "traces disagree with the runtime:\n{report}");
+}
@@ -0,0 +1,238 @@
+//! Deduplication and limiting of error reports.
+//!
+//! Loops that run into the same error would otherwise bury everything else
+//! in the output, so only the first of the errors with the same kind, site and
+//! allocation site is reported. Later ones are still counted, and the number
+//! of repeats is printed in the summary at exit. `BSAN_DEDUP_ERRORS=0` reports
+//! every error.
+//!
+//! Programs can still run into many distinct errors, so `BSAN_MAX_ERRORS=<n>`
+//! stops reporting them after the first `n`. Later errors are only counted,
+//! unless `BSAN_EXIT_ON_MAX_ERRORS=1` has the process exit after the last one
+//! that is reported, as `BSAN_HALT_ON_ERROR=1` does after the first.
+
+use core::cmp;
+use core::hash::Hasher;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::io;
+use crate::sync::SpinLock;
+
+/// The number of distinct errors that are remembered. Errors past this are
+/// reported even if they repeat.
+pub const MAX_UNIQUE_ERRORS: usize = 1024;
+
+/// The errors that were reported, by the hashes of what identifies them.
+#[derive(Debug)]
+pub struct ErrorTable {
+    enabled: bool,
+    // An open-addressed set, in which every hash can be stored.
+    hashes: SpinLock<[Option<u64>; MAX_UNIQUE_ERRORS]>,
+    repeats: AtomicU64,
+}
+
+impl Default for ErrorTable {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+impl ErrorTable {
+    /// A table that only lets the first error with each hash be reported, if
+    /// it is `enabled`.
+    pub const fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            hashes: SpinLock::new([None; MAX_UNIQUE_ERRORS]),
+            repeats: AtomicU64::new(0),
+        }
+    }
+
+    /// Reads whether errors are deduplicated from the environment.
+    pub fn from_env() -> Self {
+        Self::new(io::env_flag_or(c"BSAN_DEDUP_ERRORS", true))
+    }
+
+    /// Records an error with `hash`, and returns whether it should be
+    /// reported, because no earlier error had the same hash.
+    pub fn record(&self, hash: u64) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        let mut hashes = self.hashes.lock();
+        let start = hash as usize % MAX_UNIQUE_ERRORS;
+        for i in 0..MAX_UNIQUE_ERRORS {
+            let slot = &mut hashes[(start + i) % MAX_UNIQUE_ERRORS];
+            match *slot {
+                Some(other) if other == hash => {
+                    self.repeats.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+                Some(_) => {}
+                None => {
+                    *slot = Some(hash);
+                    return true;
+                }
+            }
+        }
+        true
+    }
+
+    /// The number of errors that weren't reported because they repeated an
+    /// earlier one.
+    pub fn repeats(&self) -> u64 {
+        self.repeats.load(Ordering::Relaxed)
+    }
+}
+
+/// What happens to an error that isn't a repeat, under a [`ReportLimit`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Admission {
+    Report,
+    /// The error is the last one that is reported.
+    ReportLast,
+    Drop,
+}
+
+/// The number of distinct errors that are reported.
+#[derive(Debug)]
+pub struct ReportLimit {
+    // 0 for no limit.
+    max: u64,
+    exit: bool,
+    admitted: AtomicU64,
+}
+
+impl Default for ReportLimit {
+    fn default() -> Self {
+        Self::new(0, false)
+    }
+}
+
+impl ReportLimit {
+    /// A limit of `max` reports, or none if it is 0, after which the process
+    /// exits if `exit` is set.
+    pub const fn new(max: u64, exit: bool) -> Self {
+        Self { max, exit, admitted: AtomicU64::new(0) }
+    }
+
+    /// Reads the limit from the environment.
+    pub fn from_env() -> Self {
+        Self::new(io::env_parse(c"BSAN_MAX_ERRORS", 0), io::env_flag(c"BSAN_EXIT_ON_MAX_ERRORS"))
+    }
+
+    /// Decides whether an error is reported, counting it towards the limit.
+    pub fn admit(&self) -> Admission {
+        if self.max == 0 {
+            return Admission::Report;
+        }
+        let n = self.admitted.fetch_add(1, Ordering::Relaxed) + 1;
+        match n.cmp(&self.max) {
+            cmp::Ordering::Less => Admission::Report,
+            cmp::Ordering::Equal => Admission::ReportLast,
+            cmp::Ordering::Greater => Admission::Drop,
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Whether the process exits after the last error that is reported.
+    pub fn exits(&self) -> bool {
+        self.exit
+    }
+
+    /// The number of errors that weren't reported because of the limit.
+    pub fn dropped(&self) -> u64 {
+        self.admitted.load(Ordering::Relaxed).saturating_sub(self.max)
+    }
+}
+
+/// The 64-bit FNV-1a hash, which is stable across runs, unlike the hashers of
+/// `std`.
+#[derive(Debug, Copy, Clone)]
+pub struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FnvHasher {
+    pub const fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_first_of_each_error_is_reported() {
+        let table = ErrorTable::new(true);
+        assert!(table.record(7));
+        assert!(table.record(7 + MAX_UNIQUE_ERRORS as u64));
+        assert!(!table.record(7));
+        assert!(!table.record(7 + MAX_UNIQUE_ERRORS as u64));
+        // 0 is kept apart from empty slots, and from 1.
+        assert!(table.record(0));
+        assert!(!table.record(0));
+        assert!(table.record(1));
+        assert!(!table.record(1));
+        assert_eq!(table.repeats(), 4);
+        let table = ErrorTable::new(false);
+        assert!(table.record(7) && table.record(7));
+        assert_eq!(table.repeats(), 0);
+    }
+
+    #[test]
+    fn full_tables_report_every_error() {
+        let table = ErrorTable::new(true);
+        for hash in 1..=MAX_UNIQUE_ERRORS as u64 {
+            assert!(table.record(hash));
+        }
+        assert!(table.record(0x1_0000));
+        assert!(table.record(0x1_0000));
+        assert!(!table.record(1));
+    }
+
+    #[test]
+    fn reports_stop_at_the_limit() {
+        let limit = ReportLimit::new(2, false);
+        assert_eq!(limit.admit(), Admission::Report);
+        assert_eq!(limit.admit(), Admission::ReportLast);
+        assert_eq!(limit.dropped(), 0);
+        assert_eq!(limit.admit(), Admission::Drop);
+        assert_eq!(limit.admit(), Admission::Drop);
+        assert_eq!(limit.dropped(), 2);
+        let unlimited = ReportLimit::default();
+        assert!((0..10).all(|_| unlimited.admit() == Admission::Report));
+        assert_eq!(unlimited.dropped(), 0);
+    }
+
+    #[test]
+    fn hashes_are_fnv_1a() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = FnvHasher::new();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
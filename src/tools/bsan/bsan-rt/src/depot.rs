@@ -0,0 +1,161 @@
+//! Storage for the call stacks at which heap allocations are made.
+//!
+//! With `BSAN_ALLOC_STACK_DEPTH=<n>`, the runtime captures the innermost `n`
+//! frames, up to [`MAX_FRAMES`], of the call stack of every heap allocation,
+//! and reports about an allocation show where it was made. This is off by
+//! default, since unwinding at every allocation is expensive. As in ASan's
+//! stack depot, each distinct stack is stored once, for the rest of the run,
+//! so allocations made at the same place share it and their metadata only
+//! grows by a pointer.
+
+use core::alloc::{Allocator, Layout};
+use core::hash::Hasher;
+use core::ptr::{self, NonNull};
+use core::slice;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::BsanAllocator;
+use crate::backtrace::Backtrace;
+pub use crate::backtrace::MAX_FRAMES;
+use crate::dedup::FnvHasher;
+
+/// The number of distinct stacks that can be stored. Stacks past this aren't
+/// recorded.
+pub const DEPOT_LEN: usize = 1 << 14;
+
+/// The depot that the stacks of allocations are stored in. It outlives the
+/// global context, which can be replaced, and stores no more than it can hold
+/// outside of it.
+pub static ALLOC_STACKS: StackDepot = StackDepot::new();
+
+/// A set of call stacks, which are never removed.
+#[derive(Debug)]
+pub struct StackDepot {
+    // An open-addressed table of the stored stacks.
+    slots: [AtomicPtr<StoredStack>; DEPOT_LEN],
+}
+
+// A stack in the depot, which is followed by its frames.
+#[repr(C)]
+#[derive(Debug)]
+struct StoredStack {
+    hash: u64,
+    len: usize,
+}
+
+/// A handle to a call stack in a [`StackDepot`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StackRef(NonNull<StoredStack>);
+
+unsafe impl Send for StackRef {}
+unsafe impl Sync for StackRef {}
+
+impl StackRef {
+    /// The return addresses of the frames of the stack, innermost first.
+    pub fn frames(self) -> &'static [usize] {
+        unsafe {
+            let stack = self.0.as_ptr();
+            slice::from_raw_parts(stack.add(1).cast::<usize>(), (*stack).len)
+        }
+    }
+
+    pub fn to_backtrace(self) -> Backtrace {
+        Backtrace::from_frames(self.frames())
+    }
+}
+
+impl Default for StackDepot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StackDepot {
+    pub const fn new() -> Self {
+        Self { slots: [const { AtomicPtr::new(ptr::null_mut()) }; DEPOT_LEN] }
+    }
+
+    /// Stores `frames`, allocating memory for them with `allocator` unless the
+    /// same stack was stored before. Returns `None` if the depot is full or
+    /// the memory couldn't be allocated.
+    pub fn store(&self, allocator: &BsanAllocator, frames: &[usize]) -> Option<StackRef> {
+        let mut hasher = FnvHasher::new();
+        for &frame in frames {
+            hasher.write_usize(frame);
+        }
+        let hash = hasher.finish();
+        let mut new = None;
+        let start = hash as usize % DEPOT_LEN;
+        for i in 0..DEPOT_LEN {
+            let slot = &self.slots[(start + i) % DEPOT_LEN];
+            let mut stored = slot.load(Ordering::Acquire);
+            if stored.is_null() {
+                let stack = match new {
+                    Some(stack) => stack,
+                    None => *new.insert(allocate(allocator, hash, frames)?),
+                };
+                match slot.compare_exchange(
+                    ptr::null_mut(),
+                    stack,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                ) {
+                    Ok(_) => return NonNull::new(stack).map(StackRef),
+                    // Another thread stored a stack here first.
+                    Err(other) => stored = other,
+                }
+            }
+            let stored = StackRef(unsafe { NonNull::new_unchecked(stored) });
+            if unsafe { (*stored.0.as_ptr()).hash } == hash && stored.frames() == frames {
+                if let Some(stack) = new {
+                    unsafe { deallocate(allocator, stack) };
+                }
+                return Some(stored);
+            }
+        }
+        if let Some(stack) = new {
+            unsafe { deallocate(allocator, stack) };
+        }
+        None
+    }
+}
+
+fn layout(len: usize) -> Layout {
+    let frames = Layout::array::<usize>(len).unwrap();
+    Layout::new::<StoredStack>().extend(frames).unwrap().0.pad_to_align()
+}
+
+fn allocate(allocator: &BsanAllocator, hash: u64, frames: &[usize]) -> Option<*mut StoredStack> {
+    let stack = allocator.allocate(layout(frames.len())).ok()?.cast::<StoredStack>().as_ptr();
+    unsafe {
+        stack.write(StoredStack { hash, len: frames.len() });
+        let dst = stack.add(1).cast::<usize>();
+        ptr::copy_nonoverlapping(frames.as_ptr(), dst, frames.len());
+    }
+    Some(stack)
+}
+
+unsafe fn deallocate(allocator: &BsanAllocator, stack: *mut StoredStack) {
+    let layout = layout((*stack).len);
+    allocator.deallocate(NonNull::new_unchecked(stack).cast(), layout);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::TEST_ALLOCATOR;
+
+    #[test]
+    fn identical_stacks_are_stored_once() {
+        let depot = Box::new(StackDepot::new());
+        let first = depot.store(&TEST_ALLOCATOR, &[0x10, 0x20, 0x30]).unwrap();
+        let second = depot.store(&TEST_ALLOCATOR, &[0x10, 0x20]).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first.frames(), [0x10, 0x20, 0x30]);
+        assert_eq!(second.frames(), [0x10, 0x20]);
+        assert_eq!(depot.store(&TEST_ALLOCATOR, &[0x10, 0x20, 0x30]), Some(first));
+        let empty = depot.store(&TEST_ALLOCATOR, &[]).unwrap();
+        assert_eq!(empty.frames(), [] as [usize; 0]);
+        assert_eq!(empty.to_backtrace().frames(), [] as [usize; 0]);
+    }
+}
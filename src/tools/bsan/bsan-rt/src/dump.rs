@@ -0,0 +1,92 @@
+//! Dumps of the allocation registry for offline analysis.
+//!
+//! `bsan_dump_registry` writes every live allocation to a JSON file, in order
+//! of their base addresses. Each allocation is on its own line, so large dumps
+//! can also be processed as a stream:
+//!
+//! ```text
+//! {"version":1,"pid":<pid>,"epoch":<current epoch>,"allocations":[
+//! {"id":<id>,"base":<base address>,"size":<size>,"tag":<root tag>,"state":"live"},
+//! ...
+//! ]}
+//! ```
+//!
+//! Addresses are written as decimal numbers, which is exact in JSON for the
+//! 48-bit addresses of current targets. New fields are only ever added, and
+//! `version` is incremented whenever the meaning of an existing field changes.
+//! Like checkpoints, dumps are written to `<path>.tmp` and then renamed.
+
+use core::fmt::{self, Write};
+
+use crate::global::GlobalContext;
+use crate::io::{self, CPathBuf};
+use crate::registry::AllocState;
+
+pub const DUMP_VERSION: u32 = 1;
+
+/// Writes a dump of the registry to `path`. Returns `false` if the path is
+/// too long or the file could not be written.
+pub fn dump_registry(ctx: &GlobalContext, path: CPathBuf) -> bool {
+    let Some(tmp_path) = path.with_suffix(b".tmp") else { return false };
+    unsafe { io::write_atomically(&path, &tmp_path, |out| serialize(ctx, out)) }
+}
+
+fn serialize(ctx: &GlobalContext, out: &mut impl Write) -> fmt::Result {
+    write!(out, "{{\"version\":{DUMP_VERSION},\"pid\":{},", unsafe { libc::getpid() })?;
+    writeln!(out, "\"epoch\":{},\"allocations\":[", ctx.clock().epoch())?;
+    let mut res = Ok(());
+    let mut first = true;
+    ctx.registry().for_each(|meta| {
+        if res.is_err() {
+            return;
+        }
+        let state = match meta.state {
+            AllocState::Live => "live",
+            AllocState::Freed => "freed",
+        };
+        res = writeln!(
+            out,
+            "{}{{\"id\":{},\"base\":{},\"size\":{},\"tag\":{},\"state\":\"{state}\"}}",
+            if first { "" } else { "," },
+            meta.id.get(),
+            meta.base_addr,
+            meta.size,
+            meta.root_tag.get(),
+        );
+        first = false;
+    });
+    res?;
+    writeln!(out, "]}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::TEST_ALLOCATOR;
+
+    #[test]
+    fn dump_lists_live_allocations() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let path = std::env::temp_dir().join(format!("bsan-dump-{}.json", std::process::id()));
+        unsafe {
+            ctx.new_allocation(0x1000, 8).unwrap();
+            ctx.new_allocation(0x2000, 16).unwrap();
+            ctx.new_allocation(0x3000, 4).unwrap();
+            assert!(ctx.free_allocation(0x3000));
+        }
+        assert!(dump_registry(&ctx, CPathBuf::new(path.to_str().unwrap().as_bytes()).unwrap()));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(lines[0].starts_with("{\"version\":1,\"pid\":"));
+        assert!(lines[0].ends_with(",\"epoch\":4,\"allocations\":["));
+        assert_eq!(
+            &lines[1..],
+            [
+                "{\"id\":1,\"base\":4096,\"size\":8,\"tag\":1,\"state\":\"live\"}",
+                ",{\"id\":2,\"base\":8192,\"size\":16,\"tag\":2,\"state\":\"live\"}",
+                "]}",
+            ]
+        );
+    }
+}
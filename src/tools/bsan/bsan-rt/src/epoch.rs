@@ -0,0 +1,178 @@
+//! Epoch-based reclamation, which defers freeing the runtime's own memory until
+//! no thread can still be reading it.
+//!
+//! A hook may reach the metadata of an allocation without holding a reference
+//! to it: through the registry, or through provenance that it loads from
+//! shadow memory while another thread overwrites it. If the last reference is
+//! released in the meantime, the metadata must not be reused until the hook is
+//! done with it. Every hook [`pin`]s the current thread for as long as it
+//! runs, and memory that is retired at some epoch is only freed once the
+//! global epoch has advanced twice past it. The epoch only advances when every
+//! pinned thread has seen the current one, so no thread that was in a hook
+//! when the memory was retired is still in it then.
+//!
+//! Each thread that runs a hook is given a record of the epoch that it is
+//! pinned at the first time it does, which is allocated from the
+//! [runtime's allocator](crate::alloc::runtime_allocator).
+//! `bsan_thread_exit` frees it; the records of threads that exit without
+//! calling it are never pinned again, so they don't hold the epoch back.
+
+use core::alloc::{Allocator, Layout};
+use core::cell::Cell;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{self, AtomicU64, Ordering};
+
+use crate::alloc::runtime_allocator;
+use crate::sync::SpinLock;
+
+// The state of a participant: `epoch << 1 | 1` while it is pinned, and 0
+// otherwise.
+const PINNED: u64 = 1;
+
+#[derive(Debug)]
+struct Participant {
+    state: AtomicU64,
+    next: *mut Participant,
+}
+
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+// Every participant whose record hasn't been freed.
+static PARTICIPANTS: SpinLock<Participants> = SpinLock::new(Participants(ptr::null_mut()));
+
+struct Participants(*mut Participant);
+
+unsafe impl Send for Participants {}
+
+#[thread_local]
+static LOCAL: Cell<*const Participant> = Cell::new(ptr::null());
+
+/// Keeps the current thread pinned until it is dropped, unless it returns its
+/// record first.
+#[derive(Debug)]
+pub struct EpochGuard(());
+
+/// Pins the current thread at the current epoch, so that nothing that is
+/// retired from now on is freed until the guard is dropped. Returns `None` if
+/// the thread has no record and one couldn't be allocated.
+#[inline(always)]
+pub fn pin() -> Option<EpochGuard> {
+    let mut participant = LOCAL.get();
+    if participant.is_null() {
+        participant = register()?;
+    }
+    let epoch = EPOCH.load(Ordering::Relaxed);
+    unsafe { (*participant).state.store(epoch << 1 | PINNED, Ordering::Relaxed) };
+    // Reads of shared metadata must not be ordered before the pin is visible
+    // to the threads that advance the epoch.
+    atomic::fence(Ordering::SeqCst);
+    Some(EpochGuard(()))
+}
+
+impl Drop for EpochGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if let Some(participant) = unsafe { LOCAL.get().as_ref() } {
+            participant.state.store(0, Ordering::Release);
+        }
+    }
+}
+
+// Gives the current thread a record.
+#[cold]
+fn register() -> Option<*const Participant> {
+    let layout = Layout::new::<Participant>();
+    let participant = runtime_allocator().allocate(layout).ok()?.cast::<Participant>().as_ptr();
+    let mut participants = PARTICIPANTS.lock();
+    unsafe { participant.write(Participant { state: AtomicU64::new(0), next: participants.0 }) };
+    participants.0 = participant;
+    LOCAL.set(participant);
+    Some(participant)
+}
+
+/// Unpins the current thread and frees its record, if it has one.
+pub fn unregister() {
+    let Some(participant) = NonNull::new(LOCAL.replace(ptr::null()).cast_mut()) else {
+        return;
+    };
+    let mut participants = PARTICIPANTS.lock();
+    let mut link = &mut participants.0;
+    unsafe {
+        while *link != participant.as_ptr() {
+            link = &mut (**link).next;
+        }
+        *link = participant.as_ref().next;
+    }
+    drop(participants);
+    unsafe { runtime_allocator().deallocate(participant.cast(), Layout::new::<Participant>()) };
+}
+
+/// The current epoch, which memory that is retired now is tagged with.
+#[inline]
+pub fn current() -> u64 {
+    EPOCH.load(Ordering::SeqCst)
+}
+
+/// Advances the epoch if every pinned thread has seen the current one, and
+/// returns the epoch afterwards.
+pub fn try_advance() -> u64 {
+    // Records are freed as threads exit, so the list stays locked while it
+    // is scanned.
+    let participants = PARTICIPANTS.lock();
+    let epoch = EPOCH.load(Ordering::Relaxed);
+    atomic::fence(Ordering::SeqCst);
+    let mut current = participants.0;
+    while let Some(participant) = unsafe { current.as_ref() } {
+        let state = participant.state.load(Ordering::Relaxed);
+        if state & PINNED != 0 && state >> 1 != epoch {
+            return epoch;
+        }
+        current = participant.next;
+    }
+    drop(participants);
+    atomic::fence(Ordering::Acquire);
+    match EPOCH.compare_exchange(epoch, epoch + 1, Ordering::SeqCst, Ordering::Relaxed) {
+        Ok(_) => epoch + 1,
+        Err(epoch) => epoch,
+    }
+}
+
+/// Whether memory that was retired at `retired_at` can be freed at `epoch`.
+#[inline]
+pub fn is_reclaimable(retired_at: u64, epoch: u64) -> bool {
+    epoch >= retired_at + 2
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn pinned_threads_hold_the_epoch_back() {
+        let (pinned, wait) = mpsc::channel();
+        let (release, done) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let guard = pin().unwrap();
+            pinned.send(()).unwrap();
+            done.recv().unwrap();
+            unregister();
+            drop(guard);
+        });
+        wait.recv().unwrap();
+        // The pinned thread may have pinned an epoch behind the current one,
+        // if other tests advanced it in the meantime, but it can't fall two
+        // behind.
+        let retired_at = current();
+        for _ in 0..4 {
+            assert!(!is_reclaimable(retired_at, try_advance()));
+        }
+        release.send(()).unwrap();
+        thread.join().unwrap();
+        let mut epoch = current();
+        while !is_reclaimable(retired_at, epoch) {
+            epoch = try_advance();
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! The call stack of each thread of the instrumented program, as reported by
+//! `bsan_func_entry` and `bsan_func_exit`, and the stack allocations and
+//! protectors of each of its frames.
+//!
+//! The stack is tracked by its depth, along with the function of each of the
+//! innermost [`MAX_NAMED_FRAMES`] frames, if the pass identified it. Each stack
+//! allocation records the depth of the frame that made it, and is linked into
+//! a per-thread list, most recent first, through its metadata. When a frame
+//! returns, every allocation at its depth or deeper is retired. Deeper ones are
+//! left behind by frames that never returned normally, such as when `longjmp`
+//! skips over them.
+//!
+//! A tag that is retagged on function entry is protected until the function
+//! returns. The protected tags of each thread are kept on a stack of their own,
+//! along with the depth of the frame that protects each one, and are released
+//! the same way as stack allocations.
+
+use core::cell::Cell;
+use core::ptr::{self, NonNull};
+
+use crate::global::GlobalContext;
+use crate::registry::AllocMetadata;
+use crate::{BorTag, SourceInfo, thread};
+
+/// The number of frames whose functions are recorded on each thread. Frames
+/// past this depth are still counted, but are anonymous.
+pub const MAX_NAMED_FRAMES: usize = 256;
+
+/// The number of tags that can be protected at once on each thread. Further
+/// retags on function entry don't protect their tags until one is released.
+pub const MAX_PROTECTORS: usize = 512;
+
+#[derive(Debug, Copy, Clone)]
+struct Protector {
+    tag: BorTag,
+    depth: usize,
+}
+
+const NO_PROTECTOR: Protector = Protector { tag: BorTag::INVALID, depth: 0 };
+
+/// The call stack of a thread, which is kept in its
+/// [`ThreadContext`](crate::thread::ThreadContext).
+#[derive(Debug)]
+pub struct FrameStack {
+    depth: Cell<usize>,
+    functions: [Cell<*const SourceInfo>; MAX_NAMED_FRAMES],
+    stack_allocs: Cell<*mut AllocMetadata>,
+    protectors: [Cell<Protector>; MAX_PROTECTORS],
+    num_protectors: Cell<usize>,
+}
+
+impl FrameStack {
+    pub const fn new() -> Self {
+        Self {
+            depth: Cell::new(0),
+            functions: [const { Cell::new(ptr::null()) }; MAX_NAMED_FRAMES],
+            stack_allocs: Cell::new(ptr::null_mut()),
+            protectors: [const { Cell::new(NO_PROTECTOR) }; MAX_PROTECTORS],
+            num_protectors: Cell::new(0),
+        }
+    }
+}
+
+#[inline(always)]
+fn stack() -> &'static FrameStack {
+    &thread::current().frames
+}
+
+/// Records a call on the current thread to the function described by `func`,
+/// which may be null if it is unknown.
+#[inline]
+pub fn enter(func: *const SourceInfo) {
+    let depth = stack().depth.get();
+    if let Some(slot) = stack().functions.get(depth) {
+        slot.set(func);
+    }
+    stack().depth.set(depth + 1);
+}
+
+/// Records a return on the current thread, and retires the stack allocations
+/// and releases the protectors of the frame that returned. Returns without a
+/// matching [`enter`] are ignored.
+#[inline]
+pub unsafe fn exit(ctx: &GlobalContext) {
+    let depth = stack().depth.get();
+    if depth == 0 {
+        return;
+    }
+    while let Some(meta) = NonNull::new(stack().stack_allocs.get()) {
+        if meta.as_ref().frame_depth < depth {
+            break;
+        }
+        stack().stack_allocs.set(meta.as_ref().older_in_stack);
+        ctx.retire_stack_allocation(meta);
+    }
+    let mut protectors = stack().num_protectors.get();
+    while protectors > 0 && stack().protectors[protectors - 1].get().depth >= depth {
+        protectors -= 1;
+        stack().protectors[protectors].set(NO_PROTECTOR);
+    }
+    stack().num_protectors.set(protectors);
+    stack().depth.set(depth - 1);
+}
+
+/// Protects `tag` until the current frame returns. Returns `false` if there is
+/// no current frame, or if the thread already has [`MAX_PROTECTORS`].
+pub fn protect(tag: BorTag) -> bool {
+    let (depth, protectors) = (stack().depth.get(), stack().num_protectors.get());
+    if depth == 0 || protectors == MAX_PROTECTORS {
+        return false;
+    }
+    stack().protectors[protectors].set(Protector { tag, depth });
+    stack().num_protectors.set(protectors + 1);
+    true
+}
+
+/// The depth of the frame on the current thread that protects `tag`, if any.
+pub fn protector_depth(tag: BorTag) -> Option<usize> {
+    stack().protectors[..stack().num_protectors.get()]
+        .iter()
+        .map(Cell::get)
+        .find(|protector| protector.tag == tag)
+        .map(|protector| protector.depth)
+}
+
+/// Adds a stack allocation to the current frame.
+pub unsafe fn push(meta: NonNull<AllocMetadata>) {
+    let meta = meta.as_ptr();
+    (*meta).frame_depth = stack().depth.get();
+    (*meta).older_in_stack = stack().stack_allocs.get();
+    stack().stack_allocs.set(meta);
+}
+
+/// Returns from every frame on the current thread, as when it exits. This also
+/// retires the stack allocations that were made outside of any frame.
+pub unsafe fn unwind(ctx: &GlobalContext) {
+    while let Some(meta) = NonNull::new(stack().stack_allocs.get()) {
+        stack().stack_allocs.set(meta.as_ref().older_in_stack);
+        ctx.retire_stack_allocation(meta);
+    }
+    for protector in &stack().protectors[..stack().num_protectors.replace(0)] {
+        protector.set(NO_PROTECTOR);
+    }
+    stack().depth.set(0);
+}
+
+/// Forgets the current thread's stack allocations, without retiring them, once
+/// the context that they were registered with is gone.
+pub fn forget() {
+    stack().stack_allocs.set(ptr::null_mut());
+}
+
+/// The function of the frame at `depth` on the current thread, counting from 1
+/// for the outermost frame, if it is known.
+///
+/// # Safety
+/// The `SourceInfo` passed to [`enter`] for the frame must still be valid.
+pub unsafe fn function_at<'a>(depth: usize) -> Option<&'a SourceInfo> {
+    stack().functions.get(depth.checked_sub(1)?)?.get().as_ref()
+}
+
+/// The depth of the current thread's call stack.
+pub fn depth() -> usize {
+    stack().depth.get()
+}
+
+/// The function of the innermost frame on the current thread, if it is known.
+///
+/// # Safety
+/// The `SourceInfo` passed to [`enter`] for the frame must still be valid.
+pub unsafe fn current_function<'a>() -> Option<&'a SourceInfo> {
+    function_at(stack().depth.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Provenance;
+    use crate::access::{self, AccessError};
+    use crate::alloc::TEST_ALLOCATOR;
+
+    fn resolves(ctx: &GlobalContext, prov: Provenance, addr: usize) -> bool {
+        access::resolve_access(ctx, addr, 1) == Ok(prov)
+    }
+
+    #[test]
+    fn stack_allocations_are_retired_with_their_frame() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            enter(ptr::null());
+            let outer = ctx.new_stack_allocation(0x1000, 16).unwrap();
+            enter(ptr::null());
+            let inner = ctx.new_stack_allocation(0x2000, 16).unwrap();
+            // Returning from a frame that was skipped over, as by `longjmp`.
+            enter(ptr::null());
+            let skipped = ctx.new_stack_allocation(0x3000, 16).unwrap();
+            stack().depth.set(depth() - 1);
+            assert!(resolves(&ctx, skipped, 0x3000));
+            exit(&ctx);
+            assert!(resolves(&ctx, outer, 0x1000));
+            assert_eq!(access::resolve_access(&ctx, 0x2000, 1), Err(AccessError::UnknownMemory));
+            assert_eq!(access::resolve_access(&ctx, 0x3000, 1), Err(AccessError::UnknownMemory));
+            exit(&ctx);
+            assert_eq!(access::resolve_access(&ctx, 0x1000, 1), Err(AccessError::UnknownMemory));
+            assert_eq!(depth(), 0);
+            exit(&ctx);
+            assert_eq!(depth(), 0);
+            for prov in [outer, inner, skipped] {
+                ctx.release_metadata(NonNull::new_unchecked(prov.lock_address.cast()));
+            }
+            assert_eq!(ctx.live_metadata(), 0);
+        }
+    }
+
+    #[test]
+    fn unwinding_retires_every_frame() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let outside = ctx.new_stack_allocation(0x1000, 16).unwrap();
+            enter(ptr::null());
+            enter(ptr::null());
+            let inner = ctx.new_stack_allocation(0x2000, 16).unwrap();
+            assert!(protect(BorTag::new(1)));
+            unwind(&ctx);
+            assert_eq!(depth(), 0);
+            assert_eq!(protector_depth(BorTag::new(1)), None);
+            for (prov, addr) in [(outside, 0x1000), (inner, 0x2000)] {
+                assert_eq!(access::resolve_access(&ctx, addr, 1), Err(AccessError::UnknownMemory));
+                ctx.release_metadata(NonNull::new_unchecked(prov.lock_address.cast()));
+            }
+            assert_eq!(ctx.live_metadata(), 0);
+        }
+    }
+
+    #[test]
+    fn protectors_are_released_with_their_frame() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let (outer, inner, skipped) = (BorTag::new(1), BorTag::new(2), BorTag::new(3));
+        unsafe {
+            assert!(!protect(outer));
+            enter(ptr::null());
+            assert!(protect(outer));
+            enter(ptr::null());
+            assert!(protect(inner));
+            enter(ptr::null());
+            assert!(protect(skipped));
+            stack().depth.set(depth() - 1);
+            assert_eq!(protector_depth(skipped), Some(3));
+            exit(&ctx);
+            assert_eq!(protector_depth(outer), Some(1));
+            assert_eq!(protector_depth(inner), None);
+            assert_eq!(protector_depth(skipped), None);
+            // The protectors that don't fit are dropped.
+            for _ in 0..MAX_PROTECTORS - 1 {
+                assert!(protect(inner));
+            }
+            assert!(!protect(skipped));
+            exit(&ctx);
+            assert_eq!(protector_depth(outer), None);
+            assert_eq!(stack().num_protectors.get(), 0);
+        }
+    }
+
+    #[test]
+    fn frames_record_their_function() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let info = |function: &'static core::ffi::CStr| SourceInfo {
+            file: ptr::null(),
+            line: 0,
+            column: 0,
+            function: function.as_ptr(),
+        };
+        let (outer, inner) = (info(c"outer"), info(c"inner"));
+        unsafe {
+            assert!(current_function().is_none());
+            enter(&outer);
+            enter(&inner);
+            assert!(ptr::eq(current_function().unwrap(), &inner));
+            enter(ptr::null());
+            assert!(current_function().is_none());
+            exit(&ctx);
+            exit(&ctx);
+            assert!(ptr::eq(current_function().unwrap(), &outer));
+            // Frames past the last named one are anonymous.
+            for _ in 1..MAX_NAMED_FRAMES {
+                enter(&inner);
+            }
+            enter(&inner);
+            assert!(current_function().is_none());
+            for _ in 0..MAX_NAMED_FRAMES {
+                exit(&ctx);
+            }
+            assert!(ptr::eq(current_function().unwrap(), &outer));
+            exit(&ctx);
+            assert_eq!(depth(), 0);
+        }
+    }
+}
@@ -1,27 +1,1551 @@
-use core::cell::SyncUnsafeCell;
-use core::sync::atomic::AtomicUsize;
+use core::cell::{Cell, SyncUnsafeCell};
+use core::ffi::c_int;
+use core::fmt::{self, Write};
+use core::hint;
+use core::ops::ControlFlow;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 
-use crate::BsanAllocator;
+use crate::abi::AbiMode;
+use crate::backtrace::{Backtrace, MAX_FRAMES};
+use crate::checkpoint::Checkpointer;
+use crate::clock::{self, EventStamp, LogicalClock, ThreadId};
+use crate::dedup::{ErrorTable, ReportLimit};
+use crate::depot::{ALLOC_STACKS, StackRef};
+use crate::history::{Event, EventKind};
+use crate::ignore::IgnoreList;
+use crate::io::{self, FdWriter};
+use crate::miri::{self, Tracked};
+use crate::module::ModuleTable;
+use crate::pool::BlockPool;
+use crate::registry::{AllocKind, AllocMetadata, AllocRegistry, AllocState, RetiredList};
+use crate::report::{self, Addr, ErrorKind, OutputOptions, Severities, Severity};
+use crate::shadow::{self, ShadowHeap};
+use crate::stats::{self, ShadowStats, StatCounters};
+use crate::symbolize::Symbolizer;
+use crate::tag::TagHint;
+use crate::{
+    AllocId, BorTag, BsanAllocator, Provenance, SourceInfo, TagAllocator, access, alloc, epoch,
+    frame, host, options, sanitizer, signal, thread, trace,
+};
+
+// The blocks that the metadata of allocations are kept in. Like the depot, it
+// outlives the global context, since metadata can outlive the context that
+// registered it.
+static METADATA_POOL: BlockPool<AllocMetadata> = BlockPool::new();
+
+// The function of the current thread's innermost frame, recorded in the
+// metadata of the allocations that it makes and frees.
+fn frame_function() -> *const SourceInfo {
+    unsafe { frame::current_function() }.map_or(ptr::null(), ptr::from_ref)
+}
+
+/// The options that decide how the runtime behaves, other than those of its
+/// output. They are read from the environment once, by `bsan_init`, and only
+/// read afterwards, so hooks check them with plain loads.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RuntimeFlags {
+    /// How the hooks treat calls that break their ABI.
+    pub abi_mode: AbiMode,
+    /// Whether the process is terminated after the first error that is
+    /// reported, rather than continuing so that later errors are found too.
+    pub halt_on_error: bool,
+    /// The status that the process exits with when it is halted on an error.
+    pub exit_code: c_int,
+    /// Whether allocations keep a history of the recent events through their
+    /// pointers, for reports.
+    pub alloc_history: bool,
+    /// The number of frames of the call stacks of heap allocations that are
+    /// captured, which is 0 if they aren't.
+    pub alloc_stack_depth: usize,
+    /// Whether the heap allocations that are still live at exit are reported,
+    /// unless `BSAN_IGNORE_LEAKS` is set, as Miri's `-Zmiri-ignore-leaks` would.
+    pub detect_leaks: bool,
+    /// Whether accesses through pointers whose provenance doesn't allow them
+    /// fall back to the allocation at their address, if a pointer to it was
+    /// exposed to uninstrumented code. C libraries that stash pointers and
+    /// hand them back, or overwrite pointers that the runtime tracks, leave
+    /// instrumented code with provenance that no longer matches.
+    pub lenient_foreign: bool,
+    /// The number of retags of pointers into a single allocation, which is
+    /// found by the address of the retagged pointer, that are allowed before
+    /// the allocation is made a wildcard, or unlimited if it is 0. A wildcard
+    /// allocation is logged, and its pointers are no longer told apart, so
+    /// accesses to it through its own provenance are only checked against its
+    /// bounds. Retags don't say which pointer they derive from, so this counts
+    /// every tag that was derived, not how deep they are nested.
+    pub max_derived_tags: usize,
+    /// Whether pointers without provenance, such as those cast from integers,
+    /// can't be used to access memory. This makes wildcard accesses errors by
+    /// default, to check that a program follows strict provenance, rather than
+    /// letting them access any allocation whose address they have.
+    pub strict_provenance: bool,
+    /// Only 1 in this many reads and writes of each thread are checked, which
+    /// misses errors but makes long runs cheaper. Retags, frees and the other
+    /// hooks that keep the runtime's state are never skipped.
+    pub sample_rate: u32,
+    /// How much the runtime says about itself, besides errors. With 1 or more,
+    /// it prints these flags when it is initialized.
+    pub verbosity: u32,
+}
+
+impl Default for RuntimeFlags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RuntimeFlags {
+    pub const fn new() -> Self {
+        Self {
+            abi_mode: AbiMode::Permissive,
+            halt_on_error: false,
+            exit_code: DEFAULT_EXIT_CODE,
+            alloc_history: false,
+            alloc_stack_depth: 0,
+            detect_leaks: false,
+            lenient_foreign: false,
+            max_derived_tags: 0,
+            strict_provenance: false,
+            sample_rate: 1,
+            verbosity: 0,
+        }
+    }
+
+    /// Reads the flags from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            abi_mode: AbiMode::from_env(),
+            halt_on_error: io::env_flag(c"BSAN_HALT_ON_ERROR"),
+            exit_code: io::env_parse(c"BSAN_EXITCODE", DEFAULT_EXIT_CODE),
+            alloc_history: io::env_flag(c"BSAN_ALLOC_HISTORY"),
+            alloc_stack_depth: io::env_parse(c"BSAN_ALLOC_STACK_DEPTH", 0).min(MAX_FRAMES),
+            detect_leaks: io::env_flag(c"BSAN_DETECT_LEAKS") && !io::env_flag(c"BSAN_IGNORE_LEAKS"),
+            lenient_foreign: io::env_flag(c"BSAN_LENIENT_FOREIGN"),
+            max_derived_tags: io::env_parse(c"BSAN_MAX_DERIVED_TAGS", 0),
+            strict_provenance: io::env_flag(c"BSAN_STRICT_PROVENANCE"),
+            sample_rate: io::env_parse(c"BSAN_SAMPLE_RATE", 1).max(1),
+            verbosity: io::env_parse(c"BSAN_VERBOSITY", 0),
+        }
+    }
+}
+
+/// The flags as `BSAN_OPTIONS` would set them.
+impl fmt::Display for RuntimeFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "strict_abi={}:halt_on_error={}:exitcode={}:alloc_history={}:alloc_stack_depth={}:\
+             detect_leaks={}:lenient_foreign={}:max_derived_tags={}:strict_provenance={}:\
+             sample_rate={}:verbosity={}",
+            u8::from(self.abi_mode == AbiMode::Strict),
+            u8::from(self.halt_on_error),
+            self.exit_code,
+            u8::from(self.alloc_history),
+            self.alloc_stack_depth,
+            u8::from(self.detect_leaks),
+            u8::from(self.lenient_foreign),
+            self.max_derived_tags,
+            u8::from(self.strict_provenance),
+            self.sample_rate,
+            self.verbosity
+        )
+    }
+}
+
+/// The number of allocation IDs that a thread reserves from the context at
+/// once, so that it only touches the shared counter for one allocation in
+/// this many.
+pub const ALLOC_ID_BLOCK: usize = 64;
+
+/// The allocation IDs that a thread reserved from a context and hasn't handed
+/// out yet, which are kept in its
+/// [`ThreadContext`](crate::thread::ThreadContext) like its
+/// [`TagBlock`](crate::tag::TagBlock). Those that are left when the thread
+/// exits are never used.
+#[derive(Debug)]
+pub struct AllocIdBlock {
+    // The context that the IDs were reserved from, by its ID.
+    context: Cell<u64>,
+    next: Cell<usize>,
+    end: Cell<usize>,
+    // The number of IDs that the thread has taken, if they are counted per
+    // thread.
+    counted: Cell<usize>,
+}
+
+impl AllocIdBlock {
+    pub const fn new() -> Self {
+        Self { context: Cell::new(0), next: Cell::new(0), end: Cell::new(0), counted: Cell::new(0) }
+    }
+}
+
+static NEXT_CONTEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Retired metadata is reclaimed once every this many allocation events,
+/// rather than on each one, since it takes the retired list's lock.
+const RECLAIM_INTERVAL: usize = 64;
 
 #[derive(Debug)]
 pub struct GlobalContext {
     allocator: BsanAllocator,
+    // Identifies the context in the `AllocIdBlock` of each thread, as
+    // `TagAllocator`s are in their `TagBlock`s.
+    id: u64,
     next_alloc_id: AtomicUsize,
+    // Whether allocation IDs, like the tags of `tags`, are counted per thread.
+    per_thread_ids: bool,
+    tags: TagAllocator,
+    registry: AllocRegistry,
+    retired: RetiredList,
+    // The allocations that have been registered, and those whose metadata has
+    // been retired since, whose difference is the metadata that is live. They
+    // only ever grow, so the first is also the number of allocations made.
+    registered_metadata: AtomicUsize,
+    retired_metadata: AtomicUsize,
+    peak_metadata: AtomicUsize,
+    alloc_events: AtomicUsize,
+    flags: RuntimeFlags,
+    clock: LogicalClock,
+    shadow: ShadowHeap<Provenance>,
+    stats: StatCounters,
+    checkpoint: Option<Checkpointer>,
+    modules: ModuleTable,
+    ignored: IgnoreList,
+    tracked: Tracked,
+    symbolizer: Symbolizer,
+    output: OutputOptions,
+    reported_errors: ErrorTable,
+    report_limit: ReportLimit,
+    severities: Severities,
+    // When the runtime was initialized, by the host's clock.
+    started_at: u64,
 }
 
 impl GlobalContext {
-    fn new(allocator: BsanAllocator) -> Self {
-        Self { allocator, next_alloc_id: AtomicUsize::new(1) }
+    /// Creates a new context. Returns `None` if the shadow heap could not be
+    /// reserved.
+    pub(crate) fn new(allocator: BsanAllocator) -> Option<Self> {
+        Some(Self {
+            allocator,
+            id: NEXT_CONTEXT_ID.fetch_add(1, Ordering::Relaxed),
+            next_alloc_id: AtomicUsize::new(1),
+            per_thread_ids: false,
+            tags: TagAllocator::new(),
+            registry: AllocRegistry::new(),
+            retired: RetiredList::new(),
+            registered_metadata: AtomicUsize::new(0),
+            retired_metadata: AtomicUsize::new(0),
+            peak_metadata: AtomicUsize::new(0),
+            alloc_events: AtomicUsize::new(0),
+            flags: RuntimeFlags::new(),
+            clock: LogicalClock::new(),
+            shadow: ShadowHeap::new(allocator)?,
+            stats: StatCounters::new(),
+            checkpoint: None,
+            modules: ModuleTable::new(),
+            ignored: IgnoreList::default(),
+            tracked: Tracked::new(),
+            symbolizer: Symbolizer::default(),
+            output: OutputOptions::new(),
+            reported_errors: ErrorTable::default(),
+            report_limit: ReportLimit::default(),
+            severities: Severities::new(),
+            started_at: host::now_ns(),
+        })
+    }
+
+    #[inline]
+    pub fn allocator(&self) -> &BsanAllocator {
+        &self.allocator
+    }
+
+    #[inline]
+    pub fn tags(&self) -> &TagAllocator {
+        &self.tags
+    }
+
+    #[inline]
+    pub fn registry(&self) -> &AllocRegistry {
+        &self.registry
+    }
+
+    #[inline]
+    pub fn flags(&self) -> &RuntimeFlags {
+        &self.flags
+    }
+
+    #[cfg(test)]
+    pub fn flags_mut(&mut self) -> &mut RuntimeFlags {
+        &mut self.flags
+    }
+
+    /// Records `kind` of event in `meta`, by a pointer at `addr` with `tag`,
+    /// if `BSAN_ALLOC_HISTORY` is set.
+    #[inline]
+    pub fn record_event(
+        &self,
+        meta: &AllocMetadata,
+        kind: EventKind,
+        addr: usize,
+        size: usize,
+        tag: BorTag,
+    ) {
+        if !self.flags.alloc_history {
+            return;
+        }
+        meta.history.record(
+            &meta.arena,
+            &self.allocator,
+            Event {
+                kind,
+                offset: addr.wrapping_sub(meta.base_addr),
+                size,
+                tag,
+                thread: ThreadId::current(),
+                function: frame_function(),
+            },
+        );
+    }
+
+    /// Like [`GlobalContext::record_event`], for a pointer whose allocation
+    /// isn't known, which is looked up from `addr`.
+    pub unsafe fn record_event_at(&self, kind: EventKind, addr: usize, tag: BorTag) {
+        if !self.flags.alloc_history {
+            return;
+        }
+        if let Some(meta) = self.registry.find(addr) {
+            self.record_event(meta.as_ref(), kind, addr, 0, tag);
+        }
+    }
+
+    #[inline]
+    pub fn clock(&self) -> &LogicalClock {
+        &self.clock
+    }
+
+    #[inline]
+    pub fn shadow(&self) -> &ShadowHeap<Provenance> {
+        &self.shadow
+    }
+
+    #[inline]
+    pub fn stats(&self) -> &StatCounters {
+        &self.stats
+    }
+
+    #[inline]
+    pub fn modules(&self) -> &ModuleTable {
+        &self.modules
+    }
+
+    /// The functions and modules whose accesses and retags are treated
+    /// leniently.
+    #[inline]
+    pub fn ignored(&self) -> &IgnoreList {
+        &self.ignored
+    }
+
+    /// The allocations and tags whose events are logged.
+    #[inline]
+    pub fn tracked(&self) -> &Tracked {
+        &self.tracked
+    }
+
+    #[inline]
+    pub fn symbolizer(&self) -> &Symbolizer {
+        &self.symbolizer
+    }
+
+    #[inline]
+    pub fn output(&self) -> OutputOptions {
+        self.output
+    }
+
+    #[inline]
+    pub fn reported_errors(&self) -> &ErrorTable {
+        &self.reported_errors
+    }
+
+    /// How many of the errors that aren't repeats are reported.
+    #[inline]
+    pub fn report_limit(&self) -> &ReportLimit {
+        &self.report_limit
+    }
+
+    #[inline]
+    pub fn severities(&self) -> &Severities {
+        &self.severities
+    }
+
+    pub fn shadow_stats(&self) -> ShadowStats {
+        self.shadow.usage().into()
+    }
+
+    /// Hands out an allocation ID, or `None` if the current thread has run
+    /// out of the IDs that are counted per thread. IDs are taken from a block
+    /// that the thread reserved, so those of different threads are unique but
+    /// not in the order of their allocations.
+    #[inline]
+    pub fn new_alloc_id(&self) -> Option<AllocId> {
+        let block = &thread::current().alloc_ids;
+        if self.per_thread_ids {
+            let n = block.counted.get() + 1;
+            let id = clock::thread_scoped_id(n as u64)?;
+            block.counted.set(n);
+            return Some(AllocId::new(id as usize));
+        }
+        let next = block.next.get();
+        if block.context.get() == self.id && next < block.end.get() {
+            block.next.set(next + 1);
+            return Some(AllocId::new(next));
+        }
+        Some(self.reserve_alloc_ids(block))
+    }
+
+    // Reserves the next block of allocation IDs for the current thread, and
+    // returns its first one.
+    #[cold]
+    fn reserve_alloc_ids(&self, block: &AllocIdBlock) -> AllocId {
+        let first = self.next_alloc_id.fetch_add(ALLOC_ID_BLOCK, Ordering::Relaxed);
+        block.context.set(self.id);
+        block.next.set(first + 1);
+        block.end.set(first + ALLOC_ID_BLOCK);
+        AllocId::new(first)
+    }
+
+    /// The number of allocation IDs that have been handed out to allocations
+    /// so far, excluding those that threads have reserved but not used yet.
+    pub fn allocs_issued(&self) -> usize {
+        self.registered_metadata.load(Ordering::Relaxed)
+    }
+
+    /// Creates and registers the metadata for a new allocation, returning
+    /// the provenance of its root pointer.
+    #[inline(never)]
+    pub unsafe fn new_allocation(&self, base_addr: usize, size: usize) -> Option<Provenance> {
+        let stack = self.capture_alloc_stack();
+        self.register(base_addr, size, 1, AllocKind::Heap, stack)
+    }
+
+    /// Like [`GlobalContext::new_allocation`], for an allocation that was
+    /// requested with an alignment of `align` bytes, such as by `aligned_alloc`.
+    #[inline(never)]
+    pub unsafe fn new_aligned_allocation(
+        &self,
+        base_addr: usize,
+        size: usize,
+        align: usize,
+    ) -> Option<Provenance> {
+        let stack = self.capture_alloc_stack();
+        self.register(base_addr, size, align, AllocKind::Heap, stack)
+    }
+
+    /// Captures the call stack of a heap allocation if
+    /// `BSAN_ALLOC_STACK_DEPTH` is set, starting at the hook that called the
+    /// caller, which must not be inlined.
+    #[inline(always)]
+    fn capture_alloc_stack(&self) -> Option<StackRef> {
+        if self.flags.alloc_stack_depth == 0 {
+            return None;
+        }
+        let trace = Backtrace::capture_with_depth(1, self.flags.alloc_stack_depth);
+        ALLOC_STACKS.store(&self.allocator, trace.frames())
+    }
+
+    /// Registers the global variable of `size` bytes at `base_addr`, returning
+    /// the provenance of its root pointer. Since globals are never freed, their
+    /// shadow memory is cleared first, in case it belonged to a library that has
+    /// since been unloaded. Registering the same global twice, as happens when
+    /// its constructor is duplicated across codegen units, returns the existing
+    /// root provenance.
+    pub unsafe fn register_global(&self, base_addr: usize, size: usize) -> Option<Provenance> {
+        if let Some(meta) = self.registry.find_base(base_addr) {
+            let meta = meta.as_ref();
+            if meta.kind == AllocKind::Global && meta.size == size {
+                meta.retain();
+                return Some(meta.root_provenance());
+            }
+        }
+        self.shadow.clear_range(base_addr, size);
+        self.register(base_addr, size, 1, AllocKind::Global, None)
+    }
+
+    /// Registers the `size` bytes mapped at `base_addr` by `mmap`, returning the
+    /// provenance of its root pointer.
+    pub unsafe fn new_mapping(&self, base_addr: usize, size: usize) -> Option<Provenance> {
+        self.register(base_addr, size, 1, AllocKind::Mapping, None)
+    }
+
+    /// Moves or resizes the mapping at `old_base` after `mremap` remapped it to
+    /// the `new_size` bytes at `new_base`, returning the provenance of its root
+    /// pointer. Unlike [`GlobalContext::reallocate`], the allocation keeps its
+    /// identity, since the pages keep their contents. Pointers stored in the
+    /// pages that were kept move with them. Returns `None` if there is no such
+    /// mapping, in which case nothing changes.
+    pub unsafe fn remap(
+        &self,
+        old_base: usize,
+        new_base: usize,
+        new_size: usize,
+    ) -> Option<Provenance> {
+        let meta = self.registry.find_base(old_base)?;
+        if meta.as_ref().kind != AllocKind::Mapping {
+            return None;
+        }
+        let old_size = meta.as_ref().size;
+        self.registry.remove(meta);
+        let kept = old_size.min(new_size);
+        if new_base == old_base {
+            self.shadow.clear_range(old_base + kept, old_size - kept);
+        } else {
+            self.shadow.copy_range(new_base, old_base, kept);
+            self.shadow.clear_range(old_base, old_size);
+        }
+        (*meta.as_ptr()).base_addr = new_base;
+        (*meta.as_ptr()).size = new_size;
+        access::invalidate_caches();
+        self.registry.insert(meta);
+        self.on_alloc_event();
+        meta.as_ref().retain();
+        Some(meta.as_ref().root_provenance())
+    }
+
+    /// Registers the stack slot of `size` bytes at `base_addr` in the current
+    /// thread's innermost frame, returning the provenance of pointers to it.
+    /// It is retired by [`frame::exit`] once the frame returns.
+    pub unsafe fn new_stack_allocation(&self, base_addr: usize, size: usize) -> Option<Provenance> {
+        let root = self.register(base_addr, size, 1, AllocKind::Stack, None)?;
+        frame::push(NonNull::new_unchecked(root.lock_address.cast()));
+        Some(root)
+    }
+
+    /// Registers a new allocation of `kind`, made in the function of the current
+    /// thread's innermost frame, at `alloc_stack` if it was captured.
+    unsafe fn register(
+        &self,
+        base_addr: usize,
+        size: usize,
+        align: usize,
+        kind: AllocKind,
+        alloc_stack: Option<StackRef>,
+    ) -> Option<Provenance> {
+        let alloc_id = self.new_alloc_id()?;
+        // Most accesses are through pointers with the root tag, which the
+        // access hooks check without going through the access cache.
+        let bor_tag = self.tags.fresh()?.with_hint(TagHint::ROOT);
+        let meta = METADATA_POOL.allocate(&self.allocator)?;
+        meta.write(AllocMetadata::new(alloc_id, base_addr, size, bor_tag, kind));
+        (*meta.as_ptr()).align = align;
+        (*meta.as_ptr()).created_in = frame_function();
+        (*meta.as_ptr()).alloc_stack = alloc_stack;
+        let retired = self.retired_metadata.load(Ordering::Relaxed);
+        let live = self.registered_metadata.fetch_add(1, Ordering::Relaxed) + 1 - retired;
+        self.peak_metadata.fetch_max(live, Ordering::Relaxed);
+        self.registry.insert(meta);
+        // One reference for the registry, and one for the returned provenance.
+        meta.as_ref().retain();
+        if self.tracked.allocs.contains(alloc_id.get() as u64) {
+            miri::log_tracked(
+                self,
+                format_args!(
+                    "created allocation {} ({kind}, {size} bytes at {})",
+                    alloc_id.get(),
+                    Addr(base_addr)
+                ),
+            );
+        }
+        self.on_alloc_event();
+        Some(Provenance { alloc_id, bor_tag, lock_address: meta.as_ptr().cast() })
+    }
+
+    /// Retires the live heap allocation starting at `base_addr` and clears the
+    /// provenance of the pointers stored in it. Returns `false` if there is no
+    /// such allocation. The metadata itself is kept alive until the
+    /// last `Provenance` referring to it is released.
+    pub unsafe fn free_allocation(&self, base_addr: usize) -> bool {
+        let Some(meta) = self.registry.find_base(base_addr) else { return false };
+        if meta.as_ref().kind != AllocKind::Heap {
+            return false;
+        }
+        self.retire(meta);
+        true
+    }
+
+    /// Retires a stack allocation whose frame has returned. It must have been
+    /// made by [`GlobalContext::new_stack_allocation`], and removed from its
+    /// thread's frames.
+    pub unsafe fn retire_stack_allocation(&self, meta: NonNull<AllocMetadata>) {
+        debug_assert_eq!(meta.as_ref().kind, AllocKind::Stack);
+        self.retire(meta);
+    }
+
+    /// Retires the globals in `[start, end)` and clears its shadow memory, once
+    /// the library whose image was mapped there has been unloaded. Returns the
+    /// number of globals that were retired.
+    pub unsafe fn unload_range(&self, start: usize, end: usize) -> usize {
+        let retired = self.retire_in_range(start, end, |meta| meta.kind == AllocKind::Global);
+        self.shadow.clear_range(start, end - start);
+        retired
+    }
+
+    /// Retires the mappings that lie entirely within `[start, end)` once it has
+    /// been unmapped, and clears its shadow memory. Mappings that were only
+    /// partly unmapped are kept, so accesses to their unmapped pages aren't
+    /// caught. Returns the number of mappings that were retired.
+    pub unsafe fn unmap_range(&self, start: usize, end: usize) -> usize {
+        let retired = self.retire_in_range(start, end, |meta| {
+            meta.kind == AllocKind::Mapping
+                && meta.base_addr >= start
+                && meta.size <= end - meta.base_addr
+        });
+        self.shadow.clear_range(start, end - start);
+        retired
+    }
+
+    /// Retires every allocation in `[start, end)` that `f` selects.
+    unsafe fn retire_in_range(
+        &self,
+        start: usize,
+        end: usize,
+        f: impl Fn(&AllocMetadata) -> bool,
+    ) -> usize {
+        let mut retired = 0;
+        // Allocations can't be retired while the registry is being visited.
+        let mut from = start;
+        loop {
+            let mut found = None;
+            self.registry.for_each_in_range(from, end, |meta| {
+                if !f(meta) {
+                    return ControlFlow::Continue(());
+                }
+                found = Some(NonNull::from(meta));
+                ControlFlow::Break(())
+            });
+            let Some(meta) = found else { break };
+            from = meta.as_ref().base_addr.max(from);
+            self.retire(meta);
+            retired += 1;
+        }
+        retired
+    }
+
+    unsafe fn retire(&self, meta: NonNull<AllocMetadata>) {
+        self.unregister(meta);
+        self.shadow.clear_range(meta.as_ref().base_addr, meta.as_ref().size);
+        self.release_metadata(meta);
+        self.on_alloc_event();
+    }
+
+    // Removes a live allocation from the registry and marks it as freed in the
+    // function of the current thread's innermost frame, leaving its shadow
+    // memory and the registry's reference to its metadata to the caller.
+    unsafe fn unregister(&self, meta: NonNull<AllocMetadata>) {
+        let id = meta.as_ref().id.get();
+        if self.tracked.allocs.contains(id as u64) {
+            miri::log_tracked(self, format_args!("freed allocation {id}"));
+        }
+        self.registry.remove(meta);
+        (*meta.as_ptr()).state = AllocState::Freed;
+        (*meta.as_ptr()).freed_in = frame_function();
+    }
+
+    /// Retires the live heap allocation starting at `old_base` after it was
+    /// reallocated to the `new_size` bytes at `new_base`, and registers the
+    /// new allocation, returning the provenance of its root pointer. Pointers
+    /// to the old allocation are invalidated even if it was resized in place,
+    /// as the C standard requires. The new allocation is recorded with the
+    /// requested alignment `align`, which is 1 for memory returned by
+    /// `realloc`. The provenance of the pointers stored in the part of the old
+    /// allocation that was copied moves to the new one, and the rest of the old
+    /// allocation's shadow memory is cleared. Returns `None` if there is no
+    /// such allocation, in which case nothing changes.
+    #[inline(never)]
+    pub unsafe fn reallocate(
+        &self,
+        old_base: usize,
+        new_base: usize,
+        new_size: usize,
+        align: usize,
+    ) -> Option<Provenance> {
+        let meta = self.registry.find_base(old_base)?;
+        if meta.as_ref().kind != AllocKind::Heap {
+            return None;
+        }
+        let old_size = meta.as_ref().size;
+        self.unregister(meta);
+        let copied = old_size.min(new_size);
+        if new_base == old_base {
+            self.shadow.clear_range(old_base + copied, old_size - copied);
+        } else {
+            self.shadow.copy_range(new_base, old_base, copied);
+            self.shadow.clear_range(old_base, old_size);
+        }
+        self.release_metadata(meta);
+        let stack = self.capture_alloc_stack();
+        self.register(new_base, new_size, align, AllocKind::Heap, stack)
+    }
+
+    /// Takes a new reference to the metadata of an allocation.
+    pub unsafe fn retain_metadata(&self, meta: NonNull<AllocMetadata>) {
+        meta.as_ref().retain();
+    }
+
+    /// Gives up a reference to the metadata of an allocation, retiring it if
+    /// this was the last one.
+    pub unsafe fn release_metadata(&self, meta: NonNull<AllocMetadata>) {
+        if meta.as_ref().release() {
+            self.retire_metadata(meta);
+        }
+    }
+
+    // Metadata without references can't be reached by the program anymore,
+    // but hooks on other threads may still be reading it, so it is only freed
+    // once they have returned.
+    unsafe fn retire_metadata(&self, meta: NonNull<AllocMetadata>) {
+        debug_assert_eq!(meta.as_ref().state, AllocState::Freed);
+        self.retired.push(meta);
+        self.retired_metadata.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Frees the retired metadata that no hook can be reading anymore, and
+    /// recycles the root tags of their allocations.
+    pub fn reclaim_metadata(&self) {
+        if self.retired.is_empty() {
+            return;
+        }
+        self.retired.reclaim(epoch::try_advance(), |meta| unsafe {
+            self.tags.recycle(meta.as_ref().root_tag);
+            meta.as_ref().arena.release(&self.allocator);
+            meta.drop_in_place();
+            METADATA_POOL.deallocate(meta);
+        });
+    }
+
+    /// The number of allocations whose metadata is still reachable,
+    /// including allocations that have been freed.
+    pub fn live_metadata(&self) -> usize {
+        // Metadata is only retired once it has been registered, so reading
+        // the retired count first can't make it exceed the registered one.
+        let retired = self.retired_metadata.load(Ordering::Relaxed);
+        self.registered_metadata.load(Ordering::Relaxed) - retired
+    }
+
+    /// The most allocations whose metadata was reachable at once.
+    pub fn peak_metadata(&self) -> usize {
+        self.peak_metadata.load(Ordering::Relaxed)
+    }
+
+    // Allocations and frees synchronize through the registry lock, so each
+    // one ends an epoch.
+    #[inline]
+    fn on_alloc_event(&self) -> EventStamp {
+        let stamp = self.clock.stamp_sync();
+        let events = self.alloc_events.fetch_add(1, Ordering::Relaxed) + 1;
+        if events % RECLAIM_INTERVAL == 0 {
+            AllocMetadata::take_pending_dealloc(|meta| unsafe { self.retire_metadata(meta) });
+            self.reclaim_metadata();
+        }
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.tick(self);
+        }
+        stamp
+    }
+}
+
+// Provenance stored in shadow memory holds a reference to its metadata. The
+// shadow heap has no access to the allocator, so metadata whose last reference
+// was held there is retired by a later allocation event.
+unsafe impl shadow::Provenance for Provenance {
+    #[inline(always)]
+    unsafe fn retain(&self) {
+        if let Some(meta) = NonNull::new(self.lock_address.cast::<AllocMetadata>()) {
+            meta.as_ref().retain();
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn release(&self) {
+        if let Some(meta) = NonNull::new(self.lock_address.cast::<AllocMetadata>()) {
+            if meta.as_ref().release() {
+                AllocMetadata::defer_dealloc(meta);
+            }
+        }
     }
 }
 
 pub static GLOBAL_CTX: SyncUnsafeCell<Option<GlobalContext>> = SyncUnsafeCell::new(None);
 
+// Hooks can run before `bsan_init`, from C++ static initializers, Rust
+// constructors or threads that those start. The first hook to run bootstraps a
+// context with the libc allocator and the default options, which `bsan_init`
+// then configures. The context is `BUSY` while it is being created, configured
+// or torn down, and hooks that need it in the meantime wait until it is
+// `READY`, unless they are run by the thread that holds it, which would wait
+// for itself.
+const UNINIT: u8 = 0;
+const BUSY: u8 = 1;
+const READY: u8 = 2;
+
+static CTX_STATE: AtomicU8 = AtomicU8::new(UNINIT);
+
+// The thread that holds the context while it is `BUSY`.
+static CTX_OWNER: AtomicU32 = AtomicU32::new(0);
+
+// Whether `bsan_init` has configured the context.
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+// Makes the context `BUSY` for the current thread, waiting for any other
+// thread that holds it, and returns whether it was `READY` rather than
+// uninitialized.
+#[cold]
+fn acquire_ctx() -> bool {
+    let was_ready = loop {
+        match CTX_STATE.compare_exchange_weak(UNINIT, BUSY, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => break false,
+            Err(READY) => {
+                let acquired = CTX_STATE
+                    .compare_exchange_weak(READY, BUSY, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok();
+                if acquired {
+                    break true;
+                }
+            }
+            Err(_) => wait_for_ctx(),
+        }
+    };
+    CTX_OWNER.store(ThreadId::current().get(), Ordering::Relaxed);
+    was_ready
+}
+
+// Releases the context that the current thread holds, leaving it in `state`.
+fn release_ctx(state: u8) {
+    CTX_OWNER.store(0, Ordering::Relaxed);
+    CTX_STATE.store(state, Ordering::Release);
+}
+
+// Waits while another thread holds the context. The runtime is used by the
+// thread that holds it if something that it calls while creating or
+// configuring the context is instrumented or calls back into a hook, which
+// can't be waited out, so it is a failure of the runtime.
+#[cold]
+fn wait_for_ctx() {
+    while CTX_STATE.load(Ordering::Acquire) == BUSY {
+        if CTX_OWNER.load(Ordering::Relaxed) == ThreadId::current().get() {
+            let _ =
+                writeln!(FdWriter::log(), "bsan: the runtime was used while it was initialized");
+            internal_failure();
+        }
+        hint::spin_loop();
+    }
+}
+
+/// Configures the global context, creating it first if no hook has run yet.
+/// Only the first call does anything; calls on other threads wait for it to
+/// finish, and later calls return right away. Hooks that run on other threads
+/// in the meantime wait for the context to be configured.
+///
+/// # Safety
+/// No hook may be running on another thread by the time this is called if a
+/// hook has already bootstrapped the context, since its options are changed
+/// in place.
 pub unsafe fn init_global_ctx(alloc: BsanAllocator) {
-    *GLOBAL_CTX.get() = Some(GlobalContext::new(alloc));
+    if CONFIGURED.load(Ordering::Acquire) {
+        return;
+    }
+    let bootstrapped = acquire_ctx();
+    if !bootstrapped {
+        create_ctx(alloc);
+    } else if CONFIGURED.load(Ordering::Acquire) {
+        release_ctx(READY);
+        return;
+    }
+    configure_ctx((*GLOBAL_CTX.get()).as_mut().unwrap_unchecked(), alloc, bootstrapped);
+    CONFIGURED.store(true, Ordering::Release);
+    release_ctx(READY);
+}
+
+// `bootstrapped` is whether a hook created the context with the libc allocator.
+unsafe fn configure_ctx(ctx: &mut GlobalContext, alloc: BsanAllocator, bootstrapped: bool) {
+    // Metadata must be freed by the allocator that allocated it, so the
+    // bootstrap allocator is kept once it has been used. Until then, nothing
+    // has been stored in the shadow heap either, so it is reserved again with
+    // the configured allocator. Likewise, IDs are only counted per thread from
+    // the start, so that they can't collide with those that were handed out
+    // before.
+    alloc::install(alloc);
+    let unused = ctx.allocs_issued() == 0;
+    if bootstrapped && unused {
+        ctx.allocator = alloc;
+        let Some(shadow) = ShadowHeap::new(alloc) else {
+            let _ = writeln!(FdWriter::log(), "bsan: failed to reserve the shadow heap");
+            internal_failure();
+        };
+        ctx.shadow = shadow;
+    } else if bootstrapped {
+        let _ = writeln!(
+            FdWriter::log(),
+            "bsan: error: allocations were made before bsan_init, so the runtime's memory \
+             is still allocated by libc rather than by the allocator it was given"
+        );
+    }
+    if unused && io::env_flag(c"BSAN_DETERMINISTIC") {
+        ctx.per_thread_ids = true;
+        ctx.tags = TagAllocator::per_thread();
+    }
+    io::open_log_from_env();
+    options::warn_about_invalid();
+    miri::warn_about_unsupported();
+    ctx.flags = RuntimeFlags::from_env();
+    ctx.started_at = host::now_ns();
+    if ctx.flags.verbosity >= 1 {
+        let _ = writeln!(FdWriter::log(), "bsan: initialized with {}", ctx.flags);
+    }
+    ctx.checkpoint = Checkpointer::from_env();
+    ctx.ignored = IgnoreList::from_env();
+    ctx.tracked = Tracked::from_env();
+    ctx.symbolizer = Symbolizer::from_env();
+    ctx.output = OutputOptions::from_env();
+    ctx.reported_errors = ErrorTable::from_env();
+    ctx.report_limit = ReportLimit::from_env();
+    ctx.severities = Severities::from_env(ctx.flags.strict_provenance);
+    access::invalidate_caches();
+    ctx.shadow.set_huge_pages(io::env_flag(c"BSAN_SHADOW_HUGEPAGES"));
+    // Handlers run in reverse order, so the shadow statistics are printed
+    // before the context is torn down.
+    libc::atexit(exit_handler);
+    if io::env_flag(c"BSAN_SHADOW_STATS") {
+        libc::atexit(report_shadow_stats);
+    }
+    if io::env_flag(c"BSAN_STATS") {
+        stats::set_counting(true);
+        libc::atexit(report_stats);
+    }
+    if trace::start_from_env(&ctx.allocator) {
+        libc::atexit(dump_trace);
+    }
+    signal::install_from_env();
+}
+
+extern "C" fn dump_trace() {
+    unsafe { trace::dump() }
+}
+
+// The number of hooks whose calls are listed at exit.
+const MAX_LISTED_HOOKS: usize = 64;
+
+extern "C" fn report_stats() {
+    let mut out = FdWriter::log();
+    let _ = writeln!(out, "bsan: stats: {}", unsafe { global_ctx() }.stats().snapshot());
+    let mut hooks = [(c"", 0); MAX_LISTED_HOOKS];
+    let mut len = 0;
+    stats::for_each_hook(|name, calls| {
+        if len < MAX_LISTED_HOOKS {
+            hooks[len] = (name, calls);
+            len += 1;
+        }
+    });
+    let hooks = &mut hooks[..len];
+    hooks.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    for (name, calls) in hooks {
+        let _ = writeln!(out, "bsan:   {}: {calls} calls", name.to_str().unwrap_or("?"));
+    }
+}
+
+extern "C" fn report_shadow_stats() {
+    let stats = unsafe { global_ctx() }.shadow_stats();
+    let _ = writeln!(FdWriter::log(), "bsan: shadow memory: {stats}");
+}
+
+// The number of leaked allocations that are listed individually at exit.
+const MAX_LISTED_LEAKS: usize = 32;
+
+static EXITED: AtomicBool = AtomicBool::new(false);
+
+// The exit statuses for errors in the program, and for failures of the runtime
+// itself. The latter is `EX_SOFTWARE` from `sysexits.h`.
+const DEFAULT_EXIT_CODE: c_int = 1;
+const DEFAULT_INTERNAL_EXIT_CODE: c_int = 70;
+
+/// Terminates the process after a failure of the runtime itself, rather than
+/// an error in the program, such as an ABI violation or a panic. The status is
+/// the one in `BSAN_INTERNAL_EXITCODE`, which defaults to 70, so that harnesses
+/// can tell these apart from both the errors that the runtime finds and the
+/// crashes of the program. It is read here, since there may be no context yet.
+pub fn internal_failure() -> ! {
+    die(io::env_parse(c"BSAN_INTERNAL_EXITCODE", DEFAULT_INTERNAL_EXIT_CODE))
+}
+
+/// Terminates the process with `code`, after calling the callback that the
+/// program set with `__sanitizer_set_death_callback`, if any. Exit handlers
+/// aren't run, since they could run into the state that the runtime found to
+/// be broken.
+pub fn die(code: c_int) -> ! {
+    sanitizer::run_death_callback();
+    host::abort(code)
+}
+
+/// Whether the runtime has been shut down. Allocations made before then are
+/// unknown to the context that replaces it, so errors are no longer reported.
+pub fn has_exited() -> bool {
+    EXITED.load(Ordering::Relaxed)
+}
+
+extern "C" fn exit_handler() {
+    unsafe { exit_global_ctx() }
+}
+
+/// Writes a final checkpoint, prints a summary of the errors that were found
+/// and of any leaks, and tears down the global context. Only the first call
+/// does anything. Hooks that run afterwards, such as those in later `atexit`
+/// handlers, bootstrap a new context with the default options.
+///
+/// # Safety
+/// No other thread may be using the runtime.
+pub unsafe fn exit_global_ctx() {
+    if EXITED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    if !acquire_ctx() {
+        release_ctx(UNINIT);
+        return;
+    }
+    let ctx = (*GLOBAL_CTX.get()).take().unwrap_unchecked();
+    release_ctx(UNINIT);
+    frame::forget();
+    if let Some(checkpoint) = &ctx.checkpoint {
+        checkpoint.write(&ctx);
+    }
+    let mut out = FdWriter::log();
+    let (leaks, leaked_bytes) =
+        if ctx.flags.detect_leaks { report_leaks(&ctx, &mut out) } else { (0, 0) };
+    if ctx.stats().snapshot().errors > 0 || leaks > 0 {
+        let _ = write_summary(&ctx, leaks, leaked_bytes, &mut out);
+    }
+    if ctx.flags.verbosity >= 1 {
+        let elapsed = host::now_ns().saturating_sub(ctx.started_at);
+        let _ = writeln!(out, "bsan: exiting after {} ms", elapsed / 1_000_000);
+    }
+    out.flush();
+}
+
+/// Writes the summary printed at exit: the number of errors and leaks, the
+/// errors of each kind that was found, how many of the errors and warnings
+/// weren't printed, and the most memory that the metadata of allocations and
+/// shadow memory took up at once.
+fn write_summary(
+    ctx: &GlobalContext,
+    leaks: usize,
+    leaked_bytes: usize,
+    out: &mut impl Write,
+) -> fmt::Result {
+    const KIB: usize = 1 << 10;
+    const MIB: usize = 1 << 20;
+    // Errors and warnings are counted whether or not they were printed, so
+    // those that weren't are listed apart from them.
+    write!(out, "bsan: summary: {} errors", ctx.stats().snapshot().errors)?;
+    match ctx.stats().warnings() {
+        0 => {}
+        warnings => write!(out, ", {warnings} warnings")?,
+    }
+    writeln!(out, ", {leaks} leaked allocations ({leaked_bytes} bytes)")?;
+    let by_kind = ErrorKind::NAMES.iter().zip(ctx.stats().errors_by_kind());
+    for ((name, count), severity) in by_kind.zip(ctx.severities().by_index()) {
+        match (count, severity) {
+            (0, _) => {}
+            (count, Severity::Warning) => writeln!(out, "bsan:   {name}: {count} (warnings)")?,
+            (count, _) => writeln!(out, "bsan:   {name}: {count}")?,
+        }
+    }
+    match (ctx.reported_errors().repeats(), ctx.report_limit().dropped()) {
+        (0, 0) => {}
+        (repeats, 0) => writeln!(out, "bsan:   not printed: {repeats} repeats of earlier reports")?,
+        (0, dropped) => writeln!(out, "bsan:   not printed: {dropped} past BSAN_MAX_ERRORS")?,
+        (repeats, dropped) => writeln!(
+            out,
+            "bsan:   not printed: {repeats} repeats of earlier reports, {dropped} past \
+             BSAN_MAX_ERRORS"
+        )?,
+    }
+    let peak = ctx.peak_metadata();
+    let peak_bytes = peak * size_of::<AllocMetadata>();
+    writeln!(out, "bsan:   peak metadata: {peak} allocations ({} KiB)", peak_bytes.div_ceil(KIB))?;
+    let usage = ctx.shadow().usage();
+    writeln!(
+        out,
+        "bsan:   peak shadow memory: {} chunks ({} MiB)",
+        usage.peak_chunks,
+        usage.peak_bytes.div_ceil(MIB)
+    )
+}
+
+/// Lists the heap allocations that are still live, and returns their number
+/// and total size.
+fn report_leaks(ctx: &GlobalContext, out: &mut impl Write) -> (usize, usize) {
+    let (mut leaks, mut bytes) = (0, 0);
+    ctx.registry().for_each(|meta| {
+        if meta.kind != AllocKind::Heap {
+            return;
+        }
+        leaks += 1;
+        bytes += meta.size;
+        if leaks <= MAX_LISTED_LEAKS {
+            let _ = writeln!(
+                out,
+                "bsan: leak of {} bytes at {} (allocation {})",
+                meta.size,
+                Addr(meta.base_addr),
+                meta.id.get()
+            );
+        }
+    });
+    if leaks > MAX_LISTED_LEAKS {
+        let _ =
+            writeln!(out, "bsan: {} more leaked allocations not listed", leaks - MAX_LISTED_LEAKS);
+    }
+    (leaks, bytes)
+}
+
+/// Writes what the runtime currently knows to `out`: the number of errors
+/// reported so far, the live allocations of each kind and the memory used by
+/// shadow memory.
+pub fn report_status(ctx: &GlobalContext, out: &mut impl Write) {
+    let (mut heap, mut stack, mut global, mut mapped) = ((0, 0), (0, 0), (0, 0), (0, 0));
+    ctx.registry().for_each(|meta| {
+        let (count, bytes) = match meta.kind {
+            AllocKind::Heap => &mut heap,
+            AllocKind::Stack => &mut stack,
+            AllocKind::Global => &mut global,
+            AllocKind::Mapping => &mut mapped,
+        };
+        *count += 1;
+        *bytes += meta.size;
+    });
+    let _ = writeln!(out, "bsan: {} errors so far", ctx.stats().snapshot().errors);
+    let _ = writeln!(
+        out,
+        "bsan: live allocations: {} heap ({} bytes), {} stack ({} bytes), {} global ({} bytes), \
+         {} mapped ({} bytes)",
+        heap.0, heap.1, stack.0, stack.1, global.0, global.1, mapped.0, mapped.1
+    );
+    let _ = writeln!(out, "bsan: shadow memory: {}", ctx.shadow_stats());
+}
+
+// Bootstraps the context with the default options, unless another thread
+// does first, in which case this waits for it to be ready.
+#[cold]
+unsafe fn ensure_global_ctx(alloc: BsanAllocator) {
+    loop {
+        match CTX_STATE.compare_exchange_weak(UNINIT, BUSY, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => break,
+            Err(READY) => return,
+            Err(_) => wait_for_ctx(),
+        }
+    }
+    CTX_OWNER.store(ThreadId::current().get(), Ordering::Relaxed);
+    create_ctx(alloc);
+    release_ctx(READY);
+}
+
+// Creates the context, which the current thread must hold.
+unsafe fn create_ctx(alloc: BsanAllocator) {
+    let Some(ctx) = GlobalContext::new(alloc) else {
+        let _ = writeln!(FdWriter::log(), "bsan: failed to reserve the shadow heap");
+        internal_failure();
+    };
+    *GLOBAL_CTX.get() = Some(ctx);
+}
+
+/// The global context, if it is initialized, without initializing it, as
+/// signal handlers must not.
+pub fn initialized_global_ctx() -> Option<&'static GlobalContext> {
+    if CTX_STATE.load(Ordering::Acquire) != READY {
+        return None;
+    }
+    unsafe { (*GLOBAL_CTX.get()).as_ref() }
 }
 
 #[inline]
 pub unsafe fn global_ctx() -> &'static GlobalContext {
+    if CTX_STATE.load(Ordering::Acquire) != READY {
+        ensure_global_ctx(alloc::runtime_allocator());
+    }
     (&(*GLOBAL_CTX.get())).as_ref().unwrap_unchecked()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::RetagKind;
+    use crate::access::{self, AccessError, AccessHistory, AccessKind};
+    use crate::alloc::TEST_ALLOCATOR;
+
+    #[test]
+    fn metadata_outlives_free_until_released() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let prov = ctx.new_allocation(0x1000, 8).unwrap();
+            let meta = NonNull::new(prov.lock_address.cast::<AllocMetadata>()).unwrap();
+            ctx.retain_metadata(meta);
+            assert!(ctx.free_allocation(0x1000));
+            assert_eq!(ctx.registry().len(), 0);
+            assert_eq!(ctx.live_metadata(), 1);
+            assert_eq!(meta.as_ref().state, AllocState::Freed);
+            ctx.release_metadata(meta);
+            assert_eq!(ctx.live_metadata(), 1);
+            ctx.release_metadata(meta);
+            assert_eq!(ctx.live_metadata(), 0);
+        }
+    }
+
+    #[test]
+    fn root_tags_are_recycled_once_their_metadata_is_reclaimed() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let prov = ctx.new_allocation(0x1000, 8).unwrap();
+            assert!(ctx.free_allocation(0x1000));
+            ctx.release_metadata(NonNull::new_unchecked(prov.lock_address.cast()));
+            // Every few allocations try to reclaim the retired metadata, once
+            // the epoch has advanced past it, after taking their own tags.
+            let recycled = (1..1000)
+                .map(|i| ctx.new_allocation(0x1000 + 0x10 * i, 8).unwrap())
+                .find(|new| new.bor_tag.get() == prov.bor_tag.get())
+                .unwrap();
+            assert!(recycled.bor_tag.hint().contains(TagHint::ROOT));
+            assert_ne!(recycled.alloc_id, prov.alloc_id);
+        }
+    }
+
+    #[test]
+    fn heap_allocations_record_their_stacks_when_asked() {
+        let mut ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let stack_of = |prov: Provenance| unsafe {
+            (*prov.lock_address.cast::<AllocMetadata>()).alloc_stack.map(|stack| stack.frames())
+        };
+        unsafe {
+            assert_eq!(stack_of(ctx.new_allocation(0x1000, 8).unwrap()), None);
+            ctx.flags.alloc_stack_depth = 3;
+            let first = stack_of(ctx.new_allocation(0x2000, 8).unwrap()).unwrap();
+            assert!(!first.is_empty() && first.len() <= 3);
+            assert!(stack_of(ctx.new_mapping(0x3000, 8).unwrap()).is_none());
+        }
+    }
+
+    #[test]
+    fn alloc_ids_are_reserved_in_blocks_per_thread() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let first = ctx.new_alloc_id().unwrap();
+        assert_eq!(first.get(), 1);
+        assert_eq!(ctx.new_alloc_id().unwrap().get(), 2);
+        // Only the IDs of allocations are counted as issued, not the rest of
+        // the block.
+        assert_eq!(ctx.allocs_issued(), 0);
+        let prov = unsafe { ctx.new_allocation(0x1000, 8).unwrap() };
+        assert_eq!(prov.alloc_id.get(), 3);
+        assert_eq!(ctx.allocs_issued(), 1);
+        let mut ids: Vec<usize> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..3 * ALLOC_ID_BLOCK / 2)
+                            .map(|_| ctx.new_alloc_id().unwrap().get())
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect()
+        });
+        ids.extend([1, 2, 3]);
+        let len = ids.len();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), len);
+        // A block that was reserved from another context isn't used.
+        let other = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        assert_eq!(other.new_alloc_id().unwrap().get(), 1);
+    }
+
+    #[test]
+    fn deterministic_runs_count_ids_per_thread_and_hide_addresses() {
+        let mut ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        ctx.per_thread_ids = true;
+        ctx.tags = TagAllocator::per_thread();
+        ctx.output.hide_addresses = true;
+        unsafe {
+            let first = ctx.new_allocation(0x1000, 8).unwrap();
+            let second = ctx.new_allocation(0x2000, 8).unwrap();
+            assert_eq!(second.alloc_id.get(), first.alloc_id.get() + 1);
+            assert_eq!(second.bor_tag.get(), first.bor_tag.get() + 1);
+            // Another thread counts from the start, in IDs of its own.
+            let other = std::thread::scope(|scope| {
+                let other = scope.spawn(|| {
+                    let prov = ctx.new_allocation(0x3000, 8).unwrap();
+                    (prov.alloc_id.get(), clock::thread_scoped_id(1).unwrap() as usize)
+                });
+                other.join().unwrap()
+            });
+            assert_eq!(other.0, other.1);
+            assert_eq!(ctx.allocs_issued(), 3);
+        }
+        let mut out = String::new();
+        report::write_stack(&ctx, &Backtrace::capture(0), false, &mut out).unwrap();
+        assert!(out.starts_with("    #0") && !out.contains(" 0x"), "{out}");
+    }
+
+    #[test]
+    fn flags_are_printed_as_options() {
+        let flags =
+            RuntimeFlags { halt_on_error: true, alloc_stack_depth: 8, ..RuntimeFlags::new() };
+        assert_eq!(
+            flags.to_string(),
+            "strict_abi=0:halt_on_error=1:exitcode=1:alloc_history=0:alloc_stack_depth=8:\
+             detect_leaks=0:lenient_foreign=0:max_derived_tags=0:strict_provenance=0:sample_rate=1:\
+             verbosity=0"
+        );
+        // Every flag can be set again through `BSAN_OPTIONS`.
+        options::parse(
+            &flags.to_string(),
+            |key, _| assert!(options::KNOWN.contains(&key)),
+            |_| panic!(),
+        );
+    }
+
+    #[test]
+    fn freeing_clears_shadow_of_contents() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let prov = ctx.new_allocation(0x1000, 32).unwrap();
+            assert!(ctx.shadow().store(0x1008, prov));
+            assert!(ctx.shadow().store(0x1020, prov));
+            assert!(ctx.free_allocation(0x1000));
+            assert!(ctx.shadow().iter_provenance().eq([(0x1020, prov)]));
+        }
+    }
+
+    #[test]
+    fn leaks_are_heap_allocations_that_were_never_freed() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            ctx.new_allocation(0x1000, 8).unwrap();
+            ctx.new_allocation(0x2000, 16).unwrap();
+            ctx.register_global(0x3000, 32).unwrap();
+            for i in 0..MAX_LISTED_LEAKS {
+                ctx.new_allocation(0x10000 + i * 0x10, 1).unwrap();
+            }
+            assert!(ctx.free_allocation(0x2000));
+        }
+        let mut out = String::new();
+        assert_eq!(report_leaks(&ctx, &mut out), (MAX_LISTED_LEAKS + 1, 8 + MAX_LISTED_LEAKS));
+        assert!(out.starts_with("bsan: leak of 8 bytes at 0x1000 (allocation 1)\n"));
+        assert_eq!(out.lines().count(), MAX_LISTED_LEAKS + 1);
+        assert!(out.ends_with("bsan: 1 more leaked allocations not listed\n"));
+    }
+
+    #[test]
+    fn status_reports_count_live_allocations_by_kind() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            ctx.new_allocation(0x1000, 8).unwrap();
+            ctx.new_allocation(0x2000, 16).unwrap();
+            ctx.register_global(0x3000, 32).unwrap();
+            assert!(ctx.free_allocation(0x2000));
+        }
+        ctx.stats().error(ErrorKind::InvalidFree);
+        let mut out = String::new();
+        report_status(&ctx, &mut out);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "bsan: 1 errors so far");
+        assert_eq!(
+            lines[1],
+            "bsan: live allocations: 1 heap (8 bytes), 0 stack (0 bytes), 1 global (32 bytes), \
+             0 mapped (0 bytes)"
+        );
+        assert!(lines[2].starts_with("bsan: shadow memory: "));
+    }
+
+    #[test]
+    fn events_are_recorded_if_enabled() {
+        let mut ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let func = SourceInfo { file: ptr::null(), line: 0, column: 0, function: c"poke".as_ptr() };
+        unsafe {
+            let prov = ctx.new_allocation(0x1000, 16).unwrap();
+            let meta = &*prov.lock_address.cast::<AllocMetadata>();
+            let read = EventKind::Access(AccessKind::Read);
+            ctx.record_event(meta, read, 0x1000, 8, prov.bor_tag);
+            assert_eq!(meta.history.events().count(), 0);
+            ctx.flags.alloc_history = true;
+            frame::enter(&func);
+            ctx.record_event(meta, read, 0x1004, 4, prov.bor_tag);
+            ctx.record_event_at(EventKind::Retag(RetagKind::Raw), 0x1008, BorTag::new(9));
+            ctx.record_event_at(EventKind::Expose, 0x2000, BorTag::INVALID);
+            frame::exit(&ctx);
+            let thread = ThreadId::current().get();
+            let history = AccessHistory { meta, addr: 0x1000, size: 1, tag: BorTag::INVALID };
+            let history = history.to_string();
+            let expected = format!(
+                "    recent history of allocation 1, oldest first:\n\
+                 \x20     read of 4 bytes at offset 0x4 through tag 1 on thread {thread} in poke\n\
+                 \x20     raw retag at offset 0x8 to tag 9 on thread {thread} in poke\n"
+            );
+            assert!(history.ends_with(&expected), "{history}");
+            ctx.release_metadata(NonNull::new_unchecked(prov.lock_address.cast()));
+        }
+    }
+
+    #[test]
+    fn summaries_break_errors_down_by_kind() {
+        let mut ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        ctx.report_limit = ReportLimit::new(1, false);
+        ctx.severities.apply("wildcard-access=warning", |_| unreachable!());
+        unsafe {
+            let prov = ctx.new_allocation(0x1000, 8).unwrap();
+            ctx.new_allocation(0x2000, 16).unwrap();
+            assert!(ctx.free_allocation(0x2000));
+            assert!(ctx.shadow().store(0x1000, prov));
+        }
+        let oob =
+            AccessError::OutOfBounds { alloc_id: AllocId::new(1), base_addr: 0x1000, size: 8 };
+        ctx.stats().error(ErrorKind::Access(oob));
+        ctx.stats().error(ErrorKind::Access(oob));
+        ctx.stats().error(ErrorKind::InvalidFree);
+        ctx.stats().warning(ErrorKind::WildcardAccess);
+        assert!(ctx.reported_errors().record(1));
+        assert!(!ctx.reported_errors().record(1));
+        assert!(ctx.reported_errors().record(2));
+        ctx.report_limit().admit();
+        ctx.report_limit().admit();
+        let mut out = String::new();
+        write_summary(&ctx, 1, 8, &mut out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[..6],
+            [
+                "bsan: summary: 3 errors, 1 warnings, 1 leaked allocations (8 bytes)",
+                "bsan:   out-of-bounds: 2",
+                "bsan:   invalid-free: 1",
+                "bsan:   wildcard-access: 1 (warnings)",
+                "bsan:   not printed: 1 repeats of earlier reports, 1 past BSAN_MAX_ERRORS",
+                "bsan:   peak metadata: 2 allocations (1 KiB)",
+            ]
+        );
+        assert!(lines[6].starts_with("bsan:   peak shadow memory: 1 chunks ("));
+        assert_eq!(lines.len(), 7);
+    }
+
+    #[test]
+    fn unloading_a_library_retires_its_globals() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let global = ctx.register_global(0x1000, 8).unwrap();
+            ctx.register_global(0x1ff8, 16).unwrap();
+            let heap = ctx.new_allocation(0x3000, 8).unwrap();
+            assert!(ctx.shadow().store(0x1008, heap));
+            assert!(ctx.shadow().store(0x3000, global));
+            assert_eq!(ctx.unload_range(0x1000, 0x2000), 2);
+            assert!(ctx.registry().find(0x1000).is_none());
+            assert!(ctx.registry().find(0x1ff8).is_none());
+            assert!(ctx.registry().find(0x3000).is_some());
+            assert_eq!(ctx.shadow().load(0x1008), Provenance::null());
+            // Pointers to the globals are left dangling.
+            assert_eq!(ctx.shadow().load(0x3000), global);
+        }
+    }
+
+    #[test]
+    fn mappings_are_retired_once_fully_unmapped() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let first = ctx.new_mapping(0x10000, 0x2000).unwrap();
+            let second = ctx.new_mapping(0x20000, 0x2000).unwrap();
+            ctx.new_allocation(0x30000, 8).unwrap();
+            assert!(ctx.shadow().store(0x11000, first));
+            assert!(ctx.shadow().store(0x21000, second));
+            // Only the second page of the first mapping.
+            assert_eq!(ctx.unmap_range(0x11000, 0x12000), 0);
+            assert!(ctx.registry().find(0x10000).is_some());
+            assert_eq!(ctx.shadow().load(0x11000), Provenance::null());
+            assert_eq!(ctx.unmap_range(0x10000, 0x40000), 2);
+            assert!(ctx.registry().find(0x10000).is_none());
+            assert!(ctx.registry().find(0x20000).is_none());
+            assert!(ctx.registry().find(0x30000).is_some());
+            assert_eq!(ctx.shadow().load(0x21000), Provenance::null());
+        }
+    }
+
+    #[test]
+    fn remapping_rebases_the_mapping() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let target = ctx.new_allocation(0x1000, 8).unwrap();
+            let mapping = ctx.new_mapping(0x10000, 0x2000).unwrap();
+            assert!(ctx.shadow().store(0x10008, target));
+            assert!(ctx.shadow().store(0x11000, target));
+            // Shrinking in place drops the pointers in the pages that were unmapped.
+            assert_eq!(ctx.remap(0x10000, 0x10000, 0x1000), Some(mapping));
+            assert_eq!(ctx.shadow().load(0x11000), Provenance::null());
+            let moved = ctx.remap(0x10000, 0x40000, 0x4000).unwrap();
+            assert_eq!(moved, mapping);
+            assert_eq!(ctx.shadow().load(0x40008), target);
+            assert_eq!(ctx.shadow().load(0x10008), Provenance::null());
+            assert_eq!(access::check_access_with(&ctx, moved, 0x43ff8, 8), Ok(moved));
+            assert!(access::check_access_with(&ctx, moved, 0x10000, 8).is_err());
+            assert!(ctx.registry().find(0x10000).is_none());
+            assert_eq!(ctx.remap(0x1000, 0x2000, 8), None);
+        }
+    }
+
+    #[test]
+    fn reallocation_moves_stored_pointers() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let target = ctx.new_allocation(0x1000, 8).unwrap();
+            let old = ctx.new_allocation(0x2000, 32).unwrap();
+            assert!(ctx.shadow().store(0x2000, target));
+            assert!(ctx.shadow().store(0x2018, target));
+            // Shrinking moves the allocation, and drops the pointer past its end.
+            let new = ctx.reallocate(0x2000, 0x3000, 16, 1).unwrap();
+            assert_ne!(new.alloc_id, old.alloc_id);
+            assert_eq!(ctx.shadow().load(0x3000), target);
+            assert_eq!(ctx.shadow().load(0x3018), Provenance::null());
+            assert!(ctx.shadow().iter_provenance().all(|(addr, _)| addr >= 0x3000));
+            assert!(ctx.registry().find(0x2000).is_none());
+            assert_eq!(ctx.registry().find(0x3008).unwrap().as_ref().id, new.alloc_id);
+            // Growing in place keeps them, but still creates a new allocation.
+            let grown = ctx.reallocate(0x3000, 0x3000, 64, 1).unwrap();
+            assert_ne!(grown.alloc_id, new.alloc_id);
+            assert_eq!(ctx.shadow().load(0x3000), target);
+            assert_eq!(ctx.registry().find(0x3030).unwrap().as_ref().id, grown.alloc_id);
+            assert!(ctx.reallocate(0x2000, 0x4000, 8, 1).is_none());
+            for prov in [old, new, grown] {
+                ctx.release_metadata(NonNull::new_unchecked(prov.lock_address.cast()));
+            }
+        }
+    }
+
+    #[test]
+    fn globals_are_registered_once_and_never_freed() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        unsafe {
+            let stale = ctx.new_allocation(0x2000, 8).unwrap();
+            assert!(ctx.shadow().store(0x1008, stale));
+            let prov = ctx.register_global(0x1000, 16).unwrap();
+            assert_eq!(ctx.shadow().load(0x1008), Provenance::null());
+            assert_eq!(ctx.register_global(0x1000, 16), Some(prov));
+            assert_eq!(ctx.registry().len(), 2);
+            let meta = ctx.registry().find(0x100f).unwrap();
+            assert_eq!(meta.as_ref().kind, AllocKind::Global);
+            assert!(!ctx.free_allocation(0x1000));
+            assert_eq!(ctx.registry().find(0x1000), Some(meta));
+        }
+    }
+
+    #[test]
+    fn hooks_before_init_bootstrap_a_context() {
+        unsafe {
+            let prov = global_ctx().new_allocation(0x1000, 8).unwrap();
+            init_global_ctx(TEST_ALLOCATOR);
+            let meta = global_ctx().registry().find(0x1000).unwrap();
+            assert_eq!(meta.as_ref().root_provenance(), prov);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! A per-thread flag for whether the runtime is running, which keeps it from
+//! checking itself.
+//!
+//! If the runtime's own calls into libc, such as `mmap` or `memcpy`, are routed
+//! back into its hooks, whether by an interceptor or by an instrumented libc,
+//! a hook would run within another one, and either recurse into the same
+//! lock or report an "error" in the runtime's own memory. Every hook that the
+//! instrumented program calls as it runs enters a [`HookGuard`] first, and
+//! returns right away if the thread is already in the runtime, leaving the
+//! provenance that it would have written null. The functions that the program
+//! calls to query or configure the runtime, such as `bsan_get_stats`, aren't
+//! guarded, so that callbacks can still call them from within a hook.
+//!
+//! The guard also [pins](crate::epoch::pin) the thread, so that the metadata
+//! that the hook reads isn't freed under it by another thread.
+
+use core::cell::Cell;
+
+use crate::epoch::{self, EpochGuard};
+
+#[thread_local]
+static IN_RUNTIME: Cell<bool> = Cell::new(false);
+
+/// Marks the current thread as being in the runtime until it is dropped.
+#[derive(Debug)]
+pub struct HookGuard {
+    _pin: Option<EpochGuard>,
+}
+
+impl HookGuard {
+    /// Enters the runtime, unless the current thread is already in it.
+    #[inline(always)]
+    pub fn enter() -> Option<HookGuard> {
+        if IN_RUNTIME.replace(true) { None } else { Some(HookGuard { _pin: epoch::pin() }) }
+    }
+}
+
+impl Drop for HookGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        IN_RUNTIME.set(false);
+    }
+}
+
+/// Whether the current thread is in a hook.
+pub fn in_runtime() -> bool {
+    IN_RUNTIME.get()
+}
+
+/// Enters a [`HookGuard`] for the rest of the enclosing hook, `$hook`, or
+/// returns from it with `$ret` if the current thread is already in the
+/// runtime. Calls that enter it are counted with a [`HookCounter`], and traced
+/// as a [`Span`] while tracing.
+///
+/// [`HookCounter`]: crate::stats::HookCounter
+/// [`Span`]: crate::trace::Span
+macro_rules! enter_hook {
+    ($hook:ident) => {
+        let Some(_guard) = $crate::guard::HookGuard::enter() else { return };
+        $crate::guard::enter_hook!(@count $hook);
+    };
+    ($hook:ident, $ret:expr) => {
+        let Some(_guard) = $crate::guard::HookGuard::enter() else { return $ret };
+        $crate::guard::enter_hook!(@count $hook);
+    };
+    (@count $hook:ident) => {
+        static CALLS: $crate::stats::HookCounter = $crate::stats::HookCounter::new(unsafe {
+            core::ffi::CStr::from_bytes_with_nul_unchecked(
+                concat!(stringify!($hook), "\0").as_bytes(),
+            )
+        });
+        CALLS.count();
+        let _span = $crate::trace::Span::enter(CALLS.name());
+    };
+}
+
+pub(crate) use enter_hook;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_hooks_are_short_circuited() {
+        assert!(!in_runtime());
+        let outer = HookGuard::enter().unwrap();
+        assert!(in_runtime() && HookGuard::enter().is_none());
+        // Other threads aren't affected.
+        std::thread::spawn(|| assert!(HookGuard::enter().is_some())).join().unwrap();
+        drop(outer);
+        assert!(!in_runtime() && HookGuard::enter().is_some());
+    }
+}
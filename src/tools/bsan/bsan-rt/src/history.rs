@@ -0,0 +1,206 @@
+//! The recent events of each allocation, for error reports.
+//!
+//! With `BSAN_ALLOC_HISTORY=1`, the runtime records the accesses, retags and
+//! exposes of each allocation in a ring buffer, which keeps the last
+//! [`HISTORY_LEN`] of them, and reports about the allocation list them. The
+//! ring is allocated from the arena of the allocation's metadata once its first
+//! event is recorded, so metadata doesn't grow by it when this is off.
+//! This is off by default: unlike accesses, retags and exposes don't carry the
+//! provenance of the pointer, so their allocation has to be looked up in the
+//! registry.
+
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::{fmt, ptr};
+
+use crate::abi::RetagKind;
+use crate::access::AccessKind;
+use crate::arena::Arena;
+use crate::clock::ThreadId;
+use crate::sync::SpinLock;
+use crate::{BorTag, BsanAllocator, SourceInfo};
+
+/// The number of events kept for each allocation.
+pub const HISTORY_LEN: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EventKind {
+    /// An access of `size` bytes through a pointer with `tag`.
+    Access(AccessKind),
+    /// A retag that gave the pointer `tag`.
+    Retag(RetagKind),
+    /// An expose of the pointer, whose tag isn't known.
+    Expose,
+}
+
+impl EventKind {
+    /// The name of the kind in JSON reports.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Access(AccessKind::Read) => "read",
+            EventKind::Access(AccessKind::Write) => "write",
+            EventKind::Retag(_) => "retag",
+            EventKind::Expose => "expose",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    /// The offset of the pointer into the allocation.
+    pub offset: usize,
+    pub size: usize,
+    pub tag: BorTag,
+    pub thread: ThreadId,
+    /// The function of the innermost frame of the thread, or null if it is
+    /// unknown.
+    pub function: *const SourceInfo,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = self.offset;
+        match self.kind {
+            EventKind::Access(kind) => write!(
+                f,
+                "{kind} of {} bytes at offset {offset:#x} through tag {}",
+                self.size,
+                self.tag.get()
+            )?,
+            EventKind::Retag(kind) => {
+                write!(f, "{kind} retag at offset {offset:#x} to tag {}", self.tag.get())?
+            }
+            EventKind::Expose => write!(f, "expose at offset {offset:#x}")?,
+        }
+        write!(f, " on thread {} in ", self.thread.get())?;
+        match unsafe { self.function.as_ref() } {
+            Some(func) => write!(f, "{func}"),
+            None => f.write_str("an unknown function"),
+        }
+    }
+}
+
+/// The last [`HISTORY_LEN`] events of an allocation.
+#[derive(Debug)]
+pub struct AllocHistory {
+    ring: AtomicPtr<SpinLock<Ring>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Ring {
+    events: [Option<Event>; HISTORY_LEN],
+    // The slot that the next event is written to, which holds the oldest
+    // event once the ring is full.
+    next: usize,
+}
+
+impl Default for AllocHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ring {
+    const EMPTY: Ring = Ring { events: [None; HISTORY_LEN], next: 0 };
+
+    fn into_events(self) -> impl Iterator<Item = Event> {
+        (0..HISTORY_LEN).filter_map(move |i| self.events[(self.next + i) % HISTORY_LEN])
+    }
+}
+
+impl AllocHistory {
+    pub const fn new() -> Self {
+        Self { ring: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    /// Records `event`, replacing the oldest event if the history is full. The
+    /// ring is allocated from `arena` with `allocator` for the first event,
+    /// which is dropped if it can't be.
+    pub fn record(&self, arena: &Arena, allocator: &BsanAllocator, event: Event) {
+        let mut ring = self.ring.load(Ordering::Acquire);
+        if ring.is_null() {
+            let Some(new) = arena.alloc(allocator, SpinLock::new(Ring::EMPTY)) else { return };
+            // If another thread got there first, the arena keeps the ring that
+            // lost until it is released.
+            ring = match self.ring.compare_exchange(
+                ptr::null_mut(),
+                new.as_ptr(),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new.as_ptr(),
+                Err(winner) => winner,
+            };
+        }
+        let mut ring = unsafe { (*ring).lock() };
+        let next = ring.next;
+        ring.events[next] = Some(event);
+        ring.next = (next + 1) % HISTORY_LEN;
+    }
+
+    /// The events in the history, oldest first.
+    pub fn events(&self) -> impl Iterator<Item = Event> {
+        let ring = match unsafe { self.ring.load(Ordering::Acquire).as_ref() } {
+            Some(ring) => *ring.lock(),
+            None => Ring::EMPTY,
+        };
+        ring.into_events()
+    }
+
+    /// Like [`AllocHistory::events`], but returns `None` rather than waiting
+    /// if an event is being recorded, as it may be by a thread that a signal
+    /// interrupted.
+    pub fn try_events(&self) -> Option<impl Iterator<Item = Event>> {
+        let ring = match unsafe { self.ring.load(Ordering::Acquire).as_ref() } {
+            Some(ring) => *ring.try_lock()?,
+            None => Ring::EMPTY,
+        };
+        Some(ring.into_events())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ptr;
+
+    use super::*;
+    use crate::alloc::TEST_ALLOCATOR;
+
+    #[test]
+    fn histories_keep_the_latest_events() {
+        let (history, arena) = (AllocHistory::new(), Arena::new());
+        let record = |event| history.record(&arena, &TEST_ALLOCATOR, event);
+        let event = |offset| Event {
+            kind: EventKind::Access(AccessKind::Read),
+            offset,
+            size: 1,
+            tag: BorTag::new(1),
+            thread: ThreadId::current(),
+            function: ptr::null(),
+        };
+        assert_eq!(history.events().count(), 0);
+        assert_eq!(arena.allocated_bytes(), 0);
+        record(event(0));
+        record(event(1));
+        assert_eq!(history.events().map(|e| e.offset).collect::<Vec<_>>(), [0, 1]);
+        for offset in 2..HISTORY_LEN + 3 {
+            record(event(offset));
+        }
+        let offsets: Vec<usize> = history.events().map(|e| e.offset).collect();
+        assert_eq!(offsets, (3..HISTORY_LEN + 3).collect::<Vec<_>>());
+        assert_ne!(arena.allocated_bytes(), 0);
+        let ring = unsafe { (*history.ring.load(Ordering::Relaxed)).lock() };
+        assert!(history.try_events().is_none());
+        drop(ring);
+        assert_eq!(history.try_events().unwrap().count(), HISTORY_LEN);
+        let event = Event { kind: EventKind::Retag(RetagKind::FnEntry), ..event(0x10) };
+        let thread = ThreadId::current().get();
+        assert_eq!(
+            event.to_string(),
+            format!(
+                "fn-entry retag at offset 0x10 to tag 1 on thread {thread} in an unknown function"
+            )
+        );
+        unsafe { arena.release(&TEST_ALLOCATOR) };
+    }
+}
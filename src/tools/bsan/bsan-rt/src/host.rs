@@ -0,0 +1,152 @@
+//! The services of the operating system that the runtime uses, which the
+//! program may provide its own of through the [`BsanHooks`] that it passes to
+//! `bsan_init`.
+//!
+//! The runtime is `no_std`, so it calls into the host for the little it needs
+//! of the OS: mapping memory for its heap and shadow and advising the kernel
+//! about it, writing its logs and reports, terminating the process, and
+//! reading the time. By default, these
+//! are libc's, but an embedder that can't let the runtime call libc, such as a
+//! kernel or a program that intercepts these functions itself, can replace any
+//! of them. Those that are left null keep their defaults.
+
+use core::ffi::{c_int, c_void};
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Writes at most `len` bytes from `buf` to the file descriptor `fd`, like
+/// `write(2)`, and returns how many were written, or -1 with `errno` set.
+pub type Write = unsafe extern "C" fn(fd: c_int, buf: *const c_void, len: usize) -> isize;
+
+/// Terminates the process with `status` at once, like `_exit(2)`, without
+/// running exit handlers.
+pub type Abort = unsafe extern "C" fn(status: c_int) -> !;
+
+/// Returns the time of a monotonic clock in nanoseconds.
+pub type Clock = unsafe extern "C" fn() -> u64;
+
+/// Advises the kernel about the use of the `len` bytes at `addr`, like
+/// `madvise(2)`.
+pub type Madvise = unsafe extern "C" fn(addr: *mut c_void, len: usize, advice: c_int) -> c_int;
+
+/// Sets a byte of `vec` for each page of the `len` bytes at `addr`, whose
+/// lowest bit is set if the page is resident, like `mincore(2)`.
+pub type Mincore = unsafe extern "C" fn(addr: *mut c_void, len: usize, vec: *mut u8) -> c_int;
+
+/// Returns the size of a page of memory in bytes.
+pub type PageSize = unsafe extern "C" fn() -> usize;
+
+/// The services that a program can provide to the runtime in place of libc's.
+/// `mmap` and `munmap` replace those of the [`BsanAllocator`] that is passed
+/// along with them. `write` is a [`Write`], `abort` an [`Abort`], `clock` a
+/// [`Clock`], `madvise` a [`Madvise`], `mincore` a [`Mincore`] and
+/// `page_size` a [`PageSize`]; their types are spelled out so that the header
+/// declares them as nullable function pointers.
+///
+/// [`BsanAllocator`]: crate::BsanAllocator
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct BsanHooks {
+    pub mmap:
+        Option<unsafe extern "C" fn(*mut c_void, usize, c_int, c_int, c_int, i64) -> *mut c_void>,
+    pub munmap: Option<unsafe extern "C" fn(*mut c_void, usize) -> c_int>,
+    pub write: Option<unsafe extern "C" fn(c_int, *const c_void, usize) -> isize>,
+    pub abort: Option<unsafe extern "C" fn(c_int) -> !>,
+    pub clock: Option<unsafe extern "C" fn() -> u64>,
+    pub madvise: Option<unsafe extern "C" fn(*mut c_void, usize, c_int) -> c_int>,
+    pub mincore: Option<unsafe extern "C" fn(*mut c_void, usize, *mut u8) -> c_int>,
+    pub page_size: Option<unsafe extern "C" fn() -> usize>,
+}
+
+// The hooks that are installed, where 0 stands for the default.
+static WRITE: AtomicUsize = AtomicUsize::new(0);
+static ABORT: AtomicUsize = AtomicUsize::new(0);
+static CLOCK: AtomicUsize = AtomicUsize::new(0);
+static MADVISE: AtomicUsize = AtomicUsize::new(0);
+static MINCORE: AtomicUsize = AtomicUsize::new(0);
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Uses the services in `hooks` from now on, and the defaults for those that
+/// are null.
+pub fn install(hooks: &BsanHooks) {
+    WRITE.store(hooks.write.map_or(0, |write| write as usize), Ordering::Release);
+    ABORT.store(hooks.abort.map_or(0, |abort| abort as usize), Ordering::Release);
+    CLOCK.store(hooks.clock.map_or(0, |clock| clock as usize), Ordering::Release);
+    MADVISE.store(hooks.madvise.map_or(0, |madvise| madvise as usize), Ordering::Release);
+    MINCORE.store(hooks.mincore.map_or(0, |mincore| mincore as usize), Ordering::Release);
+    PAGE_SIZE.store(hooks.page_size.map_or(0, |page_size| page_size as usize), Ordering::Release);
+}
+
+/// Writes to `fd` with the installed [`Write`].
+pub unsafe fn write(fd: c_int, buf: *const c_void, len: usize) -> isize {
+    match WRITE.load(Ordering::Acquire) {
+        0 => libc::write(fd, buf, len),
+        write => mem::transmute::<usize, Write>(write)(fd, buf, len),
+    }
+}
+
+/// Terminates the process with the installed [`Abort`].
+pub fn abort(status: c_int) -> ! {
+    unsafe {
+        match ABORT.load(Ordering::Acquire) {
+            0 => libc::_exit(status),
+            abort => mem::transmute::<usize, Abort>(abort)(status),
+        }
+    }
+}
+
+/// The time of the installed [`Clock`], in nanoseconds.
+pub fn now_ns() -> u64 {
+    unsafe {
+        match CLOCK.load(Ordering::Acquire) {
+            0 => {
+                let mut time: libc::timespec = mem::zeroed();
+                libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time);
+                time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+            }
+            clock => mem::transmute::<usize, Clock>(clock)(),
+        }
+    }
+}
+
+/// Advises the kernel about the `len` bytes at `addr` with the installed
+/// [`Madvise`].
+pub unsafe fn madvise(addr: *mut c_void, len: usize, advice: c_int) -> c_int {
+    match MADVISE.load(Ordering::Acquire) {
+        0 => libc::madvise(addr, len, advice),
+        madvise => mem::transmute::<usize, Madvise>(madvise)(addr, len, advice),
+    }
+}
+
+/// Queries which pages of the `len` bytes at `addr` are resident with the
+/// installed [`Mincore`].
+pub unsafe fn mincore(addr: *mut c_void, len: usize, vec: *mut u8) -> c_int {
+    match MINCORE.load(Ordering::Acquire) {
+        0 => libc::mincore(addr, len, vec),
+        mincore => mem::transmute::<usize, Mincore>(mincore)(addr, len, vec),
+    }
+}
+
+/// The size of a page of memory, by the installed [`PageSize`].
+pub fn page_size() -> usize {
+    unsafe {
+        match PAGE_SIZE.load(Ordering::Acquire) {
+            0 => libc::sysconf(libc::_SC_PAGESIZE) as usize,
+            page_size => mem::transmute::<usize, PageSize>(page_size)(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_defaults_are_libcs() {
+        let before = now_ns();
+        assert!(now_ns() >= before && before > 0);
+        assert!(page_size().is_power_of_two());
+        let msg = b"";
+        assert_eq!(unsafe { write(1, msg.as_ptr().cast(), 0) }, 0);
+    }
+}
@@ -0,0 +1,222 @@
+//! Lists of functions and modules whose accesses and retags are treated
+//! leniently, so that known problems in parts of a large program can be set
+//! aside while the rest of it is checked.
+//!
+//! `BSAN_IGNORE_FNS` is a comma-separated list of the names of functions, as
+//! recorded by `bsan_func_entry`, and `BSAN_IGNORE_MODULES` one of modules:
+//! either Rust module paths, which cover the functions within them, or the
+//! file names of shared libraries, which cover the code that they map.
+//! Entries that end with `*` match any name that starts with the rest, so
+//! `BSAN_IGNORE_MODULES=mycrate::ffi,libfoo.so*` covers every function in
+//! `mycrate::ffi` and its submodules, along with any version of `libfoo.so`.
+//! The accesses that these make aren't checked, and the arguments that they
+//! retag on entry aren't protected, though their pointers are still tagged so
+//! that the rest of the program can use them.
+
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::sync::SpinLock;
+use crate::{backtrace, frame, io, location};
+
+/// The number of shared libraries whose code can be ignored. Libraries past
+/// this are checked as usual.
+pub const MAX_IGNORED_IMAGES: usize = 16;
+
+#[derive(Debug)]
+pub struct IgnoreList {
+    functions: Option<&'static str>,
+    modules: Option<&'static str>,
+    // The ranges of addresses that the loaded libraries in `modules` are mapped
+    // at, which are searched for the return addresses of hooks.
+    images: SpinLock<[Option<(usize, usize)>; MAX_IGNORED_IMAGES]>,
+    has_images: AtomicBool,
+}
+
+impl Default for IgnoreList {
+    fn default() -> Self {
+        Self::new(None, None)
+    }
+}
+
+impl IgnoreList {
+    pub const fn new(functions: Option<&'static str>, modules: Option<&'static str>) -> Self {
+        Self {
+            functions,
+            modules,
+            images: SpinLock::new([None; MAX_IGNORED_IMAGES]),
+            has_images: AtomicBool::new(false),
+        }
+    }
+
+    /// Reads the lists from the environment, and finds the libraries that are
+    /// already loaded.
+    pub fn from_env() -> Self {
+        let list = |name| io::env_str(name).filter(|list| !list.is_empty());
+        let ignored = Self::new(list(c"BSAN_IGNORE_FNS"), list(c"BSAN_IGNORE_MODULES"));
+        ignored.find_images();
+        ignored
+    }
+
+    /// Whether nothing is ignored, which hooks check before anything else.
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_none() && self.modules.is_none()
+    }
+
+    /// Whether the function named `function` is ignored, by name or by the
+    /// module that it's in.
+    pub fn ignores_function(&self, function: &[u8]) -> bool {
+        let listed = |list: Option<&str>, matches: fn(&[u8], &[u8]) -> bool| {
+            list.is_some_and(|list| {
+                list.split(',').any(|entry| matches(entry.as_bytes(), function))
+            })
+        };
+        listed(self.functions, matches) || listed(self.modules, contains)
+    }
+
+    /// Whether a library whose file name is `name` is ignored.
+    pub fn ignores_image(&self, name: &[u8]) -> bool {
+        self.modules
+            .is_some_and(|list| list.split(',').any(|entry| matches(entry.as_bytes(), name)))
+    }
+
+    /// Whether `pc` is in the code of an ignored library that is loaded.
+    pub fn ignores_pc(&self, pc: usize) -> bool {
+        self.images.lock().iter().flatten().any(|&(start, end)| (start..end).contains(&pc))
+    }
+
+    /// Whether the hook that calls this was called from code that is ignored:
+    /// from the current thread's innermost function, if it is known, or from
+    /// an ignored library, which none of the runtime's frames are in.
+    #[inline(always)]
+    pub fn ignores_caller(&self) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let function = unsafe { frame::current_function() }
+            .and_then(|func| unsafe { location::name(func.function) });
+        if function.is_some_and(|function| self.ignores_function(function)) {
+            return true;
+        }
+        // Unwinding is only worth it if an ignored library is loaded.
+        if !self.has_images.load(Ordering::Relaxed) {
+            return false;
+        }
+        let mut pcs = [0; CALLER_FRAMES];
+        let len = backtrace::unwind(0, &mut pcs);
+        pcs[..len].iter().any(|&pc| self.ignores_pc(pc))
+    }
+
+    /// Finds the mapped ranges of the ignored libraries that are loaded. This
+    /// is done again whenever a library is loaded or unloaded.
+    pub fn find_images(&self) {
+        if self.modules.is_none() {
+            return;
+        }
+        let mut images = self.images.lock();
+        *images = [None; MAX_IGNORED_IMAGES];
+        let mut slots = images.iter_mut();
+        unsafe {
+            for_each_image(|name, start, end| {
+                if self.ignores_image(name) {
+                    if let Some(slot) = slots.next() {
+                        *slot = Some((start, end));
+                    }
+                }
+            })
+        };
+        self.has_images.store(images[0].is_some(), Ordering::Relaxed);
+    }
+}
+
+// Whether `pattern` is `name`, or a prefix of it followed by `*`.
+fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.strip_suffix(b"*") {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
+
+// Whether the function `name` is in the module `pattern`, or one of its
+// submodules.
+fn contains(pattern: &[u8], name: &[u8]) -> bool {
+    if matches(pattern, name) {
+        return true;
+    }
+    let pattern = pattern.strip_suffix(b"*").unwrap_or(pattern);
+    !pattern.is_empty() && name.strip_prefix(pattern).is_some_and(|rest| rest.starts_with(b"::"))
+}
+
+// The number of innermost frames that are searched for a return address into
+// an ignored library. Hooks are entered through a wrapper that may or may not
+// be inlined, so this is enough for the runtime's own frames and the caller.
+const CALLER_FRAMES: usize = 4;
+
+// Calls `f` with the file name and the mapped range of each loaded library.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+unsafe fn for_each_image<F: FnMut(&[u8], usize, usize)>(mut f: F) {
+    unsafe extern "C" fn visit<F: FnMut(&[u8], usize, usize)>(
+        info: *mut libc::dl_phdr_info,
+        _size: usize,
+        data: *mut c_void,
+    ) -> libc::c_int {
+        let (info, f) = (&*info, &mut *data.cast::<F>());
+        // The program itself has no name.
+        let Some(path) = location::name(info.dlpi_name).filter(|path| !path.is_empty()) else {
+            return 0;
+        };
+        let name = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+        let phdrs = core::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum.into());
+        let mut range: Option<(usize, usize)> = None;
+        for phdr in phdrs.iter().filter(|phdr| phdr.p_type == libc::PT_LOAD) {
+            let start = info.dlpi_addr as usize + phdr.p_vaddr as usize;
+            let end = start + phdr.p_memsz as usize;
+            range = Some(match range {
+                Some((lo, hi)) => (lo.min(start), hi.max(end)),
+                None => (start, end),
+            });
+        }
+        if let Some((start, end)) = range {
+            f(name, start, end);
+        }
+        0
+    }
+
+    libc::dl_iterate_phdr(Some(visit::<F>), (&raw mut f).cast());
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+unsafe fn for_each_image(f: impl FnMut(&[u8], usize, usize)) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn functions_are_ignored_by_name_and_module() {
+        let ignored = IgnoreList::new(Some("parse,legacy_*"), Some("app::ffi,vendored*"));
+        assert!(ignored.ignores_function(b"parse"));
+        assert!(!ignored.ignores_function(b"parse_all"));
+        assert!(ignored.ignores_function(b"legacy_init"));
+        assert!(ignored.ignores_function(b"app::ffi::call"));
+        assert!(ignored.ignores_function(b"app::ffi::raw::call"));
+        assert!(!ignored.ignores_function(b"app::ffix::call"));
+        assert!(!ignored.ignores_function(b"app::main"));
+        assert!(ignored.ignores_function(b"vendored_zlib::inflate"));
+        assert!(ignored.ignores_image(b"vendored.so"));
+        assert!(!ignored.ignores_image(b"libc.so.6"));
+        assert!(IgnoreList::default().is_empty());
+        assert!(!IgnoreList::default().ignores_function(b"parse"));
+    }
+
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    #[test]
+    fn loaded_libraries_are_found_by_file_name() {
+        let ignored = IgnoreList::new(None, Some("libc.so*"));
+        ignored.find_images();
+        assert!(ignored.has_images.load(Ordering::Relaxed));
+        assert!(ignored.ignores_pc(libc::getpid as *const () as usize));
+        assert!(!ignored.ignores_pc(matches as *const () as usize));
+    }
+}
@@ -0,0 +1,428 @@
+use core::ffi::{CStr, c_char};
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use core::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+use libc::c_int;
+
+use crate::sync::SpinLock;
+use crate::{host, options};
+
+const BUF_LEN: usize = 512;
+
+#[inline]
+pub fn errno() -> c_int {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        *libc::__errno_location()
+    }
+    #[cfg(target_vendor = "apple")]
+    unsafe {
+        *libc::__error()
+    }
+}
+
+/// Whether the environment variable `name` is set to anything other than an
+/// empty string or `0`. Like the other `env_` functions, this falls back to
+/// the entry for `name` in `BSAN_OPTIONS`.
+pub fn env_flag(name: &CStr) -> bool {
+    env_flag_or(name, false)
+}
+
+/// Like [`env_flag`], for flags that are `default` unless set.
+pub fn env_flag_or(name: &CStr, default: bool) -> bool {
+    env_str(name).map_or(default, |value| !matches!(value, "" | "0"))
+}
+
+/// The value of the environment variable `name`, if it is set to valid UTF-8.
+pub fn env_str(name: &CStr) -> Option<&'static str> {
+    options::lookup(name)
+}
+
+/// The path in the environment variable `name`, if it is set to one that
+/// isn't empty or too long.
+pub fn env_path(name: &CStr) -> Option<CPathBuf> {
+    CPathBuf::new(env_str(name)?.as_bytes())
+}
+
+/// The value of the environment variable `name` parsed as a `T`, or `default`
+/// if it isn't set. Invalid values are warned about and ignored.
+pub fn env_parse<T: FromStr>(name: &CStr, default: T) -> T {
+    let Some(value) = env_str(name) else { return default };
+    match value.trim().parse() {
+        Ok(value) => value,
+        Err(_) => {
+            let name = name.to_str().unwrap_or_default();
+            let _ = writeln!(FdWriter::log(), "bsan: invalid value `{value}` for {name}");
+            default
+        }
+    }
+}
+
+const PATH_LEN: usize = 256;
+
+/// A NUL-terminated path stored inline, so that paths taken from the
+/// environment can be kept in the global context without allocating.
+#[derive(Clone)]
+pub struct CPathBuf {
+    buf: [u8; PATH_LEN],
+    len: usize,
+}
+
+impl CPathBuf {
+    /// Copies `path`, returning `None` if it is empty or too long.
+    pub fn new(path: &[u8]) -> Option<Self> {
+        Self::empty().with_suffix(path).filter(|path| path.len > 0)
+    }
+
+    /// # Safety
+    /// `path` must be null or point to a valid C string.
+    pub unsafe fn from_ptr(path: *const c_char) -> Option<Self> {
+        if path.is_null() { None } else { Self::new(CStr::from_ptr(path).to_bytes()) }
+    }
+
+    const fn empty() -> Self {
+        Self { buf: [0; PATH_LEN], len: 0 }
+    }
+
+    /// Returns a copy of this path with `suffix` appended.
+    pub fn with_suffix(&self, suffix: &[u8]) -> Option<Self> {
+        let len = self.len + suffix.len();
+        if len >= PATH_LEN || suffix.contains(&0) {
+            return None;
+        }
+        let mut path = self.clone();
+        path.buf[self.len..len].copy_from_slice(suffix);
+        path.len = len;
+        Some(path)
+    }
+
+    pub fn as_ptr(&self) -> *const c_char {
+        self.buf.as_ptr().cast()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl fmt::Write for CPathBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        *self = self.with_suffix(s.as_bytes()).ok_or(fmt::Error)?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for CPathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match core::str::from_utf8(self.as_bytes()) {
+            Ok(path) => fmt::Debug::fmt(path, f),
+            Err(_) => fmt::Debug::fmt(self.as_bytes(), f),
+        }
+    }
+}
+
+// The file descriptor that the runtime writes its output to, whether the
+// runtime opened it and so has to close it when the output is sent elsewhere,
+// and the path of the log file that it is open on, if any, for reopening
+// after a fork.
+static LOG_FD: AtomicI32 = AtomicI32::new(libc::STDERR_FILENO);
+static LOG_OWNED: AtomicBool = AtomicBool::new(false);
+static LOG_PATH: SpinLock<Option<CPathBuf>> = SpinLock::new(None);
+
+/// The file descriptor that the runtime writes its output to.
+#[inline]
+pub fn log_fd() -> c_int {
+    LOG_FD.load(Ordering::Relaxed)
+}
+
+/// Sends the runtime's output to the file named by `BSAN_LOG_PATH`, if it is
+/// set, as other sanitizers do with `log_path`. Only the first call does
+/// anything.
+pub fn open_log_from_env() {
+    static OPENED: AtomicBool = AtomicBool::new(false);
+    if OPENED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    if let Some(template) = env_path(c"BSAN_LOG_PATH") {
+        set_log_path(template);
+    }
+}
+
+/// Sends the runtime's output to the file at `template`. `%p` in the path is
+/// replaced by the process ID and `%n` by the name of the program, and
+/// `.<pid>` is appended to paths without a `%p`, so that each process writes
+/// to a file of its own. `stderr` and `stdout` name those streams. If the file
+/// can't be opened, the output goes to `stderr`.
+pub fn set_log_path(template: CPathBuf) {
+    match template.as_bytes() {
+        b"stderr" => return set_log_fd(libc::STDERR_FILENO),
+        b"stdout" => return set_log_fd(libc::STDOUT_FILENO),
+        _ => {}
+    }
+    *LOG_PATH.lock() = Some(template);
+    open_log();
+    static REGISTERED: AtomicBool = AtomicBool::new(false);
+    if !REGISTERED.swap(true, Ordering::Relaxed) {
+        // A child would otherwise write to the log of its parent.
+        unsafe { libc::pthread_atfork(None, None, Some(reopen_log)) };
+    }
+}
+
+/// Sends the runtime's output to `fd`, which the runtime never closes.
+pub fn set_log_fd(fd: c_int) {
+    *LOG_PATH.lock() = None;
+    replace_log_fd(fd, false);
+}
+
+fn replace_log_fd(fd: c_int, owned: bool) {
+    let old = LOG_FD.swap(fd, Ordering::Relaxed);
+    if LOG_OWNED.swap(owned, Ordering::Relaxed) && old != fd {
+        unsafe { libc::close(old) };
+    }
+}
+
+extern "C" fn reopen_log() {
+    open_log();
+}
+
+fn open_log() {
+    let Some(template) = LOG_PATH.lock().clone() else { return };
+    let pid = unsafe { libc::getpid() };
+    let Some(path) = expand_log_path(template.as_bytes(), pid, program_name()) else {
+        replace_log_fd(libc::STDERR_FILENO, false);
+        let _ = writeln!(FdWriter::log(), "bsan: log path {template:?} is too long");
+        return;
+    };
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND | libc::O_CLOEXEC;
+    let fd = unsafe { libc::open(path.as_ptr(), flags, 0o644 as libc::c_uint) };
+    if fd < 0 {
+        replace_log_fd(libc::STDERR_FILENO, false);
+        let _ = writeln!(FdWriter::log(), "bsan: failed to open log file {path:?}");
+        return;
+    }
+    replace_log_fd(fd, true);
+}
+
+/// `template` with `%p` replaced by `pid`, `%n` by `name` and `%%` by `%`,
+/// and `.<pid>` appended if it has no `%p`.
+fn expand_log_path(template: &[u8], pid: libc::pid_t, name: &[u8]) -> Option<CPathBuf> {
+    let mut path = CPathBuf::empty();
+    let mut has_pid = false;
+    let mut bytes = template.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'%' {
+            path = path.with_suffix(&[byte])?;
+            continue;
+        }
+        match bytes.next() {
+            Some(b'p') => {
+                has_pid = true;
+                write!(path, "{pid}").ok()?;
+            }
+            Some(b'n') => path = path.with_suffix(name)?,
+            Some(b'%') => path = path.with_suffix(b"%")?,
+            Some(&other) => path = path.with_suffix(&[b'%', other])?,
+            None => path = path.with_suffix(b"%")?,
+        }
+    }
+    if !has_pid {
+        write!(path, ".{pid}").ok()?;
+    }
+    (path.len > 0).then_some(path)
+}
+
+// The name that the program was started with, without its directory.
+fn program_name() -> &'static [u8] {
+    #[cfg(target_os = "linux")]
+    {
+        extern "C" {
+            static program_invocation_short_name: *const c_char;
+        }
+        unsafe { CStr::from_ptr(program_invocation_short_name) }.to_bytes()
+    }
+    #[cfg(target_vendor = "apple")]
+    {
+        unsafe { CStr::from_ptr(libc::getprogname()) }.to_bytes()
+    }
+}
+
+/// A buffered `fmt::Write` adapter over a raw file descriptor. Output is
+/// written with `write(2)` directly, without going through the host's stdio.
+pub struct FdWriter {
+    fd: c_int,
+    len: usize,
+    buf: [u8; BUF_LEN],
+    failed: bool,
+}
+
+impl FdWriter {
+    pub const fn new(fd: c_int) -> Self {
+        Self { fd, len: 0, buf: [0; BUF_LEN], failed: false }
+    }
+
+    /// A writer for the runtime's own output, which goes to standard error
+    /// unless `BSAN_LOG_PATH` is set.
+    pub fn log() -> Self {
+        Self::new(log_fd())
+    }
+
+    /// Writes out any buffered bytes. Returns `false` if any write
+    /// so far has failed.
+    pub fn flush(&mut self) -> bool {
+        let mut written = 0;
+        while written < self.len && !self.failed {
+            let rest = &self.buf[written..self.len];
+            let res = unsafe { host::write(self.fd, rest.as_ptr().cast(), rest.len()) };
+            if res > 0 {
+                written += res as usize;
+            } else if res < 0 && errno() == libc::EINTR {
+                continue;
+            } else {
+                self.failed = true;
+            }
+        }
+        self.len = 0;
+        !self.failed
+    }
+}
+
+impl fmt::Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            if self.len == BUF_LEN && !self.flush() {
+                return Err(fmt::Error);
+            }
+            let n = bytes.len().min(BUF_LEN - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FdWriter {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Writes into a buffer, dropping what doesn't fit, and leaves room for the
+/// NUL that [`SliceWriter::finish`] terminates it with.
+pub struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    /// A writer into `buf`, which must not be empty.
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn finish(self) {
+        self.buf[self.len] = 0;
+    }
+}
+
+impl fmt::Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - 1 - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Reads line `line` of the file at `path`, counting from 1, into `buf`,
+/// without its line terminator. Lines longer than `buf` are truncated. Returns
+/// the length of the line, or `None` if the file can't be read or is shorter.
+///
+/// # Safety
+/// `path` must point to a valid C string.
+pub unsafe fn read_line(path: *const c_char, line: usize, buf: &mut [u8]) -> Option<usize> {
+    let fd = libc::open(path, libc::O_RDONLY | libc::O_CLOEXEC);
+    if fd < 0 {
+        return None;
+    }
+    let mut chunk = [0u8; BUF_LEN];
+    let (mut current, mut len, mut found) = (1, 0, false);
+    'read: loop {
+        let res = libc::read(fd, chunk.as_mut_ptr().cast(), BUF_LEN);
+        if res < 0 && errno() == libc::EINTR {
+            continue;
+        }
+        if res <= 0 {
+            // The last line need not end in a newline.
+            found = current == line && res == 0 && len > 0;
+            break;
+        }
+        for &byte in &chunk[..res as usize] {
+            if byte == b'\n' {
+                if current == line {
+                    found = true;
+                    break 'read;
+                }
+                current += 1;
+            } else if current == line && len < buf.len() {
+                buf[len] = byte;
+                len += 1;
+            }
+        }
+    }
+    libc::close(fd);
+    if len > 0 && buf[len - 1] == b'\r' {
+        len -= 1;
+    }
+    found.then_some(len)
+}
+
+/// Writes a file by calling `f` on a writer for `tmp_path`, which is then
+/// renamed over `path`, so readers only ever observe complete files.
+///
+/// # Safety
+/// `f` must not close the writer's file descriptor.
+pub unsafe fn write_atomically(
+    path: &CPathBuf,
+    tmp_path: &CPathBuf,
+    f: impl FnOnce(&mut FdWriter) -> fmt::Result,
+) -> bool {
+    let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC;
+    let fd = libc::open(tmp_path.as_ptr(), flags, 0o644 as libc::c_uint);
+    if fd < 0 {
+        return false;
+    }
+    let mut out = FdWriter::new(fd);
+    let mut ok = f(&mut out).is_ok() && out.flush();
+    ok &= libc::close(fd) == 0;
+    ok && libc::rename(tmp_path.as_ptr(), path.as_ptr()) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_values_fall_back_to_the_default() {
+        std::env::set_var("BSAN_TEST_ENV_PARSE", " 42 ");
+        assert_eq!(env_parse(c"BSAN_TEST_ENV_PARSE", 1), 42);
+        std::env::set_var("BSAN_TEST_ENV_PARSE", "forty-two");
+        assert_eq!(env_parse(c"BSAN_TEST_ENV_PARSE", 1), 1);
+        std::env::remove_var("BSAN_TEST_ENV_PARSE");
+        assert_eq!(env_parse(c"BSAN_TEST_ENV_PARSE", 7), 7);
+    }
+
+    #[test]
+    fn log_paths_are_expanded() {
+        let expand = |template: &[u8]| {
+            let path = expand_log_path(template, 42, b"prog").unwrap();
+            String::from_utf8(path.as_bytes().to_vec()).unwrap()
+        };
+        assert_eq!(expand(b"/tmp/bsan.log"), "/tmp/bsan.log.42");
+        assert_eq!(expand(b"/tmp/%n-%p.log"), "/tmp/prog-42.log");
+        assert_eq!(expand(b"/tmp/%n.%%p.%x%"), "/tmp/prog.%p.%x%.42");
+        assert!(expand_log_path(&[b'x'; PATH_LEN - 3], 42, b"prog").is_none());
+    }
+}
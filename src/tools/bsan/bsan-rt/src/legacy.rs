@@ -0,0 +1,49 @@
+//! The entry points of the ABI of the legacy `bsanrt` runtime, which are
+//! exported instead of the current ones when the runtime is built with the
+//! `legacy-abi` feature. Programs instrumented by older versions of the pass
+//! link against that build of `libbsan_rt.a`, which can't be linked together
+//! with the other one. The header declares them in place of the current ones
+//! if `BSAN_LEGACY_ABI` is defined.
+//!
+//! Only the entry points whose signatures changed are defined here. All other
+//! hooks are exported unchanged. This will be removed once the pass has
+//! migrated to the current ABI.
+
+use core::ffi::c_void;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+use crate::Provenance;
+use crate::alloc::LIBC_ALLOCATOR;
+
+/// The legacy runtime took no arguments and allocated with the host's libc.
+#[no_mangle]
+unsafe extern "C" fn bsan_init() {
+    crate::bsan_init(LIBC_ALLOCATOR, ptr::null(), crate::BSAN_API_VERSION);
+}
+
+/// The legacy runtime returned the provenance of the new allocation by value.
+#[no_mangle]
+unsafe extern "C" fn bsan_malloc(ptr: *mut c_void, size: usize) -> Provenance {
+    let mut prov = MaybeUninit::uninit();
+    crate::bsan_malloc(ptr, size, prov.as_mut_ptr());
+    prov.assume_init()
+}
+
+/// The legacy runtime checked accesses against the allocation found from the
+/// address alone.
+#[no_mangle]
+unsafe extern "C" fn bsan_read(ptr: *mut c_void, access_size: u64) {
+    crate::bsan_read(ptr, access_size, ptr::null(), ptr::null());
+}
+
+#[no_mangle]
+unsafe extern "C" fn bsan_write(ptr: *mut c_void, access_size: u64) {
+    crate::bsan_write(ptr, access_size, ptr::null(), ptr::null());
+}
+
+/// The legacy runtime didn't identify the function of a frame.
+#[no_mangle]
+unsafe extern "C" fn bsan_func_entry() {
+    crate::bsan_func_entry(ptr::null());
+}
@@ -1,51 +1,2027 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![feature(allocator_api)]
 #![feature(sync_unsafe_cell)]
 #![feature(alloc_layout_extra)]
 #![feature(strict_overflow_ops)]
+#![feature(thread_local)]
+#![feature(linkage)]
 #![allow(unused)]
 
+// The crate is renamed, since the runtime's own allocator is `crate::alloc`.
+extern crate alloc as alloc_crate;
+
 mod global;
-use global::init_global_ctx;
+use global::{GlobalContext, exit_global_ctx, global_ctx, init_global_ctx, report_status};
+
+mod abi;
+use abi::{AbiViolation, PlaceKind, RetagKind};
 
 mod alloc;
+mod arena;
 pub use alloc::BsanAllocator;
 
+mod tag;
+use tag::TagHint;
+pub use tag::{BorTag, TagAllocator};
+
+mod access;
+pub use access::AccessDesc;
+use access::{AccessError, AccessKind};
+
+mod backtrace;
+use backtrace::Backtrace;
+pub use backtrace::BacktraceHook;
+
+mod checkpoint;
+mod clock;
+#[cfg(test)]
+mod corpus;
+mod dedup;
+mod depot;
+mod dump;
+mod epoch;
+mod frame;
+mod guard;
+use guard::enter_hook;
+mod history;
+mod host;
+pub use host::BsanHooks;
+mod ignore;
+use history::EventKind;
+mod io;
+use io::FdWriter;
+
+#[cfg(feature = "legacy-abi")]
+mod legacy;
+mod location;
+pub use location::SourceInfo;
+
+mod miri;
+mod module;
+mod options;
+mod pool;
+mod registry;
+use registry::{AllocKind, AllocMetadata};
 mod shadow;
+mod signal;
+mod stats;
+pub use stats::{HookStats, ShadowStats, Stats};
+mod report;
+use report::{Addr, ErrorKind, Report, Severity};
+pub use report::{ErrorCallback, ErrorInfo};
+mod sanitizer;
+mod symbolize;
+mod sync;
+mod thread;
+mod trace;
 
 use core::cell::UnsafeCell;
-use core::ffi::c_void;
+use core::ffi::{c_char, c_int, c_void};
+use core::fmt::{self, Write};
 use core::num::NonZero;
-#[cfg(not(test))]
+use core::ops::ControlFlow;
+#[cfg(not(any(test, feature = "std")))]
 use core::panic::PanicInfo;
+use core::ptr::NonNull;
+use core::{mem, ptr, slice};
+
+/// The version of the ABI between instrumented code and the runtime, which the
+/// pass passes to [`bsan_init`]. The pass and the runtime are built separately,
+/// so a runtime refuses to run code that was instrumented for any other version.
+///
+/// The version must be incremented whenever the ABI changes in a way that code
+/// instrumented for the previous version could observe: when the signature or
+/// meaning of a hook changes, or when a hook is removed, and when the layout of
+/// a `#[repr(C)]` type that crosses the boundary changes. These are
+/// [`Provenance`], [`SourceInfo`], [`AccessDesc`], [`BsanAllocator`],
+/// [`BsanHooks`], [`Stats`], [`HookStats`] and [`ShadowStats`], along with the
+/// raw values of the retag and place kinds. Fields may not be reordered or
+/// resized without a new version; the layout of `Provenance`, which the pass
+/// hard-codes to keep provenance in stack slots, is also checked when the
+/// runtime is built. Adding a hook does not require a new version, since code
+/// that was instrumented earlier never calls it.
+pub const BSAN_API_VERSION: u32 = 5;
+
+/// A unique identifier for an allocation. IDs `0` and `usize::MAX` are reserved
+/// and never assigned to an allocation.
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AllocId(usize);
+
+impl AllocId {
+    pub const INVALID: AllocId = AllocId(0);
+    pub const ZST: AllocId = AllocId(!0);
+
+    #[inline]
+    pub const fn new(raw: usize) -> Self {
+        AllocId(raw)
+    }
+
+    #[inline]
+    pub const fn get(self) -> usize {
+        self.0
+    }
+}
+
+/// The provenance of a pointer: the allocation it was derived from, its borrow
+/// tag, and the address of the allocation's metadata, which the runtime uses to
+/// look up the allocation without consulting the registry.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    pub alloc_id: AllocId,
+    pub bor_tag: BorTag,
+    pub lock_address: *mut c_void,
+}
+
+// Metadata is shared between threads, and only modified atomically or under
+// the registry lock.
+unsafe impl Send for Provenance {}
+unsafe impl Sync for Provenance {}
+
+const _: () = assert!(mem::size_of::<Provenance>() == 3 * mem::size_of::<usize>());
+const _: () = assert!(mem::align_of::<Provenance>() == mem::align_of::<usize>());
+
+impl Provenance {
+    /// The provenance of pointers that are not derived from any known allocation.
+    pub const fn null() -> Self {
+        Provenance {
+            alloc_id: AllocId::INVALID,
+            bor_tag: BorTag::INVALID,
+            lock_address: core::ptr::null_mut(),
+        }
+    }
+
+    /// The provenance of non-null pointers that are only valid for zero-sized
+    /// accesses, such as `NonNull::dangling()`. These do not belong to any
+    /// allocation, but unlike [`Provenance::null`], they are not an error to use.
+    pub const fn zst() -> Self {
+        Provenance {
+            alloc_id: AllocId::ZST,
+            bor_tag: BorTag::INVALID,
+            lock_address: core::ptr::null_mut(),
+        }
+    }
+}
+
+/// Initializes the runtime. Hooks that run earlier, such as those in static
+/// initializers, use a context with the default options and libc's allocator,
+/// which this then configures. If they made allocations, the runtime keeps
+/// using libc's allocator, and reports it as an error. `hooks`, if it isn't
+/// null, provides the services of the OS that the runtime uses in place of
+/// libc's, as described by [`BsanHooks`]. `api_version` is the
+/// [`BSAN_API_VERSION`] that the program was instrumented for; the process is
+/// terminated if it isn't the runtime's own. It may be called more than once,
+/// from any thread, but only the first call configures the runtime, and the
+/// others wait for it to finish.
+///
+/// # Safety
+/// If hooks have already run, no hook may be running on another thread.
+/// `hooks` must be null or valid for reads.
+pub unsafe extern "C" fn bsan_init(
+    alloc: BsanAllocator,
+    hooks: *const BsanHooks,
+    api_version: u32,
+) {
+    enter_hook!(bsan_init);
+    let hooks = hooks.as_ref().copied().unwrap_or_default();
+    host::install(&hooks);
+    if api_version != BSAN_API_VERSION {
+        let _ = writeln!(
+            FdWriter::log(),
+            "bsan: the program was instrumented for version {api_version} of the runtime ABI, \
+             but this runtime implements version {BSAN_API_VERSION}"
+        );
+        global::internal_failure();
+    }
+    init_global_ctx(alloc.with_hooks(&hooks));
+}
+
+/// Returns the [`BSAN_API_VERSION`] that the runtime implements.
+#[no_mangle]
+extern "C" fn bsan_get_api_version() -> u32 {
+    BSAN_API_VERSION
+}
+
+/// Registers a new heap allocation of `size` bytes at `ptr` and writes the
+/// provenance of its root pointer to `prov`.
+///
+/// # Safety
+/// The runtime must be initialized, and `prov` must be valid for writes.
+pub unsafe extern "C" fn bsan_malloc(ptr: *mut c_void, size: usize, prov: *mut Provenance) {
+    enter_hook!(bsan_malloc, null_out(prov));
+    malloc("bsan_malloc", ptr, size, prov);
+}
+
+#[inline(always)]
+unsafe fn malloc(hook: &str, ptr: *mut c_void, size: usize, prov: *mut Provenance) {
+    let ctx = global_ctx();
+    let root = ctx.new_allocation(ptr.addr(), size).unwrap_or(Provenance::null());
+    if prov.is_null() {
+        abi::violation(ctx, hook, AbiViolation::NullArgument("prov"));
+        // Nothing holds the reference that came with the root provenance.
+        if let Some(meta) = NonNull::new(root.lock_address.cast()) {
+            ctx.release_metadata(meta);
+        }
+        return;
+    }
+    *prov = root;
+}
+
+// Writes null provenance to `prov` for a hook that is short-circuited, unless
+// `prov` is null.
+unsafe fn null_out(prov: *mut Provenance) {
+    if !prov.is_null() {
+        *prov = Provenance::null();
+    }
+}
+
+/// Registers a new heap allocation of `size` bytes at `ptr`, returned by
+/// `aligned_alloc(align, size)`, and writes the provenance of its root pointer
+/// to `prov`. The alignment is recorded in the allocation's metadata.
+#[no_mangle]
+unsafe extern "C" fn bsan_aligned_alloc(
+    ptr: *mut c_void,
+    align: usize,
+    size: usize,
+    prov: *mut Provenance,
+) {
+    enter_hook!(bsan_aligned_alloc, null_out(prov));
+    aligned_malloc("bsan_aligned_alloc", ptr, align.is_power_of_two(), align, size, prov);
+}
+
+/// Like [`bsan_aligned_alloc`], for `memalign(align, size)`.
+#[no_mangle]
+unsafe extern "C" fn bsan_memalign(
+    ptr: *mut c_void,
+    align: usize,
+    size: usize,
+    prov: *mut Provenance,
+) {
+    enter_hook!(bsan_memalign, null_out(prov));
+    aligned_malloc("bsan_memalign", ptr, align.is_power_of_two(), align, size, prov);
+}
+
+/// Like [`bsan_aligned_alloc`], for a successful `posix_memalign(&ptr, align,
+/// size)`, which also requires the alignment to be a multiple of the size of a
+/// pointer.
+#[no_mangle]
+unsafe extern "C" fn bsan_posix_memalign(
+    ptr: *mut c_void,
+    align: usize,
+    size: usize,
+    prov: *mut Provenance,
+) {
+    enter_hook!(bsan_posix_memalign, null_out(prov));
+    let valid = align.is_power_of_two() && align % mem::size_of::<*mut c_void>() == 0;
+    aligned_malloc("bsan_posix_memalign", ptr, valid, align, size, prov);
+}
+
+#[inline(always)]
+unsafe fn aligned_malloc(
+    hook: &str,
+    ptr: *mut c_void,
+    valid: bool,
+    align: usize,
+    size: usize,
+    prov: *mut Provenance,
+) {
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, hook, AbiViolation::NullArgument("prov"));
+    }
+    // The allocator fails for these, so there is no allocation to register.
+    if !valid {
+        *prov = Provenance::null();
+        return abi::violation(ctx, hook, AbiViolation::InvalidAlignment(align));
+    }
+    if ptr.addr() % align != 0 {
+        let _ = writeln!(FdWriter::log(), "bsan: {hook}: {ptr:p} is not aligned to {align} bytes");
+    }
+    *prov = ctx.new_aligned_allocation(ptr.addr(), size, align).unwrap_or(Provenance::null());
+}
+
+/// Registers a new zero-initialized heap allocation of `num` elements of `size`
+/// bytes at `ptr`, as returned by `calloc`, and writes the provenance of its
+/// root pointer to `prov`. Its contents are known to be zero, so any
+/// provenance left in its shadow memory, such as from memory that was
+/// unmapped without being cleared, is discarded.
+#[no_mangle]
+unsafe extern "C" fn bsan_calloc(ptr: *mut c_void, num: usize, size: usize, prov: *mut Provenance) {
+    enter_hook!(bsan_calloc, null_out(prov));
+    let ctx = global_ctx();
+    let Some(total) = num.checked_mul(size) else {
+        let size = (num as u128 * size as u128).min(u64::MAX as u128) as u64;
+        return abi::violation(ctx, "bsan_calloc", AbiViolation::InvalidSize(size));
+    };
+    ctx.shadow().clear_range(ptr.addr(), total);
+    malloc("bsan_calloc", ptr, total, prov);
+}
+
+/// Records that the heap allocation at `old_ptr`, whose pointer had the
+/// provenance at `old_prov`, was reallocated to the `new_size` bytes at
+/// `new_ptr`, and writes the provenance of the new root pointer to `prov`. The
+/// old allocation is retired even if it was resized in place, and the
+/// provenance of the pointers stored in the part that was copied moves to the
+/// new one. A null `old_ptr` is a plain allocation, like [`bsan_malloc`]. If
+/// `new_ptr` is null, the reallocation failed and nothing changes; when
+/// `realloc(ptr, 0)` frees `ptr` instead, the pass should call [`bsan_free`].
+#[no_mangle]
+unsafe extern "C" fn bsan_realloc(
+    old_ptr: *mut c_void,
+    old_prov: *const Provenance,
+    new_ptr: *mut c_void,
+    new_size: usize,
+    prov: *mut Provenance,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_realloc, null_out(prov));
+    reallocate("bsan_realloc", old_ptr, old_prov, new_ptr, new_size, 1, prov, loc);
+}
+
+#[inline(always)]
+#[allow(clippy::too_many_arguments)]
+unsafe fn reallocate(
+    hook: &str,
+    old_ptr: *mut c_void,
+    old_prov: *const Provenance,
+    new_ptr: *mut c_void,
+    new_size: usize,
+    align: usize,
+    prov: *mut Provenance,
+    loc: *const SourceInfo,
+) {
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, hook, AbiViolation::NullArgument("prov"));
+    }
+    *prov = Provenance::null();
+    if new_ptr.is_null() {
+        return;
+    }
+    if old_ptr.is_null() {
+        *prov = ctx.new_aligned_allocation(new_ptr.addr(), new_size, align).unwrap_or(*prov);
+        return;
+    }
+    if old_prov.is_null() {
+        return abi::violation(ctx, hook, AbiViolation::NullArgument("old_prov"));
+    }
+    if let Err(err) = abi::check_metadata(ctx, (*old_prov).lock_address) {
+        return abi::violation(ctx, hook, err);
+    }
+    let live = ctx.registry().find_base(old_ptr.addr());
+    if live.is_some_and(|meta| meta.as_ref().id != (*old_prov).alloc_id) {
+        report_error_at(
+            ErrorKind::InvalidRealloc,
+            loc,
+            format_args!(
+                "realloc of {} through a pointer to another allocation",
+                Addr(old_ptr.addr())
+            ),
+        );
+    }
+    // The new memory belongs to the program either way, so it is registered
+    // even if the old allocation is unknown.
+    let root = match ctx.reallocate(old_ptr.addr(), new_ptr.addr(), new_size, align) {
+        Some(root) => Some(root),
+        None => {
+            let args = format_args!("realloc of unknown allocation {}", Addr(old_ptr.addr()));
+            report_error_at(ErrorKind::InvalidRealloc, loc, args);
+            ctx.new_aligned_allocation(new_ptr.addr(), new_size, align)
+        }
+    };
+    *prov = root.unwrap_or(Provenance::null());
+}
+
+/// Retires the heap allocation starting at `ptr`.
+#[no_mangle]
+unsafe extern "C" fn bsan_free(ptr: *mut c_void, loc: *const SourceInfo) {
+    enter_hook!(bsan_free);
+    if !ptr.is_null() && !global_ctx().free_allocation(ptr.addr()) {
+        let args = format_args!("free of unknown allocation {}", Addr(ptr.addr()));
+        report_error_at(ErrorKind::InvalidFree, loc, args);
+    }
+}
+
+/// Registers a new heap allocation of `size` bytes at `ptr`, returned by
+/// `__rust_alloc(size, align)`, and writes the provenance of its root pointer
+/// to `prov`. The pass calls this and the other `bsan_rust_*` hooks after each
+/// call to the corresponding allocator shim, so that allocations made through
+/// Rust's `GlobalAlloc` are tracked with their layout even if the program's
+/// global allocator never calls `malloc`. If `ptr` is null, the allocation
+/// failed, and `prov` is set to [`Provenance::null`].
+#[no_mangle]
+unsafe extern "C" fn bsan_rust_alloc(
+    ptr: *mut c_void,
+    size: usize,
+    align: usize,
+    prov: *mut Provenance,
+) {
+    enter_hook!(bsan_rust_alloc, null_out(prov));
+    rust_alloc("bsan_rust_alloc", ptr, size, align, prov);
+}
+
+#[inline(always)]
+unsafe fn rust_alloc(
+    hook: &str,
+    ptr: *mut c_void,
+    size: usize,
+    align: usize,
+    prov: *mut Provenance,
+) {
+    if ptr.is_null() && !prov.is_null() {
+        *prov = Provenance::null();
+        return;
+    }
+    aligned_malloc(hook, ptr, align.is_power_of_two(), align, size, prov);
+}
+
+/// Like [`bsan_rust_alloc`], for `__rust_alloc_zeroed(size, align)`. As with
+/// [`bsan_calloc`], any provenance left in the shadow memory of the
+/// allocation is discarded.
+#[no_mangle]
+unsafe extern "C" fn bsan_rust_alloc_zeroed(
+    ptr: *mut c_void,
+    size: usize,
+    align: usize,
+    prov: *mut Provenance,
+) {
+    enter_hook!(bsan_rust_alloc_zeroed, null_out(prov));
+    if !ptr.is_null() {
+        global_ctx().shadow().clear_range(ptr.addr(), size);
+    }
+    rust_alloc("bsan_rust_alloc_zeroed", ptr, size, align, prov);
+}
+
+/// Retires the heap allocation at `ptr`, freed by `__rust_dealloc(ptr, size,
+/// align)`. `GlobalAlloc` requires the layout to be the one that the
+/// allocation was made with, so a different size or alignment is reported.
+#[no_mangle]
+unsafe extern "C" fn bsan_rust_dealloc(
+    ptr: *mut c_void,
+    size: usize,
+    align: usize,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_rust_dealloc);
+    check_layout("deallocation", ptr, size, align, loc);
+    if !global_ctx().free_allocation(ptr.addr()) {
+        let args = format_args!("deallocation of unknown allocation {}", Addr(ptr.addr()));
+        report_error_at(ErrorKind::InvalidFree, loc, args);
+    }
+}
+
+/// Like [`bsan_realloc`], for `__rust_realloc(old_ptr, old_size, align,
+/// new_size)`, which returned `new_ptr`. The layout of the old allocation is
+/// checked as by [`bsan_rust_dealloc`], and the new allocation keeps its
+/// alignment. If `new_ptr` is null, the reallocation failed and nothing
+/// changes.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn bsan_rust_realloc(
+    old_ptr: *mut c_void,
+    old_prov: *const Provenance,
+    old_size: usize,
+    align: usize,
+    new_ptr: *mut c_void,
+    new_size: usize,
+    prov: *mut Provenance,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_rust_realloc, null_out(prov));
+    if !new_ptr.is_null() {
+        check_layout("reallocation", old_ptr, old_size, align, loc);
+    }
+    reallocate("bsan_rust_realloc", old_ptr, old_prov, new_ptr, new_size, align, prov, loc);
+}
+
+/// Reports an `op` of the heap allocation at `ptr` with a layout other than
+/// the one it was allocated with. Unknown allocations are left to the caller.
+unsafe fn check_layout(
+    op: &str,
+    ptr: *mut c_void,
+    size: usize,
+    align: usize,
+    loc: *const SourceInfo,
+) {
+    let Some(meta) = global_ctx().registry().find_base(ptr.addr()) else { return };
+    let meta = meta.as_ref();
+    if meta.kind == AllocKind::Heap && (meta.size != size || meta.align != align) {
+        report::report(&Report {
+            alloc: Some(meta),
+            ..Report::new(
+                ErrorKind::LayoutMismatch,
+                loc.as_ref(),
+                format_args!(
+                    "{op} of {} with size {size} and alignment {align}, but it was \
+                     allocated with size {} and alignment {}",
+                    Addr(ptr.addr()),
+                    meta.size,
+                    meta.align
+                ),
+            )
+        });
+    }
+}
+
+/// Registers the global or static variable of `size` bytes at `ptr`. This is
+/// called for each global by a constructor that the pass adds to every
+/// instrumented module, which then records the provenance of any pointers in
+/// the global's initializer with [`bsan_store_prov`]. Pointers to a global are
+/// given provenance with [`bsan_global_prov`].
+#[no_mangle]
+unsafe extern "C" fn bsan_register_global(ptr: *mut c_void, size: usize) {
+    enter_hook!(bsan_register_global);
+    let ctx = global_ctx();
+    match ctx.register_global(ptr.addr(), size) {
+        // The registry holds the reference that keeps a global alive.
+        Some(root) => {
+            if let Some(meta) = NonNull::new(root.lock_address.cast()) {
+                ctx.release_metadata(meta);
+            }
+        }
+        None => {
+            let _ = writeln!(FdWriter::log(), "bsan: failed to register global {ptr:p}");
+        }
+    }
+}
+
+/// Writes the root provenance of the global variable that contains `ptr` to
+/// `prov`, for pointers produced by taking the address of a global. If `ptr`
+/// is not within a registered global, `prov` is set to [`Provenance::null`].
+#[no_mangle]
+unsafe extern "C" fn bsan_global_prov(ptr: *const c_void, prov: *mut Provenance) {
+    enter_hook!(bsan_global_prov, null_out(prov));
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, "bsan_global_prov", AbiViolation::NullArgument("prov"));
+    }
+    *prov = match ctx.registry().find(ptr.addr()) {
+        Some(meta) if meta.as_ref().kind == AllocKind::Global => {
+            ctx.retain_metadata(meta);
+            meta.as_ref().root_provenance()
+        }
+        _ => Provenance::null(),
+    };
+}
+
+/// Records that `dlopen` returned `handle`, and whether the library that it
+/// loaded was instrumented. Accesses to memory in the image of a library that
+/// wasn't are not checked, since it doesn't register its globals.
+#[no_mangle]
+unsafe extern "C" fn bsan_dlopen(handle: *mut c_void, instrumented: bool) {
+    enter_hook!(bsan_dlopen);
+    let ctx = global_ctx();
+    if handle.is_null() {
+        return abi::violation(ctx, "bsan_dlopen", AbiViolation::NullArgument("handle"));
+    }
+    let Some((start, end)) = module::image_range(handle) else { return };
+    if !ctx.modules().open(handle.addr(), start, end, instrumented) {
+        let _ = writeln!(FdWriter::log(), "bsan: failed to allocate memory to track {handle:p}");
+    }
+    ctx.ignored().find_images();
+}
+
+/// Records that `dlclose` was called on `handle`, after it returned. If this
+/// unloaded the library, the globals that it registered are retired, and the
+/// provenance of the pointers stored in its image is cleared.
+#[no_mangle]
+unsafe extern "C" fn bsan_dlclose(handle: *mut c_void) {
+    enter_hook!(bsan_dlclose);
+    let ctx = global_ctx();
+    if let Some(module) = ctx.modules().close(handle.addr()) {
+        ctx.unload_range(module.start, module.end);
+    }
+    ctx.ignored().find_images();
+}
+
+/// Registers the `len` bytes that `mmap` mapped at `ptr` as an allocation of
+/// their own, and writes the provenance of its root pointer to `prov`. The
+/// allocation extends to the end of the last page, which is accessible even
+/// past `len`. A `ptr` of `MAP_FAILED` is ignored, and `prov` is set to
+/// [`Provenance::null`].
+#[no_mangle]
+unsafe extern "C" fn bsan_mmap(ptr: *mut c_void, len: usize, prov: *mut Provenance) {
+    enter_hook!(bsan_mmap, null_out(prov));
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, "bsan_mmap", AbiViolation::NullArgument("prov"));
+    }
+    *prov = Provenance::null();
+    if ptr == libc::MAP_FAILED || ptr.is_null() {
+        return;
+    }
+    let len = len.next_multiple_of(host::page_size());
+    *prov = ctx.new_mapping(ptr.addr(), len).unwrap_or(Provenance::null());
+}
+
+/// Records that the `len` bytes at `ptr` were unmapped by `munmap`. This
+/// retires the mappings that were unmapped entirely, and clears the provenance
+/// of the pointers stored in the range.
+#[no_mangle]
+unsafe extern "C" fn bsan_munmap(ptr: *mut c_void, len: usize) {
+    enter_hook!(bsan_munmap);
+    let Some(end) = ptr.addr().checked_add(len.next_multiple_of(host::page_size())) else { return };
+    global_ctx().unmap_range(ptr.addr(), end);
+}
+
+/// Records that `mremap` remapped the mapping at `old_ptr` to the `new_len`
+/// bytes at `new_ptr`, and writes the provenance of its root pointer to `prov`.
+/// The mapping keeps its identity, so provenance that refers to it stays valid
+/// for the new range, and pointers stored in it move along. If `old_ptr` isn't
+/// a mapping registered with [`bsan_mmap`], the new range is registered as one.
+/// A `new_ptr` of `MAP_FAILED` means that the remapping failed, so nothing
+/// changes and `prov` is set to [`Provenance::null`].
+#[no_mangle]
+unsafe extern "C" fn bsan_mremap(
+    old_ptr: *mut c_void,
+    new_ptr: *mut c_void,
+    new_len: usize,
+    prov: *mut Provenance,
+) {
+    enter_hook!(bsan_mremap, null_out(prov));
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, "bsan_mremap", AbiViolation::NullArgument("prov"));
+    }
+    *prov = Provenance::null();
+    if new_ptr == libc::MAP_FAILED || new_ptr.is_null() {
+        return;
+    }
+    let new_len = new_len.next_multiple_of(host::page_size());
+    let root = match ctx.remap(old_ptr.addr(), new_ptr.addr(), new_len) {
+        Some(root) => Some(root),
+        None => ctx.new_mapping(new_ptr.addr(), new_len),
+    };
+    *prov = root.unwrap_or(Provenance::null());
+}
 
+/// Clears the provenance of every pointer stored in the `len` bytes at `ptr`.
+/// This must be called when memory is released without going through
+/// `bsan_free` or `bsan_munmap`, so that the shadow state of its contents
+/// can't be attributed to a later allocation at the same address.
 #[no_mangle]
-unsafe extern "C" fn bsan_init(alloc: BsanAllocator) {
-    init_global_ctx(alloc);
+unsafe extern "C" fn bsan_clear_shadow(ptr: *mut c_void, len: usize) {
+    enter_hook!(bsan_clear_shadow);
+    global_ctx().shadow().clear_range(ptr.addr(), len);
 }
 
+/// Copies the provenance at `src` to `dst`, taking a new reference to the
+/// metadata of the allocation that it refers to.
 #[no_mangle]
-extern "C" fn bsan_expose_tag(ptr: *mut c_void) {}
+unsafe extern "C" fn bsan_clone_provenance(src: *const Provenance, dst: *mut Provenance) {
+    enter_hook!(bsan_clone_provenance, null_out(dst));
+    let ctx = global_ctx();
+    if dst.is_null() {
+        return abi::violation(ctx, "bsan_clone_provenance", AbiViolation::NullArgument("dst"));
+    }
+    if src.is_null() {
+        abi::violation(ctx, "bsan_clone_provenance", AbiViolation::NullArgument("src"));
+        *dst = Provenance::null();
+        return;
+    }
+    let prov = *src;
+    if let Err(err) = abi::check_metadata(ctx, prov.lock_address) {
+        return abi::violation(ctx, "bsan_clone_provenance", err);
+    }
+    if let Some(meta) = NonNull::new(prov.lock_address.cast()) {
+        ctx.retain_metadata(meta);
+    }
+    *dst = prov;
+}
+
+/// Takes a new reference to the allocation metadata at `lock_address`.
+/// Null addresses, such as those of [`Provenance::null`], are ignored.
+#[no_mangle]
+unsafe extern "C" fn bsan_retain_alloc_metadata(lock_address: *mut c_void) {
+    enter_hook!(bsan_retain_alloc_metadata);
+    let ctx = global_ctx();
+    if let Err(err) = abi::check_metadata(ctx, lock_address) {
+        return abi::violation(ctx, "bsan_retain_alloc_metadata", err);
+    }
+    if let Some(meta) = NonNull::new(lock_address.cast()) {
+        ctx.retain_metadata(meta);
+    }
+}
+
+/// Gives up a reference to the allocation metadata at `lock_address`. This must
+/// be called once for each `Provenance` produced by the runtime, when the
+/// instrumented program discards it.
+#[no_mangle]
+unsafe extern "C" fn bsan_release_alloc_metadata(lock_address: *mut c_void) {
+    enter_hook!(bsan_release_alloc_metadata);
+    let ctx = global_ctx();
+    if let Err(err) = abi::check_metadata(ctx, lock_address) {
+        return abi::violation(ctx, "bsan_release_alloc_metadata", err);
+    }
+    if let Some(meta) = NonNull::new(lock_address.cast()) {
+        ctx.release_metadata(meta);
+    }
+}
+
+/// Writes the live allocations to the file at `path`, in the format described
+/// in `dump.rs`. Returns `false` if the file could not be written.
+#[no_mangle]
+unsafe extern "C" fn bsan_dump_registry(path: *const c_char) -> bool {
+    match io::CPathBuf::from_ptr(path) {
+        Some(path) => dump::dump_registry(global_ctx(), path),
+        None => false,
+    }
+}
+
+/// Called by [`bsan_allocs_in_range`] with the ID, base address and size of an
+/// allocation. Returning `false` stops the enumeration.
+pub type AllocCallback = unsafe extern "C" fn(
+    data: *mut c_void,
+    alloc_id: usize,
+    base: *mut c_void,
+    size: usize,
+) -> bool;
+
+/// Calls `callback` with `data` for every live allocation that overlaps
+/// `[start, end)`, in order of their base addresses. The registry is locked for
+/// the duration, so `callback` must not allocate or free instrumented memory.
+#[no_mangle]
+unsafe extern "C" fn bsan_allocs_in_range(
+    start: *mut c_void,
+    end: *mut c_void,
+    callback: AllocCallback,
+    data: *mut c_void,
+) {
+    global_ctx().registry().for_each_in_range(start.addr(), end.addr(), |meta| {
+        let base = core::ptr::without_provenance_mut(meta.base_addr);
+        match callback(data, meta.id.get(), base, meta.size) {
+            true => ControlFlow::Continue(()),
+            false => ControlFlow::Break(()),
+        }
+    });
+}
+
+/// Records that the pointer `ptr` was exposed, such as by casting it to an
+/// integer or passing it to code that isn't instrumented.
+#[no_mangle]
+unsafe extern "C" fn bsan_expose_tag(ptr: *mut c_void) {
+    enter_hook!(bsan_expose_tag);
+    let ctx = global_ctx();
+    if ctx.flags().lenient_foreign {
+        if let Some(meta) = ctx.registry().find(ptr.addr()) {
+            meta.as_ref().expose();
+        }
+    }
+    ctx.record_event_at(EventKind::Expose, ptr.addr(), BorTag::INVALID);
+}
 
 #[no_mangle]
-extern "C" fn bsan_retag(ptr: *mut c_void, retag_kind: u8, place_kind: u8) -> u64 {
-    0
+unsafe extern "C" fn bsan_retag(ptr: *mut c_void, retag_kind: u8, place_kind: u8) -> u64 {
+    enter_hook!(bsan_retag, BorTag::INVALID.get());
+    let ctx = global_ctx();
+    let retag_kind = RetagKind::from_raw(retag_kind).unwrap_or_else(|| {
+        abi::violation(ctx, "bsan_retag", AbiViolation::InvalidRetagKind(retag_kind));
+        RetagKind::Default
+    });
+    let place_kind = PlaceKind::from_raw(place_kind).unwrap_or_else(|| {
+        abi::violation(ctx, "bsan_retag", AbiViolation::InvalidPlaceKind(place_kind));
+        PlaceKind::Default
+    });
+    // If the tag space is exhausted, the pointer is left untagged rather than
+    // being given a tag that may alias an existing one. A recycled tag may
+    // still carry the hint of the root tag that it was.
+    let tag = ctx.tags().fresh().map_or(BorTag::INVALID, |tag| tag.with_hint(TagHint::NONE));
+    if ctx.tracked().tags.contains(tag.get()) {
+        miri::log_tracked(ctx, format_args!("created tag {} at {}", tag.get(), Addr(ptr.addr())));
+    }
+    let limit = ctx.flags().max_derived_tags;
+    if limit != 0 {
+        if let Some(meta) = ctx.registry().find(ptr.addr()) {
+            let meta = meta.as_ref();
+            if meta.derive_tag(limit) {
+                let _ = writeln!(
+                    FdWriter::log(),
+                    "bsan: allocation {} has more than {limit} tags, so its pointers are no \
+                     longer told apart",
+                    meta.id.get()
+                );
+            }
+        }
+    }
+    // Arguments retagged on entry stay protected until the function returns,
+    // unless it is ignored.
+    if retag_kind == RetagKind::FnEntry && tag.is_valid() && !ctx.ignored().ignores_caller() {
+        frame::protect(tag);
+    }
+    ctx.record_event_at(EventKind::Retag(retag_kind), ptr.addr(), tag);
+    tag.get()
+}
+
+/// Checks a read of `access_size` bytes at `ptr`, through a pointer with the
+/// provenance at `prov`. The access must stay within the allocation that the
+/// pointer was derived from, which must not have been freed. A null `prov`
+/// means that the provenance isn't known, and the allocation is found from the
+/// address instead. If the access is invalid, the error is reported at `loc`,
+/// which may be null.
+///
+/// # Safety
+/// `prov` must be null or point to provenance returned by the runtime, and
+/// `loc` must be null or point to a valid [`SourceInfo`].
+pub unsafe extern "C" fn bsan_read(
+    ptr: *mut c_void,
+    access_size: u64,
+    prov: *const Provenance,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_read);
+    let ctx = global_ctx();
+    if access_size > isize::MAX as u64 {
+        return abi::violation(ctx, "bsan_read", AbiViolation::InvalidSize(access_size));
+    }
+    if let Some(prov) = prov.as_ref() {
+        if let Err(violation) = abi::check_metadata(ctx, prov.lock_address) {
+            return abi::violation(ctx, "bsan_read", violation);
+        }
+    }
+    check_access(ptr, access_size, prov, loc, AccessKind::Read);
 }
 
+/// Like [`bsan_read`], for writes.
+///
+/// # Safety
+/// `prov` must be null or point to provenance returned by the runtime, and
+/// `loc` must be null or point to a valid [`SourceInfo`].
+pub unsafe extern "C" fn bsan_write(
+    ptr: *mut c_void,
+    access_size: u64,
+    prov: *const Provenance,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_write);
+    let ctx = global_ctx();
+    if access_size > isize::MAX as u64 {
+        return abi::violation(ctx, "bsan_write", AbiViolation::InvalidSize(access_size));
+    }
+    if let Some(prov) = prov.as_ref() {
+        if let Err(violation) = abi::check_metadata(ctx, prov.lock_address) {
+            return abi::violation(ctx, "bsan_write", violation);
+        }
+    }
+    check_access(ptr, access_size, prov, loc, AccessKind::Write);
+}
+
+/// Checks the `n` accesses at `entries` as if each were passed to [`bsan_read`]
+/// or [`bsan_write`], in order, so that the pass can check all of the accesses
+/// of a basic block with one call. Runs of accesses of the same kind through
+/// the same provenance whose ranges overlap or touch, like those to the fields
+/// of a struct, are checked at once as a single range. If the range doesn't
+/// pass, its accesses are checked one by one, so errors are reported as they
+/// would be otherwise.
+///
+/// # Safety
+/// `entries` must be valid for reads of `n` [`AccessDesc`]s, whose fields
+/// satisfy the requirements of `bsan_read`.
 #[no_mangle]
-extern "C" fn bsan_read(ptr: *mut c_void, access_size: u64) {}
+unsafe extern "C" fn bsan_check_accesses(entries: *const AccessDesc, n: usize) {
+    enter_hook!(bsan_check_accesses);
+    let ctx = global_ctx();
+    if n == 0 || access::checks_disabled() {
+        return;
+    }
+    if entries.is_null() {
+        return abi::violation(ctx, "bsan_check_accesses", AbiViolation::NullArgument("entries"));
+    }
+    let entries = slice::from_raw_parts(entries, n);
+    let mut start = 0;
+    while start < entries.len() {
+        let first = &entries[start];
+        let Some(kind) = validate_access(ctx, first) else {
+            start += 1;
+            continue;
+        };
+        let prov = first.prov.as_ref().copied().unwrap_or(Provenance::null());
+        let base = first.ptr.addr();
+        let mut end = base.saturating_add(first.size as usize);
+        let mut len = 1;
+        // Sampling counts accesses one by one, so they aren't merged.
+        while ctx.flags().sample_rate <= 1 && start + len < entries.len() {
+            let next = &entries[start + len];
+            let mergeable = next.kind == first.kind
+                && next.size <= isize::MAX as u64
+                && next.prov.as_ref().copied().unwrap_or(Provenance::null()) == prov
+                && (base..=end).contains(&next.ptr.addr());
+            if !mergeable {
+                break;
+            }
+            end = end.max(next.ptr.addr().saturating_add(next.size as usize));
+            len += 1;
+        }
+        let run = &entries[start..start + len];
+        if len == 1 || !check_range(ctx, prov, base, end - base, kind, run.len()) {
+            for entry in run {
+                if let Some(kind) = validate_access(ctx, entry) {
+                    check_access(entry.ptr, entry.size, entry.prov, entry.loc, kind);
+                }
+            }
+        }
+        start += len;
+    }
+}
 
+// Validates an entry of a batch as `bsan_read` validates its arguments, and
+// returns its kind if it can be checked.
+unsafe fn validate_access(ctx: &GlobalContext, entry: &AccessDesc) -> Option<AccessKind> {
+    const HOOK: &str = "bsan_check_accesses";
+    if entry.size > isize::MAX as u64 {
+        abi::violation(ctx, HOOK, AbiViolation::InvalidSize(entry.size));
+        return None;
+    }
+    if let Some(prov) = entry.prov.as_ref() {
+        if let Err(violation) = abi::check_metadata(ctx, prov.lock_address) {
+            abi::violation(ctx, HOOK, violation);
+            return None;
+        }
+    }
+    let kind = AccessKind::from_raw(entry.kind);
+    if kind.is_none() {
+        abi::violation(ctx, HOOK, AbiViolation::InvalidAccessKind(entry.kind));
+    }
+    kind
+}
+
+/// Checks a vector read of `lanes` elements of `elem_size` bytes at `ptr`, such
+/// as an `llvm.masked.load`. Only the lanes enabled by `mask` are checked: lane
+/// `i` is enabled if bit `i % 64` of `mask[i / 64]` is set. A null `mask`
+/// enables every lane, for plain vector loads.
 #[no_mangle]
-extern "C" fn bsan_write(ptr: *mut c_void, access_size: u64) {}
+unsafe extern "C" fn bsan_read_vector(
+    ptr: *mut c_void,
+    elem_size: u64,
+    lanes: u64,
+    mask: *const u64,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_read_vector);
+    if elem_size.checked_mul(lanes).is_none_or(|size| size > isize::MAX as u64) {
+        let size = elem_size.saturating_mul(lanes);
+        return abi::violation(global_ctx(), "bsan_read_vector", AbiViolation::InvalidSize(size));
+    }
+    check_vector_access(ptr, elem_size, lanes, mask, loc, AccessKind::Read);
+}
 
+/// Like [`bsan_read_vector`], for vector writes such as `llvm.masked.store`.
 #[no_mangle]
-extern "C" fn bsan_func_entry() {}
+unsafe extern "C" fn bsan_write_vector(
+    ptr: *mut c_void,
+    elem_size: u64,
+    lanes: u64,
+    mask: *const u64,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_write_vector);
+    if elem_size.checked_mul(lanes).is_none_or(|size| size > isize::MAX as u64) {
+        let size = elem_size.saturating_mul(lanes);
+        return abi::violation(global_ctx(), "bsan_write_vector", AbiViolation::InvalidSize(size));
+    }
+    check_vector_access(ptr, elem_size, lanes, mask, loc, AccessKind::Write);
+}
 
+#[inline(always)]
+unsafe fn check_vector_access(
+    ptr: *mut c_void,
+    elem_size: u64,
+    lanes: u64,
+    mask: *const u64,
+    loc: *const SourceInfo,
+    kind: AccessKind,
+) {
+    let lanes = lanes as usize;
+    let mask = (!mask.is_null()).then(|| slice::from_raw_parts(mask, lanes.div_ceil(64)));
+    for (first, count) in access::LaneRuns::new(lanes, mask) {
+        let offset = first as u64 * elem_size;
+        let ptr = ptr.wrapping_byte_add(offset as usize);
+        check_access(ptr, count as u64 * elem_size, ptr::null(), loc, kind);
+    }
+}
+
+/// Records that a pointer with the provenance at `prov` was stored at `ptr`.
+///
+/// Provenance is passed to and returned from these hooks through pointers,
+/// rather than by value. At three words, a `Provenance` would be passed in
+/// memory by most C ABIs anyway, and the pass keeps the provenance of each
+/// pointer-typed local in a stack slot of its own that it can point to. The
+/// shadow entry takes its own reference to the allocation's metadata, so the
+/// caller keeps the reference that it holds for `*prov`. Storing
+/// [`Provenance::null`] clears the provenance of the pointer at `ptr`, which the
+/// pass does when an integer or other non-pointer value overwrites it.
 #[no_mangle]
-extern "C" fn bsan_func_exit() {}
+unsafe extern "C" fn bsan_store_prov(ptr: *mut c_void, prov: *const Provenance) {
+    enter_hook!(bsan_store_prov);
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, "bsan_store_prov", AbiViolation::NullArgument("prov"));
+    }
+    if let Err(err) = abi::check_metadata(ctx, (*prov).lock_address) {
+        return abi::violation(ctx, "bsan_store_prov", err);
+    }
+    // A pointer stored in the image of a library that isn't instrumented is
+    // handed to code that the runtime can't see.
+    if global_ctx().flags().lenient_foreign && global_ctx().modules().is_uninstrumented(ptr.addr())
+    {
+        if let Some(meta) = ((*prov).lock_address as *const AllocMetadata).as_ref() {
+            meta.expose();
+        }
+    }
+    store_prov("bsan_store_prov", ptr, *prov);
+}
 
-#[cfg(not(test))]
+/// Writes the provenance of the pointer stored at `ptr` to `prov`, or
+/// [`Provenance::null`] if no pointer is stored there. Like
+/// [`bsan_clone_provenance`], this takes a new reference to its metadata,
+/// which the caller must give up with [`bsan_release_alloc_metadata`].
+#[no_mangle]
+unsafe extern "C" fn bsan_load_prov(ptr: *const c_void, prov: *mut Provenance) {
+    enter_hook!(bsan_load_prov, null_out(prov));
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, "bsan_load_prov", AbiViolation::NullArgument("prov"));
+    }
+    *prov = load_prov("bsan_load_prov", ptr);
+}
+
+/// Like [`bsan_store_prov`], for pointers spilled to stack slots. The shadow
+/// heap is keyed by address, so it covers the stack as well; these are only
+/// kept apart so that spills can be counted. Slots must be cleared with
+/// [`bsan_clear_shadow`] when their frame is popped.
+#[no_mangle]
+unsafe extern "C" fn bsan_store_stack_prov(ptr: *mut c_void, prov: *const Provenance) {
+    enter_hook!(bsan_store_stack_prov);
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, "bsan_store_stack_prov", AbiViolation::NullArgument("prov"));
+    }
+    if let Err(err) = abi::check_metadata(ctx, (*prov).lock_address) {
+        return abi::violation(ctx, "bsan_store_stack_prov", err);
+    }
+    ctx.stats().stack_spill();
+    store_prov("bsan_store_stack_prov", ptr, *prov);
+}
+
+/// Like [`bsan_load_prov`], for pointers reloaded from stack slots.
+#[no_mangle]
+unsafe extern "C" fn bsan_load_stack_prov(ptr: *const c_void, prov: *mut Provenance) {
+    enter_hook!(bsan_load_stack_prov, null_out(prov));
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, "bsan_load_stack_prov", AbiViolation::NullArgument("prov"));
+    }
+    *prov = load_prov("bsan_load_stack_prov", ptr);
+}
+
+/// Reports a pointer passed to `hook` whose `size` bytes extend past the user
+/// address space, such as a non-canonical or kernel-space address. The shadow
+/// heap can't track pointers stored there, so the hook is skipped.
+#[cold]
+fn invalid_address(hook: &str, ptr: *const c_void, size: usize) {
+    report_error(
+        ErrorKind::Access(AccessError::InvalidAddress),
+        format_args!(
+            "{hook}: invalid address {} ({size} bytes), outside of the user address space",
+            Addr(ptr.addr())
+        ),
+    );
+}
+
+#[inline(always)]
+unsafe fn store_prov(hook: &str, ptr: *mut c_void, prov: Provenance) {
+    if !global_ctx().shadow().covers(ptr.addr(), mem::size_of::<usize>()) {
+        return invalid_address(hook, ptr, mem::size_of::<usize>());
+    }
+    if !global_ctx().shadow().store(ptr.addr(), prov) {
+        let _ = writeln!(FdWriter::log(), "bsan: failed to allocate shadow memory for {ptr:p}");
+    }
+}
+
+#[inline(always)]
+unsafe fn load_prov(hook: &str, ptr: *const c_void) -> Provenance {
+    if !global_ctx().shadow().covers(ptr.addr(), mem::size_of::<usize>()) {
+        invalid_address(hook, ptr, mem::size_of::<usize>());
+        return Provenance::null();
+    }
+    let prov = global_ctx().shadow().load(ptr.addr());
+    if let Some(meta) = NonNull::new(prov.lock_address.cast()) {
+        global_ctx().retain_metadata(meta);
+    }
+    prov
+}
+
+/// Checks a `memcpy` of `len` bytes from `src` to `dst`, and copies the
+/// provenance of the pointers stored in the source range.
+#[no_mangle]
+unsafe extern "C" fn bsan_memcpy(
+    dst: *mut c_void,
+    src: *const c_void,
+    len: usize,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_memcpy);
+    check_access(src.cast_mut(), len as u64, ptr::null(), loc, AccessKind::Read);
+    check_access(dst, len as u64, ptr::null(), loc, AccessKind::Write);
+    global_ctx().shadow().copy_range(dst.addr(), src.addr(), len);
+}
+
+/// Like [`bsan_memcpy`], but the ranges may overlap.
+#[no_mangle]
+unsafe extern "C" fn bsan_memmove(
+    dst: *mut c_void,
+    src: *const c_void,
+    len: usize,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_memmove);
+    check_access(src.cast_mut(), len as u64, ptr::null(), loc, AccessKind::Read);
+    check_access(dst, len as u64, ptr::null(), loc, AccessKind::Write);
+    global_ctx().shadow().copy_range(dst.addr(), src.addr(), len);
+}
+
+/// Checks a `memset` of `len` bytes at `ptr`, and clears the provenance of every
+/// pointer that it overwrites, even in part, since the bytes it writes can't
+/// form a pointer that carries any.
+#[no_mangle]
+unsafe extern "C" fn bsan_memset(
+    ptr: *mut c_void,
+    value: c_int,
+    len: usize,
+    loc: *const SourceInfo,
+) {
+    enter_hook!(bsan_memset);
+    check_access(ptr, len as u64, ptr::null(), loc, AccessKind::Write);
+    global_ctx().shadow().clear_overlapping(ptr.addr(), len);
+}
+
+/// Records that every word of the `len` bytes at `ptr` holds a pointer with the
+/// provenance at `prov`, as when a buffer is filled with copies of one pointer.
+/// Large fills are recorded compactly, so this should be preferred over
+/// calling [`bsan_store_prov`] for each word. As with a store, the caller keeps
+/// its reference for `*prov`.
+#[no_mangle]
+unsafe extern "C" fn bsan_fill_prov(ptr: *mut c_void, len: usize, prov: *const Provenance) {
+    enter_hook!(bsan_fill_prov);
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, "bsan_fill_prov", AbiViolation::NullArgument("prov"));
+    }
+    if let Err(err) = abi::check_metadata(ctx, (*prov).lock_address) {
+        return abi::violation(ctx, "bsan_fill_prov", err);
+    }
+    if !ctx.shadow().covers(ptr.addr(), len) {
+        return invalid_address("bsan_fill_prov", ptr, len);
+    }
+    if !ctx.shadow().fill_range(ptr.addr(), len, *prov) {
+        let _ = writeln!(FdWriter::log(), "bsan: failed to allocate shadow memory for {ptr:p}");
+    }
+}
+
+/// Registers the stack slot of `size` bytes at `ptr`, whose address is taken,
+/// and writes the provenance of pointers to it to `prov`. The slot belongs to
+/// the frame of the latest [`bsan_func_entry`] on this thread, and is retired
+/// when that frame returns through [`bsan_func_exit`]. Like a freed heap
+/// allocation, it can no longer be accessed then, and the provenance of the
+/// pointers stored in it is cleared.
+#[no_mangle]
+unsafe extern "C" fn bsan_alloc_stack(ptr: *mut c_void, size: usize, prov: *mut Provenance) {
+    enter_hook!(bsan_alloc_stack, null_out(prov));
+    let ctx = global_ctx();
+    if prov.is_null() {
+        return abi::violation(ctx, "bsan_alloc_stack", AbiViolation::NullArgument("prov"));
+    }
+    *prov = ctx.new_stack_allocation(ptr.addr(), size).unwrap_or(Provenance::null());
+}
+
+/// Registers a stack allocation that the pass has proven is local-only: its
+/// address is never stored, passed to a call, or otherwise allowed to escape.
+/// These are only counted, and accesses to them are reported with
+/// [`bsan_read_local`] and [`bsan_write_local`]. Allocations whose address
+/// might escape must be registered with [`bsan_alloc_stack`].
+#[no_mangle]
+unsafe extern "C" fn bsan_alloca_local(ptr: *mut c_void, size: usize) {
+    enter_hook!(bsan_alloca_local);
+    global_ctx().stats().local_alloca();
+}
+
+/// Records a read through a pointer derived directly from a local-only stack
+/// allocation. Since no other pointer can reach it, the access is not checked.
+#[no_mangle]
+unsafe extern "C" fn bsan_read_local(ptr: *mut c_void, access_size: u64) {
+    enter_hook!(bsan_read_local);
+    global_ctx().stats().elided_access();
+}
+
+/// Like [`bsan_read_local`], but for writes.
+#[no_mangle]
+unsafe extern "C" fn bsan_write_local(ptr: *mut c_void, access_size: u64) {
+    enter_hook!(bsan_write_local);
+    global_ctx().stats().elided_access();
+}
+
+/// Stops checking the accesses of the current thread until [`bsan_enable`] is
+/// called as many times as this, so that regions known to break the aliasing
+/// model, such as callbacks into unsound libraries, can be left out without
+/// recompiling them. Allocations, frees and retags are still tracked, so that
+/// accesses are checked correctly once checks are enabled again.
+#[no_mangle]
+extern "C" fn bsan_disable() {
+    access::disable_checks();
+}
+
+/// Undoes a call to [`bsan_disable`] on the current thread.
+#[no_mangle]
+extern "C" fn bsan_enable() {
+    if !access::enable_checks() {
+        abi::violation(unsafe { global_ctx() }, "bsan_enable", AbiViolation::UnbalancedEnable);
+    }
+}
+
+/// Writes a snapshot of the runtime's counters to `stats`. The calls of hooks
+/// and the lookups in the registry are only counted while `BSAN_STATS` is set,
+/// which also prints the counters at exit.
+#[no_mangle]
+unsafe extern "C" fn bsan_get_stats(stats: *mut Stats) {
+    let ctx = global_ctx();
+    if stats.is_null() {
+        return abi::violation(ctx, "bsan_get_stats", AbiViolation::NullArgument("stats"));
+    }
+    *stats = ctx.stats().snapshot();
+}
+
+/// Writes the number of calls of each hook that was called while `BSAN_STATS`
+/// is set to `stats`, which has room for `len` of them, in no particular
+/// order. Returns the number of such hooks, which may be more than `len`. The
+/// names are static strings.
+#[no_mangle]
+unsafe extern "C" fn bsan_get_hook_stats(stats: *mut HookStats, len: usize) -> usize {
+    if stats.is_null() && len != 0 {
+        let ctx = global_ctx();
+        abi::violation(ctx, "bsan_get_hook_stats", AbiViolation::NullArgument("stats"));
+        return 0;
+    }
+    let mut count = 0;
+    stats::for_each_hook(|name, calls| {
+        if count < len {
+            *stats.add(count) = HookStats { name: name.as_ptr(), calls };
+        }
+        count += 1;
+    });
+    count
+}
+
+/// Prints the number of errors reported so far, the live allocations and the
+/// memory used by shadow memory to the runtime's log. This is meant to be
+/// called from a debugger, or by the program at points of its choosing, such as
+/// periodically in a long-running service. Like [`bsan_shadow_stats`], it is
+/// slow.
+#[no_mangle]
+extern "C" fn bsan_print_report() {
+    if global::has_exited() {
+        return;
+    }
+    report_status(unsafe { global_ctx() }, &mut FdWriter::log());
+}
+
+/// Returns the number of errors reported so far, or zero once the runtime has
+/// been shut down.
+#[no_mangle]
+extern "C" fn bsan_error_count() -> u64 {
+    if global::has_exited() {
+        return 0;
+    }
+    unsafe { global_ctx() }.stats().snapshot().errors
+}
+
+/// Writes the memory used by shadow memory to `stats`. Since this asks the
+/// kernel which pages are resident, it is much slower than [`bsan_get_stats`].
+/// Setting `BSAN_SHADOW_STATS=1` prints the same numbers at exit.
+#[no_mangle]
+unsafe extern "C" fn bsan_shadow_stats(stats: *mut ShadowStats) {
+    let ctx = global_ctx();
+    if stats.is_null() {
+        return abi::violation(ctx, "bsan_shadow_stats", AbiViolation::NullArgument("stats"));
+    }
+    *stats = ctx.shadow_stats();
+}
+
+// Checks the `size` bytes at `addr`, which `accesses` accesses of `kind`
+// through `prov` cover, at once. Returns `false` if they need to be checked one
+// by one: if the range didn't pass, if any of them would be skipped or
+// reported even so, or if they have no provenance, in which case each one is
+// checked against the allocation at its own address.
+unsafe fn check_range(
+    ctx: &GlobalContext,
+    prov: Provenance,
+    addr: usize,
+    size: usize,
+    kind: AccessKind,
+    accesses: usize,
+) -> bool {
+    if prov.lock_address.is_null()
+        || size == 0
+        || ctx.ignored().ignores_caller()
+        || ctx.tags().is_disabled(prov.bor_tag)
+    {
+        return false;
+    }
+    let cache = &thread::current().access_cache;
+    let cached =
+        access::check_root_access(ctx, prov, addr, size).or_else(|| cache.lookup(prov, addr, size));
+    let meta = match cached {
+        Some(meta) => meta,
+        None => match access::check_access_with(ctx, prov, addr, size) {
+            Ok(resolved) if resolved.lock_address == prov.lock_address => {
+                let Some(meta) = (resolved.lock_address as *const AllocMetadata).as_ref() else {
+                    return false;
+                };
+                cache.insert(prov, meta);
+                meta
+            }
+            _ => return false,
+        },
+    };
+    ctx.stats().checked_accesses(accesses as u64);
+    ctx.record_event(meta, EventKind::Access(kind), addr, size, prov.bor_tag);
+    true
+}
+
+#[inline(always)]
+unsafe fn check_access(
+    ptr: *mut c_void,
+    access_size: u64,
+    prov: *const Provenance,
+    loc: *const SourceInfo,
+    kind: AccessKind,
+) {
+    if access::checks_disabled() {
+        return;
+    }
+    let ctx = global_ctx();
+    if !access::sampled(ctx.flags().sample_rate) || ctx.ignored().ignores_caller() {
+        return;
+    }
+    ctx.stats().checked_access();
+    let prov = prov.as_ref().copied().unwrap_or(Provenance::null());
+    if ctx.tags().is_disabled(prov.bor_tag) {
+        return;
+    }
+    let size = access_size as usize;
+    if let Some(meta) = access::check_root_access(ctx, prov, ptr.addr(), size) {
+        ctx.record_event(meta, EventKind::Access(kind), ptr.addr(), size, prov.bor_tag);
+        return;
+    }
+    let cache = &thread::current().access_cache;
+    if let Some(meta) = cache.lookup(prov, ptr.addr(), size) {
+        let tag = if prov.lock_address.is_null() { meta.root_tag } else { prov.bor_tag };
+        ctx.record_event(meta, EventKind::Access(kind), ptr.addr(), size, tag);
+        return;
+    }
+    let checked = access::check_access_with(ctx, prov, ptr.addr(), size);
+    let (checked, wildcard) = match checked {
+        Err(err) if ctx.flags().lenient_foreign => {
+            match access::resolve_wildcard(ctx, err, ptr.addr(), size) {
+                Some(resolved) => (Ok(resolved), true),
+                None => (Err(err), false),
+            }
+        }
+        checked => (checked, prov.lock_address.is_null()),
+    };
+    let err = match checked {
+        Ok(resolved) => {
+            let Some(meta) = (resolved.lock_address as *const AllocMetadata).as_ref() else {
+                return;
+            };
+            ctx.record_event(meta, EventKind::Access(kind), ptr.addr(), size, resolved.bor_tag);
+            let reported = ctx.severities().of(ErrorKind::WildcardAccess) != Severity::Ignore;
+            // Accesses that were resolved through another allocation than the
+            // one in their provenance aren't cached, since the next one
+            // through the same pointer may resolve differently.
+            if !wildcard || prov.lock_address.is_null() && !reported {
+                cache.insert(prov, meta);
+            }
+            if wildcard && reported {
+                let pointer = if prov.lock_address.is_null() {
+                    "without provenance"
+                } else {
+                    "with the provenance of another allocation"
+                };
+                let forbidden = if ctx.flags().strict_provenance {
+                    ", which strict provenance forbids"
+                } else {
+                    ""
+                };
+                report::report(&Report {
+                    access: Some((kind, ptr.addr(), size)),
+                    alloc: Some(meta),
+                    ..Report::new(
+                        ErrorKind::WildcardAccess,
+                        loc.as_ref(),
+                        format_args!(
+                            "{kind} of {access_size} bytes at {} through a pointer \
+                             {pointer}{forbidden}",
+                            Addr(ptr.addr())
+                        ),
+                    )
+                });
+            }
+            return;
+        }
+        Err(err) => err,
+    };
+    if err == AccessError::UnknownMemory && ctx.modules().is_uninstrumented(ptr.addr()) {
+        return;
+    }
+    // The metadata in the provenance may have been reused for another
+    // allocation since the one that the pointer was derived from was freed.
+    let alloc = (prov.lock_address as *const AllocMetadata)
+        .as_ref()
+        .filter(|meta| meta.id == prov.alloc_id)
+        .or_else(|| Some(ctx.registry().find_base(err.base_addr()?)?.as_ref()));
+    report::report(&Report {
+        access: Some((kind, ptr.addr(), size)),
+        tag: prov.bor_tag,
+        alloc,
+        ..Report::new(
+            ErrorKind::Access(err),
+            loc.as_ref(),
+            format_args!("invalid {kind} of {access_size} bytes at {}: {err}", Addr(ptr.addr())),
+        )
+    });
+    // Any later access through the same pointer would repeat the error.
+    ctx.tags().disable(prov.bor_tag);
+}
+
+/// Reports an error of `kind` in the instrumented program, as described by
+/// [`report::report`].
+fn report_error(kind: ErrorKind, args: fmt::Arguments<'_>) {
+    report_error_at(kind, ptr::null(), args);
+}
+
+/// Like [`report_error`], for an error in the code at `loc`, if it isn't null.
+fn report_error_at(kind: ErrorKind, loc: *const SourceInfo, args: fmt::Arguments<'_>) {
+    report::report(&Report::new(kind, unsafe { loc.as_ref() }, args));
+}
+
+/// Makes the runtime collect the call stacks of the errors that it reports with
+/// `hook`, rather than with the system unwinder. This is for programs that
+/// can't be unwound, such as those built without unwind tables. A null `hook`
+/// goes back to the unwinder.
+#[no_mangle]
+extern "C" fn bsan_set_backtrace_hook(hook: BacktraceHook) {
+    backtrace::set_hook(hook);
+}
+
+/// Makes the runtime pass each error that it finds to `callback` before
+/// reporting it, with the details in an [`ErrorInfo`]. If `callback` returns
+/// `true`, the error is neither reported nor counted, so test frameworks can
+/// check for expected errors, and drivers such as fuzzers can abort to turn
+/// them into crashes. Kinds that `BSAN_SEVERITY` ignores aren't passed to it.
+/// A null `callback` removes it.
+#[no_mangle]
+extern "C" fn bsan_set_error_callback(callback: ErrorCallback) {
+    report::set_error_callback(callback);
+}
+
+/// Shuts the runtime down, printing a summary of the errors that it found and,
+/// with `BSAN_DETECT_LEAKS=1`, of the heap allocations that were never freed.
+/// [`bsan_init`] registers this to run at exit, and instrumentation may call
+/// it earlier, in which case the summary is only printed once.
+///
+/// # Safety
+/// No other thread may be using the runtime.
+#[no_mangle]
+pub unsafe extern "C" fn bsan_exit() {
+    enter_hook!(bsan_exit);
+    exit_global_ctx();
+}
+
+/// Sets up the runtime's state for the current thread, which was just spawned
+/// by instrumented code. This must be called on the new thread before it runs
+/// any instrumented code. Spawning the thread synchronizes with its start, so
+/// the events of the new thread are ordered after those of its parent before
+/// the spawn.
+#[no_mangle]
+unsafe extern "C" fn bsan_thread_start() {
+    enter_hook!(bsan_thread_start);
+    let ctx = global_ctx();
+    thread::current().flush(ctx);
+    clock::ThreadId::current();
+    ctx.clock().stamp_sync();
+}
+
+/// Tears down the runtime's state for the current thread, which is about to
+/// exit: the frames that are still on its stack are popped, retiring their
+/// stack allocations and releasing their protectors. The thread must not run
+/// any instrumented code afterwards, so its record in the epoch-based
+/// reclamation scheme is freed.
+#[no_mangle]
+unsafe extern "C" fn bsan_thread_exit() {
+    enter_hook!(bsan_thread_exit);
+    let ctx = global_ctx();
+    thread::current().flush(ctx);
+    ctx.clock().stamp_sync();
+    epoch::unregister();
+}
+
+/// Records that the current thread joined another one, which called
+/// [`bsan_thread_exit`] before it exited. The events of the joined thread are
+/// ordered before those of the current thread after the join.
+#[no_mangle]
+unsafe extern "C" fn bsan_thread_join() {
+    enter_hook!(bsan_thread_join);
+    global_ctx().clock().stamp_sync();
+}
+
+/// Records a call on the current thread to the instrumented function described
+/// by `func`, whose `function` names it and whose location is where it's
+/// defined. Errors reported without a location of their own name the function
+/// of the innermost frame instead. `func` may be null if the function is
+/// unknown.
+///
+/// # Safety
+/// `func` must be null or point to a [`SourceInfo`] that outlives the frame.
+pub unsafe extern "C" fn bsan_func_entry(func: *const SourceInfo) {
+    enter_hook!(bsan_func_entry);
+    frame::enter(func);
+}
+
+/// Records a return from the instrumented function of the innermost frame on
+/// the current thread, retiring the stack allocations made by it and releasing
+/// the protectors of the tags that were retagged on its entry.
+#[no_mangle]
+unsafe extern "C" fn bsan_func_exit() {
+    enter_hook!(bsan_func_exit);
+    frame::exit(global_ctx());
+}
+
+/// The exports of the entry points whose signatures differ from the legacy
+/// `bsanrt` ABI, which [`legacy`] replaces with its own. They are kept apart
+/// from the functions that they call, rather than using `cfg_attr`, so that
+/// cbindgen declares them in the header.
+#[cfg(not(feature = "legacy-abi"))]
+mod exports {
+    use super::*;
+
+    #[no_mangle]
+    unsafe extern "C" fn bsan_init(
+        alloc: BsanAllocator,
+        hooks: *const BsanHooks,
+        api_version: u32,
+    ) {
+        super::bsan_init(alloc, hooks, api_version);
+    }
+
+    #[no_mangle]
+    unsafe extern "C" fn bsan_malloc(ptr: *mut c_void, size: usize, prov: *mut Provenance) {
+        super::bsan_malloc(ptr, size, prov);
+    }
+
+    #[no_mangle]
+    unsafe extern "C" fn bsan_read(
+        ptr: *mut c_void,
+        access_size: u64,
+        prov: *const Provenance,
+        loc: *const SourceInfo,
+    ) {
+        super::bsan_read(ptr, access_size, prov, loc);
+    }
+
+    #[no_mangle]
+    unsafe extern "C" fn bsan_write(
+        ptr: *mut c_void,
+        access_size: u64,
+        prov: *const Provenance,
+        loc: *const SourceInfo,
+    ) {
+        super::bsan_write(ptr, access_size, prov, loc);
+    }
+
+    #[no_mangle]
+    unsafe extern "C" fn bsan_func_entry(func: *const SourceInfo) {
+        super::bsan_func_entry(func);
+    }
+}
+
+#[cfg(not(any(test, feature = "std")))]
 #[panic_handler]
 fn panic(info: &PanicInfo<'_>) -> ! {
-    loop {}
+    let _ = writeln!(FdWriter::log(), "bsan: internal error: {info}");
+    global::internal_failure()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ffi::CStr;
+    use core::mem::MaybeUninit;
+    use core::ptr;
+
+    use super::*;
+
+    unsafe fn malloc(size: usize) -> (*mut c_void, Provenance) {
+        let ptr = libc::malloc(size);
+        let mut prov = MaybeUninit::uninit();
+        bsan_malloc(ptr, size, prov.as_mut_ptr());
+        (ptr, prov.assume_init())
+    }
+
+    unsafe fn load_stack(slot: *const c_void) -> Provenance {
+        let mut prov = MaybeUninit::uninit();
+        bsan_load_stack_prov(slot, prov.as_mut_ptr());
+        prov.assume_init()
+    }
+
+    #[test]
+    fn pointers_round_trip_through_stack_slots() {
+        unsafe {
+            let (heap_ptr, prov) = malloc(16);
+            let mut slot: MaybeUninit<*mut c_void> = MaybeUninit::new(heap_ptr);
+            let slot_addr = slot.as_mut_ptr().cast::<c_void>();
+            bsan_store_stack_prov(slot_addr, &prov);
+            assert_eq!(load_stack(slot_addr), prov);
+            // An unaligned slot, as in a packed struct.
+            let mut packed = [0u8; 2 * mem::size_of::<usize>()];
+            let packed_addr = packed.as_mut_ptr().add(1).cast::<c_void>();
+            ptr::write_unaligned(packed_addr.cast(), heap_ptr);
+            bsan_store_stack_prov(packed_addr, &prov);
+            assert_eq!(load_stack(packed_addr), prov);
+            // Popping the frame clears its slots.
+            bsan_clear_shadow(slot_addr, mem::size_of_val(&slot));
+            bsan_clear_shadow(packed_addr, mem::size_of_val(&packed));
+            assert_eq!(load_stack(slot_addr), Provenance::null());
+            assert_eq!(load_stack(packed_addr), Provenance::null());
+            for _ in 0..2 {
+                bsan_release_alloc_metadata(prov.lock_address);
+            }
+            bsan_free(heap_ptr, ptr::null());
+            libc::free(heap_ptr);
+        }
+    }
+
+    #[test]
+    fn stored_pointers_keep_their_metadata_alive() {
+        unsafe {
+            let (target, target_prov) = malloc(8);
+            let (holder, holder_prov) = malloc(mem::size_of::<*mut c_void>());
+            bsan_store_prov(holder, &target_prov);
+            bsan_release_alloc_metadata(target_prov.lock_address);
+            bsan_free(target, ptr::null());
+            // The dangling pointer still refers to the metadata of its allocation.
+            let mut loaded = MaybeUninit::uninit();
+            bsan_load_prov(holder, loaded.as_mut_ptr());
+            let loaded = loaded.assume_init();
+            assert_eq!(loaded, target_prov);
+            let meta = loaded.lock_address.cast::<registry::AllocMetadata>();
+            assert!(registry::AllocMetadata::is_valid(meta));
+            assert_eq!((*meta).state, registry::AllocState::Freed);
+            bsan_release_alloc_metadata(loaded.lock_address);
+            // Overwriting the pointer with an integer clears its provenance.
+            bsan_store_prov(holder, &Provenance::null());
+            let mut cleared = MaybeUninit::uninit();
+            bsan_load_prov(holder, cleared.as_mut_ptr());
+            assert_eq!(cleared.assume_init(), Provenance::null());
+            bsan_release_alloc_metadata(holder_prov.lock_address);
+            bsan_free(holder, ptr::null());
+            libc::free(target);
+            libc::free(holder);
+        }
+    }
+
+    #[test]
+    fn pointers_to_and_within_globals_carry_provenance() {
+        static mut TARGET: u64 = 0;
+        static mut POINTER: *mut u64 = ptr::null_mut();
+        unsafe {
+            let target = (&raw mut TARGET).cast::<c_void>();
+            let pointer = (&raw mut POINTER).cast::<c_void>();
+            bsan_register_global(target, mem::size_of::<u64>());
+            bsan_register_global(pointer, mem::size_of::<*mut u64>());
+            // The constructor for `static mut POINTER = &raw mut TARGET`.
+            let mut prov = MaybeUninit::uninit();
+            bsan_global_prov(target, prov.as_mut_ptr());
+            let prov = prov.assume_init();
+            assert_eq!(
+                prov.alloc_id,
+                global_ctx().registry().find(target.addr()).unwrap().as_ref().id
+            );
+            bsan_store_prov(pointer, &prov);
+            let mut loaded = MaybeUninit::uninit();
+            bsan_load_prov(pointer, loaded.as_mut_ptr());
+            assert_eq!(loaded.assume_init(), prov);
+            bsan_release_alloc_metadata(prov.lock_address);
+            bsan_release_alloc_metadata(prov.lock_address);
+        }
+    }
+
+    #[test]
+    fn aligned_allocations_record_their_alignment() {
+        unsafe {
+            let ptr = libc::aligned_alloc(64, 128);
+            let mut prov = MaybeUninit::uninit();
+            bsan_aligned_alloc(ptr, 64, 128, prov.as_mut_ptr());
+            let prov = prov.assume_init();
+            let meta = global_ctx().registry().find(ptr.addr()).unwrap();
+            assert_eq!((meta.as_ref().id, meta.as_ref().align), (prov.alloc_id, 64));
+            bsan_release_alloc_metadata(prov.lock_address);
+            bsan_free(ptr, ptr::null());
+            libc::free(ptr);
+            // An invalid alignment has no allocation.
+            let mut prov = MaybeUninit::uninit();
+            bsan_posix_memalign(ptr, 12, 128, prov.as_mut_ptr());
+            assert_eq!(prov.assume_init(), Provenance::null());
+        }
+    }
+
+    #[test]
+    fn zeroed_allocations_hold_no_pointers() {
+        unsafe {
+            let (target, target_prov) = malloc(8);
+            let buf = libc::calloc(4, 8);
+            // Stale provenance from memory that was unmapped without a clear.
+            bsan_store_prov(buf.byte_add(8), &target_prov);
+            let mut prov = MaybeUninit::uninit();
+            bsan_calloc(buf, 4, 8, prov.as_mut_ptr());
+            let prov = prov.assume_init();
+            assert_eq!(access::resolve_access(global_ctx(), buf.addr(), 32), Ok(prov));
+            let mut loaded = MaybeUninit::uninit();
+            bsan_load_prov(buf.byte_add(8), loaded.as_mut_ptr());
+            assert_eq!(loaded.assume_init(), Provenance::null());
+            bsan_release_alloc_metadata(prov.lock_address);
+            bsan_release_alloc_metadata(target_prov.lock_address);
+            bsan_free(buf, ptr::null());
+            bsan_free(target, ptr::null());
+            libc::free(buf);
+            libc::free(target);
+        }
+    }
+
+    #[test]
+    fn accesses_are_not_checked_while_disabled() {
+        unsafe {
+            let (ptr, prov) = malloc(8);
+            bsan_disable();
+            bsan_disable();
+            bsan_write(ptr.byte_add(4), 8, &prov, ptr::null());
+            bsan_enable();
+            bsan_read(ptr.byte_add(4), 8, &prov, ptr::null());
+            assert!(!global_ctx().tags().is_disabled(prov.bor_tag));
+            bsan_enable();
+            bsan_read(ptr.byte_add(4), 8, &prov, ptr::null());
+            assert!(global_ctx().tags().is_disabled(prov.bor_tag));
+            bsan_release_alloc_metadata(prov.lock_address);
+            bsan_free(ptr, ptr::null());
+            libc::free(ptr);
+        }
+    }
+
+    #[test]
+    fn hooks_are_counted_while_counting() {
+        unsafe {
+            stats::set_counting(true);
+            let (ptr, prov) = malloc(8);
+            for _ in 0..3 {
+                bsan_read(ptr, 8, &prov, ptr::null());
+            }
+            bsan_release_alloc_metadata(prov.lock_address);
+            bsan_free(ptr, ptr::null());
+            libc::free(ptr);
+            let mut stats = MaybeUninit::uninit();
+            bsan_get_stats(stats.as_mut_ptr());
+            let stats = stats.assume_init();
+            assert!(stats.hook_calls >= 6 && stats.registry_lookups >= 1);
+            assert!(stats.registry_max_visited >= 1);
+            let len = bsan_get_hook_stats(ptr::null_mut(), 0);
+            let mut hooks = vec![HookStats { name: ptr::null(), calls: 0 }; len];
+            assert_eq!(bsan_get_hook_stats(hooks.as_mut_ptr(), len), len);
+            let calls = |hook: &CStr| {
+                hooks.iter().find(|stats| CStr::from_ptr(stats.name) == hook).unwrap().calls
+            };
+            assert!(calls(c"bsan_read") >= 3 && calls(c"bsan_malloc") >= 1);
+        }
+    }
+
+    #[test]
+    fn errors_disable_the_tag_of_the_pointer() {
+        unsafe {
+            let (ptr, prov) = malloc(8);
+            let (other, other_prov) = malloc(8);
+            bsan_write(ptr.byte_add(4), 8, &prov, ptr::null());
+            assert!(global_ctx().tags().is_disabled(prov.bor_tag));
+            bsan_write(other, 8, &other_prov, ptr::null());
+            assert!(!global_ctx().tags().is_disabled(other_prov.bor_tag));
+            for (ptr, prov) in [(ptr, prov), (other, other_prov)] {
+                bsan_release_alloc_metadata(prov.lock_address);
+                bsan_free(ptr, ptr::null());
+                libc::free(ptr);
+            }
+        }
+    }
+
+    #[test]
+    fn batched_accesses_are_checked_like_single_ones() {
+        unsafe {
+            let (ptr, prov) = malloc(16);
+            let (other, other_prov) = malloc(8);
+            let desc = |ptr: *mut c_void, size, prov: &Provenance, kind: AccessKind| AccessDesc {
+                ptr,
+                size,
+                prov,
+                loc: ptr::null(),
+                kind: kind as u8,
+            };
+            let batch = [
+                desc(ptr, 8, &prov, AccessKind::Read),
+                desc(ptr.byte_add(8), 8, &prov, AccessKind::Read),
+                desc(ptr.byte_add(4), 4, &prov, AccessKind::Read),
+                desc(other, 8, &other_prov, AccessKind::Write),
+            ];
+            let checked = global_ctx().stats().snapshot().checked_accesses;
+            bsan_check_accesses(batch.as_ptr(), batch.len());
+            bsan_check_accesses(ptr::null(), 0);
+            assert!(global_ctx().stats().snapshot().checked_accesses >= checked + 4);
+            assert!(!global_ctx().tags().is_disabled(prov.bor_tag));
+            assert!(!global_ctx().tags().is_disabled(other_prov.bor_tag));
+            // A run that goes out of bounds is checked access by access.
+            let batch = [
+                desc(other, 4, &other_prov, AccessKind::Write),
+                desc(other.byte_add(4), 8, &other_prov, AccessKind::Write),
+            ];
+            bsan_check_accesses(batch.as_ptr(), batch.len());
+            assert!(global_ctx().tags().is_disabled(other_prov.bor_tag));
+            for (ptr, prov) in [(ptr, prov), (other, other_prov)] {
+                bsan_release_alloc_metadata(prov.lock_address);
+                bsan_free(ptr, ptr::null());
+                libc::free(ptr);
+            }
+        }
+    }
+
+    #[test]
+    fn exited_threads_retire_their_stack_allocations() {
+        unsafe {
+            let mut slot = [0u64; 2];
+            let addr = slot.as_mut_ptr().addr();
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    bsan_thread_start();
+                    bsan_func_entry(ptr::null());
+                    let mut prov = MaybeUninit::uninit();
+                    bsan_alloc_stack(ptr::without_provenance_mut(addr), 16, prov.as_mut_ptr());
+                    let prov = prov.assume_init();
+                    assert!(global_ctx().registry().find(addr).is_some());
+                    // The thread exits without returning from its frame.
+                    bsan_thread_exit();
+                    assert!(global_ctx().registry().find(addr).is_none());
+                    assert_eq!(frame::depth(), 0);
+                    bsan_release_alloc_metadata(prov.lock_address);
+                });
+            });
+            bsan_thread_join();
+        }
+    }
+
+    #[test]
+    fn mapped_memory_is_an_allocation_of_its_own() {
+        unsafe {
+            let len = host::page_size() + 1;
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            let ptr = libc::mmap(ptr::null_mut(), len, prot, flags, -1, 0);
+            let mut prov = MaybeUninit::uninit();
+            bsan_mmap(ptr, len, prov.as_mut_ptr());
+            let prov = prov.assume_init();
+            let ctx = global_ctx();
+            let whole = 2 * host::page_size();
+            assert_eq!(access::check_access_with(ctx, prov, ptr.addr(), whole), Ok(prov));
+            assert!(access::check_access_with(ctx, prov, ptr.addr(), whole + 1).is_err());
+            libc::munmap(ptr, len);
+            bsan_munmap(ptr, len);
+            assert!(matches!(
+                access::check_access_with(ctx, prov, ptr.addr(), 1),
+                Err(AccessError::UseAfterFree { .. })
+            ));
+            bsan_release_alloc_metadata(prov.lock_address);
+        }
+    }
+
+    #[test]
+    fn memset_clears_stored_pointers() {
+        unsafe {
+            let (target, target_prov) = malloc(8);
+            let (buf, buf_prov) = malloc(32);
+            for offset in [0, 8, 24] {
+                bsan_store_prov(buf.byte_add(offset), &target_prov);
+            }
+            libc::memset(buf.byte_add(4), 0, 20);
+            bsan_memset(buf.byte_add(4), 0, 20, ptr::null());
+            for (offset, expected) in
+                [(0, Provenance::null()), (8, Provenance::null()), (24, target_prov)]
+            {
+                let mut loaded = MaybeUninit::uninit();
+                bsan_load_prov(buf.byte_add(offset), loaded.as_mut_ptr());
+                let loaded = loaded.assume_init();
+                assert_eq!(loaded, expected);
+                bsan_release_alloc_metadata(loaded.lock_address);
+            }
+            for prov in [target_prov, buf_prov] {
+                bsan_release_alloc_metadata(prov.lock_address);
+            }
+            bsan_free(buf, ptr::null());
+            bsan_free(target, ptr::null());
+            libc::free(buf);
+            libc::free(target);
+        }
+    }
+
+    #[test]
+    fn reallocated_buffers_keep_their_pointers() {
+        unsafe {
+            let (target, target_prov) = malloc(8);
+            let (old, old_prov) = malloc(16);
+            bsan_store_prov(old, &target_prov);
+            let new = libc::realloc(old, 64);
+            let mut new_prov = MaybeUninit::uninit();
+            bsan_realloc(old, &old_prov, new, 64, new_prov.as_mut_ptr(), ptr::null());
+            let new_prov = new_prov.assume_init();
+            assert_ne!(new_prov.alloc_id, old_prov.alloc_id);
+            assert_eq!(access::resolve_access(global_ctx(), new.addr() + 48, 16), Ok(new_prov));
+            let mut loaded = MaybeUninit::uninit();
+            bsan_load_prov(new, loaded.as_mut_ptr());
+            assert_eq!(loaded.assume_init(), target_prov);
+            for prov in [target_prov, old_prov, new_prov, target_prov] {
+                bsan_release_alloc_metadata(prov.lock_address);
+            }
+            bsan_free(target, ptr::null());
+            bsan_free(new, ptr::null());
+            libc::free(target);
+            libc::free(new);
+        }
+    }
+
+    #[test]
+    fn rust_allocations_keep_their_layout() {
+        unsafe {
+            let layout = std::alloc::Layout::from_size_align(24, 64).unwrap();
+            let ptr = std::alloc::alloc(layout).cast::<c_void>();
+            let mut prov = MaybeUninit::uninit();
+            bsan_rust_alloc(ptr, 24, 64, prov.as_mut_ptr());
+            let prov = prov.assume_init();
+            let new = std::alloc::realloc(ptr.cast(), layout, 100).cast::<c_void>();
+            let mut new_prov = MaybeUninit::uninit();
+            bsan_rust_realloc(ptr, &prov, 24, 64, new, 100, new_prov.as_mut_ptr(), ptr::null());
+            let new_prov = new_prov.assume_init();
+            let meta = global_ctx().registry().find_base(new.addr()).unwrap();
+            assert_eq!((meta.as_ref().size, meta.as_ref().align), (100, 64));
+            assert_eq!(meta.as_ref().id, new_prov.alloc_id);
+            bsan_rust_dealloc(new, 100, 64, ptr::null());
+            assert!(global_ctx().registry().find(new.addr()).is_none());
+            for prov in [prov, new_prov] {
+                bsan_release_alloc_metadata(prov.lock_address);
+            }
+            std::alloc::dealloc(new.cast(), std::alloc::Layout::from_size_align(100, 64).unwrap());
+        }
+    }
+
+    #[test]
+    fn pointers_at_invalid_addresses_are_dropped() {
+        let kernel = ptr::without_provenance_mut::<c_void>(0xffff_8000_0000_0000);
+        unsafe {
+            let (ptr, prov) = malloc(16);
+            bsan_store_prov(kernel, &prov);
+            bsan_fill_prov(kernel, 64, &prov);
+            let mut loaded = MaybeUninit::uninit();
+            bsan_load_prov(kernel, loaded.as_mut_ptr());
+            assert_eq!(loaded.assume_init(), Provenance::null());
+            bsan_release_alloc_metadata(prov.lock_address);
+            bsan_free(ptr, ptr::null());
+            libc::free(ptr);
+        }
+    }
+
+    #[test]
+    fn error_callbacks_can_handle_errors() {
+        use core::ffi::CStr;
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        static HANDLED: AtomicBool = AtomicBool::new(false);
+        unsafe extern "C" fn on_error(error: *const ErrorInfo) -> bool {
+            let error = &*error;
+            // Other tests report errors of their own meanwhile.
+            if !CStr::from_ptr(error.message).to_bytes().ends_with(b"allocation 0xbad0") {
+                return false;
+            }
+            let expected = CStr::from_ptr(error.kind) == c"invalid-free"
+                && !error.is_warning
+                && error.loc.is_null()
+                && (error.address, error.alloc_id, error.tag) == (0, 0, 0)
+                && error.num_frames > 0;
+            HANDLED.store(expected, Ordering::Relaxed);
+            true
+        }
+        bsan_set_error_callback(Some(on_error));
+        unsafe { bsan_free(ptr::without_provenance_mut(0xbad0), ptr::null()) };
+        bsan_set_error_callback(None);
+        assert!(HANDLED.load(Ordering::Relaxed));
+    }
 }
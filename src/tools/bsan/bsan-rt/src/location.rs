@@ -0,0 +1,88 @@
+use core::ffi::{CStr, c_char};
+use core::fmt::{self, Write};
+
+/// The location in the source of the instrumented program that a hook was
+/// called for, as emitted by the pass. Hooks that can report an error take an
+/// optional pointer to one, which the pass keeps in a constant of its own.
+/// Any of the fields may be missing: a null `file` or `function` if the name
+/// isn't known, and a zero `line` or `column` likewise.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SourceInfo {
+    pub file: *const c_char,
+    pub line: u32,
+    pub column: u32,
+    pub function: *const c_char,
+}
+
+unsafe impl Send for SourceInfo {}
+unsafe impl Sync for SourceInfo {}
+
+/// Displays a location as `function (file:line:column)`, leaving out whatever
+/// is missing.
+impl fmt::Display for SourceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let function = unsafe { name(self.function) };
+        let file = unsafe { name(self.file) };
+        if let Some(function) = function {
+            write_lossy(f, function)?;
+            if file.is_none() {
+                return Ok(());
+            }
+            f.write_str(" (")?;
+        }
+        match file {
+            Some(file) => write_lossy(f, file)?,
+            None => f.write_str("<unknown>")?,
+        }
+        if self.line != 0 {
+            write!(f, ":{}", self.line)?;
+            if self.column != 0 {
+                write!(f, ":{}", self.column)?;
+            }
+        }
+        if function.is_some() {
+            f.write_char(')')?;
+        }
+        Ok(())
+    }
+}
+
+/// The bytes of the C string at `ptr`, unless it is null.
+pub unsafe fn name<'a>(ptr: *const c_char) -> Option<&'a [u8]> {
+    (!ptr.is_null()).then(|| CStr::from_ptr(ptr).to_bytes())
+}
+
+/// Writes `bytes`, replacing invalid UTF-8 as `String::from_utf8_lossy` does.
+pub fn write_lossy(f: &mut impl Write, bytes: &[u8]) -> fmt::Result {
+    for chunk in bytes.utf8_chunks() {
+        f.write_str(chunk.valid())?;
+        if !chunk.invalid().is_empty() {
+            f.write_char(char::REPLACEMENT_CHARACTER)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ptr;
+
+    use super::*;
+
+    #[test]
+    fn missing_fields_are_left_out() {
+        let info = |file: &CStr, line, column, function: &CStr| SourceInfo {
+            file: if file.is_empty() { ptr::null() } else { file.as_ptr() },
+            line,
+            column,
+            function: if function.is_empty() { ptr::null() } else { function.as_ptr() },
+        };
+        let show = |info: SourceInfo| info.to_string();
+        assert_eq!(show(info(c"src/lib.rs", 12, 5, c"crate::f")), "crate::f (src/lib.rs:12:5)");
+        assert_eq!(show(info(c"src/lib.rs", 12, 0, c"")), "src/lib.rs:12");
+        assert_eq!(show(info(c"", 12, 5, c"crate::f")), "crate::f");
+        assert_eq!(show(info(c"", 0, 0, c"")), "<unknown>");
+        assert_eq!(show(info(c"a\xffb.rs", 0, 0, c"")), "a\u{fffd}b.rs");
+    }
+}
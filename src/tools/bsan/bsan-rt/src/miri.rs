@@ -0,0 +1,172 @@
+//! Options named after Miri's, so that a run of Miri can be reproduced under
+//! bsan and their results compared.
+//!
+//! `BSAN_MIRIFLAGS` takes Miri's flags as they would be passed in `MIRIFLAGS`,
+//! and sets the options that they correspond to, unless those are set by their
+//! own variables or in `BSAN_OPTIONS`:
+//!
+//! - `-Zmiri-track-alloc-id=<ids>` sets `track_alloc_id`, which logs where the
+//!   allocations with the comma-separated IDs are made and freed,
+//! - `-Zmiri-track-pointer-tag=<tags>` sets `track_pointer_tag`, which logs
+//!   where the tags are created by retags,
+//! - `-Zmiri-ignore-leaks` sets `ignore_leaks`, which overrides
+//!   `detect_leaks`, and
+//! - `-Zmiri-strict-provenance` sets `strict_provenance`.
+//!
+//! `bsan_init` warns about the other flags. Most of them configure Miri's
+//! interpreter, and the ones that configure Tree Borrows, such as
+//! `-Zmiri-unique-is-unique`, `-Zmiri-retag-fields` and
+//! `-Zmiri-provenance-gc`, have no counterpart either: which pointers are
+//! retagged is decided when the program is compiled, and the runtime keeps no
+//! tree of tags to configure or collect.
+
+use core::fmt::{self, Write};
+
+use crate::backtrace::Backtrace;
+use crate::global::GlobalContext;
+use crate::io::{self, FdWriter};
+use crate::{options, report};
+
+/// Miri's flags that have a counterpart, without their `-Zmiri-` prefix, and
+/// the keys of the options that they set.
+pub const FLAGS: [(&str, &str); 4] = [
+    ("ignore-leaks", "ignore_leaks"),
+    ("strict-provenance", "strict_provenance"),
+    ("track-alloc-id", "track_alloc_id"),
+    ("track-pointer-tag", "track_pointer_tag"),
+];
+
+/// Calls `f` with the name, without its `-Zmiri-` prefix, and the value of
+/// each of Miri's flags in `flags`, which is `None` for those that take none.
+/// Arguments that aren't Miri's flags are skipped.
+pub fn parse<'a>(flags: &'a str, mut f: impl FnMut(&'a str, Option<&'a str>)) {
+    for flag in flags.split_whitespace().filter_map(|arg| arg.strip_prefix("-Zmiri-")) {
+        match flag.split_once('=') {
+            Some((name, value)) => f(name, Some(value)),
+            None => f(flag, None),
+        }
+    }
+}
+
+/// The value for the option `key` that the last of Miri's flags in `flags`
+/// that sets it gives it, where flags without a value set options to `1`.
+pub fn find<'a>(flags: &'a str, key: &str) -> Option<&'a str> {
+    let mut found = None;
+    parse(flags, |name, value| {
+        let sets_key = FLAGS.iter().any(|&(flag, k)| flag == name && k.eq_ignore_ascii_case(key));
+        if sets_key {
+            found = Some(value.unwrap_or("1"));
+        }
+    });
+    found
+}
+
+/// Warns about the flags in `BSAN_MIRIFLAGS` that have no counterpart.
+pub fn warn_about_unsupported() {
+    let Some(flags) = options::getenv(c"BSAN_MIRIFLAGS").and_then(|flags| flags.to_str().ok())
+    else {
+        return;
+    };
+    parse(flags, |name, _| {
+        if !FLAGS.iter().any(|&(flag, _)| flag == name) {
+            let _ = writeln!(
+                FdWriter::log(),
+                "bsan: `-Zmiri-{name}` in BSAN_MIRIFLAGS has no counterpart, so it is ignored"
+            );
+        }
+    });
+}
+
+/// The number of IDs that can be tracked. Those past this are ignored.
+pub const MAX_TRACKED: usize = 8;
+
+/// A set of the allocation IDs or tags that are tracked, like Miri's.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TrackedIds {
+    ids: [u64; MAX_TRACKED],
+    len: usize,
+}
+
+impl TrackedIds {
+    pub const fn new() -> Self {
+        Self { ids: [0; MAX_TRACKED], len: 0 }
+    }
+
+    /// Parses a comma-separated list of IDs, skipping those that aren't
+    /// numbers.
+    pub fn parse(list: &str) -> Self {
+        let mut tracked = Self::new();
+        for id in list.split(',').filter_map(|id| id.trim().parse().ok()).take(MAX_TRACKED) {
+            tracked.ids[tracked.len] = id;
+            tracked.len += 1;
+        }
+        tracked
+    }
+
+    /// Reads the list in the variable `name`.
+    pub fn from_env(name: &core::ffi::CStr) -> Self {
+        io::env_str(name).map(Self::parse).unwrap_or_default()
+    }
+
+    #[inline]
+    pub fn contains(&self, id: u64) -> bool {
+        self.len != 0 && self.ids[..self.len].contains(&id)
+    }
+}
+
+/// The allocations and tags whose events are logged.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Tracked {
+    pub allocs: TrackedIds,
+    pub tags: TrackedIds,
+}
+
+impl Tracked {
+    pub const fn new() -> Self {
+        Self { allocs: TrackedIds::new(), tags: TrackedIds::new() }
+    }
+
+    pub fn from_env() -> Self {
+        Self {
+            allocs: TrackedIds::from_env(c"BSAN_TRACK_ALLOC_ID"),
+            tags: TrackedIds::from_env(c"BSAN_TRACK_POINTER_TAG"),
+        }
+    }
+}
+
+/// Logs an event of a tracked allocation or tag, described by `args`, along
+/// with the call stack at which it happened.
+#[inline(never)]
+pub fn log_tracked(ctx: &GlobalContext, args: fmt::Arguments<'_>) {
+    let mut out = FdWriter::log();
+    let _ = writeln!(out, "bsan: {args}");
+    let color = ctx.output().color(io::log_fd());
+    let _ = report::write_stack(ctx, &Backtrace::capture(1), color, &mut out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn miri_flags_set_their_counterparts() {
+        let flags = "-Zmiri-disable-isolation -Zmiri-track-alloc-id=3,7 -Zmiri-ignore-leaks \
+                     --verbose -Zmiri-track-alloc-id=9";
+        assert_eq!(find(flags, "track_alloc_id"), Some("9"));
+        assert_eq!(find(flags, "IGNORE_LEAKS"), Some("1"));
+        assert_eq!(find(flags, "track_pointer_tag"), None);
+        let mut names = Vec::new();
+        parse(flags, |name, value| names.push((name, value)));
+        assert_eq!(names[0], ("disable-isolation", None));
+        assert_eq!(names.len(), 4);
+    }
+
+    #[test]
+    fn tracked_ids_are_parsed_from_lists() {
+        let tracked = TrackedIds::parse("3, 7,x,,12");
+        assert!(tracked.contains(3) && tracked.contains(7) && tracked.contains(12));
+        assert!(!tracked.contains(0) && !tracked.contains(4));
+        assert!(!TrackedIds::new().contains(0));
+        assert_eq!(TrackedIds::parse("1,2,3,4,5,6,7,8,9").len, MAX_TRACKED);
+    }
+}
@@ -0,0 +1,174 @@
+//! The shared libraries that the instrumented program loads at run time, as
+//! reported by `bsan_dlopen` and `bsan_dlclose`.
+//!
+//! An instrumented library registers its globals from its own constructors, so
+//! the runtime only needs to know where each library is mapped: once it has
+//! been unloaded, the globals that were registered in it are retired, and its
+//! shadow memory is cleared. Libraries that weren't instrumented register no
+//! globals at all, so accesses to memory within their image, such as to a
+//! global of the library exposed through its API, are not reported as accesses
+//! outside of any allocation.
+
+use core::ffi::c_void;
+
+use alloc_crate::vec::Vec;
+
+use crate::sync::SpinLock;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Module {
+    pub handle: usize,
+    /// The range of addresses that the library's image is mapped at.
+    pub start: usize,
+    pub end: usize,
+    pub instrumented: bool,
+    // The number of times that the library was opened and not yet closed,
+    // since `dlopen` returns the same handle for a library that's already
+    // loaded, and only unloads it once it has been closed as often.
+    opened: usize,
+}
+
+#[derive(Debug)]
+pub struct ModuleTable {
+    modules: SpinLock<Vec<Module>>,
+}
+
+impl Default for ModuleTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ModuleTable {
+    pub const fn new() -> Self {
+        Self { modules: SpinLock::new(Vec::new()) }
+    }
+
+    /// Records that the library with `handle`, whose image is mapped at
+    /// `[start, end)`, was opened. Returns `false` if the table couldn't grow
+    /// to hold it, in which case it is checked as if the runtime didn't know
+    /// about it.
+    pub fn open(&self, handle: usize, start: usize, end: usize, instrumented: bool) -> bool {
+        let mut modules = self.modules.lock();
+        if let Some(module) = modules.iter_mut().find(|m| m.handle == handle) {
+            module.opened += 1;
+            return true;
+        }
+        if modules.try_reserve(1).is_err() {
+            return false;
+        }
+        modules.push(Module { handle, start, end, instrumented, opened: 1 });
+        true
+    }
+
+    /// Records that the library with `handle` was closed. Returns the library
+    /// if this unloaded it, because it was closed as often as it was opened.
+    pub fn close(&self, handle: usize) -> Option<Module> {
+        let mut modules = self.modules.lock();
+        let index = modules.iter().position(|m| m.handle == handle)?;
+        modules[index].opened -= 1;
+        if modules[index].opened > 0 {
+            return None;
+        }
+        Some(modules.swap_remove(index))
+    }
+
+    /// Whether `addr` is within the image of a library that is loaded and
+    /// wasn't instrumented.
+    pub fn is_uninstrumented(&self, addr: usize) -> bool {
+        let modules = self.modules.lock();
+        modules.iter().any(|m| !m.instrumented && (m.start..m.end).contains(&addr))
+    }
+}
+
+/// The range of addresses that the image of the library with `handle`, as
+/// returned by `dlopen`, is mapped at. This is only known on Linux.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub unsafe fn image_range(handle: *mut c_void) -> Option<(usize, usize)> {
+    struct Search {
+        base: usize,
+        range: Option<(usize, usize)>,
+    }
+
+    unsafe extern "C" fn visit(
+        info: *mut libc::dl_phdr_info,
+        _size: usize,
+        data: *mut c_void,
+    ) -> libc::c_int {
+        let (info, search) = (&*info, &mut *data.cast::<Search>());
+        if info.dlpi_addr as usize != search.base {
+            return 0;
+        }
+        let phdrs = core::slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum.into());
+        for phdr in phdrs.iter().filter(|phdr| phdr.p_type == libc::PT_LOAD) {
+            let start = search.base + phdr.p_vaddr as usize;
+            let end = start + phdr.p_memsz as usize;
+            search.range = Some(match search.range {
+                Some((lo, hi)) => (lo.min(start), hi.max(end)),
+                None => (start, end),
+            });
+        }
+        // Any nonzero value stops the iteration.
+        1
+    }
+
+    // The load address is the first field of the `link_map` of the library.
+    let mut map: *const usize = core::ptr::null();
+    if libc::dlinfo(handle, libc::RTLD_DI_LINKMAP, (&raw mut map).cast()) != 0 || map.is_null() {
+        return None;
+    }
+    let mut search = Search { base: *map, range: None };
+    libc::dl_iterate_phdr(Some(visit), (&raw mut search).cast());
+    search.range
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub unsafe fn image_range(handle: *mut c_void) -> Option<(usize, usize)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn libraries_are_unloaded_once_closed_as_often_as_opened() {
+        let modules = ModuleTable::new();
+        assert!(modules.open(1, 0x1000, 0x3000, false));
+        assert!(modules.open(2, 0x8000, 0x9000, true));
+        assert!(modules.open(1, 0x1000, 0x3000, false));
+        assert!(modules.is_uninstrumented(0x2000));
+        assert!(!modules.is_uninstrumented(0x3000));
+        assert!(!modules.is_uninstrumented(0x8000));
+        assert_eq!(modules.close(1), None);
+        assert!(modules.is_uninstrumented(0x2000));
+        assert!(modules.close(1).is_some_and(|m| (m.start, m.end) == (0x1000, 0x3000)));
+        assert!(!modules.is_uninstrumented(0x2000));
+        assert_eq!(modules.close(1), None);
+    }
+
+    #[test]
+    fn tables_grow_with_the_libraries_loaded() {
+        let modules = ModuleTable::new();
+        for handle in 0..200 {
+            assert!(modules.open(handle, handle * 0x1000, (handle + 1) * 0x1000, handle != 150));
+        }
+        assert!(modules.is_uninstrumented(150 * 0x1000));
+        assert!(modules.close(3).is_some());
+        assert!(modules.is_uninstrumented(150 * 0x1000));
+        assert!(!modules.is_uninstrumented(3 * 0x1000));
+    }
+
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    #[test]
+    fn images_contain_their_symbols() {
+        unsafe {
+            let handle = libc::dlopen(c"libm.so.6".as_ptr(), libc::RTLD_NOW);
+            assert!(!handle.is_null());
+            let cos = libc::dlsym(handle, c"cos".as_ptr()).addr();
+            let (start, end) = image_range(handle).unwrap();
+            assert!((start..end).contains(&cos));
+            libc::dlclose(handle);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! The `BSAN_OPTIONS` environment variable, which sets many options at once.
+//!
+//! As with `ASAN_OPTIONS`, it holds `key=value` entries that are separated by
+//! colons or whitespace, and values can be quoted with `'` or `"` to contain
+//! either. Each key is the name of one of the variables that configure the
+//! runtime, without the `BSAN_` prefix and in any case, so
+//! `BSAN_OPTIONS=halt_on_error=1:log_path=/tmp/bsan` is the same as setting
+//! `BSAN_HALT_ON_ERROR=1` and `BSAN_LOG_PATH=/tmp/bsan`. A variable that is set
+//! on its own overrides the entry for it, and later entries override earlier
+//! ones. `bsan_init` warns about the entries that it doesn't understand.
+//! Options that neither sets can still be set by Miri's flags in
+//! `BSAN_MIRIFLAGS`, as [`miri`](crate::miri) describes.
+//!
+//! As with `__asan_default_options`, a program can build in defaults of its
+//! own by defining `const char *__bsan_default_options(void)`, which returns
+//! entries in the same syntax. They are overridden by everything in the
+//! environment, so a binary can be shipped with the options it needs and
+//! still be run with others.
+
+use core::ffi::{CStr, c_char};
+use core::fmt::Write;
+
+use crate::io::FdWriter;
+use crate::miri;
+
+/// The options that can be set, as the keys of `BSAN_OPTIONS`.
+pub const KNOWN: [&str; 36] = [
+    "alloc_history",
+    "alloc_stack_depth",
+    "checkpoint_interval",
+    "checkpoint_path",
+    "color",
+    "dedup_errors",
+    "detect_leaks",
+    "deterministic",
+    "exit_on_max_errors",
+    "exitcode",
+    "halt_on_error",
+    "handle_segv",
+    "ignore_fns",
+    "ignore_leaks",
+    "ignore_modules",
+    "internal_exitcode",
+    "lenient_foreign",
+    "log_path",
+    "max_derived_tags",
+    "max_errors",
+    "output_fd",
+    "output_format",
+    "sample_rate",
+    "severity",
+    "shadow_hugepages",
+    "shadow_stats",
+    "stats",
+    "strict_abi",
+    "strict_provenance",
+    "symbolize",
+    "symbolizer_path",
+    "trace_events",
+    "trace_path",
+    "track_alloc_id",
+    "track_pointer_tag",
+    "verbosity",
+];
+
+/// Calls `set` with the key and value of each entry of `options`, in order,
+/// and `invalid` with each entry that isn't a `key=value` pair.
+pub fn parse<'a>(
+    options: &'a str,
+    mut set: impl FnMut(&'a str, &'a str),
+    mut invalid: impl FnMut(&'a str),
+) {
+    let is_separator = |c: char| c == ':' || c.is_whitespace();
+    let mut rest = options;
+    loop {
+        rest = rest.trim_start_matches(is_separator);
+        if rest.is_empty() {
+            return;
+        }
+        let end = rest.find(|c: char| c == '=' || is_separator(c)).unwrap_or(rest.len());
+        let (key, after) = rest.split_at(end);
+        let Some(value) = after.strip_prefix('=') else {
+            invalid(key);
+            rest = after;
+            continue;
+        };
+        let (value, after) = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => match value[1..].find(quote) {
+                Some(len) => (&value[1..1 + len], &value[2 + len..]),
+                None => {
+                    invalid(&rest[..end + 1 + value.len()]);
+                    return;
+                }
+            },
+            _ => value.split_at(value.find(is_separator).unwrap_or(value.len())),
+        };
+        set(key, value);
+        rest = after;
+    }
+}
+
+/// The value of the last entry for `key` in `options`, whose case is ignored.
+pub fn find<'a>(options: &'a str, key: &str) -> Option<&'a str> {
+    let mut found = None;
+    parse(
+        options,
+        |k, value| {
+            if k.eq_ignore_ascii_case(key) {
+                found = Some(value)
+            }
+        },
+        |_| {},
+    );
+    found
+}
+
+/// The value of the variable `name`, or of the entry for it in `BSAN_OPTIONS`
+/// or the flag for it in `BSAN_MIRIFLAGS` if it isn't set, as long as it is
+/// valid UTF-8.
+pub fn lookup(name: &CStr) -> Option<&'static str> {
+    if let Some(value) = getenv(name) {
+        return value.to_str().ok();
+    }
+    let key = name.to_str().ok()?.strip_prefix("BSAN_")?;
+    let in_var = |var| getenv(var).and_then(|options| options.to_str().ok());
+    in_var(c"BSAN_OPTIONS")
+        .and_then(|options| find(options, key))
+        .or_else(|| miri::find(in_var(c"BSAN_MIRIFLAGS")?, key))
+        .or_else(|| find(defaults()?, key))
+}
+
+/// The options returned by `__bsan_default_options`, if the program defines
+/// it, as long as they are valid UTF-8.
+pub fn defaults() -> Option<&'static str> {
+    extern "C" {
+        #[linkage = "extern_weak"]
+        static __bsan_default_options: Option<unsafe extern "C" fn() -> *const c_char>;
+    }
+    let options = unsafe { __bsan_default_options?() };
+    if options.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(options) }.to_str().ok()
+}
+
+/// Warns about the entries of `BSAN_OPTIONS` and of the defaults that the
+/// program built in that aren't `key=value` pairs or whose keys aren't
+/// [`KNOWN`].
+pub fn warn_about_invalid() {
+    if let Some(options) = getenv(c"BSAN_OPTIONS") {
+        match options.to_str() {
+            Ok(options) => warn_about_entries(options, "BSAN_OPTIONS"),
+            Err(_) => {
+                let _ = writeln!(FdWriter::log(), "bsan: BSAN_OPTIONS is not valid UTF-8");
+            }
+        }
+    }
+    if let Some(options) = defaults() {
+        warn_about_entries(options, "__bsan_default_options");
+    }
+}
+
+fn warn_about_entries(options: &str, source: &str) {
+    parse(
+        options,
+        |key, _| {
+            if !KNOWN.iter().any(|known| known.eq_ignore_ascii_case(key)) {
+                let _ = writeln!(FdWriter::log(), "bsan: unknown option `{key}` in {source}");
+            }
+        },
+        |entry| {
+            let _ = writeln!(FdWriter::log(), "bsan: invalid entry `{entry}` in {source}");
+        },
+    );
+}
+
+pub fn getenv(name: &CStr) -> Option<&'static CStr> {
+    let value = unsafe { libc::getenv(name.as_ptr()) };
+    if value.is_null() { None } else { Some(unsafe { CStr::from_ptr(value) }) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(options: &str) -> (Vec<(&str, &str)>, Vec<&str>) {
+        let (mut set, mut invalid) = (Vec::new(), Vec::new());
+        parse(options, |key, value| set.push((key, value)), |entry| invalid.push(entry));
+        (set, invalid)
+    }
+
+    #[test]
+    fn entries_are_split_at_colons_and_whitespace() {
+        let (set, invalid) =
+            entries(" halt_on_error=1:log_path='/tmp/a b:c'  color=never::bogus:max_errors=");
+        assert_eq!(
+            set,
+            [
+                ("halt_on_error", "1"),
+                ("log_path", "/tmp/a b:c"),
+                ("color", "never"),
+                ("max_errors", "")
+            ]
+        );
+        assert_eq!(invalid, ["bogus"]);
+        let (set, invalid) = entries("a=\"1:2\"\tb=2:c=\"unterminated");
+        assert_eq!(set, [("a", "1:2"), ("b", "2")]);
+        assert_eq!(invalid, ["c=\"unterminated"]);
+        assert_eq!(entries(""), (vec![], vec![]));
+    }
+
+    #[test]
+    fn later_entries_win() {
+        let options = "max_errors=3:HALT_ON_ERROR=1:max_errors=5";
+        assert_eq!(find(options, "max_errors"), Some("5"));
+        assert_eq!(find(options, "halt_on_error"), Some("1"));
+        assert_eq!(find(options, "color"), None);
+    }
+}
@@ -0,0 +1,134 @@
+//! A pool of fixed-size blocks, for the metadata that the runtime allocates
+//! for every allocation of the program.
+//!
+//! Each heap allocation of the program needs an [`AllocMetadata`], so taking
+//! them from the size classes of the [`BsanAllocator`] would double the
+//! traffic through its locks on the hottest path of the runtime. A pool is
+//! split into shards instead, and each thread takes blocks from, and returns
+//! them to, the shard that its ID picks, so threads only contend when they
+//! share a shard. Blocks freed on one thread may be reused on another. Shards
+//! map slabs of [`SLAB_LEN`] bytes of their own as they run out of blocks,
+//! which are never unmapped, so blocks stay readable after they are freed.
+//!
+//! [`AllocMetadata`]: crate::registry::AllocMetadata
+
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr::{self, NonNull};
+
+use crate::BsanAllocator;
+use crate::clock::ThreadId;
+use crate::sync::SpinLock;
+
+/// The number of shards of each pool.
+pub const SHARDS: usize = 16;
+
+/// The number of bytes that a shard maps at once.
+pub const SLAB_LEN: usize = 64 * 1024;
+
+// The blocks of a shard: a list of the freed ones, linked through their first
+// word, and the rest of the slab that was mapped last.
+#[derive(Debug)]
+struct Shard {
+    free: usize,
+    next: usize,
+    end: usize,
+}
+
+/// A pool of blocks that each hold a `T`.
+#[derive(Debug)]
+pub struct BlockPool<T> {
+    shards: [SpinLock<Shard>; SHARDS],
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for BlockPool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> BlockPool<T> {
+    // The stride of the blocks, which must be able to hold a link of the free
+    // list, and keep every block aligned in a page-aligned slab.
+    const BLOCK_SIZE: usize = {
+        assert!(mem::align_of::<T>() <= 4096);
+        let size = if mem::size_of::<T>() > mem::size_of::<usize>() {
+            mem::size_of::<T>()
+        } else {
+            mem::size_of::<usize>()
+        };
+        size.next_multiple_of(mem::align_of::<T>()).next_multiple_of(mem::align_of::<usize>())
+    };
+
+    pub const fn new() -> Self {
+        Self {
+            shards: [const { SpinLock::new(Shard { free: 0, next: 0, end: 0 }) }; SHARDS],
+            _marker: PhantomData,
+        }
+    }
+
+    fn shard(&self) -> &SpinLock<Shard> {
+        &self.shards[ThreadId::current().get() as usize % SHARDS]
+    }
+
+    /// Takes an uninitialized block, mapping a new slab with `allocator` if
+    /// the current thread's shard has none left. Returns `None` if the slab
+    /// couldn't be mapped.
+    pub fn allocate(&self, allocator: &BsanAllocator) -> Option<NonNull<T>> {
+        let mut shard = self.shard().lock();
+        if shard.free != 0 {
+            let block = shard.free;
+            shard.free = unsafe { *ptr::with_exposed_provenance::<usize>(block) };
+            return NonNull::new(ptr::with_exposed_provenance_mut(block));
+        }
+        if shard.end - shard.next < Self::BLOCK_SIZE {
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            let slab = unsafe { allocator.map_anonymous(ptr::null_mut(), SLAB_LEN, prot, 0) };
+            if slab.is_null() {
+                return None;
+            }
+            shard.next = slab.expose_provenance();
+            shard.end = shard.next + SLAB_LEN;
+        }
+        let block = shard.next;
+        shard.next += Self::BLOCK_SIZE;
+        NonNull::new(ptr::with_exposed_provenance_mut(block))
+    }
+
+    /// Returns `block` to the current thread's shard.
+    ///
+    /// # Safety
+    /// `block` must have been taken from this pool, and whatever it held must
+    /// have been dropped.
+    pub unsafe fn deallocate(&self, block: NonNull<T>) {
+        let mut shard = self.shard().lock();
+        *block.as_ptr().cast::<usize>() = shard.free;
+        shard.free = block.as_ptr().expose_provenance();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::TEST_ALLOCATOR;
+
+    #[test]
+    fn freed_blocks_are_reused() {
+        let pool = BlockPool::<[u64; 5]>::new();
+        let first = pool.allocate(&TEST_ALLOCATOR).unwrap();
+        let second = pool.allocate(&TEST_ALLOCATOR).unwrap();
+        assert_eq!(second.addr().get() - first.addr().get(), 40);
+        unsafe {
+            second.write([7; 5]);
+            pool.deallocate(first);
+        }
+        assert_eq!(pool.allocate(&TEST_ALLOCATOR), Some(first));
+        // Slabs are mapped as they run out.
+        let blocks: Vec<_> =
+            (0..SLAB_LEN / 40 + 1).map(|_| pool.allocate(&TEST_ALLOCATOR).unwrap()).collect();
+        assert!(blocks.iter().all(|block| block.addr().get() % 8 == 0));
+        assert_eq!(unsafe { second.read() }, [7; 5]);
+        assert_eq!(BlockPool::<u8>::BLOCK_SIZE, 8);
+    }
+}
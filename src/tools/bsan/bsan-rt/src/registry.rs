@@ -0,0 +1,743 @@
+use core::fmt;
+use core::ops::ControlFlow;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{self, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+use crate::arena::Arena;
+use crate::depot::StackRef;
+use crate::history::AllocHistory;
+use crate::sync::SpinLock;
+use crate::{AllocId, BorTag, Provenance, SourceInfo, epoch, stats};
+
+const METADATA_MAGIC: usize = 0xb5a7_a110;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AllocState {
+    Live,
+    Freed,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AllocKind {
+    Heap,
+    /// A global or static variable. These live until the program exits, or the
+    /// library that defines them is unloaded, and can't be freed.
+    Global,
+    /// A stack slot whose address is taken. These are retired when the frame
+    /// that made them returns, and can't be freed either.
+    Stack,
+    /// Memory mapped with `mmap`, such as the arenas of a custom allocator.
+    /// These are retired once all of their pages have been unmapped.
+    Mapping,
+}
+
+impl fmt::Display for AllocKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocKind::Heap => f.write_str("heap"),
+            AllocKind::Global => f.write_str("global"),
+            AllocKind::Stack => f.write_str("stack"),
+            AllocKind::Mapping => f.write_str("mapped"),
+        }
+    }
+}
+
+/// The metadata that the runtime keeps for each allocation. A pointer to this
+/// structure is carried in the `lock_address` field of every
+/// [`crate::Provenance`] derived from the allocation.
+///
+/// Metadata is reference counted. The registry holds one reference for as long
+/// as the allocation is live, and every `Provenance` that refers to it owns
+/// another: the provenance produced by `bsan_malloc` comes with a reference,
+/// copies are made with `bsan_clone_provenance` (or
+/// `bsan_retain_alloc_metadata`), and each copy is given up with
+/// `bsan_release_alloc_metadata` once the instrumented program can no longer
+/// use it. Provenance stored in shadow memory owns a reference until it is
+/// overwritten or cleared. The metadata is retired when the last reference is
+/// released, which is necessarily after the allocation has been freed, and
+/// deallocated once no hook can still be reading it.
+#[derive(Debug)]
+pub struct AllocMetadata {
+    // Set to `METADATA_MAGIC` while the metadata is valid, so that addresses
+    // passed in by instrumented code can be checked in strict ABI mode.
+    magic: usize,
+    pub id: AllocId,
+    pub base_addr: usize,
+    pub size: usize,
+    // The alignment that the allocation was requested with, or 1 if it came
+    // from an allocator that doesn't take one, like `malloc`.
+    pub align: usize,
+    pub root_tag: BorTag,
+    pub kind: AllocKind,
+    pub state: AllocState,
+    // The functions of the innermost frames that made the allocation and that
+    // freed it, for error reports. These are null if the function is unknown,
+    // and point to constants emitted by the pass otherwise.
+    pub created_in: *const SourceInfo,
+    pub freed_in: *const SourceInfo,
+    pub history: AllocHistory,
+    // The memory for the parts of the metadata that are allocated lazily,
+    // which is released when the metadata is deallocated.
+    pub arena: Arena,
+    // The call stack at which a heap allocation was made, if they are
+    // captured.
+    pub alloc_stack: Option<StackRef>,
+    // Whether a pointer to the allocation has been exposed, so that code the
+    // runtime can't see may hold pointers to it without provenance.
+    exposed: AtomicBool,
+    // The number of tags that were derived for pointers into the allocation,
+    // and whether that went past `BSAN_MAX_DERIVED_TAGS`, after which all of
+    // its pointers are treated alike.
+    derived_tags: AtomicUsize,
+    wildcard: AtomicBool,
+    refcount: AtomicUsize,
+    // The registry's intrusive interval tree.
+    node: TreeNode,
+    // For stack allocations, the depth of the frame that made them, and the
+    // next older stack allocation of the same thread.
+    pub(crate) frame_depth: usize,
+    pub(crate) older_in_stack: *mut AllocMetadata,
+}
+
+#[derive(Debug)]
+struct TreeNode {
+    left: *mut AllocMetadata,
+    right: *mut AllocMetadata,
+    height: u32,
+    // The largest `end()` of any allocation in this subtree.
+    max_end: usize,
+}
+
+impl AllocMetadata {
+    pub fn new(
+        id: AllocId,
+        base_addr: usize,
+        size: usize,
+        root_tag: BorTag,
+        kind: AllocKind,
+    ) -> Self {
+        Self {
+            magic: METADATA_MAGIC,
+            id,
+            base_addr,
+            size,
+            align: 1,
+            root_tag,
+            kind,
+            state: AllocState::Live,
+            created_in: ptr::null(),
+            freed_in: ptr::null(),
+            history: AllocHistory::new(),
+            arena: Arena::new(),
+            alloc_stack: None,
+            exposed: AtomicBool::new(false),
+            derived_tags: AtomicUsize::new(0),
+            wildcard: AtomicBool::new(false),
+            refcount: AtomicUsize::new(1),
+            node: TreeNode { left: ptr::null_mut(), right: ptr::null_mut(), height: 0, max_end: 0 },
+            frame_depth: 0,
+            older_in_stack: ptr::null_mut(),
+        }
+    }
+
+    /// Whether `meta` points to metadata that has not been deallocated yet.
+    ///
+    /// # Safety
+    /// `meta` must be valid for reads of an `AllocMetadata`.
+    pub unsafe fn is_valid(meta: *const Self) -> bool {
+        ptr::read_volatile(ptr::addr_of!((*meta).magic)) == METADATA_MAGIC
+    }
+
+    /// The provenance of the pointer returned by the allocator.
+    pub fn root_provenance(&self) -> Provenance {
+        Provenance {
+            alloc_id: self.id,
+            bor_tag: self.root_tag,
+            lock_address: (self as *const Self).cast_mut().cast(),
+        }
+    }
+
+    #[inline]
+    pub fn retain(&self) {
+        self.refcount.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gives up a reference. Returns `true` if it was the last one, in which
+    /// case the caller is responsible for deallocating the metadata.
+    #[inline]
+    pub fn release(&self) -> bool {
+        if self.refcount.fetch_sub(1, Ordering::Release) != 1 {
+            return false;
+        }
+        atomic::fence(Ordering::Acquire);
+        true
+    }
+
+    /// Records that a pointer to this allocation was exposed.
+    pub fn expose(&self) {
+        self.exposed.store(true, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn is_exposed(&self) -> bool {
+        self.exposed.load(Ordering::Relaxed)
+    }
+
+    /// Counts a tag derived for a pointer into this allocation. Returns `true`
+    /// if this is the first to go past `limit`, which makes the allocation a
+    /// wildcard.
+    pub fn derive_tag(&self, limit: usize) -> bool {
+        let derived = self.derived_tags.fetch_add(1, Ordering::Relaxed) + 1;
+        derived > limit && !self.wildcard.swap(true, Ordering::Relaxed)
+    }
+
+    /// Whether the allocation has had too many tags derived for it, so that
+    /// accesses to it are only checked against its bounds.
+    #[inline]
+    pub fn is_wildcard(&self) -> bool {
+        self.wildcard.load(Ordering::Relaxed)
+    }
+
+    /// Whether `addr` falls within this allocation. Zero-sized allocations
+    /// only contain their base address.
+    #[inline]
+    pub fn contains(&self, addr: usize) -> bool {
+        addr == self.base_addr || (addr > self.base_addr && addr - self.base_addr < self.size)
+    }
+
+    /// The end of the range of addresses that this allocation contains.
+    #[inline]
+    fn end(&self) -> usize {
+        self.base_addr.saturating_add(self.size.max(1))
+    }
+
+    /// Whether this allocation contains any address in `[start, end)`.
+    #[inline]
+    pub fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.base_addr < end && start < self.end()
+    }
+
+    // Allocations are ordered by their base addresses. IDs break ties, which
+    // only happen if the pass reports an allocation twice.
+    #[inline]
+    fn key(&self) -> (usize, AllocId) {
+        (self.base_addr, self.id)
+    }
+}
+
+// Metadata whose last reference was released somewhere that can't deallocate
+// it, such as the shadow heap. These are linked through their (unused) tree
+// nodes until the global context reclaims them.
+static PENDING_DEALLOC: AtomicPtr<AllocMetadata> = AtomicPtr::new(ptr::null_mut());
+
+impl AllocMetadata {
+    /// Queues `meta`, which has no references left, to be deallocated.
+    ///
+    /// # Safety
+    /// `meta` must not be registered or queued already.
+    pub unsafe fn defer_dealloc(meta: NonNull<Self>) {
+        let meta = meta.as_ptr();
+        let mut head = PENDING_DEALLOC.load(Ordering::Relaxed);
+        loop {
+            (*meta).node.left = head;
+            match PENDING_DEALLOC.compare_exchange_weak(
+                head,
+                meta,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Calls `f` on every metadata queued by [`AllocMetadata::defer_dealloc`],
+    /// removing them from the queue.
+    pub fn take_pending_dealloc(mut f: impl FnMut(NonNull<Self>)) {
+        let mut pending = PENDING_DEALLOC.swap(ptr::null_mut(), Ordering::Acquire);
+        while let Some(meta) = NonNull::new(pending) {
+            pending = unsafe { meta.as_ref().node.left };
+            f(meta);
+        }
+    }
+}
+
+/// Metadata that has no references left, but may still be read by hooks that
+/// were running on other threads when it was released. It is linked through
+/// its tree node, which also records the [epoch](crate::epoch) that it was
+/// retired at, in the order it was retired, until it can be freed.
+#[derive(Debug)]
+pub struct RetiredList {
+    list: SpinLock<RetiredQueue>,
+}
+
+#[derive(Debug)]
+struct RetiredQueue {
+    head: *mut AllocMetadata,
+    tail: *mut AllocMetadata,
+}
+
+unsafe impl Send for RetiredQueue {}
+
+impl Default for RetiredList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetiredList {
+    pub const fn new() -> Self {
+        Self { list: SpinLock::new(RetiredQueue { head: ptr::null_mut(), tail: ptr::null_mut() }) }
+    }
+
+    /// Retires `meta` at the current epoch, marking it invalid.
+    ///
+    /// # Safety
+    /// `meta` must have no references left, and must not be registered or
+    /// queued already.
+    pub unsafe fn push(&self, meta: NonNull<AllocMetadata>) {
+        let meta = meta.as_ptr();
+        ptr::write_volatile(&mut (*meta).magic, 0);
+        (*meta).node.left = ptr::null_mut();
+        let mut list = self.list.lock();
+        // The epoch is read under the lock, so the queue stays in order.
+        (*meta).node.max_end = epoch::current() as usize;
+        match list.tail.as_mut() {
+            Some(tail) => tail.node.left = meta,
+            None => list.head = meta,
+        }
+        list.tail = meta;
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.list.lock().head.is_null()
+    }
+
+    /// Removes the metadata that can be freed at `epoch` from the queue, and
+    /// calls `f` on each.
+    pub fn reclaim(&self, epoch: u64, mut f: impl FnMut(NonNull<AllocMetadata>)) {
+        let mut reclaimable = {
+            let mut list = self.list.lock();
+            let first = list.head;
+            let mut last = ptr::null_mut::<AllocMetadata>();
+            let mut current = list.head;
+            while let Some(meta) = unsafe { current.as_ref() } {
+                if !epoch::is_reclaimable(meta.node.max_end as u64, epoch) {
+                    break;
+                }
+                last = current;
+                current = meta.node.left;
+            }
+            if last.is_null() {
+                return;
+            }
+            list.head = current;
+            if current.is_null() {
+                list.tail = ptr::null_mut();
+            }
+            unsafe { (*last).node.left = ptr::null_mut() };
+            first
+        };
+        while let Some(meta) = NonNull::new(reclaimable) {
+            reclaimable = unsafe { meta.as_ref().node.left };
+            f(meta);
+        }
+    }
+}
+
+impl Drop for AllocMetadata {
+    fn drop(&mut self) {
+        // The write would otherwise be dead, since the memory is freed next.
+        unsafe { ptr::write_volatile(&mut self.magic, 0) };
+    }
+}
+
+#[derive(Debug)]
+struct RegistryTree {
+    root: *mut AllocMetadata,
+    len: usize,
+}
+
+unsafe impl Send for RegistryTree {}
+
+/// The set of live allocations. Their metadata is linked into an AVL tree
+/// ordered by base address, where each node also records the end of the
+/// furthest-reaching allocation below it, so that the allocations overlapping
+/// an address range can be found without visiting the rest.
+#[derive(Debug)]
+pub struct AllocRegistry {
+    tree: SpinLock<RegistryTree>,
+}
+
+impl AllocRegistry {
+    pub const fn new() -> Self {
+        Self { tree: SpinLock::new(RegistryTree { root: ptr::null_mut(), len: 0 }) }
+    }
+
+    /// Adds `meta` to the registry.
+    ///
+    /// # Safety
+    /// `meta` must be valid for as long as it is registered, and must not
+    /// already be registered.
+    pub unsafe fn insert(&self, meta: NonNull<AllocMetadata>) {
+        let mut tree = self.tree.lock();
+        let meta = meta.as_ptr();
+        (*meta).node =
+            TreeNode { left: ptr::null_mut(), right: ptr::null_mut(), height: 1, max_end: 0 };
+        update(meta);
+        tree.root = insert(tree.root, meta);
+        tree.len += 1;
+    }
+
+    /// Removes `meta` from the registry.
+    ///
+    /// # Safety
+    /// `meta` must currently be registered.
+    pub unsafe fn remove(&self, meta: NonNull<AllocMetadata>) {
+        let mut tree = self.tree.lock();
+        let meta = meta.as_ptr();
+        tree.root = remove(tree.root, meta);
+        (*meta).node.left = ptr::null_mut();
+        (*meta).node.right = ptr::null_mut();
+        tree.len -= 1;
+    }
+
+    /// Finds the live allocation containing `addr`, if any.
+    pub fn find(&self, addr: usize) -> Option<NonNull<AllocMetadata>> {
+        let mut found = None;
+        self.for_each_in_range(addr, addr.saturating_add(1), |meta| {
+            found = Some(NonNull::from(meta));
+            ControlFlow::Break(())
+        });
+        found
+    }
+
+    /// Finds the live allocation whose base address is `addr`, if any.
+    pub fn find_base(&self, addr: usize) -> Option<NonNull<AllocMetadata>> {
+        let mut found = None;
+        self.for_each_in_range(addr, addr.saturating_add(1), |meta| {
+            if meta.base_addr != addr {
+                return ControlFlow::Continue(());
+            }
+            found = Some(NonNull::from(meta));
+            ControlFlow::Break(())
+        });
+        found
+    }
+
+    /// Calls `f` with the live allocation containing `addr` or, failing that,
+    /// the one nearest to it within `window` bytes, while holding the registry
+    /// lock. Returns `None` without calling `f` if the registry is locked, as
+    /// it may be by a thread that a signal interrupted.
+    pub fn try_find_nearest<R>(
+        &self,
+        addr: usize,
+        window: usize,
+        f: impl FnOnce(Option<&AllocMetadata>) -> R,
+    ) -> Option<R> {
+        let tree = self.tree.try_lock()?;
+        let start = addr.saturating_sub(window);
+        let end = addr.saturating_add(window).saturating_add(1);
+        let mut nearest: Option<(usize, NonNull<AllocMetadata>)> = None;
+        unsafe {
+            visit(
+                tree.root,
+                start,
+                end,
+                &mut |meta| {
+                    let distance = if meta.contains(addr) {
+                        0
+                    } else if addr < meta.base_addr {
+                        meta.base_addr - addr
+                    } else {
+                        addr - meta.end() + 1
+                    };
+                    if nearest.is_none_or(|(nearest, _)| distance < nearest) {
+                        nearest = Some((distance, NonNull::from(meta)));
+                    }
+                    ControlFlow::Continue(())
+                },
+                &mut 0,
+            )
+        };
+        // The allocation can't be freed while the registry is locked.
+        Some(f(nearest.map(|(_, meta)| unsafe { meta.as_ref() })))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.lock().len
+    }
+
+    /// Calls `f` on every registered allocation, in order of their base
+    /// addresses, while holding the registry lock. `f` must not call back
+    /// into the registry.
+    pub fn for_each(&self, mut f: impl FnMut(&AllocMetadata)) {
+        self.for_each_in_range(0, usize::MAX, |meta| {
+            f(meta);
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Calls `f` on every registered allocation that contains an address in
+    /// `[start, end)`, in order of their base addresses, until it breaks.
+    /// The same restrictions apply as for [`AllocRegistry::for_each`].
+    pub fn for_each_in_range(
+        &self,
+        start: usize,
+        end: usize,
+        mut f: impl FnMut(&AllocMetadata) -> ControlFlow<()>,
+    ) {
+        let tree = self.tree.lock();
+        let mut visited = 0;
+        unsafe { visit(tree.root, start, end, &mut f, &mut visited) };
+        if stats::counting() {
+            stats::registry_lookup(visited);
+        }
+    }
+}
+
+unsafe fn visit(
+    node: *mut AllocMetadata,
+    start: usize,
+    end: usize,
+    f: &mut impl FnMut(&AllocMetadata) -> ControlFlow<()>,
+    // The number of nodes that were visited is added to `visited`.
+    visited: &mut u64,
+) -> ControlFlow<()> {
+    let Some(meta) = node.as_ref() else { return ControlFlow::Continue(()) };
+    *visited += 1;
+    if meta.node.max_end <= start {
+        return ControlFlow::Continue(());
+    }
+    visit(meta.node.left, start, end, f, visited)?;
+    if meta.base_addr >= end {
+        // Everything to the right starts even later.
+        return ControlFlow::Continue(());
+    }
+    if meta.overlaps(start, end) {
+        f(meta)?;
+    }
+    visit(meta.node.right, start, end, f, visited)
+}
+
+#[inline]
+unsafe fn height(node: *mut AllocMetadata) -> u32 {
+    node.as_ref().map_or(0, |meta| meta.node.height)
+}
+
+#[inline]
+unsafe fn max_end(node: *mut AllocMetadata) -> usize {
+    node.as_ref().map_or(0, |meta| meta.node.max_end)
+}
+
+// Recomputes the height and `max_end` of `node` from its children.
+unsafe fn update(node: *mut AllocMetadata) {
+    let meta = &mut *node;
+    meta.node.height = 1 + height(meta.node.left).max(height(meta.node.right));
+    meta.node.max_end = meta.end().max(max_end(meta.node.left)).max(max_end(meta.node.right));
+}
+
+unsafe fn rotate_right(node: *mut AllocMetadata) -> *mut AllocMetadata {
+    let left = (*node).node.left;
+    (*node).node.left = (*left).node.right;
+    (*left).node.right = node;
+    update(node);
+    update(left);
+    left
+}
+
+unsafe fn rotate_left(node: *mut AllocMetadata) -> *mut AllocMetadata {
+    let right = (*node).node.right;
+    (*node).node.right = (*right).node.left;
+    (*right).node.left = node;
+    update(node);
+    update(right);
+    right
+}
+
+// Restores the AVL invariant at `node`, whose subtrees are balanced and
+// differ in height by at most two.
+unsafe fn rebalance(node: *mut AllocMetadata) -> *mut AllocMetadata {
+    update(node);
+    let (left, right) = ((*node).node.left, (*node).node.right);
+    if height(left) > height(right) + 1 {
+        if height((*left).node.left) < height((*left).node.right) {
+            (*node).node.left = rotate_left(left);
+        }
+        return rotate_right(node);
+    }
+    if height(right) > height(left) + 1 {
+        if height((*right).node.right) < height((*right).node.left) {
+            (*node).node.right = rotate_right(right);
+        }
+        return rotate_left(node);
+    }
+    node
+}
+
+unsafe fn insert(node: *mut AllocMetadata, meta: *mut AllocMetadata) -> *mut AllocMetadata {
+    if node.is_null() {
+        return meta;
+    }
+    if (*meta).key() < (*node).key() {
+        (*node).node.left = insert((*node).node.left, meta);
+    } else {
+        (*node).node.right = insert((*node).node.right, meta);
+    }
+    rebalance(node)
+}
+
+unsafe fn remove(node: *mut AllocMetadata, meta: *mut AllocMetadata) -> *mut AllocMetadata {
+    debug_assert!(!node.is_null(), "removing an allocation that isn't registered");
+    if node != meta {
+        if (*meta).key() < (*node).key() {
+            (*node).node.left = remove((*node).node.left, meta);
+        } else {
+            (*node).node.right = remove((*node).node.right, meta);
+        }
+        return rebalance(node);
+    }
+    let (left, right) = ((*node).node.left, (*node).node.right);
+    if right.is_null() {
+        return left;
+    }
+    // Replace the node with the leftmost node of its right subtree.
+    let (right, successor) = remove_min(right);
+    (*successor).node.left = left;
+    (*successor).node.right = right;
+    rebalance(successor)
+}
+
+// Detaches the leftmost node of the subtree, returning the new subtree and
+// the detached node.
+unsafe fn remove_min(node: *mut AllocMetadata) -> (*mut AllocMetadata, *mut AllocMetadata) {
+    let left = (*node).node.left;
+    if left.is_null() {
+        return ((*node).node.right, node);
+    }
+    let (left, min) = remove_min(left);
+    (*node).node.left = left;
+    (rebalance(node), min)
+}
+
+impl Default for AllocRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn insert_find_remove() {
+        let registry = AllocRegistry::new();
+        let mut a =
+            AllocMetadata::new(AllocId::new(1), 0x1000, 16, BorTag::new(1), AllocKind::Heap);
+        let mut b = AllocMetadata::new(AllocId::new(2), 0x2000, 0, BorTag::new(2), AllocKind::Heap);
+        unsafe {
+            registry.insert(NonNull::from(&mut a));
+            registry.insert(NonNull::from(&mut b));
+        }
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.find(0x100f), Some(NonNull::from(&mut a)));
+        assert_eq!(registry.find(0x1010), None);
+        assert_eq!(registry.find(0x2000), Some(NonNull::from(&mut b)));
+        unsafe { registry.remove(NonNull::from(&mut a)) };
+        assert_eq!(registry.len(), 1);
+        assert_eq!(registry.find(0x1000), None);
+        assert_eq!(registry.find(0x2000), Some(NonNull::from(&mut b)));
+    }
+
+    #[test]
+    fn dropped_metadata_is_invalid() {
+        let mut meta =
+            AllocMetadata::new(AllocId::new(1), 0x1000, 16, BorTag::new(1), AllocKind::Heap);
+        let ptr = ptr::addr_of_mut!(meta);
+        unsafe {
+            assert!(AllocMetadata::is_valid(ptr));
+            ptr.drop_in_place();
+            assert!(!AllocMetadata::is_valid(ptr));
+        }
+    }
+
+    #[test]
+    fn retired_metadata_is_reclaimed_two_epochs_later() {
+        let retired = RetiredList::new();
+        let mut metas = [1, 2].map(|id| {
+            AllocMetadata::new(AllocId::new(id), 0x1000, 16, BorTag::new(1), AllocKind::Heap)
+        });
+        let epoch = epoch::current();
+        for meta in &mut metas {
+            unsafe { retired.push(NonNull::from(meta)) };
+        }
+        assert!(!unsafe { AllocMetadata::is_valid(&metas[0]) });
+        let mut reclaimed = Vec::new();
+        retired.reclaim(epoch + 1, |meta| reclaimed.push(unsafe { meta.as_ref().id }));
+        assert!(reclaimed.is_empty() && !retired.is_empty());
+        retired.reclaim(epoch + 2, |meta| reclaimed.push(unsafe { meta.as_ref().id }));
+        assert_eq!(reclaimed, [AllocId::new(1), AllocId::new(2)]);
+        assert!(retired.is_empty());
+    }
+
+    unsafe fn check_balanced(node: *mut AllocMetadata) -> u32 {
+        let Some(meta) = node.as_ref() else { return 0 };
+        let left = check_balanced(meta.node.left);
+        let right = check_balanced(meta.node.right);
+        assert!(left.abs_diff(right) <= 1);
+        assert_eq!(meta.node.height, 1 + left.max(right));
+        left.max(right) + 1
+    }
+
+    proptest! {
+        #[test]
+        fn range_queries_agree_with_scan(
+            allocs in prop::collection::vec((0..4096usize, 0..64usize), 1..64),
+            removed in prop::collection::vec(any::<prop::sample::Index>(), 0..32),
+            queries in prop::collection::vec((0..4200usize, 0..128usize), 1..16),
+        ) {
+            let registry = AllocRegistry::new();
+            let mut metas: Vec<Box<AllocMetadata>> = allocs
+                .iter()
+                .enumerate()
+                .map(|(i, &(base, size))| {
+                    Box::new(AllocMetadata::new(AllocId::new(i + 1), base, size, BorTag::new(1), AllocKind::Heap))
+                })
+                .collect();
+            for meta in &mut metas {
+                unsafe { registry.insert(NonNull::from(&mut **meta)) };
+            }
+            let mut live: Vec<bool> = vec![true; metas.len()];
+            for index in removed {
+                let i = index.index(metas.len());
+                if live[i] {
+                    unsafe { registry.remove(NonNull::from(&mut *metas[i])) };
+                    live[i] = false;
+                }
+            }
+            prop_assert_eq!(registry.len(), live.iter().filter(|&&live| live).count());
+            unsafe { check_balanced(registry.tree.lock().root) };
+            for (start, len) in queries {
+                let mut found = Vec::new();
+                registry.for_each_in_range(start, start + len, |meta| {
+                    found.push(meta.id);
+                    ControlFlow::Continue(())
+                });
+                let mut expected: Vec<(usize, AllocId)> = metas
+                    .iter()
+                    .zip(&live)
+                    .filter(|&(meta, &live)| live && meta.overlaps(start, start + len))
+                    .map(|(meta, _)| meta.key())
+                    .collect();
+                expected.sort();
+                let expected: Vec<AllocId> = expected.into_iter().map(|(_, id)| id).collect();
+                prop_assert_eq!(found, expected);
+            }
+        }
+    }
+}
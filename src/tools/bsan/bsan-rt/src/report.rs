@@ -0,0 +1,949 @@
+//! Reports of the errors found in the instrumented program.
+//!
+//! Reports are written along with the rest of the runtime's output, to
+//! standard error or the file in `BSAN_LOG_PATH`, unless `BSAN_OUTPUT_FD`
+//! names another file descriptor. They are text by default. Text reports show
+//! the line of source that the error is in, if the pass passed its location
+//! and the file can be read, and are colored if they are written to a
+//! terminal. `BSAN_COLOR` can be set to `always` or `never` instead of `auto`,
+//! and `NO_COLOR` is respected. With `BSAN_OUTPUT_FORMAT=json`, each report is
+//! a single line holding a JSON object instead, for tools that aggregate the
+//! results of many runs:
+//!
+//! ```text
+//! {"kind": "out-of-bounds", "severity": "error" | "warning", "message": "invalid read of ...",
+//!  "location": {"function": ..., "file": ..., "line": ..., "column": ...} | null,
+//!  "access": {"kind": "read", "address": "0x...", "size": 8} | null,
+//!  "tag": {"id": 12, "root": false, "protected": false, "protected_in": <function>} | null,
+//!  "allocation": {"id": 3, "kind": "heap", "base": "0x...", "size": 16, "align": 1,
+//!                 "state": "freed", "root_tag": 5, "created_in": <function>,
+//!                 "freed_in": <function>,
+//!                 "history": [{"kind": "read" | "write" | "retag" | "expose",
+//!                              "offset": "0x...", "size": 8 | null, "tag": 12 | null,
+//!                              "thread": 1, "function": <function>}, ...],
+//!                 "stack": <stack> | null} | null,
+//!  "stack": [{"pc": "0x...", "function": ..., "location": "file:line:column",
+//!             "module": ..., "offset": "0x..."}, ...]}
+//! ```
+//!
+//! Addresses are hexadecimal strings, since they may not fit in the integers
+//! that JSON parsers support. Anything that isn't known is `null`, as are
+//! addresses with `BSAN_DETERMINISTIC=1`, and functions are given as
+//! locations. The stack of the allocation, which is only captured with
+//! `BSAN_ALLOC_STACK_DEPTH`, is written as that of the report is.
+//!
+//! With `BSAN_DETERMINISTIC=1`, reports leave out addresses, which change from
+//! run to run, and allocation IDs and tags are counted per thread, so that they
+//! only depend on the order in which threads start. The reports of two runs,
+//! or of two builds of the same program, can then be compared line by line.
+
+use core::ffi::{c_char, c_int};
+use core::fmt::{self, Write};
+use core::hash::{Hash, Hasher};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::access::{AccessError, AccessHistory, AccessKind};
+use crate::backtrace::Backtrace;
+use crate::dedup::{Admission, FnvHasher};
+use crate::global::{self, GlobalContext};
+use crate::history::EventKind;
+use crate::io::{self, FdWriter, SliceWriter};
+use crate::registry::{AllocMetadata, AllocState};
+use crate::{BorTag, SourceInfo, frame, location};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Where and how errors are reported.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OutputOptions {
+    pub format: OutputFormat,
+    /// The file descriptor that reports are written to, if it isn't the
+    /// runtime's log, which the program can move while it runs.
+    pub fd: Option<c_int>,
+    /// Whether text reports are colored, or `None` to color them only when
+    /// they are written to a terminal.
+    pub color: Option<bool>,
+    /// Whether addresses, which change from run to run, are left out of
+    /// reports, as they are with `BSAN_DETERMINISTIC=1`.
+    pub hide_addresses: bool,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutputOptions {
+    pub const fn new() -> Self {
+        Self { format: OutputFormat::Text, fd: None, color: Some(false), hide_addresses: false }
+    }
+
+    /// The file descriptor that reports are written to.
+    pub fn fd(&self) -> c_int {
+        self.fd.unwrap_or_else(io::log_fd)
+    }
+
+    /// Whether reports that are written to `fd` are colored.
+    pub fn color(&self, fd: c_int) -> bool {
+        self.color.unwrap_or_else(|| unsafe { libc::isatty(fd) } == 1)
+    }
+
+    /// Reads the output options from the environment, warning about and
+    /// ignoring any that are invalid.
+    pub fn from_env() -> Self {
+        let mut options = Self::new();
+        if let Some(format) = io::env_str(c"BSAN_OUTPUT_FORMAT") {
+            match format {
+                "text" => options.format = OutputFormat::Text,
+                "json" => options.format = OutputFormat::Json,
+                _ => {
+                    let _ = writeln!(FdWriter::log(), "bsan: unknown output format `{format}`");
+                }
+            }
+        }
+        if let Some(fd) = io::env_str(c"BSAN_OUTPUT_FD") {
+            match fd.trim().parse() {
+                Ok(fd) if fd >= 0 => options.fd = Some(fd),
+                _ => {
+                    let _ = writeln!(FdWriter::log(), "bsan: invalid output fd `{fd}`");
+                }
+            }
+        }
+        options.color = match io::env_str(c"BSAN_COLOR").unwrap_or("auto") {
+            "always" => Some(true),
+            "never" => Some(false),
+            mode => {
+                if mode != "auto" {
+                    let _ = writeln!(FdWriter::log(), "bsan: unknown color mode `{mode}`");
+                }
+                // As at https://no-color.org, an empty `NO_COLOR` is ignored.
+                let allowed = io::env_str(c"NO_COLOR").is_none_or(str::is_empty)
+                    && io::env_str(c"TERM") != Some("dumb");
+                if allowed { None } else { Some(false) }
+            }
+        };
+        options.hide_addresses = io::env_flag(c"BSAN_DETERMINISTIC");
+        options
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An invalid access, or a pointer stored outside of the address space.
+    Access(AccessError),
+    /// A free of memory that isn't a live heap allocation.
+    InvalidFree,
+    /// A reallocation of memory that isn't a live heap allocation, or through
+    /// a pointer to another allocation.
+    InvalidRealloc,
+    /// A deallocation through `GlobalAlloc` with a layout other than the one
+    /// that the memory was allocated with.
+    LayoutMismatch,
+    /// An access through a pointer without provenance, which is checked
+    /// against the allocation at its address instead, as with the wildcard
+    /// provenance of pointers cast from integers.
+    WildcardAccess,
+}
+
+impl ErrorKind {
+    pub const COUNT: usize = 9;
+
+    /// The names of the kinds in JSON reports and in the summary at exit, by
+    /// their index.
+    pub const NAMES: [&'static str; Self::COUNT] = [
+        "null-pointer",
+        "unknown-memory",
+        "invalid-address",
+        "out-of-bounds",
+        "use-after-free",
+        "invalid-free",
+        "invalid-realloc",
+        "layout-mismatch",
+        "wildcard-access",
+    ];
+
+    /// The index of the kind in [`ErrorKind::NAMES`], which ignores the
+    /// details of access errors.
+    pub const fn index(self) -> usize {
+        match self {
+            ErrorKind::Access(AccessError::NullPointer) => 0,
+            ErrorKind::Access(AccessError::UnknownMemory) => 1,
+            ErrorKind::Access(AccessError::InvalidAddress) => 2,
+            ErrorKind::Access(AccessError::OutOfBounds { .. }) => 3,
+            ErrorKind::Access(AccessError::UseAfterFree { .. }) => 4,
+            ErrorKind::InvalidFree => 5,
+            ErrorKind::InvalidRealloc => 6,
+            ErrorKind::LayoutMismatch => 7,
+            ErrorKind::WildcardAccess => 8,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        Self::NAMES[self.index()]
+    }
+}
+
+/// How a kind of error is treated.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Severity {
+    /// Undefined behavior, which fails the run: it is counted in the `errors`
+    /// of [`Stats`](crate::Stats), and halts the process with
+    /// `BSAN_HALT_ON_ERROR=1`.
+    Error,
+    /// Behavior that is accepted but dubious, which is reported and counted
+    /// separately but never halts the process.
+    Warning,
+    /// Neither reported nor counted.
+    Ignore,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Ignore => "ignore",
+        }
+    }
+}
+
+/// The severity of each kind of error, by [`ErrorKind::index`].
+///
+/// Every kind is an error, except for wildcard accesses, which are ignored:
+/// programs that take most of their pointers from uninstrumented code make
+/// many of them, and may still be correct. With `BSAN_STRICT_PROVENANCE`, they
+/// are errors too. `BSAN_SEVERITY` overrides these as
+/// a list of `kind=severity`, such as
+/// `out-of-bounds=warning,wildcard-access=warning`, in which `all` stands for
+/// every kind and later entries win. This lets large codebases adopt bsan one
+/// kind of error at a time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Severities([Severity; ErrorKind::COUNT]);
+
+impl Default for Severities {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Severities {
+    pub const fn new() -> Self {
+        let mut severities = [Severity::Error; ErrorKind::COUNT];
+        severities[ErrorKind::WildcardAccess.index()] = Severity::Ignore;
+        Self(severities)
+    }
+
+    /// Reads the overrides in `BSAN_SEVERITY`, warning about and ignoring any
+    /// that are invalid. Wildcard accesses are errors unless overridden if
+    /// `strict_provenance` is set.
+    pub fn from_env(strict_provenance: bool) -> Self {
+        let mut severities = Self::new();
+        if strict_provenance {
+            severities.0[ErrorKind::WildcardAccess.index()] = Severity::Error;
+        }
+        if let Some(overrides) = io::env_str(c"BSAN_SEVERITY") {
+            severities.apply(overrides, |entry| {
+                let _ = writeln!(FdWriter::log(), "bsan: invalid severity override `{entry}`");
+            });
+        }
+        severities
+    }
+
+    /// Applies the list of `overrides`, calling `invalid` with each entry that
+    /// is invalid.
+    pub fn apply(&mut self, overrides: &str, mut invalid: impl FnMut(&str)) {
+        for entry in overrides.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let Some((kind, severity)) = entry.split_once('=') else {
+                invalid(entry);
+                continue;
+            };
+            let severity = match severity.trim() {
+                "error" => Severity::Error,
+                "warning" => Severity::Warning,
+                "ignore" => Severity::Ignore,
+                _ => {
+                    invalid(entry);
+                    continue;
+                }
+            };
+            match kind.trim() {
+                "all" => self.0 = [severity; ErrorKind::COUNT],
+                kind => match ErrorKind::NAMES.iter().position(|&name| name == kind) {
+                    Some(index) => self.0[index] = severity,
+                    None => invalid(entry),
+                },
+            }
+        }
+    }
+
+    #[inline]
+    pub fn of(&self, kind: ErrorKind) -> Severity {
+        self.0[kind.index()]
+    }
+
+    /// The severity of each kind, by [`ErrorKind::index`].
+    pub fn by_index(&self) -> [Severity; ErrorKind::COUNT] {
+        self.0
+    }
+}
+
+/// An error in the instrumented program, along with what the runtime knows
+/// about the state that led to it.
+pub struct Report<'a> {
+    pub kind: ErrorKind,
+    pub message: fmt::Arguments<'a>,
+    /// The code that the error is in, if the pass passed it to the hook.
+    pub loc: Option<&'a SourceInfo>,
+    /// The kind, address and size of the access that the error is about.
+    pub access: Option<(AccessKind, usize, usize)>,
+    /// The tag of the pointer that was used, and the allocation that it was
+    /// checked against.
+    pub tag: BorTag,
+    pub alloc: Option<&'a AllocMetadata>,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(kind: ErrorKind, loc: Option<&'a SourceInfo>, message: fmt::Arguments<'a>) -> Self {
+        Self { kind, message, loc, access: None, tag: BorTag::INVALID, alloc: None }
+    }
+}
+
+/// An error as it is passed to the callback set with
+/// `bsan_set_error_callback`. Its pointers are only valid during the call.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ErrorInfo {
+    /// The kind of the error, by its name in `BSAN_SEVERITY`, such as
+    /// `out-of-bounds`.
+    pub kind: *const c_char,
+    /// Whether the kind is reported as a warning rather than as an error.
+    pub is_warning: bool,
+    /// The message of the error, which is truncated if it is long.
+    pub message: *const c_char,
+    /// The code that the error is in, or null if the pass didn't pass it.
+    pub loc: *const SourceInfo,
+    /// The address and size of the access that the error is about, which are
+    /// 0 if it isn't about an access.
+    pub address: usize,
+    pub access_size: usize,
+    pub is_write: bool,
+    /// The allocation that the pointer was checked against, or 0.
+    pub alloc_id: usize,
+    /// The tag of the pointer, or 0.
+    pub tag: u64,
+    /// The return addresses of the call stack of the error, innermost first.
+    pub frames: *const usize,
+    pub num_frames: usize,
+}
+
+/// A function that is called with each error before it is reported, which
+/// returns whether it handled the error. Errors that it handles are neither
+/// reported nor counted, and don't halt the process.
+pub type ErrorCallback = Option<unsafe extern "C" fn(error: *const ErrorInfo) -> bool>;
+
+static ERROR_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_error_callback(callback: ErrorCallback) {
+    let callback = callback.map_or(0, |callback| callback as *const () as usize);
+    ERROR_CALLBACK.store(callback, Ordering::Release);
+}
+
+fn error_callback() -> ErrorCallback {
+    // Null is `None`, as every other value came from a function pointer.
+    unsafe { core::mem::transmute::<usize, ErrorCallback>(ERROR_CALLBACK.load(Ordering::Acquire)) }
+}
+
+// The longest message that is passed to the error callback, with its NUL.
+const CALLBACK_MESSAGE_LEN: usize = 512;
+
+/// Passes `report` to the error callback, if there is one, returning whether
+/// it handled the error.
+fn run_error_callback(report: &Report<'_>, severity: Severity, trace: &Backtrace) -> bool {
+    let Some(callback) = error_callback() else { return false };
+    let (mut kind, mut message) = ([0; 32], [0; CALLBACK_MESSAGE_LEN]);
+    let mut out = SliceWriter::new(&mut kind);
+    let _ = out.write_str(report.kind.name());
+    out.finish();
+    let mut out = SliceWriter::new(&mut message);
+    let _ = out.write_fmt(report.message);
+    out.finish();
+    let (access_kind, address, access_size) = report.access.unwrap_or((AccessKind::Read, 0, 0));
+    let frames = trace.frames();
+    let info = ErrorInfo {
+        kind: kind.as_ptr().cast(),
+        is_warning: severity == Severity::Warning,
+        message: message.as_ptr().cast(),
+        loc: report.loc.map_or(ptr::null(), ptr::from_ref),
+        address,
+        access_size,
+        is_write: access_kind == AccessKind::Write,
+        alloc_id: report.alloc.map_or(0, |meta| meta.id.get()),
+        tag: report.tag.get(),
+        frames: frames.as_ptr(),
+        num_frames: frames.len(),
+    };
+    unsafe { callback(&info) }
+}
+
+/// Reports an error, which is counted towards the summary printed by
+/// `bsan_exit` even if it isn't printed because it repeats an earlier one or is
+/// past `BSAN_MAX_ERRORS`. With `BSAN_HALT_ON_ERROR=1`, the process then exits
+/// immediately with the status in `BSAN_EXITCODE`, which defaults to 1.
+/// Otherwise, the program continues, so that a single run can find many errors.
+/// The innermost frames of the call stack that is reported are those of the
+/// hook that found the error. Kinds whose [`Severity`] is a warning are
+/// reported and counted as warnings, which never halt the process, and ignored
+/// kinds aren't reported. Before any of that, the error is passed to the
+/// callback set with `bsan_set_error_callback`, which may handle it instead.
+#[cold]
+#[inline(never)]
+pub fn report(report: &Report<'_>) {
+    if global::has_exited() {
+        return;
+    }
+    let ctx = unsafe { global::global_ctx() };
+    let severity = ctx.severities().of(report.kind);
+    if severity == Severity::Ignore {
+        return;
+    }
+    // This frame only leads to the hook.
+    let trace = Backtrace::capture(1);
+    if run_error_callback(report, severity, &trace) {
+        return;
+    }
+    match severity {
+        Severity::Warning => ctx.stats().warning(report.kind),
+        _ => ctx.stats().error(report.kind),
+    }
+    if !ctx.reported_errors().record(identity(report, &trace)) {
+        return;
+    }
+    let admission = ctx.report_limit().admit();
+    if admission == Admission::Drop {
+        return;
+    }
+    let output = ctx.output();
+    let fd = output.fd();
+    let mut out = FdWriter::new(fd);
+    let _ = match output.format {
+        OutputFormat::Text => write_text(ctx, report, &trace, output.color(fd), &mut out),
+        OutputFormat::Json => write_json(ctx, report, &trace, &mut out),
+    };
+    let limit = ctx.report_limit();
+    if admission == Admission::ReportLast {
+        let max = limit.max();
+        let _ = if limit.exits() {
+            writeln!(out, "bsan: exiting after BSAN_MAX_ERRORS={max} errors")
+        } else {
+            writeln!(
+                out,
+                "bsan: reported BSAN_MAX_ERRORS={max} errors; later errors are only counted"
+            )
+        };
+    }
+    let halt = severity == Severity::Error && ctx.flags().halt_on_error;
+    if halt || (admission == Admission::ReportLast && limit.exits()) {
+        out.flush();
+        global::die(ctx.flags().exit_code)
+    }
+}
+
+/// The hash of what identifies an error as a repeat of another: its kind, its
+/// site, and the function that made the allocation that it is about. The site
+/// is the source location that the pass passed to the hook or, without one,
+/// the call stack, which starts at the return address into the faulting code.
+fn identity(report: &Report<'_>, trace: &Backtrace) -> u64 {
+    let mut hasher = FnvHasher::new();
+    report.kind.name().hash(&mut hasher);
+    match report.loc {
+        Some(loc) => {
+            unsafe { location::name(loc.file) }.hash(&mut hasher);
+            (loc.line, loc.column).hash(&mut hasher);
+        }
+        None => trace.frames().hash(&mut hasher),
+    }
+    report.alloc.map(|meta| meta.created_in.addr()).hash(&mut hasher);
+    hasher.finish()
+}
+
+// The SGR parameters of the parts of colored text reports.
+const STYLE_ERROR: &str = "1;31";
+const STYLE_WARNING: &str = "1;33";
+const STYLE_MESSAGE: &str = "1";
+const STYLE_GUTTER: &str = "1;34";
+const STYLE_FUNCTION: &str = "1";
+const STYLE_MODULE: &str = "2";
+const STYLE_NOTE: &str = "36";
+
+// The longest source line that is shown in a snippet.
+const SNIPPET_LEN: usize = 256;
+
+/// `value`, displayed in `style` if `color` is set.
+struct Styled<T> {
+    style: &'static str,
+    value: T,
+    color: bool,
+}
+
+fn styled<T: fmt::Display>(color: bool, style: &'static str, value: T) -> Styled<T> {
+    Styled { style, value, color }
+}
+
+impl<T: fmt::Display> fmt::Display for Styled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.color {
+            return self.value.fmt(f);
+        }
+        write!(f, "\x1b[{}m{}\x1b[0m", self.style, self.value)
+    }
+}
+
+/// An address in a report, which is shown as `<address>` if the global
+/// context hides addresses, unless it is null.
+#[derive(Debug, Copy, Clone)]
+pub struct Addr(pub usize);
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hidden =
+            global::initialized_global_ctx().is_some_and(|ctx| ctx.output().hide_addresses);
+        if hidden && self.0 != 0 { f.write_str("<address>") } else { write!(f, "{:#x}", self.0) }
+    }
+}
+
+/// Bytes displayed as text, with invalid UTF-8 replaced.
+pub struct Bytes<'a>(pub &'a [u8]);
+
+impl fmt::Display for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        location::write_lossy(f, self.0)
+    }
+}
+
+fn write_text(
+    ctx: &GlobalContext,
+    report: &Report<'_>,
+    trace: &Backtrace,
+    color: bool,
+    out: &mut impl Write,
+) -> fmt::Result {
+    match ctx.severities().of(report.kind) {
+        Severity::Warning => write!(out, "{} ", styled(color, STYLE_WARNING, "bsan: warning:"))?,
+        _ => write!(out, "{} ", styled(color, STYLE_ERROR, "bsan:"))?,
+    }
+    writeln!(out, "{}", styled(color, STYLE_MESSAGE, report.message))?;
+    if let Some(loc) = report.loc {
+        writeln!(out, "    at {loc}")?;
+        write_snippet(loc, color, out)?;
+    } else if let Some(func) = unsafe { frame::current_function() } {
+        writeln!(out, "    in {func}")?;
+    }
+    write_stack(ctx, trace, color, out)?;
+    if let (Some((_, addr, size)), Some(meta)) = (report.access, report.alloc) {
+        let history = AccessHistory { meta, addr, size, tag: report.tag };
+        write!(out, "{}", styled(color, STYLE_NOTE, history))?;
+    }
+    if let Some((meta, stack)) = report.alloc.and_then(|meta| Some((meta, meta.alloc_stack?))) {
+        let made_at = format_args!("    allocation {} was made at:", meta.id.get());
+        writeln!(out, "{}", styled(color, STYLE_NOTE, made_at))?;
+        write_stack(ctx, &stack.to_backtrace(), color, out)?;
+    }
+    Ok(())
+}
+
+/// Writes the line of source that `loc` is in, with a caret under its column,
+/// as rustc does, if the file can be read.
+fn write_snippet(loc: &SourceInfo, color: bool, out: &mut impl Write) -> fmt::Result {
+    let mut line = [0; SNIPPET_LEN];
+    if loc.file.is_null() || loc.line == 0 {
+        return Ok(());
+    }
+    let Some(len) = (unsafe { io::read_line(loc.file, loc.line as usize, &mut line) }) else {
+        return Ok(());
+    };
+    let line = &line[..len];
+    let number = loc.line;
+    let width = number.ilog10() as usize + 1;
+    let gutter = styled(color, STYLE_GUTTER, "|");
+    writeln!(out, "    {:width$} {gutter}", "")?;
+    let number = format_args!("{number:>width$} |");
+    writeln!(out, "    {} {}", styled(color, STYLE_GUTTER, number), Bytes(line))?;
+    write!(out, "    {:width$} {gutter}", "")?;
+    if loc.column != 0 {
+        out.write_char(' ')?;
+        // Tabs are kept, so that the caret lines up however they are shown.
+        let indent = (loc.column as usize - 1).min(line.len());
+        for &byte in &line[..indent] {
+            // Only the first byte of each character takes up a column.
+            if byte & 0xc0 != 0x80 {
+                out.write_char(if byte == b'\t' { '\t' } else { ' ' })?;
+            }
+        }
+        write!(out, "{}", styled(color, STYLE_ERROR, "^"))?;
+    }
+    writeln!(out)
+}
+
+/// Writes each frame of `trace` on a line of its own, as
+/// `    #N 0xADDR in function file:line:column`, or with as much of that as is
+/// known, with the numbers and addresses of the frames aligned.
+pub fn write_stack(
+    ctx: &GlobalContext,
+    trace: &Backtrace,
+    color: bool,
+    out: &mut impl Write,
+) -> fmt::Result {
+    let frames = trace.frames();
+    let index_width = frames.len().saturating_sub(1).max(1).ilog10() as usize + 1;
+    let pc_width = frames.iter().map(|pc| (pc | 1).ilog2() as usize / 4 + 1).max().unwrap_or(1);
+    let hide_addresses = ctx.output().hide_addresses;
+    let mut i = 0;
+    ctx.symbolizer().for_each_frame(trace, |frame| {
+        write!(out, "    #{i:<index_width$}")?;
+        if !hide_addresses {
+            write!(out, " 0x{:0pc_width$x}", frame.pc)?;
+        }
+        i += 1;
+        match (frame.function, frame.symbol) {
+            (Some(function), _) => {
+                write!(out, " in {}", styled(color, STYLE_FUNCTION, Bytes(function)))?
+            }
+            (None, Some((symbol, offset))) => {
+                let symbol = format_args!("{}+{offset:#x}", Bytes(symbol.to_bytes()));
+                write!(out, " in {}", styled(color, STYLE_FUNCTION, symbol))?
+            }
+            (None, None) => {}
+        }
+        match (frame.location, frame.module) {
+            (Some(location), _) => write!(out, " {}", Bytes(location))?,
+            (None, Some((module, offset))) => {
+                let module = format_args!("({}+{offset:#x})", Bytes(module.to_bytes()));
+                write!(out, " {}", styled(color, STYLE_MODULE, module))?
+            }
+            (None, None) => {}
+        }
+        writeln!(out)
+    })
+}
+
+fn write_json(
+    ctx: &GlobalContext,
+    report: &Report<'_>,
+    trace: &Backtrace,
+    out: &mut impl Write,
+) -> fmt::Result {
+    let severity = ctx.severities().of(report.kind).name();
+    write!(out, "{{\"kind\":\"{}\",\"severity\":\"{severity}\",\"message\":", report.kind.name())?;
+    write_json_str(out, Lossy::Args(report.message))?;
+    out.write_str(",\"location\":")?;
+    // The location falls back to the current function, as in text reports.
+    write_json_location(out, report.loc.or_else(|| unsafe { frame::current_function() }))?;
+    out.write_str(",\"access\":")?;
+    match report.access {
+        Some((kind, addr, size)) => {
+            write!(out, "{{\"kind\":\"{kind}\",\"address\":")?;
+            write_json_addr(ctx, out, addr)?;
+            write!(out, ",\"size\":{size}}}")?
+        }
+        None => out.write_str("null")?,
+    }
+    out.write_str(",\"tag\":")?;
+    if report.tag.is_valid() {
+        let root = report.alloc.is_some_and(|meta| meta.root_tag == report.tag);
+        let depth = frame::protector_depth(report.tag);
+        write!(
+            out,
+            "{{\"id\":{},\"root\":{root},\"protected\":{},\"protected_in\":",
+            report.tag.get(),
+            depth.is_some()
+        )?;
+        write_json_location(out, depth.and_then(|depth| unsafe { frame::function_at(depth) }))?;
+        out.write_char('}')?;
+    } else {
+        out.write_str("null")?;
+    }
+    out.write_str(",\"allocation\":")?;
+    match report.alloc {
+        Some(meta) => {
+            let state = if meta.state == AllocState::Freed { "freed" } else { "live" };
+            write!(out, "{{\"id\":{},\"kind\":\"{}\",\"base\":", meta.id.get(), meta.kind)?;
+            write_json_addr(ctx, out, meta.base_addr)?;
+            write!(
+                out,
+                ",\"size\":{},\"align\":{},\"state\":\"{state}\",\"root_tag\":{},\"created_in\":",
+                meta.size,
+                meta.align,
+                meta.root_tag.get()
+            )?;
+            write_json_location(out, unsafe { meta.created_in.as_ref() })?;
+            out.write_str(",\"freed_in\":")?;
+            write_json_location(out, unsafe { meta.freed_in.as_ref() })?;
+            out.write_str(",\"history\":[")?;
+            for (i, event) in meta.history.events().enumerate() {
+                if i > 0 {
+                    out.write_char(',')?;
+                }
+                write!(
+                    out,
+                    "{{\"kind\":\"{}\",\"offset\":\"{:#x}\",",
+                    event.kind.name(),
+                    event.offset
+                )?;
+                match event.kind {
+                    EventKind::Access(_) => write!(out, "\"size\":{},", event.size)?,
+                    _ => out.write_str("\"size\":null,")?,
+                }
+                match event.tag.is_valid() {
+                    true => write!(out, "\"tag\":{},", event.tag.get())?,
+                    false => out.write_str("\"tag\":null,")?,
+                }
+                write!(out, "\"thread\":{},\"function\":", event.thread.get())?;
+                write_json_location(out, unsafe { event.function.as_ref() })?;
+                out.write_char('}')?;
+            }
+            out.write_str("],\"stack\":")?;
+            match meta.alloc_stack {
+                Some(stack) => write_json_stack(ctx, &stack.to_backtrace(), out)?,
+                None => out.write_str("null")?,
+            }
+            out.write_char('}')?;
+        }
+        None => out.write_str("null")?,
+    }
+    out.write_str(",\"stack\":")?;
+    write_json_stack(ctx, trace, out)?;
+    out.write_str("}\n")
+}
+
+fn write_json_stack(ctx: &GlobalContext, trace: &Backtrace, out: &mut impl Write) -> fmt::Result {
+    out.write_char('[')?;
+    let mut first = true;
+    ctx.symbolizer().for_each_frame(trace, |frame| {
+        if !core::mem::take(&mut first) {
+            out.write_char(',')?;
+        }
+        out.write_str("{\"pc\":")?;
+        write_json_addr(ctx, out, frame.pc)?;
+        out.write_str(",\"function\":")?;
+        match (frame.function, frame.symbol) {
+            (Some(function), _) => write_json_str(out, Lossy::Bytes(function))?,
+            (None, Some((symbol, _))) => write_json_str(out, Lossy::Bytes(symbol.to_bytes()))?,
+            (None, None) => out.write_str("null")?,
+        }
+        out.write_str(",\"location\":")?;
+        match frame.location {
+            Some(location) => write_json_str(out, Lossy::Bytes(location))?,
+            None => out.write_str("null")?,
+        }
+        out.write_str(",\"module\":")?;
+        match frame.module {
+            Some((module, offset)) => {
+                write_json_str(out, Lossy::Bytes(module.to_bytes()))?;
+                write!(out, ",\"offset\":\"{offset:#x}\"}}")
+            }
+            None => out.write_str("null,\"offset\":null}"),
+        }
+    })?;
+    out.write_char(']')
+}
+
+/// Writes `addr` as a hexadecimal string, or `null` if addresses are hidden.
+fn write_json_addr(ctx: &GlobalContext, out: &mut impl Write, addr: usize) -> fmt::Result {
+    match ctx.output().hide_addresses {
+        true => out.write_str("null"),
+        false => write!(out, "\"{addr:#x}\""),
+    }
+}
+
+fn write_json_location(out: &mut impl Write, loc: Option<&SourceInfo>) -> fmt::Result {
+    let Some(loc) = loc else { return out.write_str("null") };
+    out.write_str("{\"function\":")?;
+    match unsafe { location::name(loc.function) } {
+        Some(function) => write_json_str(out, Lossy::Bytes(function))?,
+        None => out.write_str("null")?,
+    }
+    out.write_str(",\"file\":")?;
+    match unsafe { location::name(loc.file) } {
+        Some(file) => write_json_str(out, Lossy::Bytes(file))?,
+        None => out.write_str("null")?,
+    }
+    let number = |n: u32| if n == 0 { None } else { Some(n) };
+    match number(loc.line) {
+        Some(line) => write!(out, ",\"line\":{line}")?,
+        None => out.write_str(",\"line\":null")?,
+    }
+    match number(loc.column) {
+        Some(column) => write!(out, ",\"column\":{column}}}"),
+        None => out.write_str(",\"column\":null}"),
+    }
+}
+
+// Text to be written as a JSON string, with invalid UTF-8 replaced.
+enum Lossy<'a> {
+    Bytes(&'a [u8]),
+    Args(fmt::Arguments<'a>),
+}
+
+fn write_json_str(out: &mut impl Write, text: Lossy<'_>) -> fmt::Result {
+    struct Escape<'a, W>(&'a mut W);
+
+    impl<W: Write> Write for Escape<'_, W> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for c in s.chars() {
+                match c {
+                    '"' => self.0.write_str("\\\"")?,
+                    '\\' => self.0.write_str("\\\\")?,
+                    '\n' => self.0.write_str("\\n")?,
+                    c if c.is_control() => write!(self.0, "\\u{:04x}", c as u32)?,
+                    c => self.0.write_char(c)?,
+                }
+            }
+            Ok(())
+        }
+    }
+
+    out.write_char('"')?;
+    let mut escaped = Escape(out);
+    match text {
+        Lossy::Bytes(bytes) => location::write_lossy(&mut escaped, bytes)?,
+        Lossy::Args(args) => escaped.write_fmt(args)?,
+    }
+    out.write_char('"')
+}
+
+#[cfg(test)]
+mod tests {
+    use core::ptr::{self, NonNull};
+
+    use super::*;
+    use crate::AllocId;
+    use crate::alloc::TEST_ALLOCATOR;
+    use crate::registry::AllocKind;
+
+    #[test]
+    fn repeats_are_identified_by_kind_site_and_allocation_site() {
+        let loc =
+            |line| SourceInfo { file: c"a.rs".as_ptr(), line, column: 1, function: ptr::null() };
+        let (first, second) = (loc(1), loc(2));
+        let trace = Backtrace::capture(0);
+        let id = |kind, loc, alloc: Option<&AllocMetadata>| {
+            let message = format_args!("{}", alloc.is_some());
+            identity(&Report { alloc, ..Report::new(kind, loc, message) }, &trace)
+        };
+        let base = id(ErrorKind::InvalidFree, Some(&first), None);
+        // The message doesn't matter, nor does the call stack once there is
+        // a location.
+        let other_file = SourceInfo { file: c"a.rs".to_owned().into_raw(), ..loc(1) };
+        assert_eq!(id(ErrorKind::InvalidFree, Some(&other_file), None), base);
+        assert_ne!(id(ErrorKind::InvalidRealloc, Some(&first), None), base);
+        assert_ne!(id(ErrorKind::InvalidFree, Some(&second), None), base);
+        assert_ne!(id(ErrorKind::InvalidFree, None, None), base);
+        let meta = AllocMetadata::new(AllocId::new(1), 0x1000, 8, BorTag::new(1), AllocKind::Heap);
+        let mut elsewhere =
+            AllocMetadata::new(AllocId::new(2), 0x2000, 8, BorTag::new(2), AllocKind::Heap);
+        elsewhere.created_in = &second;
+        let with_alloc = id(ErrorKind::InvalidFree, Some(&first), Some(&meta));
+        assert_ne!(with_alloc, base);
+        assert_ne!(id(ErrorKind::InvalidFree, Some(&first), Some(&elsewhere)), with_alloc);
+        drop(unsafe { std::ffi::CString::from_raw(other_file.file.cast_mut()) });
+    }
+
+    #[test]
+    fn text_reports_show_the_source_line() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let path = std::env::temp_dir().join(format!("bsan-snippet-{}.rs", std::process::id()));
+        std::fs::write(&path, "fn f() {\n\tlet x = *p;\n}\n").unwrap();
+        let file = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        let loc = SourceInfo { file: file.as_ptr(), line: 2, column: 10, function: ptr::null() };
+        let text = |color| {
+            let mut out = String::new();
+            let report = Report::new(ErrorKind::InvalidFree, Some(&loc), format_args!("oops"));
+            write_text(&ctx, &report, &Backtrace::capture(0), color, &mut out).unwrap();
+            out
+        };
+        let plain = text(false);
+        let expected = format!(
+            "bsan: oops\n    at {}:2:10\n      |\n    2 | \tlet x = *p;\n      | \t        ^\n    #0 ",
+            path.display()
+        );
+        assert!(plain.starts_with(&expected), "{plain}");
+        assert!(!plain.contains('\x1b'));
+        assert!(text(true).starts_with("\x1b[1;31mbsan:\x1b[0m \x1b[1moops\x1b[0m\n"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn severities_can_be_overridden() {
+        let oob = ErrorKind::Access(AccessError::OutOfBounds {
+            alloc_id: AllocId::new(1),
+            base_addr: 0x1000,
+            size: 8,
+        });
+        let defaults = Severities::new();
+        assert_eq!(defaults.of(oob), Severity::Error);
+        assert_eq!(defaults.of(ErrorKind::WildcardAccess), Severity::Ignore);
+        let strict = Severities::from_env(true);
+        assert_eq!(strict.of(ErrorKind::WildcardAccess), Severity::Error);
+        let mut severities = defaults;
+        let mut invalid = Vec::new();
+        severities.apply(
+            " all=warning, invalid-free = error,,out-of-bounds=fatal,bogus=error,layout-mismatch",
+            |entry| invalid.push(entry.to_owned()),
+        );
+        assert_eq!(invalid, ["out-of-bounds=fatal", "bogus=error", "layout-mismatch"]);
+        assert_eq!(severities.of(oob), Severity::Warning);
+        assert_eq!(severities.of(ErrorKind::WildcardAccess), Severity::Warning);
+        assert_eq!(severities.of(ErrorKind::InvalidFree), Severity::Error);
+        severities.apply("out-of-bounds=ignore,all=error", |_| unreachable!());
+        assert_eq!(severities.by_index(), [Severity::Error; ErrorKind::COUNT]);
+    }
+
+    #[test]
+    fn json_reports_are_single_objects() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let func =
+            SourceInfo { file: c"a \"b\".rs".as_ptr(), line: 3, column: 0, function: ptr::null() };
+        let json = |report: &Report<'_>| {
+            let mut out = String::new();
+            write_json(&ctx, report, &Backtrace::capture(0), &mut out).unwrap();
+            assert!(out.ends_with("}\n") && out.lines().count() == 1, "{out}");
+            out
+        };
+        unsafe {
+            let prov = ctx.new_allocation(0x1000, 16).unwrap();
+            let meta = &*prov.lock_address.cast::<AllocMetadata>();
+            let err = AccessError::OutOfBounds { alloc_id: meta.id, base_addr: 0x1000, size: 16 };
+            let out = json(&Report {
+                access: Some((AccessKind::Write, 0x100c, 8)),
+                tag: prov.bor_tag,
+                alloc: Some(meta),
+                ..Report::new(ErrorKind::Access(err), Some(&func), format_args!("bad\n{}", 1))
+            });
+            let expected = [
+                r#"{"kind":"out-of-bounds","severity":"error","message":"bad\n1","#,
+                r#""location":{"function":null,"#,
+                r#""file":"a \"b\".rs","line":3,"column":null},"access":{"kind":"write","#,
+                r#""address":"0x100c","size":8},"tag":{"id":1,"root":true,"protected":false,"#,
+                r#""protected_in":null},"allocation":{"id":1,"kind":"heap","base":"0x1000","#,
+                r#""size":16,"align":1,"state":"live","root_tag":1,"created_in":null,"#,
+                r#""freed_in":null,"history":[],"stack":null},"stack":[{"pc":"0x"#,
+            ]
+            .concat();
+            assert!(out.starts_with(&expected), "{out}");
+            let out = json(&Report::new(ErrorKind::InvalidFree, None, format_args!("free")));
+            let expected = concat!(
+                r#"{"kind":"invalid-free","severity":"error","message":"free","location":null,"#,
+                r#""access":null,"#,
+                r#""tag":null,"allocation":null,"#,
+            );
+            assert!(out.starts_with(expected), "{out}");
+            ctx.release_metadata(NonNull::new_unchecked(prov.lock_address.cast()));
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! The interface that the LLVM sanitizers share, which is declared in
+//! `sanitizer/common_interface_defs.h`.
+//!
+//! Build systems and test runners that already support ASan or MSan call
+//! these functions to print stacks, to move the reports, and to learn when the
+//! runtime is about to kill the process, so bsan implements the ones that make
+//! sense for it. Reports set with `__sanitizer_set_report_path` follow the
+//! rules of `BSAN_LOG_PATH`, which they override.
+
+use core::ffi::{CStr, c_char, c_int, c_void};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::{mem, ptr, slice};
+
+use crate::backtrace::Backtrace;
+use crate::global::global_ctx;
+use crate::io::{self, CPathBuf, FdWriter, SliceWriter};
+use crate::report::{self, Bytes};
+use crate::symbolize::FrameInfo;
+
+// The function that the program set with `__sanitizer_set_death_callback`.
+static DEATH_CALLBACK: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// Calls the function that the program set with
+/// `__sanitizer_set_death_callback`, if any. It is called at most once, even if
+/// it runs into an error that terminates the process itself.
+pub fn run_death_callback() {
+    let callback = DEATH_CALLBACK.swap(ptr::null_mut(), Ordering::AcqRel);
+    if !callback.is_null() {
+        unsafe { mem::transmute::<*mut c_void, unsafe extern "C" fn()>(callback)() }
+    }
+}
+
+/// Sets a function that is called just before the runtime terminates the
+/// process, because of an error with `BSAN_HALT_ON_ERROR=1` or a failure of
+/// the runtime itself. Null removes it.
+#[no_mangle]
+extern "C" fn __sanitizer_set_death_callback(callback: Option<unsafe extern "C" fn()>) {
+    let callback = callback.map_or(ptr::null_mut(), |callback| callback as *mut c_void);
+    DEATH_CALLBACK.store(callback, Ordering::Release);
+}
+
+/// Prints the call stack of the caller to the runtime's log, as the stacks of
+/// error reports are printed.
+#[no_mangle]
+#[inline(never)]
+extern "C" fn __sanitizer_print_stack_trace() {
+    let ctx = unsafe { global_ctx() };
+    let trace = Backtrace::capture(0);
+    let fd = io::log_fd();
+    let color = ctx.output().color(fd);
+    let _ = report::write_stack(ctx, &trace, color, &mut FdWriter::new(fd));
+}
+
+/// Sends the runtime's output, along with its reports, to the file at `path`,
+/// as `BSAN_LOG_PATH` does. Null sends it to `stderr`.
+///
+/// # Safety
+/// `path` must be null or point to a valid C string.
+#[no_mangle]
+unsafe extern "C" fn __sanitizer_set_report_path(path: *const c_char) {
+    // The log named in the environment would otherwise replace this one.
+    io::open_log_from_env();
+    if path.is_null() {
+        return io::set_log_fd(libc::STDERR_FILENO);
+    }
+    match CPathBuf::from_ptr(path) {
+        Some(path) => io::set_log_path(path),
+        None => {
+            let path = CStr::from_ptr(path);
+            let _ = writeln!(FdWriter::log(), "bsan: invalid report path {path:?}");
+        }
+    }
+}
+
+/// Sends the runtime's output, along with its reports, to the file descriptor
+/// `fd`, which is passed as a pointer.
+#[no_mangle]
+extern "C" fn __sanitizer_set_report_fd(fd: *mut c_void) {
+    io::open_log_from_env();
+    io::set_log_fd(fd as usize as c_int);
+}
+
+/// Writes a description of the code that the return address `pc` is in to
+/// `out_buf` as a C string, truncating it to `out_buf_size` bytes. `fmt` is
+/// made up of the specifiers below, and other characters that are copied
+/// as is:
+///
+/// - `%p`: the address;
+/// - `%m` and `%o`: the module and the offset into it;
+/// - `%f`, `%s`, `%l` and `%c`: the function, source file, line and column;
+/// - `%F`: `in ` and the function, if it is known;
+/// - `%L`: `file:line:column`, or the module and the offset into it;
+/// - `%n`: the number of the frame, which is always 0;
+/// - `%%`: a `%`.
+///
+/// # Safety
+/// `fmt` must point to a valid C string, and `out_buf` to `out_buf_size`
+/// writable bytes.
+#[no_mangle]
+unsafe extern "C" fn __sanitizer_symbolize_pc(
+    pc: *mut c_void,
+    fmt: *const c_char,
+    out_buf: *mut c_char,
+    out_buf_size: usize,
+) {
+    if out_buf.is_null() || out_buf_size == 0 {
+        return;
+    }
+    let fmt = CStr::from_ptr(fmt).to_bytes();
+    let mut out = SliceWriter::new(slice::from_raw_parts_mut(out_buf.cast(), out_buf_size));
+    let ctx = global_ctx();
+    let _ = ctx.symbolizer().symbolize(pc as usize, |frame| write_frame(frame, fmt, &mut out));
+    out.finish();
+}
+
+/// Writes `frame` as `fmt` describes, for [`__sanitizer_symbolize_pc`].
+fn write_frame(frame: &FrameInfo<'_>, fmt: &[u8], out: &mut impl Write) -> fmt::Result {
+    let [file, line, column] = frame.location.map_or([None; 3], split_location);
+    let write_module = |out: &mut dyn Write| match frame.module {
+        Some((module, offset)) => write!(out, "({}+{offset:#x})", Bytes(module.to_bytes())),
+        None => out.write_str("(<unknown module>)"),
+    };
+    let mut bytes = fmt.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'%' {
+            write!(out, "{}", Bytes(&[byte]))?;
+            continue;
+        }
+        match bytes.next() {
+            Some(b'p') => write!(out, "{:#x}", frame.pc)?,
+            Some(b'm') => match frame.module {
+                Some((module, _)) => write!(out, "{}", Bytes(module.to_bytes()))?,
+                None => out.write_str("<unknown module>")?,
+            },
+            Some(b'o') => write!(out, "{:#x}", frame.module.map_or(0, |(_, offset)| offset))?,
+            Some(b'f') => write!(out, "{}", Bytes(frame.function.unwrap_or(b"??")))?,
+            Some(b's') => write!(out, "{}", Bytes(file.unwrap_or(b"??")))?,
+            Some(b'l') => write!(out, "{}", Bytes(line.unwrap_or(b"0")))?,
+            Some(b'c') => write!(out, "{}", Bytes(column.unwrap_or(b"0")))?,
+            Some(b'F') => {
+                if let Some(function) = frame.function {
+                    write!(out, "in {}", Bytes(function))?
+                }
+            }
+            Some(b'L') => match frame.location {
+                Some(location) => write!(out, "{}", Bytes(location))?,
+                None => write_module(out)?,
+            },
+            Some(b'n') => out.write_str("0")?,
+            Some(b'%') => out.write_str("%")?,
+            Some(&other) => write!(out, "%{}", Bytes(&[other]))?,
+            None => out.write_str("%")?,
+        }
+    }
+    Ok(())
+}
+
+/// Splits a `file:line:column` location from the symbolizer, whose file may
+/// itself contain colons, into those parts.
+fn split_location(location: &[u8]) -> [Option<&[u8]>; 3] {
+    let mut parts = location.rsplitn(3, |&b| b == b':');
+    match [parts.next(), parts.next(), parts.next()] {
+        [column, Some(line), Some(file)] => [Some(file), Some(line), column],
+        [line, Some(file), None] => [Some(file), line, None],
+        [file, ..] => [file, None, None],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+    use std::os::unix::ffi::OsStringExt;
+
+    use super::*;
+
+    fn format(frame: &FrameInfo<'_>, fmt: &str, size: usize) -> String {
+        let mut buf = vec![0xff; size];
+        let mut out = SliceWriter::new(&mut buf);
+        write_frame(frame, fmt.as_bytes(), &mut out).unwrap();
+        out.finish();
+        CStr::from_bytes_until_nul(&buf).unwrap().to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn frames_are_formatted_like_other_sanitizers() {
+        let mut frame = FrameInfo {
+            pc: 0x1234,
+            module: Some((c"/usr/lib/libfoo.so", 0x234)),
+            symbol: None,
+            function: Some(b"foo::bar"),
+            location: Some(b"C:/src/foo.rs:12:5"),
+        };
+        let fmt = "#%n %p %F %L|%m+%o|%s|%l|%c|100%%";
+        assert_eq!(
+            format(&frame, fmt, 256),
+            "#0 0x1234 in foo::bar C:/src/foo.rs:12:5|/usr/lib/libfoo.so+0x234|C:/src/foo.rs|12|5|100%"
+        );
+        (frame.function, frame.location) = (None, None);
+        assert_eq!(format(&frame, "%F %L %f %s:%l", 256), " (/usr/lib/libfoo.so+0x234) ?? ??:0");
+        frame.module = None;
+        assert_eq!(format(&frame, "%L %q", 256), "(<unknown module>) %q");
+        assert_eq!(format(&frame, "%p", 4), "0x1");
+        assert_eq!(format(&frame, "%p", 1), "");
+    }
+
+    static DEATHS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn on_death() {
+        DEATHS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn death_callbacks_are_called_once() {
+        __sanitizer_set_death_callback(Some(on_death));
+        run_death_callback();
+        run_death_callback();
+        assert_eq!(DEATHS.load(Ordering::Relaxed), 1);
+        __sanitizer_set_death_callback(Some(on_death));
+        __sanitizer_set_death_callback(None);
+        run_death_callback();
+        assert_eq!(DEATHS.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn reports_can_be_sent_to_another_file() {
+        let dir = std::env::temp_dir().join(format!("bsan-report-path-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = std::ffi::CString::new(dir.join("log").into_os_string().into_vec()).unwrap();
+        unsafe { __sanitizer_set_report_path(path.as_ptr()) };
+        let _ = writeln!(FdWriter::log(), "bsan: a line for the report path");
+        __sanitizer_set_report_fd(libc::STDERR_FILENO as usize as *mut c_void);
+        assert_eq!(io::log_fd(), libc::STDERR_FILENO);
+        let log = dir.join(format!("log.{}", std::process::id()));
+        assert!(std::fs::read_to_string(log).unwrap().contains("a line for the report path"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
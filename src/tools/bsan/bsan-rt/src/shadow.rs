@@ -1,144 +1,2359 @@
-use core::alloc::Layout;
+use core::alloc::{Allocator, Layout};
+use core::cell::Cell;
+use core::ffi::c_void;
 use core::marker::PhantomData;
-use core::mem;
-use core::ops::{Add, BitAnd, Deref, DerefMut, Shr};
-
-/// Different targets have a different number
-/// of significant bits in their pointer representation.
-/// On 32-bit platforms, all 32-bits are addressable. Most
-/// 64-bit platforms only use 48-bits. Following the LLVM Project,
-/// we hard-code these values based on the underlying architecture.
-/// Most, if not all 64 bit architectures use 48-bits. However, a the
-/// Armv8-A spec allows addressing 52 or 56 bits as well. No processors
-/// implement this yet, though, so we can use target_pointer_width.
+use core::mem::MaybeUninit;
+use core::ops::{Add, BitAnd, Deref, Shr};
+use core::ptr::NonNull;
+use core::sync::atomic::{
+    self, AtomicBool, AtomicPtr, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+};
+use core::{fmt, hint, mem, ptr};
 
+use crate::alloc::{self, BsanAllocator};
+use crate::sync::SpinLock;
+use crate::{epoch, host, thread};
+
+/// The number of significant bits in the addresses that a single first-level
+/// table covers. On 32-bit and 16-bit targets, every bit of a pointer is
+/// addressable. On 64-bit targets, user space is mapped below 2^48 unless the
+/// program asks for more, as on x86-64 with 4-level paging and on AArch64 with
+/// 48-bit virtual addresses, so, following the LLVM Project, the table is laid
+/// out for 48 bits. Addresses up to [`MAX_VA_BITS`] are covered by the third
+/// level of the table, on hosts where [`probe_va_bits`] finds them.
 #[cfg(target_pointer_width = "64")]
-static VA_BITS: usize = 48;
+const VA_BITS: u32 = 48;
 
 #[cfg(target_pointer_width = "32")]
-static VA_BITS: usize = 32;
+const VA_BITS: u32 = 32;
 
 #[cfg(target_pointer_width = "16")]
-static VA_BITS: usize = 16;
+const VA_BITS: u32 = 16;
 
 // The number of bytes in a pointer
-static PTR_BYTES: usize = mem::size_of::<usize>();
+const PTR_BYTES: usize = mem::size_of::<usize>();
+
+// The page table has one slot per word, so the low bits of an address never
+// contribute to its position in the table. Pointers stored at addresses that
+// aren't word-aligned are tracked separately by `ShadowHeap`.
+const PTR_ALIGN_BITS: u32 = PTR_BYTES.ilog2();
+
+// The highest address covered by a single first-level table.
+const MAX_ADDR: usize = ((1u128 << VA_BITS) - 1) as usize;
+
+/// The number of significant bits in the widest addresses that the table can
+/// cover. Some 64-bit hosts can map addresses above `VA_BITS`: x86-64 with
+/// 5-level paging has 57-bit addresses, and AArch64 can be configured for 52
+/// bits. These are covered by a third level of the table, with one first-level
+/// table for each 2^`VA_BITS` bytes, which is only used if probing finds that
+/// the host hands out such addresses.
+#[cfg(target_pointer_width = "64")]
+const MAX_VA_BITS: u32 = 57;
+
+#[cfg(not(target_pointer_width = "64"))]
+const MAX_VA_BITS: u32 = VA_BITS;
+
+// The number of first-level tables needed to cover `MAX_VA_BITS`.
+const L0_LEN: usize = 1 << (MAX_VA_BITS - VA_BITS);
+
+#[cfg(any(
+    all(feature = "shadow-chunk-16k", feature = "shadow-chunk-32k"),
+    all(feature = "shadow-chunk-16k", feature = "shadow-chunk-64k"),
+    all(feature = "shadow-chunk-16k", feature = "shadow-chunk-128k"),
+    all(feature = "shadow-chunk-32k", feature = "shadow-chunk-64k"),
+    all(feature = "shadow-chunk-32k", feature = "shadow-chunk-128k"),
+    all(feature = "shadow-chunk-64k", feature = "shadow-chunk-128k"),
+))]
+compile_error!("at most one `shadow-chunk-*` feature may be enabled");
+
+/// The number of bytes of address space covered by a single second-level
+/// chunk of the page table. This is selected with the `shadow-chunk-*`
+/// cargo features, and defaults to 64 KiB.
+#[cfg(feature = "shadow-chunk-16k")]
+const CHUNK_BYTES: usize = 16 * 1024;
+
+#[cfg(feature = "shadow-chunk-32k")]
+const CHUNK_BYTES: usize = 32 * 1024;
+
+#[cfg(feature = "shadow-chunk-128k")]
+const CHUNK_BYTES: usize = 128 * 1024;
 
-// The number of addressable, word-aligned, pointer-sized chunks
-static NUM_ADDR_CHUNKS: u32 = VA_BITS.strict_div(PTR_BYTES).ilog2();
+#[cfg(not(any(
+    feature = "shadow-chunk-16k",
+    feature = "shadow-chunk-32k",
+    feature = "shadow-chunk-128k"
+)))]
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// The base-2 logarithm of the number of word-sized slots in a chunk
+/// covering `chunk_bytes` bytes of address space.
+const fn l2_power(chunk_bytes: usize) -> u32 {
+    chunk_bytes.ilog2() - PTR_ALIGN_BITS
+}
+
+/// The base-2 logarithm of the number of chunks needed to cover
+/// a `va_bits`-bit address space.
+const fn l1_power(va_bits: u32, chunk_bytes: usize) -> u32 {
+    va_bits - chunk_bytes.ilog2()
+}
 
 // We have 2^L2_POWER entries in the second level of the page table
-// Adding 1 ensures that we have more second-level entries than first
-// level entries if the number of addressable chunks is odd.
-static L2_POWER: u32 = NUM_ADDR_CHUNKS.strict_add(1).strict_div(2);
+const L2_POWER: u32 = l2_power(CHUNK_BYTES);
 
 // We have 2^L1_POWER entries in the first level of the page table
-static L1_POWER: u32 = NUM_ADDR_CHUNKS.strict_div(2);
+const L1_POWER: u32 = l1_power(VA_BITS, CHUNK_BYTES);
 
 // The number of entries in the second level of the page table
-static L2_LEN: usize = 2_usize.pow(L2_POWER);
+const L2_LEN: usize = 1 << L2_POWER;
 
 // The number of entries in the first level of the page table
-static L1_LEN: usize = 2_usize.pow(L1_POWER);
+const L1_LEN: usize = 1 << L1_POWER;
+
+const _: () = {
+    assert!(CHUNK_BYTES.is_power_of_two());
+    assert!(CHUNK_BYTES >= PTR_BYTES);
+    assert!(CHUNK_BYTES.ilog2() <= VA_BITS);
+    // Every significant bit of an address is used exactly once: either to
+    // select a chunk, a slot within that chunk, or a byte within that slot.
+    assert!(L1_POWER + L2_POWER + PTR_ALIGN_BITS == VA_BITS);
+    assert!(L2_LEN * PTR_BYTES == CHUNK_BYTES);
+};
 
 /// Converts an address into a pair of indices into the first and second
-/// levels of the shadow page table.
+/// levels of the shadow page table. Shifts and masks operate on the value of
+/// the address, not on its representation in memory, so this is the same on
+/// big- and little-endian targets.
 #[inline(always)]
 fn table_indices(address: usize) -> (usize, usize) {
-    #[cfg(target_endian = "little")]
-    let l1_index = address.shr(L2_POWER).bitand((L1_POWER - 1) as usize);
+    let slot = address >> PTR_ALIGN_BITS;
+    let l1_index = slot.shr(L2_POWER).bitand(L1_LEN - 1);
+    let l2_index = slot.bitand(L2_LEN - 1);
+    (l1_index, l2_index)
+}
+
+/// The inverse of [`table_indices`]: the address of the first byte of the word
+/// tracked by the given slot, within the first `2^VA_BITS` bytes.
+#[inline(always)]
+fn slot_address(l1_index: usize, l2_index: usize) -> usize {
+    ((l1_index << L2_POWER) | l2_index) << PTR_ALIGN_BITS
+}
 
-    #[cfg(target_endian = "big")]
-    let l1_index = address.shl(L2_POWER).bitand((L1_POWER - 1) as usize);
+/// Provenance values must be sized so that we can allocate an array of them
+/// for the L1 page table. We can make provenance values Copy since they are a
+/// few words of plain data, like the three words of [`crate::Provenance`], and
+/// they are not "owned" by any particular object. Values that refer to shared
+/// state can use `retain` and `release` to keep it alive for as long as they
+/// are stored in the table.
+///
+/// # Safety
+/// Second-level chunks are allocated zeroed on demand, so the all-zero bit
+/// pattern must be a valid value that represents the absence of provenance.
+/// `retain` and `release` must be no-ops for that value. Entries are copied in
+/// and out of the table a word at a time with atomic accesses, so the type must
+/// be a whole number of words, aligned to a word, without any padding.
+pub unsafe trait Provenance: Copy + Sized + PartialEq {
+    /// Called before the value is stored in the table.
+    #[inline(always)]
+    unsafe fn retain(&self) {}
 
-    let l2_index = address.bitand((L2_POWER - 1) as usize);
+    /// Called after the value has been replaced in the table.
+    #[inline(always)]
+    unsafe fn release(&self) {}
+}
 
-    (l1_index, l2_index)
+#[inline(always)]
+fn is_empty<T: Provenance>(value: &T) -> bool {
+    *value == unsafe { mem::zeroed() }
 }
 
-// Provenance values must be sized so that we can allocate an array of them
-// for the L1 page table. We can make provenance values Copy since they should
-// fit within 128 bits and they are not "owned" by any particular object.
-pub trait Provenance: Copy + Sized {}
+/// The index of the first of the `len` words at `words` that isn't zero, or
+/// `len` if they all are. Clearing or copying a range of shadow memory only
+/// has to touch the entries that hold provenance, which are few in most
+/// buffers, so the rest are skipped 64 bytes at a time. Other threads may be
+/// storing to the words while they are scanned, so each is read with a relaxed
+/// atomic load. Those compile to plain loads of aligned words, which the
+/// processor can overlap within a block, since none depends on another.
+#[inline]
+unsafe fn first_nonzero_word(words: *const usize, len: usize) -> usize {
+    const BLOCK: usize = 8;
+    let word =
+        |index: usize| AtomicUsize::from_ptr(words.add(index).cast_mut()).load(Ordering::Relaxed);
+    let mut index = 0;
+    while index + BLOCK <= len && (0..BLOCK).fold(0, |any, i| any | word(index + i)) == 0 {
+        index += BLOCK;
+    }
+    while index < len && word(index) == 0 {
+        index += 1;
+    }
+    index
+}
+
+// The value of `L2::live` once a chunk has been detached from the table.
+const DEAD: usize = usize::MAX;
+
+// The states of a chunk. Chunks that are filled with a single value by
+// `L1::fill` start out as `UNIFORM`, in which every entry is `L2::uniform` and
+// the entries themselves are never touched, so their pages are never
+// committed. The first store of a different value expands the chunk, writing
+// the uniform value to every entry. Chunks installed by a store start out
+// `EXPANDED`, since that's the all-zero state.
+const EXPANDED: u8 = 0;
+const UNIFORM: u8 = 1;
+const EXPANDING: u8 = 2;
+
+// The number of sequence locks of a chunk, each of which guards the entries
+// whose indices are congruent modulo this.
+const STRIPES: usize = 64;
 
+// Entries are read without locks, and written under the sequence lock of their
+// stripe, which is odd while a store holds it. A reader copies an entry out a
+// word at a time and retries if the stripe's sequence changed in the
+// meantime, so it never observes a torn entry, and stores to the same entry
+// each replace the value that the one before them left, so that every value
+// is released exactly once.
 #[repr(C)]
 pub struct L2<T: Provenance> {
     bytes: [T; L2_LEN],
+    // The number of entries holding provenance. Once this drops back to
+    // zero, the chunk is marked as `DEAD` and detached from the table.
+    live: AtomicUsize,
+    // Counts the times that the chunk was installed again after being
+    // detached, so that stale pointers to it in the caches of other threads
+    // aren't mistaken for the chunk that it has become.
+    generation: AtomicU64,
+    seqs: [AtomicU32; STRIPES],
+    state: AtomicU8,
+    // Whether the chunk was carved out of one of its table's slabs.
+    from_slab: bool,
+    uniform: T,
+    // The index of the first-level entry that this chunk is installed in.
+    l1_index: usize,
+    // Every installed chunk is linked into a list owned by its L1 table,
+    // so that the chunks can be freed without scanning the whole table. A
+    // chunk stays in the list once it has been linked, even while it's dead.
+    next: *mut L2<T>,
+    linked: bool,
+    // While the chunk is dead, the epoch that it was detached at, and the
+    // next chunk that was detached after it.
+    dead_at: u64,
+    next_dead: *mut L2<T>,
 }
 
 impl<T: Provenance> L2<T> {
+    // The number of words in an entry.
+    const WORDS: usize = {
+        assert!(mem::size_of::<T>() % PTR_BYTES == 0);
+        assert!(mem::align_of::<T>() >= mem::align_of::<usize>());
+        mem::size_of::<T>() / PTR_BYTES
+    };
+
+    #[inline(always)]
+    unsafe fn slot(chunk: *mut Self, index: usize) -> *mut T {
+        ptr::addr_of_mut!((*chunk).bytes).cast::<T>().add(index)
+    }
+
+    #[inline(always)]
+    unsafe fn load_words(chunk: *mut Self, index: usize) -> T {
+        let words = Self::slot(chunk, index).cast::<usize>();
+        let mut value = MaybeUninit::<T>::uninit();
+        let out = value.as_mut_ptr().cast::<usize>();
+        for word in 0..Self::WORDS {
+            out.add(word).write(AtomicUsize::from_ptr(words.add(word)).load(Ordering::Relaxed));
+        }
+        value.assume_init()
+    }
+
+    #[inline(always)]
+    unsafe fn store_words(chunk: *mut Self, index: usize, value: T) {
+        let words = Self::slot(chunk, index).cast::<usize>();
+        let value = ptr::addr_of!(value).cast::<usize>();
+        for word in 0..Self::WORDS {
+            AtomicUsize::from_ptr(words.add(word)).store(value.add(word).read(), Ordering::Relaxed);
+        }
+    }
+
+    /// The index of the first entry in `[start, end)` that holds provenance,
+    /// or `end` if none does. The chunk must be expanded.
+    #[inline]
+    unsafe fn next_occupied(chunk: *mut Self, start: usize, end: usize) -> usize {
+        let words = Self::slot(chunk, start).cast::<usize>();
+        start + first_nonzero_word(words, (end - start) * Self::WORDS) / Self::WORDS
+    }
+
+    #[inline(always)]
+    unsafe fn seq<'a>(chunk: *mut Self, index: usize) -> &'a AtomicU32 {
+        &(*chunk).seqs[index % STRIPES]
+    }
+
+    /// Reads the entry at `index`.
     #[inline(always)]
-    unsafe fn lookup_mut(&mut self, index: usize) -> &mut T {
-        self.bytes.get_unchecked_mut(index)
+    unsafe fn read(chunk: *mut Self, index: usize) -> T {
+        if (*chunk).state.load(Ordering::Acquire) != EXPANDED {
+            return (*chunk).uniform;
+        }
+        let seq = Self::seq(chunk, index);
+        loop {
+            let before = seq.load(Ordering::Acquire);
+            if before % 2 == 0 {
+                let value = Self::load_words(chunk, index);
+                atomic::fence(Ordering::Acquire);
+                if seq.load(Ordering::Relaxed) == before {
+                    return value;
+                }
+            }
+            hint::spin_loop();
+        }
     }
+
+    /// Takes the sequence lock of the stripe of the entry at `index`, and
+    /// returns the sequence to [unlock](L2::unlock) it with.
     #[inline(always)]
-    unsafe fn lookup(&mut self, index: usize) -> &T {
-        self.bytes.get_unchecked(index)
+    unsafe fn lock(chunk: *mut Self, index: usize) -> u32 {
+        let seq = Self::seq(chunk, index);
+        loop {
+            let current = seq.load(Ordering::Relaxed);
+            if current % 2 == 0
+                && seq
+                    .compare_exchange_weak(
+                        current,
+                        current + 1,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+            {
+                return current;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn unlock(chunk: *mut Self, index: usize, locked_at: u32) {
+        Self::seq(chunk, index).store(locked_at.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Writes out the entries of a uniform chunk, waiting for any other thread
+    /// that is already doing so. The uniform value's references are handed
+    /// over to the entries.
+    #[cold]
+    unsafe fn expand(chunk: *mut Self) {
+        let state = &(*chunk).state;
+        loop {
+            match state.compare_exchange(UNIFORM, EXPANDING, Ordering::Acquire, Ordering::Acquire) {
+                Ok(_) => {
+                    // Entries aren't read until the chunk is expanded, so
+                    // they don't need to be locked.
+                    let value = (*chunk).uniform;
+                    for index in 0..L2_LEN {
+                        Self::store_words(chunk, index, value);
+                    }
+                    state.store(EXPANDED, Ordering::Release);
+                    return;
+                }
+                Err(EXPANDED) => return,
+                Err(_) => hint::spin_loop(),
+            }
+        }
     }
 }
 
+/// Reserves a zeroed anonymous mapping of `size` bytes. Physical memory is
+/// only committed for the pages that are actually touched.
+unsafe fn map_zeroed(allocator: &BsanAllocator, size: usize) -> *mut c_void {
+    allocator.map_anonymous(
+        ptr::null_mut(),
+        size,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_NORESERVE,
+    )
+}
+
+/// The size of a transparent huge page. Slabs of this size are reserved at an
+/// address aligned to it, so that the kernel can back each one with a single
+/// page. This is the size on x86-64, and on AArch64 with 4 KiB base pages.
+const HUGE_PAGE_BYTES: usize = 2 * 1024 * 1024;
+
+/// Reserves a zeroed mapping of [`HUGE_PAGE_BYTES`], aligned to its size, and
+/// asks the kernel to back it with a huge page. Returns null if the mapping
+/// failed.
+unsafe fn map_huge(allocator: &BsanAllocator) -> *mut c_void {
+    // Twice the size is reserved, and the excess on either side trimmed off.
+    let len = 2 * HUGE_PAGE_BYTES;
+    let mapping = map_zeroed(allocator, len);
+    if mapping.is_null() {
+        return mapping;
+    }
+    let head = mapping.addr().next_multiple_of(HUGE_PAGE_BYTES) - mapping.addr();
+    if head > 0 {
+        allocator.unmap(mapping, head);
+    }
+    let slab = mapping.byte_add(head);
+    let tail = len - head - HUGE_PAGE_BYTES;
+    if tail > 0 {
+        allocator.unmap(slab.byte_add(HUGE_PAGE_BYTES), tail);
+    }
+    #[cfg(target_os = "linux")]
+    host::madvise(slab, HUGE_PAGE_BYTES, libc::MADV_HUGEPAGE);
+    slab
+}
+
+// A huge-page-aligned region that chunks are carved out of, in order. Slabs
+// are only unmapped as a whole, once their table is dropped, since unmapping
+// any part of one would split its huge page.
+struct Slab {
+    base: *mut c_void,
+    used: usize,
+    next: *mut Slab,
+}
+
+// With 48-bit addresses and 64 KiB chunks, the first level alone has 2^32
+// entries, so it can't live inline. Like the chunks, it's reserved as an
+// anonymous mapping instead, which only consumes physical memory for the pages
+// that are used.
+//
+// The table is shared between threads. Readers never take locks, and entries
+// are accessed as described at `L2`. Threads racing to install a chunk for the
+// same entry do so with a compare-and-swap, and the loser unmaps its chunk.
+// Chunks that become empty are detached, but they can't be unmapped while
+// another thread might still hold a pointer to them, so they stay linked into
+// `chunks` until the table is dropped. Their entries are all zero, so their
+// pages are handed back to the kernel, which will provide zeroed pages if a
+// stale pointer reads them again. Every access runs in a hook, which pins its
+// thread, so once the [epoch](crate::epoch) has advanced twice past the
+// detachment of a chunk, no access can still be using it, and it's installed
+// again in place of a new one.
 #[repr(C)]
 pub struct L1<T: Provenance> {
-    entries: [*mut L2<T>; L1_LEN],
+    entries: *mut [AtomicPtr<L2<T>>; L1_LEN],
+    chunks: AtomicPtr<L2<T>>,
+    // The chunks that have been detached, in the order they were detached.
+    dead: SpinLock<DeadChunks<T>>,
+    num_chunks: AtomicUsize,
+    // The most chunks that were installed at once.
+    peak_chunks: AtomicUsize,
+    allocator: BsanAllocator,
+    // Whether new chunks are carved out of `slabs`, rather than each mapped
+    // on its own. The head of the list is the slab that is being filled.
+    huge_pages: AtomicBool,
+    slabs: SpinLock<*mut Slab>,
+    // Identifies the table in the `ChunkCache` of each thread. IDs are never
+    // reused, so entries left behind by a table that has been dropped can't
+    // match another one.
+    id: u64,
 }
 
+struct DeadChunks<T: Provenance> {
+    head: *mut L2<T>,
+    tail: *mut L2<T>,
+}
+
+unsafe impl<T: Provenance + Send> Send for DeadChunks<T> {}
+
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(1);
+
+// Consecutive accesses tend to hit the same chunk, so each thread caches the
+// chunks that it resolved most recently, skipping the load from the first
+// level on a hit. Chunks stay mapped until their table is dropped, so a cached
+// pointer can always be dereferenced, but it may have been detached since, or
+// even installed again for another index. The cache is direct-mapped by the
+// low bits of the first-level index.
+const CACHE_WAYS: usize = 4;
+
+#[derive(Debug, Copy, Clone)]
+struct CachedChunk {
+    table: u64,
+    l1_index: usize,
+    chunk: *mut c_void,
+    generation: u64,
+}
+
+const NO_CHUNK: CachedChunk =
+    CachedChunk { table: 0, l1_index: 0, chunk: ptr::null_mut(), generation: 0 };
+
+/// The chunks that a thread resolved most recently, which are kept in its
+/// [`ThreadContext`](crate::thread::ThreadContext).
+#[derive(Debug)]
+pub struct ChunkCache {
+    ways: [Cell<CachedChunk>; CACHE_WAYS],
+    // Hits are counted locally, and only added to the global counter on a
+    // miss, so that hits don't contend on a shared cache line.
+    hits: Cell<u64>,
+}
+
+impl ChunkCache {
+    pub const fn new() -> Self {
+        Self { ways: [const { Cell::new(NO_CHUNK) }; CACHE_WAYS], hits: Cell::new(0) }
+    }
+}
+
+#[inline(always)]
+fn cache() -> &'static ChunkCache {
+    &thread::current().chunk_cache
+}
+
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// The number of chunk lookups that were served by the per-thread cache, and
+/// the number that had to go through the first level of a table. Hits on each
+/// thread since its last miss are not included yet.
+pub fn chunk_cache_stats() -> (u64, u64) {
+    (CACHE_HITS.load(Ordering::Relaxed), CACHE_MISSES.load(Ordering::Relaxed))
+}
+
+/// Empties the current thread's chunk cache, and adds the hits on it that
+/// haven't been counted yet to [`chunk_cache_stats`], as when the thread exits.
+pub fn flush_thread_cache() {
+    CACHE_HITS.fetch_add(cache().hits.replace(0), Ordering::Relaxed);
+    for way in &cache().ways {
+        way.set(NO_CHUNK);
+    }
+}
+
+unsafe impl<T: Provenance + Send> Send for L1<T> {}
+unsafe impl<T: Provenance + Send> Sync for L1<T> {}
+
 impl<T: Provenance> L1<T> {
-    fn new() -> Self {
-        Self { entries: [core::ptr::null_mut(); L1_LEN] }
+    const MAPPING_SIZE: usize = mem::size_of::<[AtomicPtr<L2<T>>; L1_LEN]>();
+
+    const CHUNK_SIZE: usize = mem::size_of::<L2<T>>();
+
+    fn new(allocator: BsanAllocator) -> Option<Self> {
+        let entries = unsafe { map_zeroed(&allocator, Self::MAPPING_SIZE) };
+        if entries.is_null() {
+            None
+        } else {
+            Some(Self {
+                entries: entries.cast(),
+                chunks: AtomicPtr::new(ptr::null_mut()),
+                dead: SpinLock::new(DeadChunks { head: ptr::null_mut(), tail: ptr::null_mut() }),
+                num_chunks: AtomicUsize::new(0),
+                peak_chunks: AtomicUsize::new(0),
+                allocator,
+                huge_pages: AtomicBool::new(false),
+                slabs: SpinLock::new(ptr::null_mut()),
+                id: NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed),
+            })
+        }
     }
 
     #[inline(always)]
-    unsafe fn lookup_mut(&mut self, index: usize) -> Option<&mut T> {
-        let (l1_index, l2_index) = table_indices(index);
-        let l2 = self.entries.get_unchecked_mut(l1_index);
-        if l2.is_null() { None } else { Some((**l2).lookup_mut(l2_index)) }
+    unsafe fn entry(&self, l1_index: usize) -> &AtomicPtr<L2<T>> {
+        (*self.entries).get_unchecked(l1_index)
     }
 
+    /// Returns the chunk installed for `l1_index`, or null if there is none,
+    /// consulting the per-thread cache first.
     #[inline(always)]
-    unsafe fn lookup(&mut self, index: usize) -> Option<&T> {
-        let (l1_index, l2_index) = table_indices(index);
-        let l2 = self.entries.get_unchecked(l1_index);
-        if l2.is_null() { None } else { Some((**l2).lookup(l2_index)) }
+    unsafe fn chunk(&self, l1_index: usize) -> *mut L2<T> {
+        let cached = cache().ways[l1_index % CACHE_WAYS].get();
+        if cached.table == self.id && cached.l1_index == l1_index {
+            let chunk = cached.chunk.cast::<L2<T>>();
+            if (*chunk).live.load(Ordering::Acquire) != DEAD
+                && (*chunk).generation.load(Ordering::Relaxed) == cached.generation
+            {
+                cache().hits.set(cache().hits.get() + 1);
+                return chunk;
+            }
+        }
+        self.chunk_uncached(l1_index)
+    }
+
+    #[inline(never)]
+    unsafe fn chunk_uncached(&self, l1_index: usize) -> *mut L2<T> {
+        CACHE_HITS.fetch_add(cache().hits.replace(0), Ordering::Relaxed);
+        CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+        let chunk = self.entry(l1_index).load(Ordering::Acquire);
+        if !chunk.is_null() {
+            cache().ways[l1_index % CACHE_WAYS].set(CachedChunk {
+                table: self.id,
+                l1_index,
+                chunk: chunk.cast(),
+                generation: (*chunk).generation.load(Ordering::Relaxed),
+            });
+        }
+        chunk
+    }
+
+    /// Returns the provenance stored for `address`. Addresses in chunks that
+    /// have never been written to have no provenance.
+    #[inline(always)]
+    pub unsafe fn load(&self, address: usize) -> T {
+        let (l1_index, l2_index) = table_indices(address);
+        let chunk = self.chunk(l1_index);
+        if chunk.is_null() { mem::zeroed() } else { L2::read(chunk, l2_index) }
+    }
+
+    /// Stores the provenance for `address`, allocating the chunk that
+    /// contains it if necessary. Returns `false` if that allocation failed.
+    /// Chunks are detached from the table as soon as their last entry is cleared.
+    #[inline(always)]
+    pub unsafe fn store(&self, address: usize, value: T) -> bool {
+        let (l1_index, l2_index) = table_indices(address);
+        let now_empty = is_empty(&value);
+        value.retain();
+        loop {
+            let mut chunk = self.chunk(l1_index);
+            if chunk.is_null() {
+                if now_empty {
+                    return true;
+                }
+                let Some(installed) = self.install(l1_index) else {
+                    value.release();
+                    return false;
+                };
+                chunk = installed;
+            }
+            let state = (*chunk).state.load(Ordering::Acquire);
+            if state != EXPANDED {
+                if state == UNIFORM && (*chunk).uniform == value {
+                    value.release();
+                    return true;
+                }
+                L2::expand(chunk);
+            }
+            let locked_at = L2::lock(chunk, l2_index);
+            let old = L2::load_words(chunk, l2_index);
+            match (is_empty(&old), now_empty) {
+                (true, false) => {
+                    // If the chunk was detached after we loaded it, start over.
+                    let claimed =
+                        (*chunk).live.fetch_update(Ordering::AcqRel, Ordering::Acquire, |live| {
+                            (live != DEAD).then_some(live + 1)
+                        });
+                    if claimed.is_err() {
+                        L2::unlock(chunk, l2_index, locked_at);
+                        continue;
+                    }
+                    L2::store_words(chunk, l2_index, value);
+                }
+                (false, true) => {
+                    L2::store_words(chunk, l2_index, value);
+                    self.release(chunk);
+                }
+                _ => L2::store_words(chunk, l2_index, value),
+            }
+            L2::unlock(chunk, l2_index, locked_at);
+            old.release();
+            return true;
+        }
+    }
+
+    /// Clears the entries of every word starting in `[start, end)`, which must
+    /// be word-aligned. Chunks that aren't installed are skipped without
+    /// touching their entries, and so are the entries that are already empty.
+    unsafe fn clear_range(&self, start: usize, end: usize) {
+        let mut address = start;
+        while address < end {
+            let (l1_index, _) = table_indices(address);
+            let chunk_end = ((address | (CHUNK_BYTES - 1)) + 1).min(end);
+            let chunk = self.entry(l1_index).load(Ordering::Acquire);
+            if !chunk.is_null() {
+                if chunk_end - address == CHUNK_BYTES && self.detach_uniform(chunk) {
+                    address = chunk_end;
+                    continue;
+                }
+                loop {
+                    address = self.next_occupied(address, chunk_end);
+                    if address >= chunk_end {
+                        break;
+                    }
+                    self.store(address, mem::zeroed());
+                    address += PTR_BYTES;
+                }
+            }
+            address = chunk_end;
+        }
+    }
+
+    /// The first word-aligned address in `[start, end)` whose entry may hold
+    /// provenance, or `end` if there is none. The entries of chunks that
+    /// aren't expanded are all taken to.
+    unsafe fn next_occupied(&self, start: usize, end: usize) -> usize {
+        let mut address = start;
+        while address < end {
+            let (l1_index, l2_index) = table_indices(address);
+            let chunk_end = ((address | (CHUNK_BYTES - 1)) + 1).min(end);
+            let chunk = self.entry(l1_index).load(Ordering::Acquire);
+            if !chunk.is_null() {
+                if (*chunk).state.load(Ordering::Acquire) != EXPANDED {
+                    return address;
+                }
+                let len = (chunk_end - address).div_ceil(PTR_BYTES);
+                let index = L2::next_occupied(chunk, l2_index, l2_index + len);
+                if index < l2_index + len {
+                    return address + (index - l2_index) * PTR_BYTES;
+                }
+            }
+            address = chunk_end;
+        }
+        end
+    }
+
+    /// Stores `value` for every word in `[start, end)`, which must be
+    /// word-aligned. Chunks covered entirely by the range are installed as
+    /// uniform chunks, which cost no more memory than their header until a
+    /// different value is stored in them. Returns `false` if shadow memory
+    /// could not be allocated.
+    unsafe fn fill(&self, start: usize, end: usize, value: T) -> bool {
+        let mut address = start;
+        while address < end {
+            let (l1_index, _) = table_indices(address);
+            let chunk_end = ((address | (CHUNK_BYTES - 1)) + 1).min(end);
+            if chunk_end - address == CHUNK_BYTES && self.fill_chunk(l1_index, value) {
+                address = chunk_end;
+                continue;
+            }
+            while address < chunk_end {
+                if !self.store(address, value) {
+                    return false;
+                }
+                address += PTR_BYTES;
+            }
+        }
+        true
+    }
+
+    /// Installs a uniform chunk holding `value` for `l1_index`. Returns `false`
+    /// if a chunk with different contents is already installed, or if the
+    /// chunk could not be allocated, in which case the entries must be stored
+    /// one by one.
+    unsafe fn fill_chunk(&self, l1_index: usize, value: T) -> bool {
+        let installed = self.entry(l1_index).load(Ordering::Acquire);
+        if !installed.is_null() {
+            return (*installed).state.load(Ordering::Acquire) == UNIFORM
+                && (*installed).uniform == value;
+        }
+        if is_empty(&value) {
+            return true;
+        }
+        let chunk = self.map_chunk();
+        if chunk.is_null() {
+            return false;
+        }
+        (*chunk).l1_index = l1_index;
+        (*chunk).uniform = value;
+        // Stale pointers to a chunk that is installed again read these, but
+        // ignore the chunk once they see its new generation.
+        (*chunk).state.store(UNIFORM, Ordering::Relaxed);
+        (*chunk).live.store(L2_LEN, Ordering::Relaxed);
+        for _ in 0..L2_LEN {
+            value.retain();
+        }
+        if self.publish(l1_index, chunk).is_err() {
+            for _ in 0..L2_LEN {
+                value.release();
+            }
+            self.unmap_chunk(chunk);
+            return false;
+        }
+        true
+    }
+
+    /// Detaches `chunk` if it is uniform, without writing out its entries.
+    /// Returns `false` if it isn't.
+    unsafe fn detach_uniform(&self, chunk: *mut L2<T>) -> bool {
+        let state = &(*chunk).state;
+        if state.compare_exchange(UNIFORM, EXPANDING, Ordering::Acquire, Ordering::Relaxed).is_err()
+        {
+            return false;
+        }
+        // Stores to the chunk are waiting for it to be expanded, and will
+        // find it dead once it is. Its entries are all still zero.
+        (*chunk).live.store(DEAD, Ordering::Release);
+        self.entry((*chunk).l1_index).store(ptr::null_mut(), Ordering::Release);
+        self.num_chunks.fetch_sub(1, Ordering::Relaxed);
+        state.store(EXPANDED, Ordering::Release);
+        let value = (*chunk).uniform;
+        for _ in 0..L2_LEN {
+            value.release();
+        }
+        self.retire(chunk);
+        true
+    }
+
+    /// The number of second-level chunks that are currently installed.
+    pub fn num_chunks(&self) -> usize {
+        self.num_chunks.load(Ordering::Relaxed)
+    }
+
+    /// Whether the chunk containing `address` is installed.
+    pub fn is_mapped(&self, address: usize) -> bool {
+        let (l1_index, _) = table_indices(address);
+        unsafe { !self.entry(l1_index).load(Ordering::Acquire).is_null() }
+    }
+
+    /// Adds the memory used by this table to `usage`.
+    fn add_usage(&self, usage: &mut ShadowUsage) {
+        usage.reserved_bytes += Self::MAPPING_SIZE;
+        usage.resident_bytes += unsafe { resident_bytes(self.entries.cast(), Self::MAPPING_SIZE) };
+        usage.chunks += self.num_chunks();
+        let peak = self.peak_chunks.load(Ordering::Relaxed);
+        usage.peak_chunks += peak;
+        usage.peak_bytes += peak * Self::CHUNK_SIZE;
+        // Dead chunks stay mapped, so they are still counted as reserved.
+        let mut chunk = self.chunks.load(Ordering::Acquire);
+        while !chunk.is_null() {
+            unsafe {
+                usage.reserved_bytes += Self::CHUNK_SIZE;
+                usage.resident_bytes += resident_bytes(chunk.cast(), Self::CHUNK_SIZE);
+                let live = (*chunk).live.load(Ordering::Relaxed);
+                if live != DEAD {
+                    usage.entries += live;
+                }
+                chunk = (*chunk).next;
+            }
+        }
+    }
+
+    /// Installs a new chunk for `l1_index`, or returns the chunk that another
+    /// thread installed first.
+    #[cold]
+    unsafe fn install(&self, l1_index: usize) -> Option<*mut L2<T>> {
+        let chunk = self.map_chunk();
+        if chunk.is_null() {
+            return None;
+        }
+        (*chunk).l1_index = l1_index;
+        match self.publish(l1_index, chunk) {
+            Ok(()) => Some(chunk),
+            Err(winner) => {
+                self.unmap_chunk(chunk);
+                Some(winner)
+            }
+        }
+    }
+
+    /// Maps a zeroed chunk, or takes a dead one that can be installed again.
+    /// Returns null if the mapping failed.
+    unsafe fn map_chunk(&self) -> *mut L2<T> {
+        let chunk = self.take_dead();
+        if !chunk.is_null() {
+            return chunk;
+        }
+        let stride = Self::slab_stride();
+        if !self.huge_pages.load(Ordering::Relaxed) || stride > HUGE_PAGE_BYTES {
+            return map_zeroed(&self.allocator, Self::CHUNK_SIZE).cast();
+        }
+        let mut slabs = self.slabs.lock();
+        let current = *slabs;
+        if current.is_null() || (*current).used + stride > HUGE_PAGE_BYTES {
+            let base = map_huge(&self.allocator);
+            if base.is_null() {
+                return ptr::null_mut();
+            }
+            let Ok(slab) = self.allocator.allocate(Layout::new::<Slab>()) else {
+                self.allocator.unmap(base, HUGE_PAGE_BYTES);
+                return ptr::null_mut();
+            };
+            let slab = slab.as_ptr().cast::<Slab>();
+            slab.write(Slab { base, used: 0, next: current });
+            *slabs = slab;
+        }
+        let slab = *slabs;
+        let chunk = (*slab).base.byte_add((*slab).used).cast::<L2<T>>();
+        (*slab).used += stride;
+        (*chunk).from_slab = true;
+        chunk
+    }
+
+    /// Gives back a chunk from `map_chunk` that was never published.
+    unsafe fn unmap_chunk(&self, chunk: *mut L2<T>) {
+        if (*chunk).linked {
+            // A dead chunk goes back to the queue, as dead as it was taken.
+            (*chunk).state.store(EXPANDED, Ordering::Relaxed);
+            (*chunk).live.store(DEAD, Ordering::Release);
+            return self.retire(chunk);
+        }
+        if !(*chunk).from_slab {
+            return self.allocator.unmap(chunk.cast(), Self::CHUNK_SIZE);
+        }
+        // Only the header has been written to. If the chunk is the last one
+        // carved from its slab, it's zeroed again and handed out next time.
+        // Otherwise its memory is lost until the table is dropped, but this
+        // only happens when threads race to install the same chunk.
+        let slabs = self.slabs.lock();
+        let slab = *slabs;
+        let stride = Self::slab_stride();
+        if (*slab).base.byte_add((*slab).used - stride) == chunk.cast() {
+            let header = ptr::addr_of_mut!((*chunk).live).cast::<u8>();
+            header.write_bytes(0, Self::CHUNK_SIZE - mem::size_of::<[T; L2_LEN]>());
+            (*slab).used -= stride;
+        }
+    }
+
+    /// The space taken by each chunk in a slab. Chunks are page-aligned, so
+    /// that the pages holding their entries can be discarded separately.
+    fn slab_stride() -> usize {
+        Self::CHUNK_SIZE.next_multiple_of(host::page_size())
+    }
+
+    /// Installs `chunk` for `l1_index` and links it into `chunks`. Fails with
+    /// the installed chunk if another thread installed one first.
+    unsafe fn publish(&self, l1_index: usize, chunk: *mut L2<T>) -> Result<(), *mut L2<T>> {
+        let entry = self.entry(l1_index);
+        entry.compare_exchange(ptr::null_mut(), chunk, Ordering::AcqRel, Ordering::Acquire)?;
+        let chunks = self.num_chunks.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_chunks.fetch_max(chunks, Ordering::Relaxed);
+        if (*chunk).linked {
+            return Ok(());
+        }
+        (*chunk).linked = true;
+        let mut head = self.chunks.load(Ordering::Relaxed);
+        loop {
+            (*chunk).next = head;
+            match self.chunks.compare_exchange_weak(
+                head,
+                chunk,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Gives up one live entry of `chunk`, detaching it if it was the last one.
+    unsafe fn release(&self, chunk: *mut L2<T>) {
+        let live = &(*chunk).live;
+        if live.fetch_sub(1, Ordering::AcqRel) == 1
+            && live.compare_exchange(0, DEAD, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+        {
+            self.entry((*chunk).l1_index).store(ptr::null_mut(), Ordering::Release);
+            self.num_chunks.fetch_sub(1, Ordering::Relaxed);
+            // Only the pages holding entries are discarded. The header at the end
+            // of the chunk must keep `live` set to `DEAD` for any stale pointers.
+            let page_size = host::page_size();
+            let entry_pages = mem::size_of::<[T; L2_LEN]>() / page_size * page_size;
+            if entry_pages > 0 {
+                host::madvise(chunk.cast(), entry_pages, libc::MADV_DONTNEED);
+            }
+            self.retire(chunk);
+        }
+    }
+
+    /// Queues `chunk`, which is dead, to be installed again once no access can
+    /// still be using it.
+    unsafe fn retire(&self, chunk: *mut L2<T>) {
+        (*chunk).next_dead = ptr::null_mut();
+        let mut dead = self.dead.lock();
+        // The epoch is read under the lock, so the queue stays in order.
+        (*chunk).dead_at = epoch::current();
+        match dead.tail.as_mut() {
+            Some(tail) => tail.next_dead = chunk,
+            None => dead.head = chunk,
+        }
+        dead.tail = chunk;
+    }
+
+    /// Takes the chunk that has been dead the longest, if no access can still
+    /// be using it, or returns null. Its entries are all zero, and it starts a
+    /// new generation, so stale pointers to it are no longer used.
+    unsafe fn take_dead(&self) -> *mut L2<T> {
+        let mut dead = self.dead.lock();
+        let chunk = dead.head;
+        if chunk.is_null() || !epoch::is_reclaimable((*chunk).dead_at, epoch::try_advance()) {
+            return ptr::null_mut();
+        }
+        dead.head = (*chunk).next_dead;
+        if dead.head.is_null() {
+            dead.tail = ptr::null_mut();
+        }
+        drop(dead);
+        (*chunk).generation.fetch_add(1, Ordering::Relaxed);
+        (*chunk).live.store(0, Ordering::Release);
+        chunk
+    }
+}
+
+impl<T: Provenance> Drop for L1<T> {
+    fn drop(&mut self) {
+        let mut chunk = *self.chunks.get_mut();
+        while !chunk.is_null() {
+            unsafe {
+                let next = (*chunk).next;
+                if !(*chunk).from_slab {
+                    self.allocator.unmap(chunk.cast(), Self::CHUNK_SIZE);
+                }
+                chunk = next;
+            }
+        }
+        let mut slab = *self.slabs.get_mut();
+        while !slab.is_null() {
+            unsafe {
+                let next = (*slab).next;
+                self.allocator.unmap((*slab).base, HUGE_PAGE_BYTES);
+                self.allocator
+                    .deallocate(NonNull::new_unchecked(slab).cast(), Layout::new::<Slab>());
+                slab = next;
+            }
+        }
+        unsafe { self.allocator.unmap(self.entries.cast(), Self::MAPPING_SIZE) };
+    }
+}
+
+/// The memory used by a shadow heap.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ShadowUsage {
+    /// Second-level chunks that are installed.
+    pub chunks: usize,
+    /// Populated entries, which hold the provenance of one pointer each.
+    pub entries: usize,
+    /// Address space reserved for the tables and chunks.
+    pub reserved_bytes: usize,
+    /// The part of `reserved_bytes` backed by physical memory.
+    pub resident_bytes: usize,
+    /// The most chunks that each table held at once, summed over the tables,
+    /// and the memory that that many chunks take up.
+    pub peak_chunks: usize,
+    pub peak_bytes: usize,
+}
+
+/// The number of bytes in the `len` bytes of the mapping at `start` whose pages
+/// are resident. The mapping is queried in pieces, so that the vector that
+/// `mincore` fills in can be kept on the stack.
+unsafe fn resident_bytes(start: *mut c_void, len: usize) -> usize {
+    const PAGES_PER_QUERY: usize = 4096;
+    let page_size = host::page_size();
+    let mut pages = [0; PAGES_PER_QUERY];
+    let mut resident = 0;
+    let mut offset = 0;
+    while offset < len {
+        let query_len = (len - offset).min(PAGES_PER_QUERY * page_size);
+        if host::mincore(start.byte_add(offset), query_len, pages.as_mut_ptr()) == 0 {
+            let queried = query_len.div_ceil(page_size);
+            resident += pages[..queried].iter().filter(|&&page| page & 1 != 0).count();
+        }
+        offset += query_len;
+    }
+    (resident * page_size).min(len)
+}
+
+/// Returns the number of significant bits in the addresses that the host can
+/// map, either `VA_BITS` or `MAX_VA_BITS`. Addresses above `VA_BITS` are only
+/// handed out when they are explicitly asked for, so we ask for a page at an
+/// address that only fits in `MAX_VA_BITS`, and check where it was mapped.
+fn probe_va_bits(allocator: &BsanAllocator) -> u32 {
+    if MAX_VA_BITS == VA_BITS {
+        return VA_BITS;
+    }
+    let hint = 1usize << (MAX_VA_BITS - 1);
+    let page_size = host::page_size();
+    let probe = unsafe {
+        allocator.map_anonymous(
+            ptr::without_provenance_mut(hint),
+            page_size,
+            libc::PROT_NONE,
+            libc::MAP_NORESERVE,
+        )
+    };
+    if probe.is_null() {
+        return VA_BITS;
+    }
+    unsafe { allocator.unmap(probe, page_size) };
+    if probe.addr() > MAX_ADDR { MAX_VA_BITS } else { VA_BITS }
+}
+
+/// The top level of the table. Addresses below `2^VA_BITS` are the only ones
+/// on most hosts, so their first-level table is kept inline. On hosts with
+/// wider addresses, the tables for the remaining regions are reserved the
+/// first time a pointer is stored in them.
+pub struct L0<T: Provenance> {
+    low: L1<T>,
+    // Also used for the first-level tables in `high`, which are allocated
+    // from its heap.
+    high: [AtomicPtr<L1<T>>; L0_LEN],
+    va_bits: u32,
+    huge_pages: AtomicBool,
+}
+
+unsafe impl<T: Provenance + Send> Send for L0<T> {}
+unsafe impl<T: Provenance + Send> Sync for L0<T> {}
+
+impl<T: Provenance> L0<T> {
+    fn new(allocator: BsanAllocator, va_bits: u32) -> Option<Self> {
+        debug_assert!((VA_BITS..=MAX_VA_BITS).contains(&va_bits));
+        Some(Self {
+            low: L1::new(allocator)?,
+            high: [const { AtomicPtr::new(ptr::null_mut()) }; L0_LEN],
+            va_bits,
+            huge_pages: AtomicBool::new(false),
+        })
+    }
+
+    /// The highest address that the host can map.
+    #[inline(always)]
+    pub fn max_addr(&self) -> usize {
+        ((1u128 << self.va_bits) - 1) as usize
+    }
+
+    /// Returns the first-level table covering `address`, if it has been
+    /// reserved.
+    #[inline(always)]
+    fn table(&self, address: usize) -> Option<&L1<T>> {
+        let region = address >> VA_BITS;
+        if region == 0 {
+            return Some(&self.low);
+        }
+        if address > self.max_addr() {
+            return None;
+        }
+        unsafe { self.high.get_unchecked(region).load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Like `table`, but reserves the table if necessary.
+    #[cold]
+    fn table_or_install(&self, address: usize) -> Option<&L1<T>> {
+        if let Some(table) = self.table(address) {
+            return Some(table);
+        }
+        if address > self.max_addr() {
+            return None;
+        }
+        let allocator = self.low.allocator;
+        let table = L1::<T>::new(allocator)?;
+        table.huge_pages.store(self.huge_pages.load(Ordering::Relaxed), Ordering::Relaxed);
+        let Ok(boxed) = allocator.allocate(Layout::new::<L1<T>>()) else { return None };
+        let boxed = boxed.as_ptr().cast::<L1<T>>();
+        unsafe { boxed.write(table) };
+        let entry = &self.high[address >> VA_BITS];
+        match entry.compare_exchange(ptr::null_mut(), boxed, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => unsafe { boxed.as_ref() },
+            Err(winner) => unsafe {
+                boxed.drop_in_place();
+                allocator.deallocate(NonNull::new_unchecked(boxed).cast(), Layout::new::<L1<T>>());
+                winner.as_ref()
+            },
+        }
+    }
+
+    #[inline(always)]
+    pub unsafe fn load(&self, address: usize) -> T {
+        match self.table(address) {
+            Some(table) => table.load(address),
+            None => mem::zeroed(),
+        }
+    }
+
+    #[inline(always)]
+    pub unsafe fn store(&self, address: usize, value: T) -> bool {
+        if let Some(table) = self.table(address) {
+            return table.store(address, value);
+        }
+        if is_empty(&value) {
+            return true;
+        }
+        match self.table_or_install(address) {
+            Some(table) => table.store(address, value),
+            None => false,
+        }
+    }
+
+    unsafe fn clear_range(&self, start: usize, end: usize) {
+        let mut address = start;
+        while address < end {
+            let region_end = ((address | MAX_ADDR) as u128 + 1).min(end as u128) as usize;
+            if let Some(table) = self.table(address) {
+                table.clear_range(address, region_end);
+            }
+            address = region_end;
+        }
+    }
+
+    unsafe fn next_occupied(&self, start: usize, end: usize) -> usize {
+        let mut address = start;
+        while address < end {
+            let region_end = ((address | MAX_ADDR) as u128 + 1).min(end as u128) as usize;
+            if let Some(table) = self.table(address) {
+                let occupied = table.next_occupied(address, region_end);
+                if occupied < region_end {
+                    return occupied;
+                }
+            }
+            address = region_end;
+        }
+        end
+    }
+
+    unsafe fn fill(&self, start: usize, end: usize, value: T) -> bool {
+        let mut address = start;
+        while address < end {
+            let region_end = ((address | MAX_ADDR) as u128 + 1).min(end as u128) as usize;
+            match self.table_or_install(address) {
+                Some(table) if table.fill(address, region_end, value) => {}
+                _ => return false,
+            }
+            address = region_end;
+        }
+        true
+    }
+
+    /// Sets whether chunks installed from now on are carved out of huge pages.
+    fn set_huge_pages(&self, enabled: bool) {
+        self.huge_pages.store(enabled, Ordering::Relaxed);
+        self.low.huge_pages.store(enabled, Ordering::Relaxed);
+        for table in &self.high {
+            if let Some(table) = unsafe { table.load(Ordering::Acquire).as_ref() } {
+                table.huge_pages.store(enabled, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The number of second-level chunks that are currently installed.
+    pub fn num_chunks(&self) -> usize {
+        let high = self.high.iter().filter_map(|table| unsafe {
+            table.load(Ordering::Acquire).as_ref().map(L1::num_chunks)
+        });
+        self.low.num_chunks() + high.sum::<usize>()
+    }
+
+    /// Whether the chunk containing `address` is installed.
+    pub fn is_mapped(&self, address: usize) -> bool {
+        self.table(address).is_some_and(|table| table.is_mapped(address))
+    }
+
+    /// Adds the memory used by this table to `usage`.
+    fn add_usage(&self, usage: &mut ShadowUsage) {
+        self.low.add_usage(usage);
+        for table in &self.high {
+            if let Some(table) = unsafe { table.load(Ordering::Acquire).as_ref() } {
+                table.add_usage(usage);
+            }
+        }
+    }
+}
+
+impl<T: Provenance> L0<T> {
+    /// Returns an iterator over the addresses and values of every populated
+    /// entry in the table, in no particular order. Entries stored or cleared
+    /// concurrently may or may not be observed.
+    pub fn entries(&self) -> Entries<'_, T> {
+        Entries { table: self, region: 0, base: 0, chunk: ptr::null_mut(), slot: 0 }
+    }
+}
+
+/// The iterator returned by [`L0::entries`]. This walks the list of installed
+/// chunks of each first-level table, so chunks that have never been written
+/// to are never visited.
+pub struct Entries<'a, T: Provenance> {
+    table: &'a L0<T>,
+    // The next region of the address space to visit.
+    region: usize,
+    // The first address in the region being visited.
+    base: usize,
+    chunk: *mut L2<T>,
+    slot: usize,
+}
+
+impl<T: Provenance> Entries<'_, T> {
+    /// Moves on to the chunks of the next first-level table that has been
+    /// reserved. Returns `false` once every region has been visited.
+    fn next_table(&mut self) -> bool {
+        while self.region < L0_LEN {
+            let region = self.region;
+            self.region += 1;
+            let table = match region {
+                0 => Some(&self.table.low),
+                _ => unsafe { self.table.high[region].load(Ordering::Acquire).as_ref() },
+            };
+            if let Some(table) = table {
+                self.base = ((region as u128) << VA_BITS) as usize;
+                self.chunk = table.chunks.load(Ordering::Acquire);
+                self.slot = 0;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl<T: Provenance> Iterator for Entries<'_, T> {
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let chunk = self.chunk;
+            if chunk.is_null() {
+                if !self.next_table() {
+                    return None;
+                }
+                continue;
+            }
+            // Detached chunks are empty, so they can be skipped entirely.
+            if self.slot == L2_LEN || unsafe { (*chunk).live.load(Ordering::Acquire) } == DEAD {
+                self.chunk = unsafe { (*chunk).next };
+                self.slot = 0;
+                continue;
+            }
+            let slot = self.slot;
+            self.slot += 1;
+            let value = unsafe { L2::read(chunk, slot) };
+            if !is_empty(&value) {
+                let l1_index = unsafe { (*chunk).l1_index };
+                return Some((self.base + slot_address(l1_index, slot), value));
+            }
+        }
+    }
+}
+
+impl<T: Provenance> Drop for L0<T> {
+    fn drop(&mut self) {
+        for table in &mut self.high {
+            let table = *table.get_mut();
+            if !table.is_null() {
+                unsafe {
+                    table.drop_in_place();
+                    let allocator = self.low.allocator;
+                    allocator
+                        .deallocate(NonNull::new_unchecked(table).cast(), Layout::new::<L1<T>>());
+                }
+            }
+        }
+    }
+}
+
+/// The provenance of a pointer stored at an address that isn't word-aligned,
+/// kept in the slot of the word containing its first byte. Two pointers can't
+/// start within the same word without overlapping, so one slot is enough.
+/// Since `offset` is never zero, the all-zero pattern still means "empty".
+#[derive(Copy, Clone, PartialEq)]
+#[repr(C)]
+struct Misaligned<T> {
+    offset: usize,
+    value: T,
+}
+
+unsafe impl<T: Provenance> Provenance for Misaligned<T> {
+    #[inline(always)]
+    unsafe fn retain(&self) {
+        self.value.retain();
+    }
+
+    #[inline(always)]
+    unsafe fn release(&self) {
+        self.value.release();
     }
 }
 
 /// A two-level page table. This wrapper struct encapsulates
 /// the interior, unsafe implementation, providing debug assertions
 /// for each method.
-#[repr(transparent)]
+///
+/// Pointers in packed structs or written with `ptr::write_unaligned` don't
+/// start on a word boundary. These go into a second table, so that they are
+/// only ever observed by a load from exactly the same address. A store of
+/// either kind clears the provenance of every pointer that it overlaps.
+/// Until the first misaligned store, aligned accesses skip the second table.
 pub struct ShadowHeap<T: Provenance> {
-    l1: L1<T>,
+    aligned: L0<T>,
+    misaligned: L0<Misaligned<T>>,
+    any_misaligned: AtomicBool,
+}
+
+impl<T: Provenance> ShadowHeap<T> {
+    /// Reserves the first level of the table. Returns `None` if
+    /// the address space for it could not be mapped. All of the table's
+    /// memory is mapped and allocated through `allocator`.
+    pub fn new(allocator: BsanAllocator) -> Option<Self> {
+        Self::with_va_bits(allocator, probe_va_bits(&allocator))
+    }
+
+    fn with_va_bits(allocator: BsanAllocator, va_bits: u32) -> Option<Self> {
+        Some(Self {
+            aligned: L0::new(allocator, va_bits)?,
+            misaligned: L0::new(allocator, va_bits)?,
+            any_misaligned: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns the provenance of the pointer stored at exactly `address`.
+    #[inline(always)]
+    pub unsafe fn load(&self, address: usize) -> T {
+        let offset = address % PTR_BYTES;
+        if offset == 0 {
+            return self.aligned.load(address);
+        }
+        if !self.any_misaligned.load(Ordering::Acquire) {
+            return mem::zeroed();
+        }
+        let entry = self.misaligned.load(address - offset);
+        if entry.offset == offset { entry.value } else { mem::zeroed() }
+    }
+
+    /// Stores the provenance of a pointer written to `address`. Returns `false`
+    /// if the shadow memory for it could not be allocated, or if the address is
+    /// outside of the address space that the table [covers](Self::covers).
+    #[inline(always)]
+    pub unsafe fn store(&self, address: usize, value: T) -> bool {
+        if address > self.aligned.max_addr() {
+            return false;
+        }
+        let offset = address % PTR_BYTES;
+        let word = address - offset;
+        if offset == 0 {
+            if self.any_misaligned.load(Ordering::Acquire) {
+                self.clear_misaligned(address);
+            }
+            return self.aligned.store(address, value);
+        }
+        if !is_empty(&value) {
+            self.any_misaligned.store(true, Ordering::Release);
+        } else if !self.any_misaligned.load(Ordering::Acquire) {
+            return self.clear_words(word);
+        }
+        self.clear_misaligned(address);
+        if !self.clear_words(word) {
+            return false;
+        }
+        is_empty(&value) || self.misaligned.store(word, Misaligned { offset, value })
+    }
+
+    /// Sets whether new chunks are allocated from 2 MiB-aligned slabs that the
+    /// kernel is asked to back with transparent huge pages. Each slab holds
+    /// several chunks, so lookups that hit nearby chunks share a TLB entry.
+    /// Slabs are committed as a whole, though, so this uses more memory when
+    /// chunks are sparsely populated. Chunks that are already installed are
+    /// not moved.
+    pub fn set_huge_pages(&self, enabled: bool) {
+        self.aligned.set_huge_pages(enabled);
+        self.misaligned.set_huge_pages(enabled);
+    }
+
+    /// Whether every byte of `[address, address + len)` is within the address
+    /// space that the host can map. Anything else, such as a non-canonical or
+    /// kernel-space address, can never hold a pointer: stores to it fail and
+    /// loads from it have no provenance.
+    #[inline(always)]
+    pub fn covers(&self, address: usize, len: usize) -> bool {
+        let max_addr = self.aligned.max_addr();
+        address <= max_addr && len.saturating_sub(1) <= max_addr - address
+    }
+
+    /// Measures the memory used by the table. This queries the kernel for the
+    /// residency of every page that has been reserved, so it is only meant to
+    /// be used for reporting.
+    pub fn usage(&self) -> ShadowUsage {
+        let mut usage = ShadowUsage::default();
+        self.aligned.add_usage(&mut usage);
+        self.misaligned.add_usage(&mut usage);
+        usage
+    }
+
+    /// Returns an iterator over the address and provenance of every pointer
+    /// in the table, in no particular order. Like [`L0::entries`], this may
+    /// miss entries that are stored concurrently.
+    pub fn iter_provenance(&self) -> impl Iterator<Item = (usize, T)> + '_ {
+        let misaligned =
+            self.misaligned.entries().map(|(word, entry)| (word + entry.offset, entry.value));
+        self.aligned.entries().chain(misaligned)
+    }
+
+    /// Copies the provenance of the pointers stored in the `len` bytes at `src`
+    /// to the `len` bytes at `dst`, as if by `memmove`, so the ranges may
+    /// overlap. Pointers that don't fit entirely within the source range are
+    /// not copied, and the destination loses the provenance of everything it
+    /// previously held, including pointers that start just before it. Only the
+    /// part of each range that the table [covers](Self::covers) is copied.
+    pub unsafe fn copy_range(&self, dst: usize, src: usize, len: usize) {
+        if len == 0 || dst == src || !self.covers(dst, 1) {
+            return;
+        }
+        if !self.covers(src, 1) {
+            return self.clear_range(dst, len);
+        }
+        let len = len.min(self.aligned.max_addr() - dst.max(src) + 1);
+        let forward = dst < src;
+        if dst % PTR_BYTES == 0
+            && src % PTR_BYTES == 0
+            && !self.any_misaligned.load(Ordering::Acquire)
+        {
+            // The common case: whole words, copied slot by slot, in blocks
+            // that are skipped if neither range holds any provenance in them.
+            const BLOCK: usize = 64;
+            let words = len / PTR_BYTES;
+            let blocks = words.div_ceil(BLOCK);
+            for block in 0..blocks {
+                let block = if forward { block } else { blocks - 1 - block };
+                let (first, last) = (block * BLOCK, (block * BLOCK + BLOCK).min(words));
+                let (start, end) = (first * PTR_BYTES, last * PTR_BYTES);
+                if self.aligned.next_occupied(src + start, src + end) == src + end
+                    && self.aligned.next_occupied(dst + start, dst + end) == dst + end
+                {
+                    continue;
+                }
+                for word in first..last {
+                    let word = if forward { word } else { first + last - 1 - word };
+                    let offset = word * PTR_BYTES;
+                    self.aligned.store(dst + offset, self.aligned.load(src + offset));
+                }
+            }
+            if len % PTR_BYTES != 0 {
+                self.aligned.store(dst + words * PTR_BYTES, mem::zeroed());
+            }
+            return;
+        }
+        // Otherwise, pointers can start at any byte. Each step only replaces the
+        // pointer starting at exactly one address, and visiting the addresses in
+        // the same order as `memmove` ensures that every source pointer is read
+        // before it can be overwritten.
+        for byte in 0..len {
+            let offset = if forward { byte } else { len - 1 - byte };
+            let value =
+                if offset + PTR_BYTES <= len { self.load(src + offset) } else { mem::zeroed() };
+            self.replace(dst + offset, value);
+        }
+        for address in dst.saturating_sub(PTR_BYTES - 1)..dst {
+            self.replace(address, mem::zeroed());
+        }
+    }
+
+    /// Records that every word within `[address, address + len)` holds a
+    /// pointer with the provenance `value`, as after filling a buffer with
+    /// copies of one pointer. Any other pointer that overlaps the range loses
+    /// its provenance. Large fills are represented compactly, so this is much
+    /// cheaper than storing each word separately. Returns `false` if the
+    /// shadow memory could not be allocated.
+    pub unsafe fn fill_range(&self, address: usize, len: usize, value: T) -> bool {
+        let end = address.saturating_add(len).min(self.aligned.max_addr());
+        if address >= end {
+            return true;
+        }
+        self.clear_overlapping(address, end - address);
+        let first_word = address.next_multiple_of(PTR_BYTES);
+        let last_word = end - end % PTR_BYTES;
+        is_empty(&value)
+            || first_word >= last_word
+            || self.aligned.fill(first_word, last_word, value)
+    }
+
+    // Sets the provenance of the pointer starting at exactly `address`, without
+    // clearing the pointers that overlap it.
+    unsafe fn replace(&self, address: usize, value: T) {
+        let offset = address % PTR_BYTES;
+        let word = address - offset;
+        if offset == 0 {
+            self.aligned.store(address, value);
+        } else if !is_empty(&value) {
+            self.any_misaligned.store(true, Ordering::Release);
+            self.misaligned.store(word, Misaligned { offset, value });
+        } else if self.misaligned.load(word).offset == offset {
+            self.misaligned.store(word, mem::zeroed());
+        }
+    }
+
+    /// Clears the provenance of every pointer stored at an address within
+    /// `[address, address + len)`, such as the contents of a freed allocation.
+    pub unsafe fn clear_range(&self, address: usize, len: usize) {
+        // The last byte of the address space is never mapped, so capping the
+        // range here keeps the table indices from wrapping around.
+        let end = address.saturating_add(len).min(self.aligned.max_addr());
+        if address >= end {
+            return;
+        }
+        let first_word = address - address % PTR_BYTES;
+        let last_word = end - end % PTR_BYTES;
+        self.aligned
+            .clear_range(first_word + if first_word < address { PTR_BYTES } else { 0 }, end);
+        if !self.any_misaligned.load(Ordering::Acquire) {
+            return;
+        }
+        // Only the first and last words can hold misaligned pointers that
+        // start outside of the range.
+        self.clear_misaligned_within(first_word, address, end);
+        if last_word != first_word {
+            self.clear_misaligned_within(last_word, address, end);
+        }
+        if first_word + PTR_BYTES < last_word {
+            self.misaligned.clear_range(first_word + PTR_BYTES, last_word);
+        }
+    }
+
+    /// Clears the provenance of every pointer that overlaps any byte within
+    /// `[address, address + len)`, as when those bytes are overwritten with
+    /// data that isn't a pointer. Unlike [`ShadowHeap::clear_range`], this
+    /// includes pointers that start just before the range.
+    pub unsafe fn clear_overlapping(&self, address: usize, len: usize) {
+        if len > 0 {
+            let start = address.saturating_sub(PTR_BYTES - 1);
+            self.clear_range(start, len.saturating_add(address - start));
+        }
+    }
+
+    // Clears the misaligned pointer in `word`, if it starts in `[start, end)`.
+    unsafe fn clear_misaligned_within(&self, word: usize, start: usize, end: usize) {
+        let entry = self.misaligned.load(word);
+        if !is_empty(&entry) && (start..end).contains(&(word + entry.offset)) {
+            self.misaligned.store(word, mem::zeroed());
+        }
+    }
+
+    // Clears the two aligned slots overlapped by a misaligned pointer in `word`.
+    unsafe fn clear_words(&self, word: usize) -> bool {
+        let mut cleared = self.aligned.store(word, mem::zeroed());
+        if word < self.aligned.max_addr() - PTR_BYTES {
+            cleared &= self.aligned.store(word + PTR_BYTES, mem::zeroed());
+        }
+        cleared
+    }
+
+    // Clears every misaligned pointer that overlaps a pointer-sized store to
+    // `address`. These all start within the word before `address` and the
+    // word after it.
+    unsafe fn clear_misaligned(&self, address: usize) {
+        let word = address - address % PTR_BYTES;
+        let first = word.saturating_sub(PTR_BYTES);
+        let last = if word < self.aligned.max_addr() - PTR_BYTES { word + PTR_BYTES } else { word };
+        let mut current = first;
+        while current <= last {
+            let entry = self.misaligned.load(current);
+            if !is_empty(&entry) && (current + entry.offset).abs_diff(address) < PTR_BYTES {
+                self.misaligned.store(current, mem::zeroed());
+            }
+            current += PTR_BYTES;
+        }
+    }
+}
+
+impl<T: Provenance> fmt::Debug for ShadowHeap<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShadowHeap")
+            .field("chunks", &self.aligned.num_chunks())
+            .field("misaligned_chunks", &self.misaligned.num_chunks())
+            .finish()
+    }
 }
 
 impl<T: Provenance> Default for ShadowHeap<T> {
     fn default() -> Self {
-        let l1 = L1::<T>::new();
-        Self { l1 }
+        Self::new(alloc::runtime_allocator()).expect("failed to reserve the shadow page table")
     }
 }
 
 impl<T: Provenance> Deref for ShadowHeap<T> {
-    type Target = L1<T>;
+    type Target = L0<T>;
     fn deref(&self) -> &Self::Target {
-        &self.l1
-    }
-}
-
-impl<T: Provenance> DerefMut for ShadowHeap<T> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.l1
+        &self.aligned
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use proptest::prelude::*;
+
     use super::*;
-    type TestProv = u8;
+    use crate::alloc::TEST_ALLOCATOR;
+    type TestProv = usize;
 
-    impl Provenance for TestProv {}
+    unsafe impl Provenance for TestProv {}
 
     #[test]
     fn create_and_drop() {
         let _ = ShadowHeap::<TestProv>::default();
     }
+
+    #[test]
+    fn memory_is_mapped_by_the_allocator() {
+        static MAPPED: AtomicUsize = AtomicUsize::new(0);
+        unsafe extern "C" fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: libc::c_int,
+            flags: libc::c_int,
+            fd: libc::c_int,
+            off: i64,
+        ) -> *mut c_void {
+            MAPPED.fetch_add(len, Ordering::Relaxed);
+            libc::mmap(addr, len, prot, flags, fd, off)
+        }
+        unsafe extern "C" fn munmap(addr: *mut c_void, len: usize) -> libc::c_int {
+            MAPPED.fetch_sub(len, Ordering::Relaxed);
+            libc::munmap(addr, len)
+        }
+        let allocator = BsanAllocator::new(mmap, munmap);
+        let heap = ShadowHeap::<TestProv>::with_va_bits(allocator, VA_BITS).unwrap();
+        let tables = MAPPED.load(Ordering::Relaxed);
+        assert_eq!(tables, 2 * L1::<TestProv>::MAPPING_SIZE);
+        unsafe { heap.store(0x1000, 1) };
+        assert_eq!(MAPPED.load(Ordering::Relaxed), tables + L1::<TestProv>::CHUNK_SIZE);
+        drop(heap);
+        assert_eq!(MAPPED.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn loads_from_unmapped_chunks_are_empty() {
+        let heap = ShadowHeap::<TestProv>::default();
+        unsafe {
+            assert_eq!(heap.load(0x1000), 0);
+            assert_eq!(heap.load(((1u128 << VA_BITS) - 1) as usize), 0);
+        }
+    }
+
+    #[test]
+    fn chunks_are_allocated_on_first_store() {
+        let heap = ShadowHeap::<TestProv>::default();
+        unsafe {
+            assert!(!heap.is_mapped(CHUNK_BYTES));
+            assert!(heap.store(CHUNK_BYTES + PTR_BYTES, 7));
+            assert_eq!(heap.load(CHUNK_BYTES + PTR_BYTES), 7);
+            // The rest of the chunk is zeroed.
+            assert!(heap.is_mapped(CHUNK_BYTES));
+            assert_eq!(heap.load(CHUNK_BYTES), 0);
+            assert!(!heap.is_mapped(2 * CHUNK_BYTES));
+            assert!(heap.store(2 * CHUNK_BYTES, 9));
+            assert_eq!(heap.load(CHUNK_BYTES + PTR_BYTES), 7);
+            assert_eq!(heap.load(2 * CHUNK_BYTES), 9);
+        }
+    }
+
+    #[test]
+    fn empty_chunks_are_reclaimed() {
+        let heap = ShadowHeap::<TestProv>::default();
+        unsafe {
+            // Clearing an unmapped address doesn't allocate a chunk.
+            assert!(heap.store(0x1000, 0));
+            assert_eq!(heap.num_chunks(), 0);
+            assert!(heap.store(0x1000, 1));
+            assert!(heap.store(0x1008, 2));
+            assert!(heap.store(CHUNK_BYTES, 3));
+            assert_eq!(heap.num_chunks(), 2);
+            assert!(heap.store(0x1000, 0));
+            assert_eq!(heap.num_chunks(), 2);
+            assert!(heap.store(0x1008, 0));
+            assert_eq!(heap.num_chunks(), 1);
+            assert!(!heap.is_mapped(0x1000));
+            assert_eq!(heap.load(CHUNK_BYTES), 3);
+            assert!(heap.store(CHUNK_BYTES, 0));
+            assert_eq!(heap.num_chunks(), 0);
+        }
+    }
+
+    #[test]
+    fn detached_chunks_stay_dead_and_empty() {
+        let heap = ShadowHeap::<TestProv>::default();
+        unsafe {
+            for word in 0..L2_LEN {
+                assert!(heap.store(word * PTR_BYTES, 1));
+            }
+            for word in 0..L2_LEN {
+                assert!(heap.store(word * PTR_BYTES, 0));
+            }
+            let chunk = heap.aligned.low.chunks.load(Ordering::Acquire);
+            assert_eq!((*chunk).live.load(Ordering::Acquire), DEAD);
+            assert!((0..L2_LEN).all(|index| L2::load_words(chunk, index) == 0));
+        }
+    }
+
+    #[test]
+    fn dead_chunks_are_installed_again() {
+        let heap = ShadowHeap::<TestProv>::default();
+        let table = &heap.aligned.low;
+        unsafe {
+            assert!(heap.store(0x1000, 1));
+            assert_eq!(heap.load(0x1000), 1);
+            let dead = table.chunks.load(Ordering::Acquire);
+            assert!(heap.store(0x1000, 0));
+            // Each chunk that is installed tries to advance the epoch, until
+            // no thread can be using the dead one anymore. None of them share
+            // the cache way of 0x1000.
+            let installs = (1..1000)
+                .find(|&install| {
+                    let addr = (4 * install + 1) * CHUNK_BYTES + 0x1000;
+                    assert!(heap.store(addr, 2));
+                    let installed = table.entry(4 * install + 1).load(Ordering::Acquire);
+                    if installed != dead {
+                        assert!(heap.store(addr, 0));
+                    }
+                    installed == dead
+                })
+                .unwrap();
+            // The chunk is still cached for 0x1000, but isn't used for it.
+            assert_eq!(heap.load(0x1000), 0);
+            assert_eq!(heap.load((4 * installs + 1) * CHUNK_BYTES + 0x1000), 2);
+            let mut chunks = 0;
+            let mut chunk = table.chunks.load(Ordering::Acquire);
+            while !chunk.is_null() {
+                chunks += 1;
+                chunk = (*chunk).next;
+            }
+            assert_eq!(chunks, installs);
+        }
+    }
+
+    #[test]
+    fn constants_cover_address_space() {
+        assert_eq!(L2_LEN * PTR_BYTES, CHUNK_BYTES);
+        assert_eq!((L1_LEN as u128) * (CHUNK_BYTES as u128), 1u128 << VA_BITS);
+        assert_eq!(l2_power(64 * 1024), 16 - PTR_ALIGN_BITS);
+        assert_eq!(l1_power(48, 64 * 1024), 32);
+        assert_eq!(l1_power(48, 16 * 1024), 34);
+        assert_eq!(l1_power(48, 128 * 1024), 31);
+    }
+
+    #[test]
+    fn indices_of_boundary_addresses() {
+        assert_eq!(table_indices(0), (0, 0));
+        assert_eq!(table_indices(PTR_BYTES), (0, 1));
+        assert_eq!(table_indices(CHUNK_BYTES - PTR_BYTES), (0, L2_LEN - 1));
+        assert_eq!(table_indices(CHUNK_BYTES), (1, 0));
+        assert_eq!(table_indices(CHUNK_BYTES + PTR_BYTES), (1, 1));
+        let max_addr = ((1u128 << VA_BITS) - 1) as usize;
+        assert_eq!(table_indices(max_addr), (L1_LEN - 1, L2_LEN - 1));
+    }
+
+    #[test]
+    fn bytes_within_a_word_share_a_slot() {
+        let base = 3 * CHUNK_BYTES + 5 * PTR_BYTES;
+        for offset in 0..PTR_BYTES {
+            assert_eq!(table_indices(base + offset), (3, 5));
+        }
+        assert_eq!(table_indices(base + PTR_BYTES), (3, 6));
+    }
+
+    #[test]
+    fn indices_round_trip() {
+        let mut address = PTR_BYTES;
+        while address < ((1u128 << VA_BITS) - 1) as usize {
+            let (l1, l2) = table_indices(address);
+            assert_eq!(slot_address(l1, l2), address);
+            address = address.wrapping_mul(3) + PTR_BYTES;
+        }
+    }
+
+    #[test]
+    fn misaligned_pointers_are_tracked_exactly() {
+        let heap = ShadowHeap::<TestProv>::default();
+        let word = CHUNK_BYTES;
+        unsafe {
+            assert!(heap.store(word, 1));
+            assert!(heap.store(word + PTR_BYTES, 2));
+            // A misaligned store overwrites both of the words it overlaps.
+            assert!(heap.store(word + 1, 3));
+            assert_eq!(heap.load(word + 1), 3);
+            assert_eq!(heap.load(word), 0);
+            assert_eq!(heap.load(word + PTR_BYTES), 0);
+            // It isn't visible from any other address within the same word.
+            assert_eq!(heap.load(word + 2), 0);
+            // An aligned store that overlaps it clears it.
+            assert!(heap.store(word + PTR_BYTES, 4));
+            assert_eq!(heap.load(word + 1), 0);
+            assert_eq!(heap.load(word + PTR_BYTES), 4);
+            assert!(heap.store(word + PTR_BYTES - 1, 5));
+            assert!(heap.store(word + 2 * PTR_BYTES - 2, 6));
+            assert_eq!(heap.load(word + PTR_BYTES - 1), 0);
+            assert_eq!(heap.load(word + 2 * PTR_BYTES - 2), 6);
+            assert!(heap.store(word + 2 * PTR_BYTES - 2, 0));
+            assert_eq!(heap.misaligned.num_chunks(), 0);
+        }
+    }
+
+    #[test]
+    fn clearing_a_range_only_clears_pointers_within_it() {
+        let heap = ShadowHeap::<TestProv>::default();
+        let start = CHUNK_BYTES - 3;
+        let end = 3 * CHUNK_BYTES + 3;
+        let inside = [
+            start,
+            CHUNK_BYTES + PTR_BYTES,
+            2 * CHUNK_BYTES + 5,
+            3 * CHUNK_BYTES - PTR_BYTES,
+            end - 1,
+        ];
+        let outside = [start - 2 * PTR_BYTES - 1, start - PTR_BYTES - 1, end + PTR_BYTES];
+        unsafe {
+            for &addr in inside.iter().chain(&outside) {
+                assert!(heap.store(addr, 1));
+            }
+            heap.clear_range(start, end - start);
+            for addr in inside {
+                assert_eq!(heap.load(addr), 0, "{addr:#x} was not cleared");
+            }
+            for addr in outside {
+                assert_eq!(heap.load(addr), 1, "{addr:#x} was cleared");
+            }
+            // The chunks that were emptied entirely are reclaimed.
+            assert!(!heap.is_mapped(2 * CHUNK_BYTES));
+            assert_eq!(heap.num_chunks(), 0);
+            assert_eq!(heap.misaligned.num_chunks(), 2);
+        }
+    }
+
+    #[test]
+    fn overwriting_a_range_clears_pointers_that_overlap_it() {
+        let heap = ShadowHeap::<TestProv>::default();
+        let (start, end) = (0x1004, 0x1014);
+        unsafe {
+            for addr in [start - PTR_BYTES, 0x1008, end - 1, end + 3] {
+                assert!(heap.store(addr, 1));
+            }
+            heap.clear_overlapping(start, end - start);
+            let mut left: Vec<usize> = heap.iter_provenance().map(|(addr, _)| addr).collect();
+            left.sort();
+            assert_eq!(left, [start - PTR_BYTES, end + 3]);
+            assert!(heap.store(start - PTR_BYTES + 1, 1));
+            heap.clear_overlapping(start, 1);
+            assert_eq!(heap.load(start - PTR_BYTES + 1), 0);
+            assert!(heap.store(start - PTR_BYTES, 1));
+            heap.clear_overlapping(start - PTR_BYTES, 0);
+            assert_eq!(heap.load(start - PTR_BYTES), 1);
+        }
+    }
+
+    #[test]
+    fn copies_move_pointers_with_their_bytes() {
+        let heap = ShadowHeap::<TestProv>::default();
+        let src = CHUNK_BYTES;
+        unsafe {
+            assert!(heap.store(src, 1));
+            assert!(heap.store(src + PTR_BYTES, 2));
+            // Copying whole words keeps pointers aligned.
+            heap.copy_range(4 * CHUNK_BYTES, src, 2 * PTR_BYTES);
+            assert_eq!(heap.load(4 * CHUNK_BYTES), 1);
+            assert_eq!(heap.load(4 * CHUNK_BYTES + PTR_BYTES), 2);
+            // Pointers that are cut off by the end of the range aren't copied.
+            heap.copy_range(5 * CHUNK_BYTES + 3, src, 2 * PTR_BYTES - 1);
+            assert_eq!(heap.load(5 * CHUNK_BYTES + 3), 1);
+            assert_eq!(heap.load(5 * CHUNK_BYTES + 3 + PTR_BYTES), 0);
+            // Overlapping copies behave like `memmove` in both directions.
+            heap.copy_range(src + 1, src, 2 * PTR_BYTES);
+            assert_eq!(heap.load(src + 1), 1);
+            assert_eq!(heap.load(src + 1 + PTR_BYTES), 2);
+            assert_eq!(heap.load(src), 0);
+            heap.copy_range(src, src + 1, 2 * PTR_BYTES);
+            assert_eq!(heap.load(src), 1);
+            assert_eq!(heap.load(src + PTR_BYTES), 2);
+            assert_eq!(heap.load(src + 1), 0);
+        }
+    }
+
+    #[test]
+    fn scans_find_the_first_nonzero_word() {
+        for len in 0..40 {
+            let mut words = vec![0usize; len];
+            assert_eq!(unsafe { first_nonzero_word(words.as_ptr(), len) }, len);
+            for index in (0..len).rev() {
+                words[index] = 1 << (index % usize::BITS as usize);
+                assert_eq!(unsafe { first_nonzero_word(words.as_ptr(), len) }, index);
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_ranges_are_cleared_and_copied_across_chunks() {
+        let heap = ShadowHeap::<TestProv>::default();
+        let len = 3 * CHUNK_BYTES;
+        let (src, dst) = (CHUNK_BYTES, 8 * CHUNK_BYTES);
+        let pointers = [0, PTR_BYTES, 67 * PTR_BYTES, CHUNK_BYTES + 8 * PTR_BYTES, len - PTR_BYTES];
+        unsafe {
+            for (value, &offset) in pointers.iter().enumerate() {
+                assert!(heap.store(src + offset, value + 1));
+            }
+            assert!(heap.store(dst + 2 * CHUNK_BYTES + 5 * PTR_BYTES, 9));
+            heap.copy_range(dst, src, len);
+            for (value, &offset) in pointers.iter().enumerate() {
+                assert_eq!(heap.load(dst + offset), value + 1);
+            }
+            assert_eq!(heap.load(dst + 2 * CHUNK_BYTES + 5 * PTR_BYTES), 0);
+            heap.clear_range(src + PTR_BYTES, len - 2 * PTR_BYTES);
+            assert_eq!(heap.load(src), 1);
+            for &offset in &pointers[1..pointers.len() - 1] {
+                assert_eq!(heap.load(src + offset), 0);
+            }
+            assert_eq!(heap.load(src + len - PTR_BYTES), pointers.len());
+        }
+    }
+
+    #[test]
+    fn cached_chunks_are_not_used_once_detached() {
+        let heap = ShadowHeap::<TestProv>::default();
+        std::thread::spawn(move || unsafe {
+            let (hits, _) = chunk_cache_stats();
+            assert!(heap.store(0x1000, 1));
+            for i in 0..100 {
+                assert_eq!(heap.load(0x1000 + (i % 2) * PTR_BYTES), [1, 0][i % 2]);
+            }
+            // The chunk is detached, and a new one is installed in its place.
+            assert!(heap.store(0x1000, 0));
+            assert_eq!(heap.num_chunks(), 0);
+            assert!(heap.store(0x1008, 2));
+            assert_eq!(heap.load(0x1000), 0);
+            assert_eq!(heap.load(0x1008), 2);
+            // Misses publish the hits that preceded them.
+            assert_eq!(heap.load(0x1000 + 2 * CHUNK_BYTES), 0);
+            assert!(chunk_cache_stats().0 >= hits + 100);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn usage_counts_chunks_and_entries() {
+        let heap = ShadowHeap::<TestProv>::with_va_bits(TEST_ALLOCATOR, VA_BITS).unwrap();
+        let empty = heap.usage();
+        assert_eq!((empty.chunks, empty.entries), (0, 0));
+        assert_eq!(empty.reserved_bytes, 2 * L1::<TestProv>::MAPPING_SIZE);
+        unsafe {
+            assert!(heap.store(0x1000, 1));
+            assert!(heap.store(0x1008, 2));
+            assert!(heap.store(0x2003, 3));
+            assert!(heap.store(4 * CHUNK_BYTES, 4));
+        }
+        let usage = heap.usage();
+        assert_eq!((usage.chunks, usage.entries), (3, 4));
+        assert_eq!(
+            usage.reserved_bytes,
+            empty.reserved_bytes
+                + 2 * L1::<TestProv>::CHUNK_SIZE
+                + L1::<Misaligned<TestProv>>::CHUNK_SIZE
+        );
+        // Every chunk has been written to, so at least one of its pages is resident.
+        assert!(usage.resident_bytes > empty.resident_bytes);
+        assert!(usage.resident_bytes <= usage.reserved_bytes);
+        unsafe { assert!(heap.store(4 * CHUNK_BYTES, 0)) };
+        let usage = heap.usage();
+        assert_eq!((usage.chunks, usage.entries), (2, 3));
+    }
+
+    #[test]
+    fn filled_chunks_are_expanded_on_demand() {
+        let heap = ShadowHeap::<TestProv>::with_va_bits(TEST_ALLOCATOR, VA_BITS).unwrap();
+        let base = 4 * CHUNK_BYTES;
+        let len = 3 * CHUNK_BYTES + 2 * PTR_BYTES;
+        unsafe {
+            assert!(heap.fill_range(base - PTR_BYTES, len, 7));
+            assert_eq!(heap.num_chunks(), 5);
+            assert_eq!(heap.load(base - PTR_BYTES), 7);
+            assert_eq!(heap.load(base + 2 * CHUNK_BYTES + 0x100), 7);
+            assert_eq!(heap.load(base + 3 * CHUNK_BYTES), 7);
+            assert_eq!(heap.load(base + 3 * CHUNK_BYTES + PTR_BYTES), 0);
+            assert_eq!(heap.iter_provenance().count(), len / PTR_BYTES);
+            let usage = heap.usage();
+            assert_eq!(usage.entries, len / PTR_BYTES);
+            // Storing the same value leaves the chunk uniform.
+            assert!(heap.store(base + 0x40, 7));
+            assert_eq!(heap.usage().resident_bytes, usage.resident_bytes);
+            // Storing a different one commits all of its entries.
+            assert!(heap.store(base + 0x40, 8));
+            assert_eq!(heap.load(base + 0x40), 8);
+            assert_eq!(heap.load(base + 0x48), 7);
+            assert_eq!(heap.load(base + CHUNK_BYTES - PTR_BYTES), 7);
+            // Clearing a uniform chunk detaches it without expanding it.
+            heap.clear_range(base + CHUNK_BYTES, CHUNK_BYTES);
+            assert_eq!(heap.num_chunks(), 4);
+            assert_eq!(heap.load(base + CHUNK_BYTES), 0);
+            assert_eq!(heap.usage().entries, len / PTR_BYTES - L2_LEN);
+        }
+    }
+
+    #[test]
+    fn iteration_visits_every_populated_entry() {
+        let heap = ShadowHeap::<TestProv>::default();
+        let addrs = [0x8, 0x1000, 0x1013, 3 * CHUNK_BYTES + 0x10, MAX_ADDR - 2 * PTR_BYTES + 1];
+        for (i, &addr) in addrs.iter().enumerate() {
+            assert!(unsafe { heap.store(addr, i as TestProv + 1) });
+        }
+        let mut found: Vec<(usize, TestProv)> = heap.iter_provenance().collect();
+        found.sort();
+        let expected: Vec<(usize, TestProv)> =
+            addrs.iter().enumerate().map(|(i, &addr)| (addr, i as TestProv + 1)).collect();
+        assert_eq!(found, expected);
+        // Chunks that are detached once emptied are no longer visited.
+        for addr in addrs {
+            assert!(unsafe { heap.store(addr, 0) });
+        }
+        assert_eq!(heap.iter_provenance().next(), None);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn wide_addresses_use_a_third_level() {
+        let heap = ShadowHeap::<TestProv>::with_va_bits(TEST_ALLOCATOR, MAX_VA_BITS).unwrap();
+        let high = 1usize << 50;
+        let highest = (1usize << MAX_VA_BITS) - PTR_BYTES;
+        unsafe {
+            assert!(heap.store(high, 1));
+            assert!(heap.store(highest, 2));
+            assert!(heap.store(high & MAX_ADDR, 3));
+            assert_eq!(heap.load(high), 1);
+            assert_eq!(heap.load(highest), 2);
+            assert_eq!(heap.load(high & MAX_ADDR), 3);
+            assert_eq!(heap.num_chunks(), 3);
+            let mut found: Vec<(usize, TestProv)> = heap.iter_provenance().collect();
+            found.sort();
+            assert_eq!(found, [(high & MAX_ADDR, 3), (high, 1), (highest, 2)]);
+            assert!(!heap.store(1 << MAX_VA_BITS, 4));
+            assert_eq!(heap.load(1 << MAX_VA_BITS), 0);
+            heap.clear_range(high - CHUNK_BYTES, 2 * CHUNK_BYTES);
+            assert_eq!(heap.load(high), 0);
+            assert_eq!(heap.num_chunks(), 2);
+        }
+        let narrow = ShadowHeap::<TestProv>::with_va_bits(TEST_ALLOCATOR, VA_BITS).unwrap();
+        unsafe {
+            assert!(!narrow.store(high, 1));
+            assert_eq!(narrow.load(high), 0);
+        }
+    }
+
+    #[test]
+    fn huge_pages_carve_chunks_out_of_aligned_slabs() {
+        let heap = ShadowHeap::<TestProv>::with_va_bits(TEST_ALLOCATOR, VA_BITS).unwrap();
+        heap.set_huge_pages(true);
+        let table = &heap.aligned.low;
+        let per_slab = HUGE_PAGE_BYTES / L1::<TestProv>::slab_stride();
+        let chunks: Vec<usize> = (0..per_slab + 1)
+            .map(|i| unsafe {
+                assert!(heap.store(i * CHUNK_BYTES, i as TestProv + 1));
+                let chunk = table.entry(i).load(Ordering::Acquire);
+                assert!((*chunk).from_slab);
+                chunk.addr()
+            })
+            .collect();
+        let slab = chunks[0] & !(HUGE_PAGE_BYTES - 1);
+        assert!(chunks[..per_slab].iter().all(|&chunk| chunk & !(HUGE_PAGE_BYTES - 1) == slab));
+        assert_ne!(chunks[per_slab] & !(HUGE_PAGE_BYTES - 1), slab);
+        unsafe {
+            for i in 0..per_slab + 1 {
+                assert_eq!(heap.load(i * CHUNK_BYTES), i as TestProv + 1);
+            }
+            // A chunk that loses the race to be installed is reused.
+            let chunk = table.map_chunk();
+            (*chunk).l1_index = 42;
+            table.unmap_chunk(chunk);
+            assert_eq!(table.map_chunk(), chunk);
+            assert_eq!((*chunk).l1_index, 0);
+            table.unmap_chunk(chunk);
+        }
+        heap.set_huge_pages(false);
+        let last = per_slab + 1;
+        unsafe {
+            assert!(heap.store(last * CHUNK_BYTES, 1));
+            assert!(!(*table.entry(last).load(Ordering::Acquire)).from_slab);
+        }
+    }
+
+    #[test]
+    fn addresses_outside_the_address_space_are_rejected() {
+        let heap = ShadowHeap::<TestProv>::with_va_bits(TEST_ALLOCATOR, VA_BITS).unwrap();
+        let kernel = 0xffff_8000_0000_0000;
+        assert!(heap.covers(MAX_ADDR - 7, 8));
+        assert!(!heap.covers(MAX_ADDR - 7, 9));
+        assert!(!heap.covers(kernel, 0));
+        assert!(!heap.covers(usize::MAX, 1));
+        unsafe {
+            assert!(!heap.store(kernel, 1));
+            assert!(!heap.store(usize::MAX - 3, 1));
+            assert_eq!(heap.load(kernel), 0);
+            assert!(heap.store(0x1000, 2));
+            assert!(heap.store(MAX_ADDR - 7, 3));
+            // Copies are truncated at the end of the address space, rather
+            // than wrapping around.
+            heap.copy_range(usize::MAX - 15, 0x1000, 16);
+            heap.copy_range(0x2000, MAX_ADDR - 7, 16);
+            assert_eq!(heap.load(0x2000), 3);
+            heap.copy_range(0x1000, kernel, 8);
+            assert_eq!(heap.load(0x1000), 0);
+            heap.clear_range(kernel, usize::MAX - kernel);
+            assert_eq!(heap.load(MAX_ADDR - 7), 3);
+        }
+    }
+
+    #[test]
+    fn concurrent_stores_share_installed_chunks() {
+        const THREADS: usize = 8;
+        let heap = ShadowHeap::<TestProv>::default();
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let heap = &heap;
+                scope.spawn(move || {
+                    for chunk in 0..16 {
+                        let addr = chunk * CHUNK_BYTES + thread * PTR_BYTES;
+                        assert!(unsafe { heap.store(addr, thread as TestProv + 1) });
+                    }
+                });
+            }
+        });
+        assert_eq!(heap.num_chunks(), 16);
+        for chunk in 0..16 {
+            for thread in 0..THREADS {
+                let addr = chunk * CHUNK_BYTES + thread * PTR_BYTES;
+                assert_eq!(unsafe { heap.load(addr) }, thread as TestProv + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_loads_never_observe_torn_entries() {
+        #[derive(Debug, Copy, Clone, PartialEq)]
+        #[repr(C)]
+        struct Pair(usize, usize);
+        unsafe impl Provenance for Pair {}
+
+        let heap = ShadowHeap::<Pair>::default();
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    let Pair(a, b) = unsafe { heap.load(0x1000) };
+                    assert_eq!(a, b);
+                }
+            });
+            let writers: Vec<_> = (1..=4)
+                .map(|writer| {
+                    let heap = &heap;
+                    scope.spawn(move || {
+                        for i in 0..10_000 {
+                            let value = writer * 100_000 + i;
+                            assert!(unsafe { heap.store(0x1000, Pair(value, value)) });
+                        }
+                    })
+                })
+                .collect();
+            writers.into_iter().for_each(|writer| writer.join().unwrap());
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+
+    /// Addresses within the significant range, biased towards the edges of
+    /// chunks and of the address space, where index math tends to go wrong.
+    fn address() -> impl Strategy<Value = usize> {
+        prop_oneof![
+            0..=MAX_ADDR,
+            (0..L1_LEN, -2isize..=2).prop_map(|(chunk, word)| {
+                (chunk * CHUNK_BYTES).wrapping_add_signed(word * PTR_BYTES as isize) & MAX_ADDR
+            }),
+            (0..4 * CHUNK_BYTES).prop_map(|offset| MAX_ADDR - offset),
+            0..4 * CHUNK_BYTES,
+        ]
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Store(usize, TestProv),
+        Load(usize),
+        Clear(usize),
+        Copy(usize, usize),
+        CopyRange(usize, usize, usize),
+        Fill(usize, usize, TestProv),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (address(), 1..=TestProv::MAX).prop_map(|(addr, value)| Op::Store(addr, value)),
+            address().prop_map(Op::Load),
+            address().prop_map(Op::Clear),
+            (address(), address()).prop_map(|(src, dst)| Op::Copy(src, dst)),
+            (address(), -32isize..=32, 0..=64usize).prop_map(|(src, delta, len)| {
+                // Keep both ranges within the address space.
+                let src = src.clamp(32, MAX_ADDR - 96);
+                Op::CopyRange(src.wrapping_add_signed(delta), src, len)
+            }),
+            (address(), 0..=3 * CHUNK_BYTES, 0..=TestProv::MAX)
+                .prop_map(|(addr, len, value)| { Op::Fill(addr.min(MAX_ADDR - len), len, value) }),
+        ]
+    }
+
+    /// The word whose slot tracks a pointer stored at `addr`.
+    fn slot(addr: usize) -> usize {
+        addr & !(PTR_BYTES - 1)
+    }
+
+    /// The model tracks pointers by their exact address. Storing a pointer
+    /// removes every pointer that it overlaps.
+    fn model_store(model: &mut HashMap<usize, TestProv>, addr: usize, value: Option<TestProv>) {
+        model.retain(|&other, _| other.abs_diff(addr) >= PTR_BYTES);
+        if let Some(value) = value {
+            model.insert(addr, value);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn indices_are_in_bounds_and_injective(a in address(), b in address()) {
+            let (a1, a2) = table_indices(a);
+            let (b1, b2) = table_indices(b);
+            prop_assert!(a1 < L1_LEN && a2 < L2_LEN);
+            prop_assert_eq!((a1, a2) == (b1, b2), slot(a) == slot(b));
+        }
+
+        #[test]
+        fn indices_round_trip_across_the_address_space(addr in address()) {
+            let (l1, l2) = table_indices(addr);
+            prop_assert_eq!(slot_address(l1, l2), slot(addr));
+            // Entries of the higher regions are only ever indexed by their low bits.
+            let region = ((1u128 << MAX_VA_BITS) - 1) as usize & !MAX_ADDR;
+            prop_assert_eq!(table_indices(addr | region), (l1, l2));
+        }
+
+        #[test]
+        fn agrees_with_model(ops in prop::collection::vec(op(), 1..64)) {
+            let heap = ShadowHeap::<TestProv>::default();
+            let mut model = HashMap::<usize, TestProv>::new();
+            for op in ops {
+                unsafe {
+                    match op {
+                        Op::Store(addr, value) => {
+                            prop_assert!(heap.store(addr, value));
+                            model_store(&mut model, addr, Some(value));
+                        }
+                        Op::Load(addr) => {
+                            let expected = model.get(&addr).copied().unwrap_or(0);
+                            prop_assert_eq!(heap.load(addr), expected);
+                        }
+                        Op::Clear(addr) => {
+                            prop_assert!(heap.store(addr, 0));
+                            model_store(&mut model, addr, None);
+                        }
+                        Op::Copy(src, dst) => {
+                            let value = heap.load(src);
+                            prop_assert!(heap.store(dst, value));
+                            let value = model.get(&src).copied();
+                            model_store(&mut model, dst, value);
+                        }
+                        // Empty copies and copies to the same address change nothing.
+                        Op::CopyRange(dst, src, len) if len == 0 || dst == src => {
+                            heap.copy_range(dst, src, len);
+                        }
+                        Op::CopyRange(dst, src, len) => {
+                            heap.copy_range(dst, src, len);
+                            let moved: Vec<(usize, TestProv)> = model
+                                .iter()
+                                .filter(|&(&addr, _)| addr >= src && addr + PTR_BYTES <= src + len)
+                                .map(|(&addr, &value)| (addr - src + dst, value))
+                                .collect();
+                            model.retain(|&addr, _| addr + PTR_BYTES <= dst || addr >= dst + len);
+                            model.extend(moved);
+                        }
+                        Op::Fill(start, 0, value) => prop_assert!(heap.fill_range(start, 0, value)),
+                        Op::Fill(start, len, value) => {
+                            prop_assert!(heap.fill_range(start, len, value));
+                            model.retain(|&addr, _| addr + PTR_BYTES <= start || addr >= start + len);
+                            if value != 0 {
+                                let words = (start.next_multiple_of(PTR_BYTES)..start + len)
+                                    .step_by(PTR_BYTES)
+                                    .filter(|&addr| addr + PTR_BYTES <= start + len);
+                                model.extend(words.map(|addr| (addr, value)));
+                            }
+                        }
+                    }
+                }
+            }
+            for (&addr, &value) in &model {
+                prop_assert_eq!(unsafe { heap.load(addr) }, value);
+            }
+            prop_assert!(heap.iter_provenance().collect::<HashMap<_, _>>() == model);
+            let chunks = |aligned: bool| {
+                model
+                    .keys()
+                    .filter(|&&addr| (addr % PTR_BYTES == 0) == aligned)
+                    .map(|&addr| table_indices(addr).0)
+                    .collect::<HashSet<usize>>()
+                    .len()
+            };
+            prop_assert_eq!(heap.num_chunks(), chunks(true));
+            prop_assert_eq!(heap.misaligned.num_chunks(), chunks(false));
+        }
+    }
 }
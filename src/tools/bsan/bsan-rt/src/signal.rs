@@ -0,0 +1,220 @@
+//! A handler for `SIGSEGV` and `SIGBUS`, for faults that escape the access
+//! hooks, such as those through pointers that the pass couldn't instrument.
+//!
+//! With `BSAN_HANDLE_SEGV=1`, the handler prints the faulting address and the
+//! call stack of the fault, along with what the runtime knows about the
+//! address: the allocation that contains it, or the nearest one, and the
+//! pointer that shadow memory holds there. It then restores the handler that
+//! was installed before it, which is usually the default one, and returns, so
+//! that the faulting access is retried and the process crashes as it would
+//! have without bsan. A previous action that ignored the signal is replaced
+//! with the default one instead, since the access would otherwise fault
+//! forever. The thread that initializes the runtime runs the handler on an
+//! alternate stack, so that its stack overflows are described too.
+//!
+//! The fault may have interrupted any code, including the runtime's own, so
+//! the handler only does what is async-signal-safe: the call stack is printed
+//! as raw program counters, without symbolizing them, and the state of the
+//! runtime is only read through locks that can be taken without waiting.
+
+use core::cell::SyncUnsafeCell;
+use core::ffi::{c_int, c_void};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{mem, ptr};
+
+use crate::backtrace::Backtrace;
+use crate::global::{self, GlobalContext};
+use crate::io::{self, FdWriter};
+use crate::registry::AllocMetadata;
+use crate::{guard, sanitizer};
+
+// How far from the faulting address allocations are looked for.
+const NEAR: usize = 4096;
+
+const ALT_STACK_LEN: usize = 64 * 1024;
+
+static ALT_STACK: SyncUnsafeCell<[u8; ALT_STACK_LEN]> = SyncUnsafeCell::new([0; ALT_STACK_LEN]);
+
+const SIGNALS: [c_int; 2] = [libc::SIGSEGV, libc::SIGBUS];
+
+// The actions that the handler replaced, by their index in `SIGNALS`.
+static PREVIOUS: SyncUnsafeCell<[libc::sigaction; 2]> =
+    SyncUnsafeCell::new(unsafe { mem::zeroed() });
+
+/// Installs the handler if `BSAN_HANDLE_SEGV` is set. Only the first call
+/// does anything.
+pub fn install_from_env() {
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+    if !io::env_flag(c"BSAN_HANDLE_SEGV") || INSTALLED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    unsafe {
+        let stack =
+            libc::stack_t { ss_sp: ALT_STACK.get().cast(), ss_flags: 0, ss_size: ALT_STACK_LEN };
+        libc::sigaltstack(&stack, ptr::null_mut());
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_fault as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+        libc::sigemptyset(&mut action.sa_mask);
+        for (&signal, previous) in SIGNALS.iter().zip(&mut *PREVIOUS.get()) {
+            libc::sigaction(signal, &action, previous);
+        }
+    }
+}
+
+extern "C" fn handle_fault(signal: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    unsafe {
+        describe(signal, (*info).si_addr().addr(), fault_pc(context));
+        let index = SIGNALS.iter().position(|&s| s == signal).unwrap_or(0);
+        let mut previous = (*PREVIOUS.get())[index];
+        if previous.sa_sigaction == libc::SIG_IGN {
+            previous.sa_sigaction = libc::SIG_DFL;
+        }
+        // A handler that was installed before may still recover.
+        if previous.sa_sigaction == libc::SIG_DFL {
+            sanitizer::run_death_callback();
+        }
+        libc::sigaction(signal, &previous, ptr::null_mut());
+        // Signals sent by another process aren't raised again by returning.
+        if (*info).si_code <= 0 {
+            libc::raise(signal);
+        }
+    }
+}
+
+// The address of the instruction that faulted.
+unsafe fn fault_pc(context: *mut c_void) -> Option<usize> {
+    let context = context.cast::<libc::ucontext_t>().as_ref()?;
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    {
+        Some(context.uc_mcontext.gregs[libc::REG_RIP as usize] as usize)
+    }
+    #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+    {
+        Some(context.uc_mcontext.pc as usize)
+    }
+    #[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+    {
+        None
+    }
+}
+
+// Describes the fault. This runs in the signal handler, so it must be
+// async-signal-safe.
+fn describe(signal: c_int, addr: usize, pc: Option<usize>) {
+    let mut out = FdWriter::log();
+    let name = if signal == libc::SIGBUS { "BUS" } else { "SEGV" };
+    let _ = write!(out, "bsan: {name} on address {addr:#x}");
+    let _ = match pc {
+        Some(pc) => writeln!(out, " (pc {pc:#x})"),
+        None => writeln!(out),
+    };
+    if guard::in_runtime() {
+        let _ = writeln!(out, "bsan: the fault happened within a hook, in the runtime itself");
+    }
+    let Some(ctx) = global::initialized_global_ctx() else { return };
+    let trace = Backtrace::capture(0);
+    let trace = match pc {
+        Some(pc) => trace.starting_at(pc),
+        None => trace,
+    };
+    // The frames are printed as raw program counters, since symbolizing them
+    // could take locks and allocate.
+    let _ = write!(out, "{trace}");
+    let _ = write_context(ctx, addr, &mut out);
+}
+
+/// Writes what the runtime knows about the faulting address `addr`.
+fn write_context(ctx: &GlobalContext, addr: usize, out: &mut impl Write) -> fmt::Result {
+    let described = ctx.registry().try_find_nearest(addr, NEAR, |meta| match meta {
+        Some(meta) => write_allocation(meta, addr, out),
+        None => writeln!(out, "    no allocation is within {NEAR} bytes of the address"),
+    });
+    match described {
+        Some(res) => res?,
+        None => writeln!(out, "    the allocation registry is locked, so it can't be searched")?,
+    }
+    if !ctx.shadow().covers(addr, 1) {
+        return writeln!(out, "    the address is outside of shadow memory");
+    }
+    let prov = unsafe { ctx.shadow().load(addr) };
+    if prov.alloc_id.get() == 0 {
+        return writeln!(out, "    shadow memory holds no pointer at the address");
+    }
+    writeln!(
+        out,
+        "    shadow memory holds a pointer at the address, to allocation {} with tag {}",
+        prov.alloc_id.get(),
+        prov.bor_tag.get()
+    )
+}
+
+fn write_allocation(meta: &AllocMetadata, addr: usize, out: &mut impl Write) -> fmt::Result {
+    let id = meta.id.get();
+    let (base, size) = (meta.base_addr, meta.size);
+    if meta.contains(addr) {
+        write!(out, "    the address is at offset {:#x} of", addr - base)?;
+    } else if addr < base {
+        write!(out, "    the address is {} bytes before", base - addr)?;
+    } else {
+        write!(out, "    the address is {} bytes past the end of", addr - (base + size))?;
+    }
+    writeln!(out, " allocation {id} ({}, {size} bytes at {base:#x})", meta.kind)?;
+    write!(out, "    allocation {id} has root tag {} and was made in ", meta.root_tag.get())?;
+    match unsafe { meta.created_in.as_ref() } {
+        Some(func) => writeln!(out, "{func}")?,
+        None => writeln!(out, "an unknown function")?,
+    }
+    let Some(events) = meta.history.try_events() else {
+        return writeln!(out, "    the history of allocation {id} is locked, so it can't be read");
+    };
+    let mut events = events.peekable();
+    if events.peek().is_some() {
+        writeln!(out, "    recent history of allocation {id}, oldest first:")?;
+        for event in events {
+            writeln!(out, "      {event}")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::TEST_ALLOCATOR;
+
+    #[test]
+    fn faults_are_described_by_the_nearest_allocation() {
+        let ctx = GlobalContext::new(TEST_ALLOCATOR).unwrap();
+        let context = |addr| {
+            let mut out = String::new();
+            write_context(&ctx, addr, &mut out).unwrap();
+            out
+        };
+        unsafe {
+            ctx.new_allocation(0x1000, 16).unwrap();
+            let second = ctx.new_allocation(0x1100, 8).unwrap();
+            assert!(ctx.shadow().store(0x1008, second));
+            assert_eq!(
+                context(0x1008),
+                "    the address is at offset 0x8 of allocation 1 (heap, 16 bytes at 0x1000)\n    \
+                 allocation 1 has root tag 1 and was made in an unknown function\n    \
+                 shadow memory holds a pointer at the address, to allocation 2 with tag 2\n"
+            );
+            let lines = |addr| context(addr).lines().next().unwrap().to_owned();
+            assert_eq!(
+                lines(0x1014),
+                "    the address is 4 bytes past the end of allocation 1 \
+                 (heap, 16 bytes at 0x1000)"
+            );
+            assert_eq!(
+                lines(0x10f0),
+                "    the address is 16 bytes before allocation 2 (heap, 8 bytes at 0x1100)"
+            );
+            assert_eq!(lines(0x4000), "    no allocation is within 4096 bytes of the address");
+            assert!(context(0x4000).ends_with("shadow memory holds no pointer at the address\n"));
+            assert!(context(usize::MAX).ends_with("the address is outside of shadow memory\n"));
+        }
+    }
+}
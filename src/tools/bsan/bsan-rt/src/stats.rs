@@ -0,0 +1,307 @@
+//! Counters describing the work done by the runtime.
+//!
+//! Most are always kept, but those on the hottest paths, the calls of each
+//! hook and the depth of registry lookups, are only counted while
+//! `BSAN_STATS` is set, so that the hooks don't all contend on them otherwise.
+
+use core::ffi::{CStr, c_char};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use core::{fmt, ptr};
+
+use crate::access;
+use crate::report::ErrorKind;
+use crate::shadow::{self, ShadowUsage};
+
+/// A snapshot of the runtime's counters.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Accesses that were checked against the registry.
+    pub checked_accesses: u64,
+    /// Accesses to local-only stack allocations, which the pass proved
+    /// can't be reached through an escaped pointer.
+    pub elided_accesses: u64,
+    /// Local-only stack allocations.
+    pub local_allocas: u64,
+    /// Pointers spilled to stack slots.
+    pub stack_spills: u64,
+    /// Errors reported in the instrumented program, such as invalid accesses
+    /// and frees.
+    pub errors: u64,
+    /// Lookups of shadow memory that were served by the per-thread cache of
+    /// recently used chunks, and those that weren't.
+    pub shadow_cache_hits: u64,
+    pub shadow_cache_misses: u64,
+    /// Accesses that passed the check that the same thread last passed,
+    /// without looking the allocation up again.
+    pub access_cache_hits: u64,
+    /// Reports of a kind whose severity is a warning.
+    pub warnings: u64,
+    /// Calls of all hooks.
+    pub hook_calls: u64,
+    /// Lookups in the registry, the nodes of its tree that they visited in
+    /// all, and the most that any one of them visited.
+    pub registry_lookups: u64,
+    pub registry_nodes_visited: u64,
+    pub registry_max_visited: u64,
+}
+
+/// The number of times a hook was called, as reported by
+/// `bsan_get_hook_stats`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct HookStats {
+    /// The name of the hook, such as `bsan_read`.
+    pub name: *const c_char,
+    pub calls: u64,
+}
+
+/// The counter of the calls of one hook, which `enter_hook!` declares for each
+/// hook. It is added to the list of those that are reported the first time it
+/// counts a call.
+#[derive(Debug)]
+pub struct HookCounter {
+    name: &'static CStr,
+    calls: AtomicU64,
+    listed: AtomicBool,
+    next: AtomicPtr<HookCounter>,
+}
+
+static COUNTING: AtomicBool = AtomicBool::new(false);
+
+static HOOK_COUNTERS: AtomicPtr<HookCounter> = AtomicPtr::new(ptr::null_mut());
+
+static REGISTRY_LOOKUPS: AtomicU64 = AtomicU64::new(0);
+static REGISTRY_NODES_VISITED: AtomicU64 = AtomicU64::new(0);
+static REGISTRY_MAX_VISITED: AtomicU64 = AtomicU64::new(0);
+
+/// Starts or stops counting the calls of hooks and the depth of registry
+/// lookups.
+pub fn set_counting(counting: bool) {
+    COUNTING.store(counting, Ordering::Relaxed);
+}
+
+/// Whether the calls of hooks and the depth of registry lookups are counted.
+#[inline(always)]
+pub fn counting() -> bool {
+    COUNTING.load(Ordering::Relaxed)
+}
+
+impl HookCounter {
+    pub const fn new(name: &'static CStr) -> Self {
+        Self {
+            name,
+            calls: AtomicU64::new(0),
+            listed: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn name(&self) -> &'static CStr {
+        self.name
+    }
+
+    #[inline(always)]
+    pub fn count(&'static self) {
+        if counting() {
+            self.count_slow();
+        }
+    }
+
+    fn count_slow(&'static self) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if self.listed.load(Ordering::Relaxed) || self.listed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let this = ptr::from_ref(self).cast_mut();
+        let mut head = HOOK_COUNTERS.load(Ordering::Relaxed);
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+            match HOOK_COUNTERS.compare_exchange_weak(
+                head,
+                this,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+/// Calls `f` with the name of each hook that was called while counting, and
+/// the number of times it was.
+pub fn for_each_hook(mut f: impl FnMut(&'static CStr, u64)) {
+    let mut current = HOOK_COUNTERS.load(Ordering::Acquire);
+    while let Some(counter) = unsafe { current.as_ref() } {
+        f(counter.name, counter.calls.load(Ordering::Relaxed));
+        current = counter.next.load(Ordering::Relaxed);
+    }
+}
+
+/// Counts a lookup in the registry that visited `nodes` nodes of its tree.
+#[inline]
+pub fn registry_lookup(nodes: u64) {
+    REGISTRY_LOOKUPS.fetch_add(1, Ordering::Relaxed);
+    REGISTRY_NODES_VISITED.fetch_add(nodes, Ordering::Relaxed);
+    REGISTRY_MAX_VISITED.fetch_max(nodes, Ordering::Relaxed);
+}
+
+/// The memory used by shadow memory, as reported by `bsan_shadow_stats`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ShadowStats {
+    /// Second-level chunks of the shadow page table that are installed.
+    pub live_chunks: u64,
+    /// Shadow entries that hold the provenance of a pointer.
+    pub entries: u64,
+    /// Address space reserved for shadow memory.
+    pub reserved_bytes: u64,
+    /// The part of `reserved_bytes` backed by physical memory.
+    pub resident_bytes: u64,
+}
+
+impl From<ShadowUsage> for ShadowStats {
+    fn from(usage: ShadowUsage) -> Self {
+        Self {
+            live_chunks: usage.chunks as u64,
+            entries: usage.entries as u64,
+            reserved_bytes: usage.reserved_bytes as u64,
+            resident_bytes: usage.resident_bytes as u64,
+        }
+    }
+}
+
+impl fmt::Display for ShadowStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const MIB: u64 = 1 << 20;
+        write!(
+            f,
+            "{} entries in {} chunks, {} MiB resident of {} MiB reserved",
+            self.entries,
+            self.live_chunks,
+            self.resident_bytes.div_ceil(MIB),
+            self.reserved_bytes / MIB,
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StatCounters {
+    checked_accesses: AtomicU64,
+    elided_accesses: AtomicU64,
+    local_allocas: AtomicU64,
+    stack_spills: AtomicU64,
+    errors: AtomicU64,
+    warnings: AtomicU64,
+    errors_by_kind: [AtomicU64; ErrorKind::COUNT],
+}
+
+impl StatCounters {
+    pub const fn new() -> Self {
+        Self {
+            checked_accesses: AtomicU64::new(0),
+            elided_accesses: AtomicU64::new(0),
+            local_allocas: AtomicU64::new(0),
+            stack_spills: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            warnings: AtomicU64::new(0),
+            errors_by_kind: [const { AtomicU64::new(0) }; ErrorKind::COUNT],
+        }
+    }
+
+    #[inline]
+    pub fn checked_access(&self) {
+        self.checked_accesses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn checked_accesses(&self, count: u64) {
+        self.checked_accesses.fetch_add(count, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn elided_access(&self) {
+        self.elided_accesses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn local_alloca(&self) {
+        self.local_allocas.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn stack_spill(&self) {
+        self.stack_spills.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn error(&self, kind: ErrorKind) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.errors_by_kind[kind.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a report of a kind whose severity is a warning, which isn't
+    /// included in the `errors` of [`Stats`].
+    #[inline]
+    pub fn warning(&self, kind: ErrorKind) {
+        self.warnings.fetch_add(1, Ordering::Relaxed);
+        self.errors_by_kind[kind.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn warnings(&self) -> u64 {
+        self.warnings.load(Ordering::Relaxed)
+    }
+
+    /// The number of errors and warnings of each kind, by
+    /// [`ErrorKind::index`].
+    pub fn errors_by_kind(&self) -> [u64; ErrorKind::COUNT] {
+        self.errors_by_kind.each_ref().map(|count| count.load(Ordering::Relaxed))
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        let (shadow_cache_hits, shadow_cache_misses) = shadow::chunk_cache_stats();
+        Stats {
+            checked_accesses: self.checked_accesses.load(Ordering::Relaxed),
+            elided_accesses: self.elided_accesses.load(Ordering::Relaxed),
+            local_allocas: self.local_allocas.load(Ordering::Relaxed),
+            stack_spills: self.stack_spills.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            shadow_cache_hits,
+            shadow_cache_misses,
+            access_cache_hits: access::cache_hits(),
+            warnings: self.warnings(),
+            hook_calls: {
+                let mut calls = 0;
+                for_each_hook(|_, count| calls += count);
+                calls
+            },
+            registry_lookups: REGISTRY_LOOKUPS.load(Ordering::Relaxed),
+            registry_nodes_visited: REGISTRY_NODES_VISITED.load(Ordering::Relaxed),
+            registry_max_visited: REGISTRY_MAX_VISITED.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} hook calls, {} checked accesses ({} from the access cache), {} elided; \
+             shadow cache: {} hits, {} misses; registry: {} lookups visiting {:.1} nodes on \
+             average, at most {}; {} errors, {} warnings",
+            self.hook_calls,
+            self.checked_accesses,
+            self.access_cache_hits,
+            self.elided_accesses,
+            self.shadow_cache_hits,
+            self.shadow_cache_misses,
+            self.registry_lookups,
+            self.registry_nodes_visited as f64 / self.registry_lookups.max(1) as f64,
+            self.registry_max_visited,
+            self.errors,
+            self.warnings,
+        )
+    }
+}
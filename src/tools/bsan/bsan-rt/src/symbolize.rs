@@ -0,0 +1,345 @@
+//! Symbolization of the call stacks in error reports.
+//!
+//! Frames are symbolized by an `llvm-symbolizer` process, as ASan does, which
+//! is started the first time an error is reported and then kept running. It
+//! is looked up in `PATH` unless `BSAN_SYMBOLIZER_PATH`, or else
+//! `LLVM_SYMBOLIZER_PATH`, names another one. If it can't be started, frames
+//! fall back to the nearest dynamic symbol and the offset into their module,
+//! which `llvm-symbolizer` or `addr2line` can still resolve by hand.
+//! `BSAN_SYMBOLIZE=0` prints raw addresses instead.
+
+use core::ffi::{CStr, c_char, c_int};
+use core::{fmt, ptr};
+
+use crate::backtrace::Backtrace;
+use crate::io::{self, CPathBuf};
+use crate::sync::SpinLock;
+
+// The largest response to a single query that is read, for a frame whose
+// function and file names are unusually long.
+const RESPONSE_LEN: usize = 1024;
+
+extern "C" {
+    static environ: *const *mut c_char;
+}
+
+#[derive(Debug)]
+pub struct Symbolizer {
+    enabled: bool,
+    path: Option<CPathBuf>,
+    process: SpinLock<Process>,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Process {
+    NotStarted,
+    // Our end of the socket connected to the standard input and output of the
+    // symbolizer.
+    Running(c_int),
+    // The symbolizer couldn't be started, or stopped responding.
+    Failed,
+}
+
+impl Default for Symbolizer {
+    fn default() -> Self {
+        Self::new(true, None)
+    }
+}
+
+impl Symbolizer {
+    /// A symbolizer that runs the `llvm-symbolizer` at `path`, or the one in
+    /// `PATH`. If it is not `enabled`, frames are printed as raw addresses.
+    pub const fn new(enabled: bool, path: Option<CPathBuf>) -> Self {
+        Self { enabled, path, process: SpinLock::new(Process::NotStarted) }
+    }
+
+    /// Reads the symbolizer configuration from the environment.
+    pub fn from_env() -> Self {
+        // `LLVM_SYMBOLIZER_PATH` is the variable that LLVM's own tools and
+        // test suites use for it.
+        let path =
+            [c"BSAN_SYMBOLIZER_PATH", c"LLVM_SYMBOLIZER_PATH"].into_iter().find_map(io::env_path);
+        Self::new(io::env_flag_or(c"BSAN_SYMBOLIZE", true), path)
+    }
+
+    /// Calls `f` with what is known about each frame of `trace`, innermost
+    /// first, stopping at the first error.
+    pub fn for_each_frame(
+        &self,
+        trace: &Backtrace,
+        mut f: impl FnMut(&FrameInfo<'_>) -> fmt::Result,
+    ) -> fmt::Result {
+        let mut process = self.process.lock();
+        for &pc in trace.frames() {
+            self.symbolize_with(&mut process, pc, &mut f)?;
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with what is known about the frame with the return address
+    /// `pc`.
+    pub fn symbolize(
+        &self,
+        pc: usize,
+        mut f: impl FnMut(&FrameInfo<'_>) -> fmt::Result,
+    ) -> fmt::Result {
+        self.symbolize_with(&mut self.process.lock(), pc, &mut f)
+    }
+
+    fn symbolize_with(
+        &self,
+        process: &mut Process,
+        pc: usize,
+        f: &mut impl FnMut(&FrameInfo<'_>) -> fmt::Result,
+    ) -> fmt::Result {
+        let mut info = FrameInfo { pc, module: None, symbol: None, function: None, location: None };
+        if !self.enabled {
+            return f(&info);
+        }
+        // Return addresses point past the call, which may be the first
+        // instruction of the next line or even function.
+        let Some(frame) = (unsafe { Frame::find(pc.wrapping_sub(1)) }) else {
+            return f(&info);
+        };
+        let mut response = [0; RESPONSE_LEN];
+        if let Some(len) = self.query(process, &frame, &mut response) {
+            (info.function, info.location) = parse_response(&response[..len]);
+        }
+        info.module = Some((frame.module, frame.offset));
+        info.symbol = frame.symbol;
+        f(&info)
+    }
+
+    /// Asks the symbolizer about `frame`, starting it first if need be, and
+    /// returns the length of its response in `response`.
+    fn query(
+        &self,
+        process: &mut Process,
+        frame: &Frame<'_>,
+        response: &mut [u8],
+    ) -> Option<usize> {
+        if let Process::NotStarted = process {
+            *process = unsafe { self.spawn() }.map_or(Process::Failed, Process::Running);
+        }
+        let Process::Running(fd) = *process else { return None };
+        let len = unsafe { request(fd, frame, response) };
+        if len.is_none() {
+            unsafe { libc::close(fd) };
+            *process = Process::Failed;
+        }
+        len
+    }
+
+    unsafe fn spawn(&self) -> Option<c_int> {
+        let mut fds = [0; 2];
+        let kind = libc::SOCK_STREAM | libc::SOCK_CLOEXEC;
+        if libc::socketpair(libc::AF_UNIX, kind, 0, fds.as_mut_ptr()) != 0 {
+            return None;
+        }
+        let path = self.path.as_ref().map_or(c"llvm-symbolizer".as_ptr(), CPathBuf::as_ptr);
+        let argv = [path.cast_mut(), c"--no-inlines".as_ptr().cast_mut(), ptr::null_mut()];
+        let mut actions = core::mem::zeroed();
+        libc::posix_spawn_file_actions_init(&mut actions);
+        // The duplicates don't inherit close-on-exec.
+        libc::posix_spawn_file_actions_adddup2(&mut actions, fds[1], libc::STDIN_FILENO);
+        libc::posix_spawn_file_actions_adddup2(&mut actions, fds[1], libc::STDOUT_FILENO);
+        let mut pid = 0;
+        let res = libc::posix_spawnp(&mut pid, path, &actions, ptr::null(), argv.as_ptr(), environ);
+        libc::posix_spawn_file_actions_destroy(&mut actions);
+        libc::close(fds[1]);
+        if res != 0 {
+            libc::close(fds[0]);
+            return None;
+        }
+        Some(fds[0])
+    }
+}
+
+/// What is known about a frame of a call stack.
+pub struct FrameInfo<'a> {
+    /// The return address of the frame.
+    pub pc: usize,
+    /// The module that the frame is in, and the offset into it that the
+    /// symbolizer would be asked about.
+    pub module: Option<(&'a CStr, usize)>,
+    /// The nearest dynamic symbol before the frame, and the offset into it.
+    pub symbol: Option<(&'a CStr, usize)>,
+    /// The function and the `file:line:column` that the symbolizer found.
+    pub function: Option<&'a [u8]>,
+    pub location: Option<&'a [u8]>,
+}
+
+/// Where a return address is, as far as the dynamic linker knows.
+struct Frame<'a> {
+    module: &'a CStr,
+    // The offset of the address within the module, as the symbolizer expects.
+    offset: usize,
+    // The nearest dynamic symbol before the address, and the offset into it.
+    symbol: Option<(&'a CStr, usize)>,
+}
+
+impl Frame<'_> {
+    unsafe fn find(pc: usize) -> Option<Self> {
+        let mut info: libc::Dl_info = core::mem::zeroed();
+        if libc::dladdr(ptr::without_provenance(pc), &mut info) == 0 || info.dli_fbase.is_null() {
+            return None;
+        }
+        let base = info.dli_fbase.addr();
+        // Position-dependent executables are symbolized by address, everything
+        // else by offset. `e_type` follows the 16 bytes of `e_ident`.
+        let exec = *info.dli_fbase.cast::<u8>().add(16).cast::<u16>() == libc::ET_EXEC;
+        let module = match info.dli_fname.is_null() {
+            false if *info.dli_fname != 0 => CStr::from_ptr(info.dli_fname),
+            // The main program may have no name of its own.
+            _ => c"/proc/self/exe",
+        };
+        let symbol = (!info.dli_sname.is_null())
+            .then(|| (CStr::from_ptr(info.dli_sname), pc - info.dli_saddr.addr()));
+        Some(Frame { module, offset: if exec { pc } else { pc - base }, symbol })
+    }
+}
+
+/// Sends the query for `frame` to the symbolizer on `fd` and reads its
+/// response into `response`, which is made to fit. Returns `None` if the
+/// symbolizer doesn't respond.
+unsafe fn request(fd: c_int, frame: &Frame<'_>, response: &mut [u8]) -> Option<usize> {
+    let query = CPathBuf::new(b"\"")?
+        .with_suffix(frame.module.to_bytes())?
+        .with_suffix(b"\" ")?
+        .with_suffix(HexOffset::new(frame.offset).as_bytes())?
+        .with_suffix(b"\n")?;
+    let mut sent = 0;
+    while sent < query.as_bytes().len() {
+        let rest = &query.as_bytes()[sent..];
+        let res = libc::send(fd, rest.as_ptr().cast(), rest.len(), libc::MSG_NOSIGNAL);
+        if res > 0 {
+            sent += res as usize;
+        } else if res < 0 && io::errno() == libc::EINTR {
+            continue;
+        } else {
+            return None;
+        }
+    }
+    // Each response ends in an empty line. Anything that doesn't fit is
+    // read and dropped.
+    let (mut len, mut last) = (0, [0; 2]);
+    while last != *b"\n\n" {
+        let mut byte = 0u8;
+        let res = libc::read(fd, (&raw mut byte).cast(), 1);
+        if res < 0 && io::errno() == libc::EINTR {
+            continue;
+        } else if res <= 0 {
+            return None;
+        }
+        last = [last[1], byte];
+        if len < response.len() {
+            response[len] = byte;
+            len += 1;
+        }
+    }
+    Some(len)
+}
+
+/// The function and the `file:line:column` in a response of the symbolizer,
+/// unless they are unknown.
+fn parse_response(response: &[u8]) -> (Option<&[u8]>, Option<&[u8]>) {
+    let mut lines = response.split(|&b| b == b'\n');
+    let function = lines.next().filter(|function| !function.is_empty() && *function != b"??");
+    let location =
+        lines.next().filter(|location| !location.is_empty() && !location.starts_with(b"??"));
+    (function, location)
+}
+
+// The `0x`-prefixed hexadecimal form of an offset.
+struct HexOffset {
+    buf: [u8; 2 + 2 * size_of::<usize>()],
+    len: usize,
+}
+
+impl HexOffset {
+    fn new(mut offset: usize) -> Self {
+        let mut digits = [0; 2 * size_of::<usize>()];
+        let mut n = 0;
+        loop {
+            digits[n] = b"0123456789abcdef"[offset % 16];
+            n += 1;
+            offset /= 16;
+            if offset == 0 {
+                break;
+            }
+        }
+        let mut hex = Self { buf: [0; 2 + 2 * size_of::<usize>()], len: 2 + n };
+        hex.buf[..2].copy_from_slice(b"0x");
+        for (i, &digit) in digits[..n].iter().rev().enumerate() {
+            hex.buf[2 + i] = digit;
+        }
+        hex
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_parts_of_responses_are_dropped() {
+        let (function, location) = parse_response(b"main\nsrc/main.rs:3:5\n\n");
+        assert_eq!((function, location), (Some(&b"main"[..]), Some(&b"src/main.rs:3:5"[..])));
+        assert_eq!(parse_response(b"??\n??:0:0\n\n"), (None, None));
+        assert_eq!(parse_response(b"f\n??:0:0\n\n"), (Some(&b"f"[..]), None));
+        assert_eq!(parse_response(b""), (None, None));
+        assert_eq!(HexOffset::new(0).as_bytes(), b"0x0");
+        assert_eq!(HexOffset::new(0x1f2e).as_bytes(), b"0x1f2e");
+        assert_eq!(HexOffset::new(usize::MAX).as_bytes(), b"0xffffffffffffffff");
+    }
+
+    #[inline(never)]
+    fn capture() -> Backtrace {
+        core::hint::black_box(Backtrace::capture(0))
+    }
+
+    // The function, location and module of the first frame of a call stack.
+    fn first_frame(symbolizer: &Symbolizer) -> [Option<String>; 3] {
+        let mut first = None;
+        let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        symbolizer
+            .for_each_frame(&capture(), |frame| {
+                first.get_or_insert_with(|| {
+                    [
+                        frame.function.map(lossy),
+                        frame.location.map(lossy),
+                        frame.module.map(|(module, _)| lossy(module.to_bytes())),
+                    ]
+                });
+                Ok(())
+            })
+            .unwrap();
+        first.unwrap()
+    }
+
+    #[test]
+    fn frames_fall_back_to_their_module() {
+        let path = CPathBuf::new(b"/nonexistent/llvm-symbolizer");
+        let [function, location, module] = first_frame(&Symbolizer::new(true, path));
+        let exe = std::env::current_exe().unwrap();
+        assert_eq!((function, location), (None, None));
+        // The module is named as the program was started.
+        assert_eq!(std::fs::canonicalize(module.unwrap()).unwrap(), exe);
+        assert_eq!(first_frame(&Symbolizer::new(false, None)), [None, None, None]);
+    }
+
+    #[test]
+    fn frames_are_symbolized() {
+        if !std::path::Path::new("/usr/bin/llvm-symbolizer").exists() {
+            return;
+        }
+        let path = CPathBuf::new(b"/usr/bin/llvm-symbolizer");
+        let [function, location, _] = first_frame(&Symbolizer::new(true, path));
+        assert!(function.unwrap().ends_with("symbolize::tests::capture"));
+        assert!(location.unwrap().contains("symbolize.rs:"));
+    }
+}
@@ -0,0 +1,445 @@
+use core::cell::Cell;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::{clock, thread};
+
+/// A borrow tag, identifying a single node within an allocation's tree.
+/// Tag `0` is reserved to mean "no tag"; every tag handed out by the
+/// [`TagAllocator`] is nonzero.
+///
+/// The top [`BorTag::HINT_BITS`] bits of the raw value hold a [`TagHint`]
+/// about the pointers that carry the tag, which the runtime sets on the tags
+/// that it hands out and code that copies provenance must preserve. They are
+/// part of the tag's identity, but not of the number that [`BorTag::get`]
+/// returns.
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BorTag(u64);
+
+impl BorTag {
+    pub const INVALID: BorTag = BorTag(0);
+
+    /// The number of bits at the top of a tag that are reserved for its hint.
+    pub const HINT_BITS: u32 = 4;
+
+    /// The largest number of a tag; the tag space is exhausted beyond it.
+    pub const MAX: u64 = u64::MAX >> Self::HINT_BITS;
+
+    #[inline]
+    pub const fn new(raw: u64) -> Self {
+        BorTag(raw)
+    }
+
+    /// The number of the tag, without its hint.
+    #[inline]
+    pub const fn get(self) -> u64 {
+        self.0 & Self::MAX
+    }
+
+    #[inline]
+    pub const fn is_valid(self) -> bool {
+        self.0 != 0
+    }
+
+    #[inline]
+    pub const fn hint(self) -> TagHint {
+        TagHint((self.0 >> (u64::BITS - Self::HINT_BITS)) as u8)
+    }
+
+    /// This tag with its hint replaced by `hint`.
+    #[inline]
+    pub const fn with_hint(self, hint: TagHint) -> Self {
+        BorTag(self.get() | (hint.0 as u64) << (u64::BITS - Self::HINT_BITS))
+    }
+}
+
+/// What is known about the pointers that carry a tag from when it was handed
+/// out, so that the access hooks can take a shortcut for the most common ones.
+/// A hint is only ever a shortcut: checks that don't pass with it are decided
+/// as if it weren't there. Bits that aren't defined here are zero.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TagHint(u8);
+
+impl TagHint {
+    pub const NONE: TagHint = TagHint(0);
+
+    /// The tag is the root tag of its allocation, so its pointers may access
+    /// all of it for as long as it is live.
+    pub const ROOT: TagHint = TagHint(1);
+
+    #[inline]
+    pub const fn contains(self, other: TagHint) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// The number of fresh tags that a thread reserves from a shared counter at
+/// once, so that it only touches the counter for one retag in this many.
+pub const TAG_BLOCK: u64 = 64;
+
+/// The tags that a thread reserved from an allocator and hasn't handed out
+/// yet, which are kept in its [`ThreadContext`](crate::thread::ThreadContext).
+/// Those that are left when the thread exits are never used.
+#[derive(Debug)]
+pub struct TagBlock {
+    // The allocator that the tags were reserved from, by its ID.
+    allocator: Cell<u64>,
+    next: Cell<u64>,
+    end: Cell<u64>,
+    // The number of tags that the thread has taken from allocators that count
+    // them per thread.
+    counted: Cell<u64>,
+}
+
+impl TagBlock {
+    pub const fn new() -> Self {
+        Self {
+            allocator: Cell::new(0),
+            next: Cell::new(0),
+            end: Cell::new(0),
+            counted: Cell::new(0),
+        }
+    }
+}
+
+static NEXT_ALLOCATOR_ID: AtomicU64 = AtomicU64::new(1);
+
+// The number of tags that can be waiting to be reused at any given time.
+// Tags that are released while every slot is occupied are simply dropped;
+// this only affects how quickly we approach exhaustion, not correctness.
+const RECYCLE_SLOTS: usize = 64;
+
+// The number of tags that can be disabled at any given time. Each tag is hashed
+// to a bucket of `DISABLED_WAYS` slots, and errors through tags that are
+// disabled while their bucket is full are still reported.
+const DISABLED_SLOTS: usize = 64;
+const DISABLED_WAYS: usize = 4;
+const DISABLED_BUCKETS: usize = DISABLED_SLOTS / DISABLED_WAYS;
+
+// The occupied slots of `TagAllocator::disabled`, one bit each, fit in a word.
+const _: () = assert!(DISABLED_SLOTS <= u64::BITS as usize);
+
+/// Hands out borrow tags for the whole process. Fresh tags are taken from
+/// a monotonically increasing counter, in blocks of [`TAG_BLOCK`] that each
+/// thread then hands out on its own. The root tags of allocations whose
+/// metadata has been reclaimed, which no pointer can carry anymore since every
+/// provenance with them held a reference to the metadata, are kept in a small
+/// lock-free pool and reused before any fresh tag, which delays exhaustion of
+/// the tag space in long-running programs.
+/// Once both the counter and the pool are empty, allocation fails instead
+/// of wrapping around and aliasing a live tag.
+///
+/// Tags of pointers that an error has been reported for can be disabled, so
+/// that a program that keeps running after the error doesn't report it again
+/// for every later access through the same pointer.
+///
+/// An allocator made with [`TagAllocator::per_thread`] instead hands each
+/// thread tags from a counter of its own, as
+/// [`clock::thread_scoped_id`] describes, and never reuses them, so that the
+/// tags of a run don't depend on how its threads interleave.
+#[derive(Debug)]
+pub struct TagAllocator {
+    // Identifies the allocator in the `TagBlock` of each thread. IDs are never
+    // reused, so a block left behind by an allocator that has been replaced
+    // can't be taken for another one.
+    id: u64,
+    per_thread: bool,
+    next: AtomicU64,
+    // An upper bound on the number of occupied slots in `recycled`, used to
+    // skip scanning the pool on the (common) path where it is empty.
+    num_recycled: AtomicUsize,
+    recycled: [AtomicU64; RECYCLE_SLOTS],
+    // A bit for each occupied slot in `disabled`, which is checked on every
+    // checked access, so that only buckets that hold disabled tags are
+    // scanned.
+    occupied_disabled: AtomicU64,
+    disabled: [AtomicU64; DISABLED_SLOTS],
+}
+
+impl Default for TagAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TagAllocator {
+    pub fn new() -> Self {
+        Self::starting_at(1)
+    }
+
+    /// An allocator whose tags are counted per thread.
+    pub fn per_thread() -> Self {
+        Self { per_thread: true, ..Self::new() }
+    }
+
+    fn starting_at(first: u64) -> Self {
+        Self {
+            id: NEXT_ALLOCATOR_ID.fetch_add(1, Ordering::Relaxed),
+            per_thread: false,
+            next: AtomicU64::new(first),
+            num_recycled: AtomicUsize::new(0),
+            recycled: [const { AtomicU64::new(0) }; RECYCLE_SLOTS],
+            occupied_disabled: AtomicU64::new(0),
+            disabled: [const { AtomicU64::new(0) }; DISABLED_SLOTS],
+        }
+    }
+
+    /// Returns an unused tag, or `None` if the tag space has been exhausted.
+    /// A recycled tag keeps the hint that it was recycled with.
+    pub fn fresh(&self) -> Option<BorTag> {
+        let block = &thread::current().tags;
+        if self.per_thread {
+            let n = block.counted.get() + 1;
+            let tag = clock::thread_scoped_id(n).filter(|&tag| tag <= BorTag::MAX)?;
+            block.counted.set(n);
+            self.next.fetch_add(1, Ordering::Relaxed);
+            return Some(BorTag(tag));
+        }
+        if let Some(tag) = self.take_recycled() {
+            return Some(tag);
+        }
+        let next = block.next.get();
+        if block.allocator.get() == self.id && next < block.end.get() {
+            block.next.set(next + 1);
+            return Some(BorTag(next));
+        }
+        self.reserve_block(block)
+    }
+
+    // Reserves the next block of tags for the current thread, and returns its
+    // first one.
+    #[cold]
+    fn reserve_block(&self, block: &TagBlock) -> Option<BorTag> {
+        // The counter saturates at `BorTag::MAX`, which is never handed out, so
+        // the last block may be shorter. Another thread may have recycled a tag
+        // while we were racing on the counter.
+        let Ok(first) = self.next.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+            (next != BorTag::MAX).then(|| next + TAG_BLOCK.min(BorTag::MAX - next))
+        }) else {
+            return self.take_recycled();
+        };
+        block.allocator.set(self.id);
+        block.next.set(first + 1);
+        block.end.set(first + TAG_BLOCK.min(BorTag::MAX - first));
+        Some(BorTag(first))
+    }
+
+    /// Makes `tag` available for reuse, along with its hint. This must only be
+    /// called once no pointer can carry `tag` anymore. Returns `false` if the
+    /// pool was full and the tag was discarded, as tags counted per thread
+    /// always are.
+    pub fn recycle(&self, tag: BorTag) -> bool {
+        debug_assert!(tag.is_valid());
+        self.enable(tag);
+        if self.per_thread {
+            return false;
+        }
+        for slot in &self.recycled {
+            if slot.compare_exchange(0, tag.0, Ordering::Release, Ordering::Relaxed).is_ok() {
+                self.num_recycled.fetch_add(1, Ordering::Release);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The number of fresh tags (excluding recycled ones) that have been issued,
+    /// including those that threads have reserved but not handed out yet.
+    pub fn issued(&self) -> u64 {
+        self.next.load(Ordering::Relaxed) - 1
+    }
+
+    /// Disables `tag`, so that accesses through it are no longer checked.
+    /// Returns `false` if `tag` is invalid, was already disabled, or if every
+    /// slot of its bucket is occupied.
+    pub fn disable(&self, tag: BorTag) -> bool {
+        if !tag.is_valid() || self.is_disabled(tag) {
+            return false;
+        }
+        for i in disabled_bucket(tag) {
+            let slot = &self.disabled[i];
+            if slot.compare_exchange(0, tag.0, Ordering::Release, Ordering::Relaxed).is_ok() {
+                self.occupied_disabled.fetch_or(1 << i, Ordering::Release);
+                return true;
+            }
+        }
+        false
+    }
+
+    #[inline]
+    pub fn is_disabled(&self, tag: BorTag) -> bool {
+        if !tag.is_valid() {
+            return false;
+        }
+        let bucket = disabled_bucket(tag);
+        let mask = ((1 << DISABLED_WAYS) - 1) << bucket.start;
+        self.occupied_disabled.load(Ordering::Acquire) & mask != 0
+            && self.disabled[bucket].iter().any(|slot| slot.load(Ordering::Acquire) == tag.0)
+    }
+
+    fn enable(&self, tag: BorTag) {
+        let bucket = disabled_bucket(tag);
+        let mask = ((1 << DISABLED_WAYS) - 1) << bucket.start;
+        if self.occupied_disabled.load(Ordering::Acquire) & mask == 0 {
+            return;
+        }
+        for i in bucket {
+            // Only the thread that recycles a tag removes it, so its slot
+            // can't be taken by another tag until it is cleared here.
+            if self.disabled[i].load(Ordering::Relaxed) == tag.0 {
+                self.occupied_disabled.fetch_and(!(1 << i), Ordering::Relaxed);
+                self.disabled[i].store(0, Ordering::Release);
+                return;
+            }
+        }
+    }
+
+    fn take_recycled(&self) -> Option<BorTag> {
+        if self.num_recycled.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        for slot in &self.recycled {
+            let tag = slot.swap(0, Ordering::Acquire);
+            if tag != 0 {
+                self.num_recycled.fetch_sub(1, Ordering::Relaxed);
+                return Some(BorTag(tag));
+            }
+        }
+        None
+    }
+}
+
+// The slots of `TagAllocator::disabled` that `tag` can be kept in.
+#[inline]
+fn disabled_bucket(tag: BorTag) -> Range<usize> {
+    let hash = tag.0.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (u64::BITS - DISABLED_BUCKETS.ilog2());
+    let start = hash as usize * DISABLED_WAYS;
+    start..start + DISABLED_WAYS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_tags_are_unique_and_nonzero() {
+        let tags = TagAllocator::new();
+        let a = tags.fresh().unwrap();
+        let b = tags.fresh().unwrap();
+        assert!(a.is_valid() && b.is_valid());
+        assert_ne!(a, b);
+        assert_eq!(tags.issued(), TAG_BLOCK);
+    }
+
+    #[test]
+    fn threads_take_tags_from_blocks_of_their_own() {
+        let tags = TagAllocator::new();
+        let mine: Vec<u64> = (0..TAG_BLOCK + 1).map(|_| tags.fresh().unwrap().get()).collect();
+        assert_eq!(mine[..TAG_BLOCK as usize], (1..=TAG_BLOCK).collect::<Vec<_>>()[..]);
+        let theirs = std::thread::scope(|scope| scope.spawn(|| tags.fresh().unwrap()).join());
+        assert_eq!(theirs.unwrap().get(), 2 * TAG_BLOCK + 1);
+        assert_eq!(tags.fresh().unwrap().get(), TAG_BLOCK + 2);
+        // A new allocator doesn't hand out the rest of another one's block.
+        assert_eq!(TagAllocator::new().fresh().unwrap().get(), 1);
+    }
+
+    #[test]
+    fn recycled_tags_are_reused() {
+        let tags = TagAllocator::new();
+        let a = tags.fresh().unwrap();
+        let _ = tags.fresh().unwrap();
+        assert!(tags.recycle(a));
+        assert_eq!(tags.fresh(), Some(a));
+        assert_eq!(tags.issued(), TAG_BLOCK);
+    }
+
+    #[test]
+    fn exhaustion_is_reported() {
+        let tags = TagAllocator::starting_at(BorTag::MAX - 1);
+        let last = tags.fresh().unwrap();
+        assert_eq!(last.get(), BorTag::MAX - 1);
+        assert_eq!(tags.fresh(), None);
+        assert!(tags.recycle(last));
+        assert_eq!(tags.fresh(), Some(last));
+        assert_eq!(tags.fresh(), None);
+    }
+
+    #[test]
+    fn disabled_tags_are_enabled_when_recycled() {
+        let tags = TagAllocator::new();
+        let a = tags.fresh().unwrap();
+        let b = tags.fresh().unwrap();
+        assert!(tags.disable(a));
+        assert!(!tags.disable(a));
+        assert!(!tags.disable(BorTag::INVALID));
+        assert!(tags.is_disabled(a) && !tags.is_disabled(b));
+        assert!(tags.recycle(a));
+        assert!(!tags.is_disabled(a));
+    }
+
+    #[test]
+    fn tags_are_disabled_within_their_bucket() {
+        let tags = TagAllocator::new();
+        let first = tags.fresh().unwrap();
+        let same_bucket: Vec<BorTag> = (0..)
+            .map(|_| tags.fresh().unwrap())
+            .filter(|&tag| disabled_bucket(tag) == disabled_bucket(first))
+            .take(DISABLED_WAYS)
+            .collect();
+        assert!(tags.disable(first));
+        for &tag in &same_bucket[..DISABLED_WAYS - 1] {
+            assert!(tags.disable(tag));
+        }
+        // The bucket is full, while the others are still empty.
+        let last = same_bucket[DISABLED_WAYS - 1];
+        assert!(!tags.disable(last) && !tags.is_disabled(last));
+        assert_eq!(
+            tags.occupied_disabled.load(Ordering::Relaxed).count_ones(),
+            DISABLED_WAYS as u32
+        );
+        assert!(tags.recycle(first));
+        assert!(tags.disable(last) && tags.is_disabled(last));
+        assert!(!tags.is_disabled(first));
+    }
+
+    #[test]
+    fn tags_counted_per_thread_are_not_reused() {
+        let tags = TagAllocator::per_thread();
+        let a = tags.fresh().unwrap();
+        let b = tags.fresh().unwrap();
+        assert_eq!(b.get(), a.get() + 1);
+        assert!(!tags.recycle(a));
+        assert_ne!(tags.fresh(), Some(a));
+        // Another thread starts from its own first tag.
+        let first = || (tags.fresh().unwrap().get(), clock::thread_scoped_id(1).unwrap());
+        let (other, expected) = std::thread::scope(|scope| scope.spawn(first).join().unwrap());
+        assert_eq!(other, expected);
+        assert_ne!(other, a.get());
+        assert_eq!(tags.issued(), 4);
+    }
+
+    #[test]
+    fn full_pool_discards_tags() {
+        let tags = TagAllocator::new();
+        let issued: Vec<BorTag> = (0..RECYCLE_SLOTS).map(|_| tags.fresh().unwrap()).collect();
+        for tag in issued {
+            assert!(tags.recycle(tag));
+        }
+        assert!(!tags.recycle(BorTag::new(u64::MAX - 1)));
+    }
+
+    #[test]
+    fn hints_are_not_part_of_the_number() {
+        let tags = TagAllocator::new();
+        let tag = tags.fresh().unwrap();
+        let root = tag.with_hint(TagHint::ROOT);
+        assert_ne!(root, tag);
+        assert_eq!(root.get(), tag.get());
+        assert!(root.hint().contains(TagHint::ROOT) && !tag.hint().contains(TagHint::ROOT));
+        assert_eq!(root.with_hint(TagHint::NONE), tag);
+        // Recycled tags are handed out again with their hint.
+        assert!(tags.recycle(root));
+        assert_eq!(tags.fresh(), Some(root));
+    }
+}
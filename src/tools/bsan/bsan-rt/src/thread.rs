@@ -0,0 +1,62 @@
+//! The state that the runtime keeps for each thread of the program, so that
+//! the hooks on its hot paths rarely touch state that other threads share.
+//!
+//! A [`ThreadContext`] holds the thread's call stack, with its stack
+//! allocations and protectors, the shadow chunks that it resolved last, the
+//! last access check that it passed, and the blocks of allocation IDs and tags
+//! that it hands out allocations and retags from. It lives in thread-local
+//! storage and is initialized by the loader like any other, so it exists as
+//! soon as a thread does, without a hook having to create it, and threads that
+//! the runtime never hears about work the same. `bsan_thread_exit`
+//! [`flush`](ThreadContext::flush)es it, which retires the stack allocations
+//! that the thread left behind, and so does `bsan_thread_start`, in case the
+//! thread ran hooks before it.
+
+use crate::access::AccessCache;
+use crate::frame::{self, FrameStack};
+use crate::global::{AllocIdBlock, GlobalContext};
+use crate::shadow::{self, ChunkCache};
+use crate::tag::TagBlock;
+
+#[derive(Debug)]
+pub struct ThreadContext {
+    pub frames: FrameStack,
+    pub chunk_cache: ChunkCache,
+    pub access_cache: AccessCache,
+    pub alloc_ids: AllocIdBlock,
+    pub tags: TagBlock,
+}
+
+impl ThreadContext {
+    pub const fn new() -> Self {
+        Self {
+            frames: FrameStack::new(),
+            chunk_cache: ChunkCache::new(),
+            access_cache: AccessCache::new(),
+            alloc_ids: AllocIdBlock::new(),
+            tags: TagBlock::new(),
+        }
+    }
+
+    /// Returns from every frame of the thread, retiring its stack allocations,
+    /// and empties its chunk cache and counts the hits on its caches, as when
+    /// it starts or exits.
+    pub unsafe fn flush(&self, ctx: &GlobalContext) {
+        frame::unwind(ctx);
+        shadow::flush_thread_cache();
+        self.access_cache.flush_hits();
+    }
+}
+
+#[thread_local]
+static THREAD_CTX: ThreadContext = ThreadContext::new();
+
+/// The context of the current thread.
+#[inline(always)]
+pub fn current() -> &'static ThreadContext {
+    // A thread-local can't be borrowed for `'static`, since the thread may
+    // exit. The context isn't `Sync`, so the reference can't be sent to
+    // another thread that outlives this one.
+    let ctx: *const ThreadContext = &raw const THREAD_CTX;
+    unsafe { &*ctx }
+}
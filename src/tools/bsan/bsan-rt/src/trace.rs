@@ -0,0 +1,223 @@
+//! A mode that records when each hook runs, for viewing in `chrome://tracing`
+//! or Perfetto alongside the program's own phases.
+//!
+//! When `BSAN_TRACE_PATH` is set, every hook that enters the runtime records
+//! an event with the thread that ran it, when it started and how long it took.
+//! Events go into a buffer of `BSAN_TRACE_EVENTS` of them (default: 1M), which
+//! is mapped once and only backed by memory as it fills up; those that don't
+//! fit are dropped and counted. At exit, the buffer is written to the path as
+//! a JSON object in the Chrome `trace_event` format, with one complete event
+//! (`"ph":"X"`) per call, in microseconds since the runtime was initialized.
+//! A child that is forked while tracing writes its own events, and those that
+//! its parent recorded before the fork, to the path with `.<pid>` appended.
+//!
+//! Threads claim slots with a shared counter, so tracing slows down programs
+//! with many threads more than the rest of the runtime does. It's meant to show
+//! where the time in the runtime goes, not to be left on.
+
+use core::ffi::{CStr, c_char};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::{mem, ptr};
+
+use crate::alloc::BsanAllocator;
+use crate::clock::ThreadId;
+use crate::host;
+use crate::io::{self, CPathBuf, FdWriter};
+use crate::sync::SpinLock;
+
+const DEFAULT_EVENTS: usize = 1 << 20;
+
+// One call of a hook. The name is stored last, so an event whose name is null
+// is still being written, or was never claimed.
+#[derive(Debug)]
+struct Event {
+    name: AtomicPtr<c_char>,
+    thread: AtomicU32,
+    start: AtomicU64,
+    duration: AtomicU64,
+}
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+static EVENTS: AtomicPtr<Event> = AtomicPtr::new(ptr::null_mut());
+static CAPACITY: AtomicUsize = AtomicUsize::new(0);
+// The number of events that were claimed, including those that didn't fit.
+static CLAIMED: AtomicUsize = AtomicUsize::new(0);
+static STARTED_AT: AtomicU64 = AtomicU64::new(0);
+
+// Where the trace is written, and the process that started recording it.
+struct Trace {
+    path: CPathBuf,
+    pid: libc::pid_t,
+}
+
+static TRACE: SpinLock<Option<Trace>> = SpinLock::new(None);
+
+/// Whether hook events are being recorded.
+#[inline(always)]
+pub fn tracing() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+/// Starts recording events if `BSAN_TRACE_PATH` is set, with a buffer mapped
+/// by `allocator`. Returns whether it did, in which case [`dump`] should be
+/// called at exit.
+///
+/// # Safety
+/// No other thread may be using the runtime.
+pub unsafe fn start_from_env(allocator: &BsanAllocator) -> bool {
+    let Some(path) = io::env_path(c"BSAN_TRACE_PATH") else { return false };
+    let capacity = io::env_parse(c"BSAN_TRACE_EVENTS", DEFAULT_EVENTS);
+    let Some(len) = capacity.checked_mul(mem::size_of::<Event>()).filter(|&len| len != 0) else {
+        return false;
+    };
+    let events = allocator.map_anonymous(
+        ptr::null_mut(),
+        len,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_NORESERVE,
+    );
+    if events.is_null() {
+        let _ = writeln!(FdWriter::log(), "bsan: could not map a trace buffer of {len} bytes");
+        return false;
+    }
+    *TRACE.lock() = Some(Trace { path, pid: libc::getpid() });
+    EVENTS.store(events.cast(), Ordering::Relaxed);
+    CAPACITY.store(capacity, Ordering::Relaxed);
+    CLAIMED.store(0, Ordering::Relaxed);
+    STARTED_AT.store(host::now_ns(), Ordering::Relaxed);
+    TRACING.store(true, Ordering::Release);
+    true
+}
+
+/// Records the call of a hook from when it is entered until it is dropped,
+/// if events are being recorded.
+#[derive(Debug)]
+pub struct Span {
+    name: &'static CStr,
+    start: u64,
+}
+
+impl Span {
+    #[inline(always)]
+    pub fn enter(name: &'static CStr) -> Option<Span> {
+        if !tracing() {
+            return None;
+        }
+        Some(Span { name, start: host::now_ns() })
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let end = host::now_ns();
+        record(self.name, self.start, end.saturating_sub(self.start));
+    }
+}
+
+fn record(name: &'static CStr, start: u64, duration: u64) {
+    let index = CLAIMED.fetch_add(1, Ordering::Relaxed);
+    if index >= CAPACITY.load(Ordering::Relaxed) || !tracing() {
+        return;
+    }
+    let event = unsafe { &*EVENTS.load(Ordering::Relaxed).add(index) };
+    event.thread.store(ThreadId::current().get(), Ordering::Relaxed);
+    event.start.store(start, Ordering::Relaxed);
+    event.duration.store(duration, Ordering::Relaxed);
+    event.name.store(name.as_ptr().cast_mut(), Ordering::Release);
+}
+
+/// Stops recording events and writes those that were recorded to
+/// `BSAN_TRACE_PATH`. Events that other threads are still writing are left
+/// out.
+///
+/// # Safety
+/// Must only be called after [`start_from_env`] returned `true`.
+pub unsafe fn dump() {
+    TRACING.store(false, Ordering::Relaxed);
+    let Some(trace) = TRACE.lock().take() else { return };
+    let pid = libc::getpid();
+    let mut path = trace.path;
+    if pid != trace.pid && write!(path, ".{pid}").is_err() {
+        return;
+    }
+    let Some(tmp_path) = path.with_suffix(b".tmp") else { return };
+    let capacity = CAPACITY.load(Ordering::Relaxed);
+    let claimed = CLAIMED.load(Ordering::Relaxed);
+    let events = core::slice::from_raw_parts(EVENTS.load(Ordering::Relaxed), claimed.min(capacity));
+    let written = io::write_atomically(&path, &tmp_path, |out| {
+        write_json(out, events, STARTED_AT.load(Ordering::Relaxed), pid)
+    });
+    let mut log = FdWriter::log();
+    if !written {
+        let _ = writeln!(log, "bsan: could not write the trace to {path:?}");
+    } else if claimed > capacity {
+        let _ = writeln!(
+            log,
+            "bsan: the trace holds the first {capacity} events; {} more were dropped",
+            claimed - capacity
+        );
+    }
+}
+
+fn write_json(out: &mut impl Write, events: &[Event], started_at: u64, pid: i32) -> fmt::Result {
+    out.write_str("{\"displayTimeUnit\":\"ns\",\"traceEvents\":[")?;
+    let mut first = true;
+    for event in events {
+        let name = event.name.load(Ordering::Acquire);
+        if name.is_null() {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap_or("?");
+        let start = event.start.load(Ordering::Relaxed).saturating_sub(started_at);
+        let duration = event.duration.load(Ordering::Relaxed);
+        if !first {
+            out.write_char(',')?;
+        }
+        first = false;
+        // Hook names are identifiers, so they need no escaping.
+        write!(
+            out,
+            "\n{{\"name\":\"{name}\",\"cat\":\"bsan\",\"ph\":\"X\",\"ts\":{}.{:03},\
+             \"dur\":{}.{:03},\"pid\":{pid},\"tid\":{}}}",
+            start / 1000,
+            start % 1000,
+            duration / 1000,
+            duration % 1000,
+            event.thread.load(Ordering::Relaxed),
+        )?;
+    }
+    out.write_str("\n]}\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_written_as_complete_events() {
+        let events = [
+            Event {
+                name: AtomicPtr::new(c"bsan_read".as_ptr().cast_mut()),
+                thread: AtomicU32::new(1),
+                start: AtomicU64::new(1_001_500),
+                duration: AtomicU64::new(250),
+            },
+            Event {
+                name: AtomicPtr::new(ptr::null_mut()),
+                thread: AtomicU32::new(0),
+                start: AtomicU64::new(0),
+                duration: AtomicU64::new(0),
+            },
+        ];
+        let mut out = String::new();
+        write_json(&mut out, &events, 1_000_000, 42).unwrap();
+        assert_eq!(
+            out,
+            "{\"displayTimeUnit\":\"ns\",\"traceEvents\":[\n\
+             {\"name\":\"bsan_read\",\"cat\":\"bsan\",\"ph\":\"X\",\"ts\":1.500,\"dur\":0.250,\
+             \"pid\":42,\"tid\":1}\n]}\n"
+        );
+    }
+}